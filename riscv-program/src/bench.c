@@ -0,0 +1,113 @@
+#include "syscalls.h"
+
+// Dhrystone-like mix of integer, string, array and branch heavy loops, used to
+// measure the emulator speed (run it with --stats). Everything is done with adds
+// and shifts so the numbers stay comparable with the rv32i builds.
+
+#define ITERATIONS 2000
+#define ARRAY_SIZE 64
+
+typedef struct record {
+  struct record* next;
+  int kind;
+  int value;
+  char name[16];
+} record_t;
+
+static record_t records[2];
+static int array[ARRAY_SIZE];
+static char string_a[32];
+static char string_b[32];
+
+static void copy_string(char* dst, const char* src) {
+  while ((*dst++ = *src++) != 0) {
+  }
+}
+
+static int compare_string(const char* a, const char* b) {
+  while (*a && *a == *b) {
+    a++;
+    b++;
+  }
+  return *a - *b;
+}
+
+static unsigned crc32(const char* data, int size) {
+  unsigned crc = 0xffffffff;
+
+  for (int i = 0; i < size; i++) {
+    crc ^= (unsigned char)data[i];
+    for (int bit = 0; bit < 8; bit++) {
+      crc = (crc >> 1) ^ (0xedb88320 & -(crc & 1));
+    }
+  }
+
+  return ~crc;
+}
+
+static void bubble_sort(int* values, int size) {
+  for (int i = 0; i < size - 1; i++) {
+    for (int j = 0; j < size - 1 - i; j++) {
+      if (values[j] > values[j + 1]) {
+        int tmp = values[j];
+        values[j] = values[j + 1];
+        values[j + 1] = tmp;
+      }
+    }
+  }
+}
+
+static int update_record(record_t* record, int value) {
+  record->next->value = record->value + value;
+  record->kind = (record->kind + 1) & 3;
+
+  switch (record->kind) {
+    case 0:
+      return value << 1;
+    case 1:
+      return value >> 1;
+    case 2:
+      return value ^ 0x5a5a;
+    default:
+      return value + record->next->value;
+  }
+}
+
+static void put_hex(unsigned value) {
+  char buffer[12] = "0x00000000\n";
+
+  for (int i = 9; i > 1; i--) {
+    buffer[i] = "0123456789abcdef"[value & 0xf];
+    value >>= 4;
+  }
+
+  syscall_puts(buffer);
+}
+
+int main() {
+  unsigned checksum = 0;
+
+  records[0].next = &records[1];
+  records[1].next = &records[0];
+
+  for (int iteration = 0; iteration < ITERATIONS; iteration++) {
+    copy_string(string_a, "DHRYSTONE PROGRAM, 1'ST STRING");
+    copy_string(string_b, "DHRYSTONE PROGRAM, 2'ND STRING");
+    checksum += compare_string(string_a, string_b);
+
+    checksum ^= crc32(string_a, 30);
+
+    for (int i = 0; i < ARRAY_SIZE; i++) {
+      array[i] = (int)((checksum >> (i & 15)) ^ (unsigned)(i << 3)) & 0xfff;
+    }
+    bubble_sort(array, ARRAY_SIZE);
+    checksum += array[0] + array[ARRAY_SIZE - 1];
+
+    checksum += update_record(&records[iteration & 1], iteration);
+  }
+
+  syscall_puts("checksum ");
+  put_hex(checksum);
+
+  return 0;
+}
@@ -1,21 +1,49 @@
 #include "boot.h"
 #include "syscalls.h"
+#include <stdint.h>
 #include <stdio.h>
 
 int keep_me;
 
 extern int main(void);
 
-void reset_handler()
-{
-    syscall_exit(main());
-}
+void reset_handler();
 
 handler_t handlers __attribute__((section(".handler_table"))) = {
     .reset = reset_handler,
-    .idk1 = NULL,
-    .idk2 = NULL,
-    .idk3 = NULL,
+    .software_interrupt = NULL,
+    .timer_interrupt = NULL,
+    .external_interrupt = NULL,
 };
 
+void __attribute__((interrupt("machine"), aligned(4))) trap_handler()
+{
+    uint32_t mcause;
+    asm volatile("csrr %0, mcause" : "=r"(mcause));
+
+    void (*handler)(void) = NULL;
+    switch (mcause) {
+    case 0x80000003:
+        handler = handlers.software_interrupt;
+        break;
+    case 0x80000007:
+        handler = handlers.timer_interrupt;
+        break;
+    case 0x8000000b:
+        handler = handlers.external_interrupt;
+        break;
+    }
+
+    if (handler == NULL) {
+        syscall_exit(-1);
+    }
+    handler();
+}
+
+void reset_handler()
+{
+    asm volatile("csrw mtvec, %0" : : "r"(trap_handler));
+    syscall_exit(main());
+}
+
 
@@ -1,7 +1,8 @@
 typedef struct
 {
     void (*reset)(void);
-    void (*idk1)(void);
-    void (*idk2)(void);
-    void (*idk3)(void);
+    // called from the machine trap handler, NULL when unused
+    void (*software_interrupt)(void);
+    void (*timer_interrupt)(void);
+    void (*external_interrupt)(void);
 } handler_t;
\ No newline at end of file
@@ -0,0 +1,15 @@
+#include <stdint.h>
+
+// core local interruptor of the emulator (--clint-base changes the address)
+#define CLINT_BASE 0x2000000
+
+#define CLINT_MSIP (*(volatile uint32_t*)(CLINT_BASE + 0x0))
+#define CLINT_MTIMECMP_LO (*(volatile uint32_t*)(CLINT_BASE + 0x4000))
+#define CLINT_MTIMECMP_HI (*(volatile uint32_t*)(CLINT_BASE + 0x4004))
+#define CLINT_MTIME_LO (*(volatile uint32_t*)(CLINT_BASE + 0xbff8))
+#define CLINT_MTIME_HI (*(volatile uint32_t*)(CLINT_BASE + 0xbffc))
+
+#define MIE_MSIE (1 << 3)
+#define MIE_MTIE (1 << 7)
+#define MIE_MEIE (1 << 11)
+#define MSTATUS_MIE (1 << 3)
@@ -0,0 +1,15 @@
+#include <stdint.h>
+
+// framebuffer of the emulator (--framebuffer enables it)
+#define FB_CONTROL_BASE 0x10070000
+
+#define FB_WIDTH (*(volatile uint32_t*)(FB_CONTROL_BASE + 0x00))
+#define FB_HEIGHT (*(volatile uint32_t*)(FB_CONTROL_BASE + 0x04))
+#define FB_FORMAT (*(volatile uint32_t*)(FB_CONTROL_BASE + 0x08))
+#define FB_STRIDE (*(volatile uint32_t*)(FB_CONTROL_BASE + 0x0c))
+#define FB_ADDRESS (*(volatile uint32_t*)(FB_CONTROL_BASE + 0x10))
+#define FB_PRESENT (*(volatile uint32_t*)(FB_CONTROL_BASE + 0x14))
+
+#define FB_FORMAT_XRGB8888 0
+#define FB_FORMAT_RGB888 1
+#define FB_FORMAT_RGB565 2
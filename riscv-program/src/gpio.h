@@ -0,0 +1,14 @@
+#include <stdint.h>
+
+// GPIO block of the emulator (--gpio, --gpio-log or --gpio-stimulus enable it)
+#define GPIO_BASE 0x10060000
+#define GPIO_IRQ 11
+
+#define GPIO_INPUT (*(volatile uint32_t*)(GPIO_BASE + 0x00))
+#define GPIO_DIRECTION (*(volatile uint32_t*)(GPIO_BASE + 0x04))
+#define GPIO_OUTPUT (*(volatile uint32_t*)(GPIO_BASE + 0x08))
+#define GPIO_RISE_IE (*(volatile uint32_t*)(GPIO_BASE + 0x0c))
+#define GPIO_FALL_IE (*(volatile uint32_t*)(GPIO_BASE + 0x10))
+#define GPIO_IP (*(volatile uint32_t*)(GPIO_BASE + 0x14))
+
+#define GPIO_PIN(n) (1u << (n))
@@ -0,0 +1,11 @@
+#include <stdint.h>
+
+// platform-level interrupt controller of the emulator (--plic-base changes the address)
+#define PLIC_BASE 0xc000000
+
+#define PLIC_PRIORITY(source) (*(volatile uint32_t*)(PLIC_BASE + 4 * (source)))
+#define PLIC_PENDING(source) (*(volatile uint32_t*)(PLIC_BASE + 0x1000 + 4 * ((source) / 32)))
+#define PLIC_ENABLE(source) (*(volatile uint32_t*)(PLIC_BASE + 0x2000 + 4 * ((source) / 32)))
+#define PLIC_THRESHOLD (*(volatile uint32_t*)(PLIC_BASE + 0x200000))
+// reading claims the highest priority pending source, writing it back completes it
+#define PLIC_CLAIM (*(volatile uint32_t*)(PLIC_BASE + 0x200004))
@@ -11,4 +11,8 @@ void syscall_exit(int exit_code) {
 
 void syscall_puts(char* str) {
   (void)interrupt(SYSCALL_PUTS, str, strlen(str));
+}
+
+void syscall_eputs(char* str) {
+  (void)interrupt(SYSCALL_EPUTS, str, strlen(str));
 }
\ No newline at end of file
@@ -3,6 +3,7 @@
 #define SYSCALL_READ_INPUT 0
 #define SYSCALL_EXIT 1
 #define SYSCALL_PUTS 2
+#define SYSCALL_EPUTS 3
 
 // to communicate with the kernel/emulator the first argument is the syscall
 // identifier
@@ -10,4 +11,5 @@ extern int interrupt(uint8_t id, ...);
 
 int syscall_read(uint8_t* buffer, uint32_t buffer_size);
 void syscall_exit(int exit_code);
-void syscall_puts(char* str);
\ No newline at end of file
+void syscall_puts(char* str);
+void syscall_eputs(char* str);
\ No newline at end of file
@@ -3,14 +3,14 @@
 int main() {
   char data;
 
-  syscall_puts("hello");
+  syscall_puts("hello\n");
 
   syscall_read(&data, sizeof(data));
 
   if (data == 1) {
-    syscall_puts("Received value 1");
+    syscall_puts("Received value 1\n");
   } else {
-    syscall_puts("Received another value");
+    syscall_puts("Received another value\n");
   }
 
   return 4 + 1;
@@ -0,0 +1,19 @@
+#include <stdint.h>
+
+// 16550 UART of the emulator (--uart enables it, --uart-base changes the address)
+#define UART_BASE 0x10000000
+#define UART_IRQ 10
+
+#define UART_RBR (*(volatile uint8_t*)(UART_BASE + 0))
+#define UART_THR (*(volatile uint8_t*)(UART_BASE + 0))
+#define UART_IER (*(volatile uint8_t*)(UART_BASE + 1))
+#define UART_IIR (*(volatile uint8_t*)(UART_BASE + 2))
+#define UART_FCR (*(volatile uint8_t*)(UART_BASE + 2))
+#define UART_LCR (*(volatile uint8_t*)(UART_BASE + 3))
+#define UART_MCR (*(volatile uint8_t*)(UART_BASE + 4))
+#define UART_LSR (*(volatile uint8_t*)(UART_BASE + 5))
+
+#define UART_IER_RX (1 << 0)
+#define UART_IER_THRE (1 << 1)
+#define UART_LSR_DATA_READY (1 << 0)
+#define UART_LSR_THRE (1 << 5)
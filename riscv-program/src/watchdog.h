@@ -0,0 +1,13 @@
+#include <stdint.h>
+
+// watchdog of the emulator (--watchdog enables it)
+#define WATCHDOG_BASE 0x10080000
+
+#define WATCHDOG_CTRL (*(volatile uint32_t*)(WATCHDOG_BASE + 0x00))
+#define WATCHDOG_TIMEOUT (*(volatile uint32_t*)(WATCHDOG_BASE + 0x04))
+#define WATCHDOG_KICK (*(volatile uint32_t*)(WATCHDOG_BASE + 0x08))
+#define WATCHDOG_COUNT (*(volatile uint32_t*)(WATCHDOG_BASE + 0x0c))
+#define WATCHDOG_STATUS (*(volatile uint32_t*)(WATCHDOG_BASE + 0x10))
+
+#define WATCHDOG_CTRL_ENABLE (1 << 0)
+#define WATCHDOG_STATUS_RESET (1 << 0)
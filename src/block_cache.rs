@@ -0,0 +1,383 @@
+use std::{
+    cell::{Cell, RefCell},
+    collections::{HashMap, HashSet},
+    rc::{Rc, Weak},
+};
+
+#[cfg(feature = "jit")]
+use crate::jit::JitState;
+use crate::vm::VM;
+
+// writes are tracked with this granularity to find the blocks they invalidate
+const CODE_PAGE_SHIFT: usize = 8;
+
+pub type MicroOpHandler = fn(&mut VM, &MicroOp);
+
+// an instruction with its operands resolved at translation time
+#[derive(Clone, Copy)]
+pub struct MicroOp {
+    pub handler: MicroOpHandler,
+    pub rd: u8,
+    pub rs1: u8,
+    pub rs2: u8,
+    pub imm: u32,
+    pub pc: u32,
+    // memory accesses, ecalls and system instructions, they may overwrite translated code or
+    // leave the block to be executed by the interpreter
+    pub side_exit: bool,
+}
+
+// straight-line sequence of instructions ending in a jump, ecall or system instruction
+pub struct Block {
+    start: u32,
+    // address after the last instruction
+    end: u32,
+    ops: Vec<MicroOp>,
+    // the last op sets the pc itself, otherwise the execution falls through to end
+    ends_with_jump: bool,
+    // the successors are known at translation time (branches, jal), so they can be chained
+    direct_exits: bool,
+    valid: Cell<bool>,
+    links: RefCell<Vec<(u32, Weak<Block>)>>,
+    #[cfg(feature = "jit")]
+    jit: RefCell<JitState>,
+}
+
+impl Block {
+    pub fn new(start: u32, ops: Vec<MicroOp>, ends_with_jump: bool, direct_exits: bool) -> Self {
+        Self {
+            start,
+            end: start + 4 * ops.len() as u32,
+            ops,
+            ends_with_jump,
+            direct_exits,
+            valid: Cell::new(true),
+            links: RefCell::new(Vec::new()),
+            #[cfg(feature = "jit")]
+            jit: RefCell::new(JitState::Cold(0)),
+        }
+    }
+
+    pub fn get_start(&self) -> u32 {
+        self.start
+    }
+
+    pub fn get_end(&self) -> u32 {
+        self.end
+    }
+
+    pub fn get_ops(&self) -> &[MicroOp] {
+        &self.ops
+    }
+
+    pub fn ends_with_jump(&self) -> bool {
+        self.ends_with_jump
+    }
+
+    #[cfg(feature = "jit")]
+    pub fn get_jit(&self) -> &RefCell<JitState> {
+        &self.jit
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.valid.get()
+    }
+
+    // successor previously chained to this block, skips the block lookup
+    pub fn get_link(&self, pc: u32) -> Option<Rc<Block>> {
+        self.links
+            .borrow()
+            .iter()
+            .find(|(target, _)| *target == pc)
+            .and_then(|(_, block)| block.upgrade())
+            .filter(|block| block.is_valid())
+    }
+
+    pub fn add_link(&self, block: &Rc<Block>) {
+        if !self.direct_exits {
+            return;
+        }
+
+        let mut links = self.links.borrow_mut();
+        links.retain(|(target, _)| *target != block.start);
+        // a branch has two successors, jal only one
+        if links.len() < 2 {
+            links.push((block.start, Rc::downgrade(block)));
+        }
+    }
+}
+
+// code pages overlapping the bytes from start to end (excluded)
+fn code_pages(start: usize, end: usize) -> std::ops::RangeInclusive<usize> {
+    (start >> CODE_PAGE_SHIFT)..=((end - 1) >> CODE_PAGE_SHIFT)
+}
+
+pub struct BlockCache {
+    blocks: HashMap<u32, Rc<Block>>,
+    // block start addresses overlapping each code page
+    code_pages: HashMap<usize, HashSet<u32>>,
+    // address range covered by blocks, writes outside of it are ignored quickly
+    code_start: usize,
+    code_end: usize,
+    enabled: bool,
+    // set when a write invalidated blocks, the block being executed may be stale
+    invalidated: bool,
+    translated: u64,
+    invalidations: u64,
+}
+
+impl Default for BlockCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BlockCache {
+    pub fn new() -> Self {
+        Self {
+            blocks: HashMap::new(),
+            code_pages: HashMap::new(),
+            code_start: usize::MAX,
+            code_end: 0,
+            enabled: true,
+            invalidated: false,
+            translated: 0,
+            invalidations: 0,
+        }
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        self.flush();
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn get(&self, pc: u32) -> Option<Rc<Block>> {
+        self.blocks.get(&pc).cloned()
+    }
+
+    pub fn insert(&mut self, block: Block) -> Rc<Block> {
+        let start = block.start as usize;
+        let end = block.end as usize;
+
+        // a re-translated block may not cover the same pages
+        if let Some(old) = self.blocks.remove(&block.start) {
+            old.valid.set(false);
+            self.remove_pages(&old);
+        }
+
+        for page in code_pages(start, end) {
+            self.code_pages.entry(page).or_default().insert(block.start);
+        }
+        self.code_start = self.code_start.min(start);
+        self.code_end = self.code_end.max(end);
+        self.translated += 1;
+
+        let block = Rc::new(block);
+        self.blocks.insert(block.start, block.clone());
+        block
+    }
+
+    fn remove_pages(&mut self, block: &Block) {
+        for page in code_pages(block.start as usize, block.end as usize) {
+            if let Some(starts) = self.code_pages.get_mut(&page) {
+                starts.remove(&block.start);
+                if starts.is_empty() {
+                    self.code_pages.remove(&page);
+                }
+            }
+        }
+    }
+
+    // drops the blocks overlapping the written bytes
+    pub fn invalidate(&mut self, address: usize, size: usize) {
+        if size == 0 || address >= self.code_end || address + size <= self.code_start {
+            return;
+        }
+
+        for page in code_pages(address, address + size) {
+            let Some(starts) = self.code_pages.get(&page) else {
+                continue;
+            };
+
+            let overlapping: Vec<_> = starts
+                .iter()
+                .filter_map(|start| self.blocks.get(start))
+                .filter(|block| {
+                    address < block.end as usize && address + size > block.start as usize
+                })
+                .cloned()
+                .collect();
+            for block in overlapping {
+                block.valid.set(false);
+                self.blocks.remove(&block.start);
+                // a block spanning several pages is removed from all of them
+                self.remove_pages(&block);
+                self.invalidated = true;
+                self.invalidations += 1;
+            }
+        }
+    }
+
+    pub fn flush(&mut self) {
+        for block in self.blocks.values() {
+            block.valid.set(false);
+        }
+        self.blocks.clear();
+        self.code_pages.clear();
+        self.code_start = usize::MAX;
+        self.code_end = 0;
+        self.invalidated = true;
+    }
+
+    pub fn clear_invalidated(&mut self) {
+        self.invalidated = false;
+    }
+
+    // whether blocks were invalidated since the last clear_invalidated
+    pub fn was_invalidated(&self) -> bool {
+        self.invalidated
+    }
+
+    pub fn get_translated(&self) -> u64 {
+        self.translated
+    }
+
+    pub fn get_invalidations(&self) -> u64 {
+        self.invalidations
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{stop_conditions::StopReason, test_utils::*};
+
+    const SP: u32 = 2;
+    const T0: u32 = 5;
+    const T1: u32 = 6;
+    const T2: u32 = 7;
+    const S0: u32 = 8;
+    const S1: u32 = 9;
+
+    fn nop(_: &mut VM, _: &MicroOp) {}
+
+    fn block(start: u32, instructions: usize) -> Block {
+        let op = MicroOp {
+            handler: nop,
+            rd: 0,
+            rs1: 0,
+            rs2: 0,
+            imm: 0,
+            pc: 0,
+            side_exit: false,
+        };
+        Block::new(start, vec![op; instructions], false, false)
+    }
+
+    fn pages_of(cache: &BlockCache, start: u32) -> Vec<usize> {
+        let mut pages: Vec<_> = cache
+            .code_pages
+            .iter()
+            .filter(|(_, starts)| starts.contains(&start))
+            .map(|(page, _)| *page)
+            .collect();
+        pages.sort();
+        pages
+    }
+
+    #[test]
+    fn replaced_block_leaves_the_pages_it_no_longer_covers() {
+        let mut cache = BlockCache::new();
+        let old = cache.insert(block(0x1f0, 0x60));
+        assert_eq!(pages_of(&cache, 0x1f0), vec![1, 2, 3]);
+
+        let new = cache.insert(block(0x1f0, 2));
+        assert!(!old.is_valid());
+        assert!(new.is_valid());
+        assert_eq!(pages_of(&cache, 0x1f0), vec![1]);
+        assert_eq!(cache.code_pages.len(), 1);
+
+        // writes to the pages of the old block don't find anything anymore
+        cache.invalidate(0x300, 4);
+        assert!(new.is_valid());
+        assert_eq!(cache.get_invalidations(), 0);
+    }
+
+    #[test]
+    fn invalidated_block_leaves_every_page() {
+        let mut cache = BlockCache::new();
+        let spanning = cache.insert(block(0x1f0, 0x60));
+        let other = cache.insert(block(0x400, 4));
+
+        cache.invalidate(0x340, 1);
+        assert!(!spanning.is_valid());
+        assert!(other.is_valid());
+        assert!(cache.get(0x1f0).is_none());
+        assert!(pages_of(&cache, 0x1f0).is_empty());
+        assert_eq!(cache.get_invalidations(), 1);
+        assert!(cache.was_invalidated());
+
+        // writes next to a block don't invalidate it
+        cache.invalidate(0x410, 4);
+        assert!(other.is_valid());
+    }
+
+    #[test]
+    fn translated_blocks_match_the_interpreter() {
+        let mut program = vec![
+            addi(T0, 0, 100),
+            addi(A1, 0, 0),
+            add(A1, A1, T0),
+            xor(T1, A1, T0),
+            slli(T1, T1, 3),
+            add(A1, A1, T1),
+            sw(A1, SP, -16),
+            lw(T2, SP, -16),
+            sub(A1, T2, T0),
+            addi(T0, T0, -1),
+            bne(T0, 0, -32),
+        ];
+        program.extend(exit());
+
+        let (interpreted_reason, interpreted) = run(&program, |vm| vm.set_block_cache(false));
+        let (reason, translated) = run(&program, |vm| vm.set_block_cache(true));
+
+        assert_eq!(interpreted.get_block_cache().get_translated(), 0);
+        assert!(translated.get_block_cache().get_translated() > 0);
+        assert_eq!(reason, interpreted_reason);
+        assert!(matches!(reason, StopReason::Exit(_)));
+        assert_eq!(translated.get_registers(), interpreted.get_registers());
+        assert_eq!(translated.get_instret(), interpreted.get_instret());
+    }
+
+    #[test]
+    fn overwritten_code_is_translated_again() {
+        // halfway through, the loop replaces its translated first instruction, which then adds
+        // 100 instead of 1
+        let mut program = Vec::new();
+        program.extend(li(S0, address_of(6)));
+        program.extend(li(S1, addi(A1, A1, 100)));
+        program.extend([
+            addi(T0, 0, 100),
+            addi(T1, 0, 50),
+            addi(A1, A1, 1),
+            addi(T0, T0, -1),
+            bne(T0, T1, 8),
+            sw(S1, S0, 0),
+            bne(T0, 0, -16),
+        ]);
+        program.extend(exit());
+
+        for enabled in [false, true] {
+            let (reason, vm) = run(&program, |vm| vm.set_block_cache(enabled));
+            assert_eq!(reason, StopReason::Exit(5050));
+            if enabled {
+                assert!(vm.get_block_cache().get_invalidations() > 0);
+            }
+        }
+    }
+}
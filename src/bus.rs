@@ -0,0 +1,653 @@
+use crate::{
+    clint::{Clint, CLINT_SIZE},
+    device_tree::DeviceTreeNode,
+    memory::Memory,
+    plic::{Plic, PLIC_SIZE},
+    replay::{Input, InputChannel, InputLog},
+    snapshot::{SnapshotReader, SnapshotWriter},
+    stop_conditions::StopReason,
+};
+
+// routes the accesses of the hart to memory, the interrupt controllers and the devices
+
+// memory mapped peripheral, accesses are 1, 2 or 4 bytes wide and use the offset from the base
+// of the device. They get the number of retired instructions, which is the time of the machine
+pub trait Device {
+    // size of the register window
+    fn size(&self) -> usize;
+
+    fn read(&mut self, offset: usize, nb_bytes: usize, instret: u64) -> u32;
+
+    fn write(&mut self, offset: usize, nb_bytes: usize, value: u32, instret: u64);
+
+    // back to the power-on state
+    fn reset(&mut self) {}
+
+    // called regularly with the retired instructions, to poll the host or let time pass
+    fn tick(&mut self, _instret: u64) {}
+
+    // instruction count at which the device must be ticked, for events that can't be late
+    fn next_event(&self) -> Option<u64> {
+        None
+    }
+
+    // level of the interrupt output, routed to the PLIC source given when attaching the device
+    fn interrupt_pending(&self) -> bool {
+        false
+    }
+
+    // set once the device powered the machine off, the execution stops with this exit code
+    fn exit_code(&self) -> Option<i32> {
+        None
+    }
+
+    // lets the device access guest memory, called after every write and tick
+    fn dma(&mut self, _memory: &mut DmaMemory, _instret: u64) {}
+
+    // action on the whole machine, collected after every write and tick
+    fn take_request(&mut self) -> Option<MachineRequest> {
+        None
+    }
+
+    // node of the device in the generated device tree, devices without one are left out
+    fn device_tree_node(&self) -> Option<DeviceTreeNode> {
+        None
+    }
+
+    // state of the device in a snapshot, the host side (files, connections) isn't part of it.
+    // Snapshots are taken between two instructions, requests and dma are already handled
+    fn save_state(&self, _snapshot: &mut SnapshotWriter) {}
+
+    fn restore_state(&mut self, _snapshot: &mut SnapshotReader) -> Result<(), String> {
+        Ok(())
+    }
+
+    // everything the device gets from the host (received bytes, entropy, input pins) is read
+    // through the channel, so that it can be recorded and replayed
+    fn set_input(&mut self, _input: InputChannel) {}
+}
+
+// what a device can ask from the machine, handled before the next instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MachineRequest {
+    // warm reset, memory is kept
+    Reset,
+    // non-maskable interrupt, taken whatever the interrupt enables
+    Nmi,
+    Stop(StopReason),
+}
+
+// guest memory as seen by the devices, only plain memory can be accessed. Accesses can't cross
+// the end of a memory region
+pub struct DmaMemory<'a> {
+    memories: &'a mut [Memory],
+    // the translated code of the written ranges is dropped by the VM
+    written: &'a mut Vec<(usize, usize)>,
+}
+
+impl<'a> DmaMemory<'a> {
+    pub(crate) fn new(memories: &'a mut [Memory], written: &'a mut Vec<(usize, usize)>) -> Self {
+        Self { memories, written }
+    }
+
+    // start of the range on the host, None when its end doesn't fit in the address space
+    fn range_start(address: u64, size: usize) -> Option<usize> {
+        let address = usize::try_from(address).ok()?;
+        address.checked_add(size).map(|_| address)
+    }
+
+    // whether the range is in a single memory region, and can be written when `write` is set
+    pub fn is_accessible(&self, address: u64, size: usize, write: bool) -> bool {
+        let Some(address) = Self::range_start(address, size) else {
+            return false;
+        };
+
+        size == 0
+            || self
+                .memories
+                .iter()
+                .any(|memory| memory.belongs(address, size) && !(write && memory.is_read_only()))
+    }
+
+    pub fn read(&self, address: u64, size: usize) -> Option<Vec<u8>> {
+        let address = Self::range_start(address, size)?;
+        if size == 0 {
+            return Some(Vec::new());
+        }
+
+        self.memories
+            .iter()
+            .find(|memory| memory.belongs(address, size))
+            .map(|memory| memory.read_n(address, size))
+    }
+
+    pub fn write(&mut self, address: u64, data: &[u8]) -> bool {
+        let Some(address) = Self::range_start(address, data.len()) else {
+            return false;
+        };
+        if data.is_empty() {
+            return true;
+        }
+
+        let Some(memory) = self
+            .memories
+            .iter_mut()
+            .find(|memory| memory.belongs(address, data.len()) && !memory.is_read_only())
+        else {
+            return false;
+        };
+
+        memory.write_n(address, data.to_vec());
+        self.written.push((address, data.len()));
+        true
+    }
+
+    pub fn read_u16(&self, address: u64) -> Option<u16> {
+        let bytes = self.read(address, 2)?;
+        Some(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn read_u32(&self, address: u64) -> Option<u32> {
+        let bytes = self.read(address, 4)?;
+        Some(u32::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    pub fn read_u64(&self, address: u64) -> Option<u64> {
+        let bytes = self.read(address, 8)?;
+        Some(u64::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    pub fn write_u16(&mut self, address: u64, value: u16) -> bool {
+        self.write(address, &value.to_le_bytes())
+    }
+
+    pub fn write_u32(&mut self, address: u64, value: u32) -> bool {
+        self.write(address, &value.to_le_bytes())
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Target {
+    Memory(usize),
+    Device(usize),
+    Clint,
+    Plic,
+}
+
+struct Region {
+    name: String,
+    base: usize,
+    size: usize,
+    target: Target,
+}
+
+impl Region {
+    fn contains(&self, address: usize, nb_bytes: usize) -> bool {
+        address >= self.base && address + nb_bytes <= self.base + self.size
+    }
+
+    fn overlaps(&self, base: usize, size: usize) -> bool {
+        base < self.base + self.size && self.base < base + size
+    }
+}
+
+struct AttachedDevice {
+    device: Box<dyn Device>,
+    irq: Option<u32>,
+}
+
+pub struct Bus {
+    regions: Vec<Region>,
+    // plain memory is looked up first, without going through the regions
+    memories: Vec<Memory>,
+    devices: Vec<AttachedDevice>,
+    clint: Clint,
+    plic: Plic,
+    // requested by a device
+    exit_code: Option<i32>,
+    // memory written by the devices since the last take_dma_writes
+    dma_written: Vec<(usize, usize)>,
+    requests: Vec<MachineRequest>,
+    // a device was written, its next event may have moved
+    events_changed: bool,
+    // recording or replay of the host inputs, given to the CLINT and the devices
+    input_log: InputLog,
+}
+
+impl Bus {
+    pub fn new(clint: Clint, plic: Plic) -> Result<Self, String> {
+        let mut bus = Self {
+            regions: Vec::new(),
+            memories: Vec::new(),
+            devices: Vec::new(),
+            clint,
+            plic,
+            exit_code: None,
+            dma_written: Vec::new(),
+            requests: Vec::new(),
+            events_changed: false,
+            input_log: InputLog::default(),
+        };
+
+        let clint_base = bus.clint.get_base();
+        bus.add_region("clint", clint_base, CLINT_SIZE, Target::Clint)?;
+        let plic_base = bus.plic.get_base();
+        bus.add_region("plic", plic_base, PLIC_SIZE, Target::Plic)?;
+
+        Ok(bus)
+    }
+
+    // adds the region or moves the existing one with the same target
+    fn add_region(
+        &mut self,
+        name: &str,
+        base: usize,
+        size: usize,
+        target: Target,
+    ) -> Result<(), String> {
+        if size == 0 || base.checked_add(size).is_none() {
+            return Err(format!("invalid {name} region {base:#x} (size {size:#x})"));
+        }
+
+        if let Some(other) = self
+            .regions
+            .iter()
+            .find(|region| region.target != target && region.overlaps(base, size))
+        {
+            return Err(format!(
+                "{name} at {base:#x}-{:#x} overlaps {} at {:#x}-{:#x}",
+                base + size,
+                other.name,
+                other.base,
+                other.base + other.size
+            ));
+        }
+
+        self.regions.retain(|region| region.target != target);
+        self.regions.push(Region {
+            name: name.to_string(),
+            base,
+            size,
+            target,
+        });
+
+        Ok(())
+    }
+
+    pub fn add_ram(&mut self, name: &str, base: usize, data: Vec<u8>) -> Result<(), String> {
+        self.add_memory(name, Memory::new(base, data))
+    }
+
+    // writes to read-only memory are invalid accesses
+    pub fn add_rom(&mut self, name: &str, base: usize, data: Vec<u8>) -> Result<(), String> {
+        self.add_memory(name, Memory::new_read_only(base, data))
+    }
+
+    fn add_memory(&mut self, name: &str, memory: Memory) -> Result<(), String> {
+        let target = Target::Memory(self.memories.len());
+        self.add_region(name, memory.get_start(), memory.get_size(), target)?;
+        self.memories.push(memory);
+        Ok(())
+    }
+
+    // the interrupt source, if any, must exist in the PLIC
+    pub fn add_device(
+        &mut self,
+        name: &str,
+        base: usize,
+        mut device: Box<dyn Device>,
+        irq: Option<u32>,
+    ) -> Result<(), String> {
+        if let Some(irq) = irq {
+            if irq == 0 || irq > self.plic.get_sources() {
+                return Err(format!("{name} interrupt source {irq} is not in the PLIC"));
+            }
+        }
+
+        let index = self.devices.len();
+        self.add_region(name, base, device.size(), Target::Device(index))?;
+        device.set_input(self.input_log.channel(Input::Device(index as u32)));
+        self.devices.push(AttachedDevice { device, irq });
+        Ok(())
+    }
+
+    pub fn get_exit_code(&self) -> Option<i32> {
+        self.exit_code
+    }
+
+    pub fn has_devices(&self) -> bool {
+        !self.devices.is_empty()
+    }
+
+    pub fn set_clint(&mut self, mut clint: Clint) -> Result<(), String> {
+        self.add_region("clint", clint.get_base(), CLINT_SIZE, Target::Clint)?;
+        clint.set_input(self.input_log.channel(Input::HostTime));
+        self.clint = clint;
+        Ok(())
+    }
+
+    // the CLINT and the devices attached before and after read the host through the log
+    pub fn set_input_log(&mut self, log: InputLog) {
+        self.clint.set_input(log.channel(Input::HostTime));
+        for (index, device) in self.devices.iter_mut().enumerate() {
+            let input = log.channel(Input::Device(index as u32));
+            device.device.set_input(input);
+        }
+        self.input_log = log;
+    }
+
+    pub fn get_input_log(&self) -> &InputLog {
+        &self.input_log
+    }
+
+    pub fn get_clint(&self) -> &Clint {
+        &self.clint
+    }
+
+    // the sources of the attached devices must still exist
+    pub fn set_plic(&mut self, plic: Plic) -> Result<(), String> {
+        if let Some(irq) = self
+            .devices
+            .iter()
+            .filter_map(|device| device.irq)
+            .find(|irq| *irq > plic.get_sources())
+        {
+            return Err(format!("interrupt source {irq} is not in the PLIC"));
+        }
+
+        self.add_region("plic", plic.get_base(), PLIC_SIZE, Target::Plic)?;
+        self.plic = plic;
+        Ok(())
+    }
+
+    pub fn get_plic(&self) -> &Plic {
+        &self.plic
+    }
+
+    pub fn get_plic_mut(&mut self) -> &mut Plic {
+        &mut self.plic
+    }
+
+    // base, size and read-only flag of the memory regions, by address
+    pub fn memory_regions(&self) -> Vec<(usize, usize, bool)> {
+        let mut regions: Vec<_> = self
+            .memories
+            .iter()
+            .map(|memory| (memory.get_start(), memory.get_size(), memory.is_read_only()))
+            .collect();
+        regions.sort_by_key(|(base, ..)| *base);
+        regions
+    }
+
+    // base, size and interrupt source of the devices that describe themselves, by address
+    pub fn device_tree_nodes(&self) -> Vec<(usize, usize, Option<u32>, DeviceTreeNode)> {
+        let mut nodes: Vec<_> = self
+            .regions
+            .iter()
+            .filter_map(|region| match region.target {
+                Target::Device(index) => {
+                    let device = &self.devices[index];
+                    let node = device.device.device_tree_node()?;
+                    Some((region.base, region.size, device.irq, node))
+                }
+                _ => None,
+            })
+            .collect();
+        nodes.sort_by_key(|(base, ..)| *base);
+        nodes
+    }
+
+    fn memory(&self, address: usize, nb_bytes: usize) -> Option<&Memory> {
+        self.memories
+            .iter()
+            .find(|memory| memory.belongs(address, nb_bytes))
+    }
+
+    fn writable_memory(&mut self, address: usize, nb_bytes: usize) -> Option<&mut Memory> {
+        self.memories
+            .iter_mut()
+            .find(|memory| memory.belongs(address, nb_bytes) && !memory.is_read_only())
+    }
+
+    pub fn is_memory(&self, address: usize, nb_bytes: usize) -> bool {
+        self.memory(address, nb_bytes).is_some()
+    }
+
+    // accesses to plain memory, None or false for devices and unmapped addresses
+
+    pub fn read_memory_u8(&self, address: usize) -> Option<u8> {
+        self.memory(address, 1)
+            .map(|memory| memory.read_u8(address))
+    }
+
+    pub fn read_memory_u16(&self, address: usize) -> Option<u16> {
+        self.memory(address, 2)
+            .map(|memory| memory.read_u16(address))
+    }
+
+    pub fn read_memory_u32(&self, address: usize) -> Option<u32> {
+        self.memory(address, 4)
+            .map(|memory| memory.read_u32(address))
+    }
+
+    pub fn read_memory_n(&self, address: usize, size: usize) -> Option<Vec<u8>> {
+        self.memory(address, size)
+            .map(|memory| memory.read_n(address, size))
+    }
+
+    pub fn write_memory_u8(&mut self, address: usize, value: u8) -> bool {
+        self.writable_memory(address, 1)
+            .map(|memory| memory.write_8(address, value))
+            .is_some()
+    }
+
+    pub fn write_memory_u16(&mut self, address: usize, value: u16) -> bool {
+        self.writable_memory(address, 2)
+            .map(|memory| memory.write_16(address, value))
+            .is_some()
+    }
+
+    pub fn write_memory_u32(&mut self, address: usize, value: u32) -> bool {
+        self.writable_memory(address, 4)
+            .map(|memory| memory.write_u32(address, value))
+            .is_some()
+    }
+
+    pub fn write_memory_n(&mut self, address: usize, data: Vec<u8>) -> bool {
+        self.writable_memory(address, data.len())
+            .map(|memory| memory.write_n(address, data))
+            .is_some()
+    }
+
+    fn region(&self, address: usize, nb_bytes: usize) -> Option<(usize, Target)> {
+        self.regions
+            .iter()
+            .find(|region| region.contains(address, nb_bytes))
+            .map(|region| (region.base, region.target))
+    }
+
+    // accesses to everything but plain memory, None or false for unmapped addresses
+
+    pub fn read_device(&mut self, address: usize, nb_bytes: usize, instret: u64) -> Option<u32> {
+        let (base, target) = self.region(address, nb_bytes)?;
+
+        match target {
+            Target::Memory(_) => None,
+            Target::Clint => Some(self.clint.read(address, nb_bytes, instret)),
+            Target::Plic => Some(self.plic.read(address, nb_bytes)),
+            Target::Device(index) => {
+                let value = self.devices[index]
+                    .device
+                    .read(address - base, nb_bytes, instret);
+                self.update_interrupt(index);
+                Some(value)
+            }
+        }
+    }
+
+    pub fn write_device(
+        &mut self,
+        address: usize,
+        nb_bytes: usize,
+        value: u32,
+        instret: u64,
+    ) -> bool {
+        let Some((base, target)) = self.region(address, nb_bytes) else {
+            return false;
+        };
+
+        match target {
+            Target::Memory(_) => return false,
+            Target::Clint => self.clint.write(address, nb_bytes, value, instret),
+            Target::Plic => self.plic.write(address, nb_bytes, value),
+            Target::Device(index) => {
+                let device = &mut self.devices[index].device;
+                device.write(address - base, nb_bytes, value, instret);
+                if let Some(exit_code) = device.exit_code() {
+                    self.exit_code = Some(exit_code);
+                }
+                self.run_dma(index, instret);
+                self.update_interrupt(index);
+                self.collect_request(index);
+                self.events_changed = true;
+            }
+        }
+
+        true
+    }
+
+    // the interrupt line of a device follows its state
+    fn update_interrupt(&mut self, index: usize) {
+        let device = &self.devices[index];
+        if let Some(irq) = device.irq {
+            self.plic.set_level(irq, device.device.interrupt_pending());
+        }
+    }
+
+    fn collect_request(&mut self, index: usize) {
+        if let Some(request) = self.devices[index].device.take_request() {
+            self.requests.push(request);
+        }
+    }
+
+    pub fn has_requests(&self) -> bool {
+        !self.requests.is_empty()
+    }
+
+    pub fn take_requests(&mut self) -> Vec<MachineRequest> {
+        std::mem::take(&mut self.requests)
+    }
+
+    // whether next_event may have changed since the last call
+    pub fn take_events_changed(&mut self) -> bool {
+        std::mem::take(&mut self.events_changed)
+    }
+
+    fn run_dma(&mut self, index: usize, instret: u64) {
+        let mut memory = DmaMemory::new(&mut self.memories, &mut self.dma_written);
+        self.devices[index].device.dma(&mut memory, instret);
+    }
+
+    // ranges of guest memory written by the devices
+    pub fn take_dma_writes(&mut self) -> Vec<(usize, usize)> {
+        std::mem::take(&mut self.dma_written)
+    }
+
+    pub fn tick_devices(&mut self, instret: u64) {
+        for index in 0..self.devices.len() {
+            self.devices[index].device.tick(instret);
+            self.run_dma(index, instret);
+            self.update_interrupt(index);
+            self.collect_request(index);
+        }
+    }
+
+    // earliest event requested by a device
+    pub fn next_event(&self) -> Option<u64> {
+        self.devices
+            .iter()
+            .filter_map(|device| device.device.next_event())
+            .min()
+    }
+
+    // memory, interrupt controllers and devices, in the order they were added
+    pub fn save_state(&self, snapshot: &mut SnapshotWriter, instret: u64) {
+        snapshot.section("memory");
+        snapshot.write_u64(self.memories.len() as u64);
+        for memory in &self.memories {
+            memory.save_state(snapshot);
+        }
+
+        snapshot.section("clint");
+        self.clint.save_state(snapshot, instret);
+        snapshot.section("plic");
+        self.plic.save_state(snapshot);
+
+        snapshot.section("devices");
+        snapshot.write_u64(self.devices.len() as u64);
+        for (index, device) in self.devices.iter().enumerate() {
+            snapshot.write_string(self.device_name(index));
+            device.device.save_state(snapshot);
+        }
+    }
+
+    // the machine must be configured like the one the snapshot was taken on
+    pub fn restore_state(
+        &mut self,
+        snapshot: &mut SnapshotReader,
+        instret: u64,
+    ) -> Result<(), String> {
+        snapshot.section("memory")?;
+        snapshot.check("memory regions", self.memories.len() as u64)?;
+        for memory in &mut self.memories {
+            memory.restore_state(snapshot)?;
+        }
+
+        snapshot.section("clint")?;
+        self.clint.restore_state(snapshot, instret)?;
+        snapshot.section("plic")?;
+        self.plic.restore_state(snapshot)?;
+
+        snapshot.section("devices")?;
+        snapshot.check("devices", self.devices.len() as u64)?;
+        for index in 0..self.devices.len() {
+            let name = snapshot.read_string()?;
+            if name != self.device_name(index) {
+                return Err(format!(
+                    "the snapshot has the device {name} where the machine has {}",
+                    self.device_name(index)
+                ));
+            }
+            self.devices[index].device.restore_state(snapshot)?;
+        }
+
+        self.exit_code = None;
+        self.dma_written.clear();
+        self.requests.clear();
+        self.events_changed = true;
+        Ok(())
+    }
+
+    fn device_name(&self, index: usize) -> &str {
+        self.regions
+            .iter()
+            .find(|region| region.target == Target::Device(index))
+            .map_or("", |region| region.name.as_str())
+    }
+
+    // the clint and the plic are reset with the hart, not with the devices
+    pub fn reset_interrupt_controllers(&mut self) {
+        self.clint.reset();
+        self.plic.reset();
+    }
+
+    pub fn reset_devices(&mut self) {
+        self.exit_code = None;
+        self.requests.clear();
+        self.events_changed = true;
+        for index in 0..self.devices.len() {
+            self.devices[index].device.reset();
+            self.update_interrupt(index);
+        }
+    }
+}
@@ -0,0 +1,240 @@
+use std::{cell::Cell, time::Instant};
+
+use crate::{
+    replay::InputChannel,
+    snapshot::{SnapshotReader, SnapshotWriter},
+};
+
+// core local interruptor, machine timer and software interrupts of the harts. They share mtime,
+// each hart has its own msip and mtimecmp
+
+pub const CLINT_ADDRESS: usize = 0x2000000;
+pub const CLINT_SIZE: usize = 0x10000;
+
+// register offsets, msip and mtimecmp are followed by the ones of the other harts
+const MSIP: usize = 0;
+const MSIP_STRIDE: usize = 4;
+const MTIMECMP: usize = 0x4000;
+const MTIMECMP_STRIDE: usize = 8;
+const MTIME: usize = 0xbff8;
+
+// harts fitting between the msip and mtimecmp registers, like on the sifive clint
+pub const MAX_HARTS: usize = 4095;
+
+// frequency of mtime when it follows the host clock
+pub const HOST_TIMEBASE: u64 = 10_000_000;
+
+// the guest reads the host clock at every access to mtime, the interrupt checks only this often
+const HOST_READING_INTERVAL: u64 = 0x1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeSource {
+    // mtime is incremented by every retired instruction, runs are reproducible
+    Instructions,
+    // mtime follows the host clock at HOST_TIMEBASE
+    Host,
+}
+
+pub struct Clint {
+    base: usize,
+    time_source: TimeSource,
+    start: Instant,
+    // host clock at the start, it resumes from the restored reading
+    start_ticks: u64,
+    // last reading of the host clock, in HOST_TIMEBASE ticks
+    host_ticks: Cell<u64>,
+    // instruction count at which the interrupt checks read the host clock again
+    next_reading: Cell<u64>,
+    // the readings of the host clock can be recorded and replayed
+    host_input: InputChannel,
+    // set by writes to mtime
+    mtime_offset: u64,
+    // one per hart
+    mtimecmp: Vec<u64>,
+    msip: Vec<bool>,
+}
+
+impl Clint {
+    pub fn new(base: usize, time_source: TimeSource, harts: usize) -> Self {
+        assert!(
+            harts > 0 && harts <= MAX_HARTS,
+            "Invalid number of harts {harts}"
+        );
+
+        Self {
+            base,
+            time_source,
+            start: Instant::now(),
+            start_ticks: 0,
+            host_ticks: Cell::new(0),
+            next_reading: Cell::new(0),
+            host_input: InputChannel::default(),
+            mtime_offset: 0,
+            // no timer interrupt until the guest programs one
+            mtimecmp: vec![u64::MAX; harts],
+            msip: vec![false; harts],
+        }
+    }
+
+    // the timer keeps running
+    pub fn reset(&mut self) {
+        self.mtimecmp.fill(u64::MAX);
+        self.msip.fill(false);
+    }
+
+    pub fn set_input(&mut self, input: InputChannel) {
+        self.host_input = input;
+    }
+
+    // mtime is saved rather than its offset, with the host clock it resumes where it stopped (at
+    // its last reading, saving doesn't read the clock)
+    pub fn save_state(&self, snapshot: &mut SnapshotWriter, instret: u64) {
+        snapshot.write_u64(self.msip.len() as u64);
+        snapshot.write_u64(self.get_mtime(instret));
+        snapshot.write_u64(self.host_ticks.get());
+        snapshot.write_u64(self.next_reading.get());
+        for (mtimecmp, msip) in self.mtimecmp.iter().zip(&self.msip) {
+            snapshot.write_u64(*mtimecmp);
+            snapshot.write_bool(*msip);
+        }
+    }
+
+    pub fn restore_state(
+        &mut self,
+        snapshot: &mut SnapshotReader,
+        instret: u64,
+    ) -> Result<(), String> {
+        snapshot.check("CLINT harts", self.msip.len() as u64)?;
+        let mtime = snapshot.read_u64()?;
+        self.host_ticks.set(snapshot.read_u64()?);
+        self.next_reading.set(snapshot.read_u64()?);
+        self.start = Instant::now();
+        self.start_ticks = self.host_ticks.get();
+        self.mtime_offset = mtime.wrapping_sub(self.ticks(instret));
+        for (mtimecmp, msip) in self.mtimecmp.iter_mut().zip(&mut self.msip) {
+            *mtimecmp = snapshot.read_u64()?;
+            *msip = snapshot.read_bool()?;
+        }
+        Ok(())
+    }
+
+    pub fn get_harts(&self) -> usize {
+        self.msip.len()
+    }
+
+    pub fn get_base(&self) -> usize {
+        self.base
+    }
+
+    pub fn get_time_source(&self) -> TimeSource {
+        self.time_source
+    }
+
+    // the clock doesn't go back when the restored reading is ahead of it
+    fn read_host_clock(&self, instret: u64) {
+        let ticks = self.host_input.read_u64(instret, || {
+            let elapsed = self.start.elapsed().as_nanos() * HOST_TIMEBASE as u128 / 1_000_000_000;
+            (self.start_ticks + elapsed as u64).max(self.host_ticks.get())
+        });
+        self.host_ticks.set(ticks);
+        self.next_reading.set(instret + HOST_READING_INTERVAL);
+    }
+
+    // time elapsed since the start, before the offset set by the guest
+    fn ticks(&self, instret: u64) -> u64 {
+        match self.time_source {
+            TimeSource::Instructions => instret,
+            TimeSource::Host => self.host_ticks.get(),
+        }
+    }
+
+    // value at the last reading of the host clock
+    pub fn get_mtime(&self, instret: u64) -> u64 {
+        self.ticks(instret).wrapping_add(self.mtime_offset)
+    }
+
+    // read by the guest, through the register or the time csr
+    pub fn read_mtime(&self, instret: u64) -> u64 {
+        if self.time_source == TimeSource::Host {
+            self.read_host_clock(instret);
+        }
+        self.get_mtime(instret)
+    }
+
+    // harts without a CLINT register never get its interrupts
+    pub fn get_msip(&self, hart: usize) -> bool {
+        self.msip.get(hart).is_some_and(|msip| *msip)
+    }
+
+    pub fn timer_pending(&self, hart: usize, instret: u64) -> bool {
+        if self.time_source == TimeSource::Host && instret >= self.next_reading.get() {
+            self.read_host_clock(instret);
+        }
+        self.mtimecmp
+            .get(hart)
+            .is_some_and(|mtimecmp| self.get_mtime(instret) >= *mtimecmp)
+    }
+
+    // instructions that can run before the timer of the hart fires. With the host clock it can
+    // only fire when the clock is read again, at a fixed instruction count so that a replay
+    // reads it at the same point
+    pub fn instructions_until_timer(&self, hart: usize, instret: u64) -> u64 {
+        let mtimecmp = self.mtimecmp.get(hart).copied().unwrap_or(u64::MAX);
+        match self.time_source {
+            TimeSource::Instructions => mtimecmp.saturating_sub(self.get_mtime(instret)),
+            TimeSource::Host => self.next_reading.get().saturating_sub(instret),
+        }
+    }
+
+    // register containing the offset, its start and its value. The msip registers are 32 bits
+    // wide, the others 64 bits
+    fn register(&self, offset: usize, instret: u64) -> Option<(usize, u64)> {
+        let harts = self.get_harts();
+        match offset {
+            MSIP..MTIMECMP => {
+                let hart = (offset - MSIP) / MSIP_STRIDE;
+                let msip = self.msip.get(hart)?;
+                Some((MSIP + hart * MSIP_STRIDE, *msip as u64))
+            }
+            MTIME..=0xbfff => Some((MTIME, self.read_mtime(instret))),
+            _ if offset >= MTIMECMP && offset < MTIMECMP + harts * MTIMECMP_STRIDE => {
+                let hart = (offset - MTIMECMP) / MTIMECMP_STRIDE;
+                Some((MTIMECMP + hart * MTIMECMP_STRIDE, self.mtimecmp[hart]))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn read(&self, address: usize, nb_bytes: usize, instret: u64) -> u32 {
+        let offset = address - self.base;
+
+        match self.register(offset, instret) {
+            Some((start, value)) => {
+                let value = value >> ((offset - start) * 8);
+                (value & (u64::MAX >> (64 - nb_bytes * 8))) as u32
+            }
+            // reserved
+            None => 0,
+        }
+    }
+
+    pub fn write(&mut self, address: usize, nb_bytes: usize, value: u32, instret: u64) {
+        let offset = address - self.base;
+
+        let Some((start, old)) = self.register(offset, instret) else {
+            return;
+        };
+
+        let shift = (offset - start) * 8;
+        let mask = (u64::MAX >> (64 - nb_bytes * 8)) << shift;
+        let new = old & !mask | (value as u64) << shift & mask;
+
+        match start {
+            MTIME => self.mtime_offset = new.wrapping_sub(self.ticks(instret)),
+            // msip is 32 bits wide with a single writable bit
+            MSIP..MTIMECMP => self.msip[(start - MSIP) / MSIP_STRIDE] = new & 1 != 0,
+            // mtimecmp of a hart
+            _ => self.mtimecmp[(start - MTIMECMP) / MTIMECMP_STRIDE] = new,
+        }
+    }
+}
@@ -0,0 +1,512 @@
+// machine and supervisor mode control and status registers, the counters and time are owned by
+// the VM
+
+use crate::{
+    pmp::Pmp,
+    snapshot::{SnapshotReader, SnapshotWriter},
+};
+
+pub const SSTATUS: u32 = 0x100;
+pub const SIE: u32 = 0x104;
+pub const STVEC: u32 = 0x105;
+pub const SCOUNTEREN: u32 = 0x106;
+pub const SSCRATCH: u32 = 0x140;
+pub const SEPC: u32 = 0x141;
+pub const SCAUSE: u32 = 0x142;
+pub const STVAL: u32 = 0x143;
+pub const SIP: u32 = 0x144;
+pub const SATP: u32 = 0x180;
+
+pub const MSTATUS: u32 = 0x300;
+pub const MISA: u32 = 0x301;
+pub const MEDELEG: u32 = 0x302;
+pub const MIDELEG: u32 = 0x303;
+pub const MIE: u32 = 0x304;
+pub const MTVEC: u32 = 0x305;
+pub const MCOUNTEREN: u32 = 0x306;
+pub const MSCRATCH: u32 = 0x340;
+pub const MEPC: u32 = 0x341;
+pub const MCAUSE: u32 = 0x342;
+pub const MTVAL: u32 = 0x343;
+pub const MIP: u32 = 0x344;
+pub const PMPCFG0: u32 = 0x3a0;
+pub const PMPCFG3: u32 = 0x3a3;
+pub const PMPADDR0: u32 = 0x3b0;
+pub const PMPADDR15: u32 = 0x3bf;
+
+pub const MCYCLE: u32 = 0xb00;
+pub const MINSTRET: u32 = 0xb02;
+pub const MCYCLEH: u32 = 0xb80;
+pub const MINSTRETH: u32 = 0xb82;
+pub const CYCLE: u32 = 0xc00;
+pub const TIME: u32 = 0xc01;
+pub const INSTRET: u32 = 0xc02;
+pub const CYCLEH: u32 = 0xc80;
+pub const TIMEH: u32 = 0xc81;
+pub const INSTRETH: u32 = 0xc82;
+
+pub const MVENDORID: u32 = 0xf11;
+pub const MARCHID: u32 = 0xf12;
+pub const MIMPID: u32 = 0xf13;
+pub const MHARTID: u32 = 0xf14;
+
+pub const MSTATUS_SIE: u32 = 1 << 1;
+pub const MSTATUS_MIE: u32 = 1 << 3;
+pub const MSTATUS_SPIE: u32 = 1 << 5;
+pub const MSTATUS_MPIE: u32 = 1 << 7;
+pub const MSTATUS_SPP: u32 = 1 << 8;
+pub const MSTATUS_MPP: u32 = 3 << 11;
+const MSTATUS_MPP_SHIFT: u32 = 11;
+// loads and stores of machine mode use the privilege in mpp
+pub const MSTATUS_MPRV: u32 = 1 << 17;
+// supervisor mode can access user pages
+pub const MSTATUS_SUM: u32 = 1 << 18;
+// loads from pages that are only executable
+pub const MSTATUS_MXR: u32 = 1 << 19;
+// trap virtual memory, wait and sret in supervisor mode
+pub const MSTATUS_TVM: u32 = 1 << 20;
+pub const MSTATUS_TW: u32 = 1 << 21;
+pub const MSTATUS_TSR: u32 = 1 << 22;
+
+const MSTATUS_WRITABLE: u32 = MSTATUS_SIE
+    | MSTATUS_MIE
+    | MSTATUS_SPIE
+    | MSTATUS_MPIE
+    | MSTATUS_SPP
+    | MSTATUS_MPP
+    | MSTATUS_MPRV
+    | MSTATUS_SUM
+    | MSTATUS_MXR
+    | MSTATUS_TVM
+    | MSTATUS_TW
+    | MSTATUS_TSR;
+
+// the part of mstatus seen through sstatus
+const SSTATUS_MASK: u32 = MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP | MSTATUS_SUM | MSTATUS_MXR;
+
+// bit positions in mie/mip are the interrupt causes
+pub const CAUSE_SUPERVISOR_SOFTWARE: u32 = 1;
+pub const CAUSE_MACHINE_SOFTWARE: u32 = 3;
+pub const CAUSE_SUPERVISOR_TIMER: u32 = 5;
+pub const CAUSE_MACHINE_TIMER: u32 = 7;
+pub const CAUSE_SUPERVISOR_EXTERNAL: u32 = 9;
+pub const CAUSE_MACHINE_EXTERNAL: u32 = 11;
+pub const MIP_SSIP: u32 = 1 << CAUSE_SUPERVISOR_SOFTWARE;
+pub const MIP_MSIP: u32 = 1 << CAUSE_MACHINE_SOFTWARE;
+pub const MIP_STIP: u32 = 1 << CAUSE_SUPERVISOR_TIMER;
+pub const MIP_MTIP: u32 = 1 << CAUSE_MACHINE_TIMER;
+pub const MIP_SEIP: u32 = 1 << CAUSE_SUPERVISOR_EXTERNAL;
+pub const MIP_MEIP: u32 = 1 << CAUSE_MACHINE_EXTERNAL;
+
+// written by machine mode software to raise supervisor interrupts
+const MIP_SUPERVISOR: u32 = MIP_SSIP | MIP_STIP | MIP_SEIP;
+const MIE_WRITABLE: u32 = MIP_MSIP | MIP_MTIP | MIP_MEIP | MIP_SUPERVISOR;
+
+// in priority order, machine level first
+pub const INTERRUPT_PRIORITIES: [u32; 6] = [
+    CAUSE_MACHINE_EXTERNAL,
+    CAUSE_MACHINE_SOFTWARE,
+    CAUSE_MACHINE_TIMER,
+    CAUSE_SUPERVISOR_EXTERNAL,
+    CAUSE_SUPERVISOR_SOFTWARE,
+    CAUSE_SUPERVISOR_TIMER,
+];
+
+// exception causes
+pub const CAUSE_INSTRUCTION_ACCESS_FAULT: u32 = 1;
+pub const CAUSE_ILLEGAL_INSTRUCTION: u32 = 2;
+pub const CAUSE_LOAD_ADDRESS_MISALIGNED: u32 = 4;
+pub const CAUSE_LOAD_ACCESS_FAULT: u32 = 5;
+pub const CAUSE_STORE_ADDRESS_MISALIGNED: u32 = 6;
+pub const CAUSE_STORE_ACCESS_FAULT: u32 = 7;
+pub const CAUSE_USER_ECALL: u32 = 8;
+pub const CAUSE_SUPERVISOR_ECALL: u32 = 9;
+pub const CAUSE_INSTRUCTION_PAGE_FAULT: u32 = 12;
+pub const CAUSE_LOAD_PAGE_FAULT: u32 = 13;
+pub const CAUSE_STORE_PAGE_FAULT: u32 = 15;
+
+// every exception but the machine mode ecall can be handled in supervisor mode
+const MEDELEG_WRITABLE: u32 = 0xb3ff;
+
+// sv32 when set, otherwise addresses aren't translated
+pub const SATP_MODE: u32 = 1 << 31;
+pub const SATP_ASID_SHIFT: u32 = 22;
+pub const SATP_ASID: u32 = 0x1ff << SATP_ASID_SHIFT;
+pub const SATP_PPN: u32 = 0x3fffff;
+
+// set in mcause for interrupts
+pub const CAUSE_INTERRUPT: u32 = 1 << 31;
+
+// the privileged spec leaves the cause of NMIs to the implementation and reserves 0 for unknown
+// causes, the watchdog is the only source here
+pub const CAUSE_NMI: u32 = 0;
+
+// RV32IMA with supervisor and user modes
+const MISA_VALUE: u32 = 1 << 30 | 1 << 20 | 1 << 18 | 1 << 12 | 1 << 8 | 1 << 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Privilege {
+    User = 0,
+    Supervisor = 1,
+    Machine = 3,
+}
+
+impl Privilege {
+    // the reserved encoding 2 reads as user mode
+    fn from_bits(bits: u32) -> Self {
+        match bits & 3 {
+            3 => Privilege::Machine,
+            1 => Privilege::Supervisor,
+            _ => Privilege::User,
+        }
+    }
+
+    // lowest privilege allowed to access the csr, encoded in its address
+    pub fn of_csr(csr: u32) -> Self {
+        Privilege::from_bits(csr >> 8)
+    }
+}
+
+// raised by an instruction instead of completing, it doesn't retire and the trap handler gets the
+// cause and the faulting value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Exception {
+    pub cause: u32,
+    pub tval: u32,
+}
+
+impl Exception {
+    pub fn new(cause: u32, tval: u32) -> Self {
+        Self { cause, tval }
+    }
+
+    // the instruction bits aren't kept after decoding, tval is 0 as the spec allows
+    pub fn illegal_instruction() -> Self {
+        Self::new(CAUSE_ILLEGAL_INSTRUCTION, 0)
+    }
+}
+
+pub struct Csrs {
+    // mhartid
+    hart_id: u32,
+    mstatus: u32,
+    medeleg: u32,
+    mideleg: u32,
+    mie: u32,
+    // pending bits driven by the interrupt sources
+    mip: u32,
+    // supervisor pending bits written by software
+    mip_software: u32,
+    mtvec: u32,
+    mcounteren: u32,
+    mscratch: u32,
+    mepc: u32,
+    mcause: u32,
+    mtval: u32,
+    stvec: u32,
+    scounteren: u32,
+    sscratch: u32,
+    sepc: u32,
+    scause: u32,
+    stval: u32,
+    satp: u32,
+    pmp: Pmp,
+}
+
+impl Csrs {
+    pub fn new(hart_id: u32) -> Self {
+        Self {
+            hart_id,
+            // an mret without a trap stays in machine mode
+            mstatus: MSTATUS_MPP,
+            medeleg: 0,
+            mideleg: 0,
+            mie: 0,
+            mip: 0,
+            mip_software: 0,
+            mtvec: 0,
+            mcounteren: 0,
+            mscratch: 0,
+            mepc: 0,
+            mcause: 0,
+            mtval: 0,
+            stvec: 0,
+            scounteren: 0,
+            sscratch: 0,
+            sepc: 0,
+            scause: 0,
+            stval: 0,
+            satp: 0,
+            pmp: Pmp::new(),
+        }
+    }
+
+    // read-only csrs have the two top bits of their address set
+    pub fn is_read_only(csr: u32) -> bool {
+        csr >> 10 == 0b11
+    }
+
+    pub fn read(&self, csr: u32) -> Option<u32> {
+        let value = match csr {
+            SSTATUS => self.mstatus & SSTATUS_MASK,
+            SIE => self.mie & self.mideleg,
+            STVEC => self.stvec,
+            SCOUNTEREN => self.scounteren,
+            SSCRATCH => self.sscratch,
+            SEPC => self.sepc,
+            SCAUSE => self.scause,
+            STVAL => self.stval,
+            SIP => self.get_mip() & self.mideleg,
+            SATP => self.satp,
+            MSTATUS => self.mstatus,
+            MISA => MISA_VALUE,
+            MEDELEG => self.medeleg,
+            MIDELEG => self.mideleg,
+            MIE => self.mie,
+            MTVEC => self.mtvec,
+            MCOUNTEREN => self.mcounteren,
+            MSCRATCH => self.mscratch,
+            MEPC => self.mepc,
+            MCAUSE => self.mcause,
+            MTVAL => self.mtval,
+            MIP => self.get_mip(),
+            PMPCFG0..=PMPCFG3 => self.pmp.read_cfg((csr - PMPCFG0) as usize),
+            PMPADDR0..=PMPADDR15 => self.pmp.read_addr((csr - PMPADDR0) as usize),
+            MVENDORID | MARCHID | MIMPID => 0,
+            MHARTID => self.hart_id,
+            _ => return None,
+        };
+
+        Some(value)
+    }
+
+    // false for unknown csrs, read-only fields keep their value
+    pub fn write(&mut self, csr: u32, value: u32) -> bool {
+        match csr {
+            SSTATUS => {
+                let mstatus = self.mstatus & !SSTATUS_MASK | value & SSTATUS_MASK;
+                self.set_mstatus(mstatus);
+            }
+            // only the delegated interrupts are visible
+            SIE => self.mie = self.mie & !self.mideleg | value & self.mideleg & MIE_WRITABLE,
+            STVEC => self.stvec = value & !2,
+            SCOUNTEREN => self.scounteren = value,
+            SSCRATCH => self.sscratch = value,
+            SEPC => self.sepc = value & !3,
+            SCAUSE => self.scause = value,
+            STVAL => self.stval = value,
+            // supervisor software interrupts are the only ones it can raise itself
+            SIP => {
+                let mask = MIP_SSIP & self.mideleg;
+                self.mip_software = self.mip_software & !mask | value & mask;
+            }
+            // modes other than bare and sv32 are ignored
+            SATP => self.satp = value,
+            MSTATUS => self.set_mstatus(value),
+            MISA => {}
+            MEDELEG => self.medeleg = value & MEDELEG_WRITABLE,
+            MIDELEG => self.mideleg = value & MIP_SUPERVISOR,
+            MIE => self.mie = value & MIE_WRITABLE,
+            // vectored or direct, the reserved modes are not supported
+            MTVEC => self.mtvec = value & !2,
+            MCOUNTEREN => self.mcounteren = value,
+            MSCRATCH => self.mscratch = value,
+            MEPC => self.mepc = value & !3,
+            MCAUSE => self.mcause = value,
+            MTVAL => self.mtval = value,
+            // the machine pending bits are driven by the interrupt sources
+            MIP => self.mip_software = value & MIP_SUPERVISOR,
+            // locked entries ignore the writes
+            PMPCFG0..=PMPCFG3 => self.pmp.write_cfg((csr - PMPCFG0) as usize, value),
+            PMPADDR0..=PMPADDR15 => self.pmp.write_addr((csr - PMPADDR0) as usize, value),
+            _ => return false,
+        }
+
+        true
+    }
+
+    // mhartid is given by the position of the hart
+    pub fn save_state(&self, snapshot: &mut SnapshotWriter) {
+        for value in self.state() {
+            snapshot.write_u32(*value);
+        }
+        self.pmp.save_state(snapshot);
+    }
+
+    pub fn restore_state(&mut self, snapshot: &mut SnapshotReader) -> Result<(), String> {
+        for value in self.state_mut() {
+            *value = snapshot.read_u32()?;
+        }
+        self.pmp.restore_state(snapshot)
+    }
+
+    fn state(&self) -> [&u32; 19] {
+        [
+            &self.mstatus,
+            &self.medeleg,
+            &self.mideleg,
+            &self.mie,
+            &self.mip,
+            &self.mip_software,
+            &self.mtvec,
+            &self.mcounteren,
+            &self.mscratch,
+            &self.mepc,
+            &self.mcause,
+            &self.mtval,
+            &self.stvec,
+            &self.scounteren,
+            &self.sscratch,
+            &self.sepc,
+            &self.scause,
+            &self.stval,
+            &self.satp,
+        ]
+    }
+
+    fn state_mut(&mut self) -> [&mut u32; 19] {
+        [
+            &mut self.mstatus,
+            &mut self.medeleg,
+            &mut self.mideleg,
+            &mut self.mie,
+            &mut self.mip,
+            &mut self.mip_software,
+            &mut self.mtvec,
+            &mut self.mcounteren,
+            &mut self.mscratch,
+            &mut self.mepc,
+            &mut self.mcause,
+            &mut self.mtval,
+            &mut self.stvec,
+            &mut self.scounteren,
+            &mut self.sscratch,
+            &mut self.sepc,
+            &mut self.scause,
+            &mut self.stval,
+            &mut self.satp,
+        ]
+    }
+
+    pub fn get_pmp(&self) -> &Pmp {
+        &self.pmp
+    }
+
+    pub fn get_mstatus(&self) -> u32 {
+        self.mstatus
+    }
+
+    // mpp keeps its value when written with the reserved privilege
+    pub fn set_mstatus(&mut self, value: u32) {
+        let mut mstatus = value & MSTATUS_WRITABLE;
+        if (value & MSTATUS_MPP) >> MSTATUS_MPP_SHIFT == 2 {
+            mstatus = mstatus & !MSTATUS_MPP | self.mstatus & MSTATUS_MPP;
+        }
+        self.mstatus = mstatus;
+    }
+
+    pub fn get_mpp(&self) -> Privilege {
+        Privilege::from_bits(self.mstatus >> MSTATUS_MPP_SHIFT)
+    }
+
+    pub fn set_mpp(&mut self, privilege: Privilege) {
+        self.mstatus = self.mstatus & !MSTATUS_MPP | (privilege as u32) << MSTATUS_MPP_SHIFT;
+    }
+
+    pub fn get_medeleg(&self) -> u32 {
+        self.medeleg
+    }
+
+    pub fn get_mideleg(&self) -> u32 {
+        self.mideleg
+    }
+
+    pub fn get_mie(&self) -> u32 {
+        self.mie
+    }
+
+    pub fn get_mip(&self) -> u32 {
+        self.mip | self.mip_software
+    }
+
+    // sets or clears pending bits, unlike a csr write
+    pub fn set_pending(&mut self, mask: u32, pending: bool) {
+        if pending {
+            self.mip |= mask;
+        } else {
+            self.mip &= !mask;
+        }
+    }
+
+    pub fn get_mtvec(&self) -> u32 {
+        self.mtvec
+    }
+
+    pub fn get_mepc(&self) -> u32 {
+        self.mepc
+    }
+
+    pub fn set_mepc(&mut self, value: u32) {
+        self.mepc = value;
+    }
+
+    pub fn set_mcause(&mut self, value: u32) {
+        self.mcause = value;
+    }
+
+    pub fn set_mtval(&mut self, value: u32) {
+        self.mtval = value;
+    }
+
+    pub fn get_stvec(&self) -> u32 {
+        self.stvec
+    }
+
+    pub fn get_sepc(&self) -> u32 {
+        self.sepc
+    }
+
+    pub fn set_sepc(&mut self, value: u32) {
+        self.sepc = value;
+    }
+
+    pub fn set_scause(&mut self, value: u32) {
+        self.scause = value;
+    }
+
+    pub fn set_stval(&mut self, value: u32) {
+        self.stval = value;
+    }
+
+    pub fn get_satp(&self) -> u32 {
+        self.satp
+    }
+
+    // whether the counter csr can be read in the privilege, counteren bits are numbered like
+    // the low byte of the counter addresses
+    pub fn counter_enabled(&self, csr: u32, privilege: Privilege) -> bool {
+        let bit = 1 << (csr & 0x1f);
+        match privilege {
+            Privilege::Machine => true,
+            Privilege::Supervisor => self.mcounteren & bit != 0,
+            Privilege::User => self.mcounteren & self.scounteren & bit != 0,
+        }
+    }
+
+    // interrupts that can be taken in the privilege. Machine interrupts are always enabled below
+    // machine mode, delegated ones below supervisor mode and never in machine mode
+    pub fn enabled_interrupts(&self, privilege: Privilege) -> u32 {
+        let machine = privilege < Privilege::Machine || self.mstatus & MSTATUS_MIE != 0;
+        let supervisor = privilege < Privilege::Supervisor
+            || privilege == Privilege::Supervisor && self.mstatus & MSTATUS_SIE != 0;
+
+        let mut enabled = 0;
+        if machine {
+            enabled |= self.mie & !self.mideleg;
+        }
+        if supervisor {
+            enabled |= self.mie & self.mideleg;
+        }
+        enabled
+    }
+}
@@ -0,0 +1,151 @@
+use crate::instructions::InstructionFormat;
+
+// direct-mapped, big enough to hold every word of the flash without conflicts
+const DECODE_CACHE_ENTRIES: usize = 0x1000;
+
+// pre-decoded instructions keyed by pc, so hot loops skip fetching and decoding
+pub struct DecodeCache {
+    entries: Vec<Option<(u32, InstructionFormat)>>,
+    enabled: bool,
+    hits: u64,
+    misses: u64,
+}
+
+impl Default for DecodeCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DecodeCache {
+    pub fn new() -> Self {
+        Self {
+            entries: vec![None; DECODE_CACHE_ENTRIES],
+            enabled: true,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    fn index(address: u32) -> usize {
+        (address >> 2) as usize & (DECODE_CACHE_ENTRIES - 1)
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        self.flush();
+    }
+
+    pub fn get(&mut self, pc: u32) -> Option<InstructionFormat> {
+        if !self.enabled {
+            return None;
+        }
+
+        match self.entries[DecodeCache::index(pc)] {
+            Some((tag, instruction)) if tag == pc => {
+                self.hits += 1;
+                Some(instruction)
+            }
+            _ => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    pub fn insert(&mut self, pc: u32, instruction: InstructionFormat) {
+        if self.enabled {
+            self.entries[DecodeCache::index(pc)] = Some((pc, instruction));
+        }
+    }
+
+    // drops every cached instruction overlapping the written bytes
+    pub fn invalidate(&mut self, address: usize, size: usize) {
+        if !self.enabled || size == 0 {
+            return;
+        }
+
+        let first = address & !3;
+        let last = (address + size - 1) & !3;
+
+        for word in (first..=last).step_by(4) {
+            let word = word as u32;
+            let entry = &mut self.entries[DecodeCache::index(word)];
+
+            if matches!(entry, Some((tag, _)) if *tag == word) {
+                *entry = None;
+            }
+        }
+    }
+
+    pub fn flush(&mut self) {
+        self.entries.fill(None);
+    }
+
+    pub fn get_hits(&self) -> u64 {
+        self.hits
+    }
+
+    pub fn get_misses(&self) -> u64 {
+        self.misses
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{instruction_decoder::decode, stop_conditions::StopReason, test_utils::*};
+
+    const T0: u32 = 5;
+    const S0: u32 = 8;
+    const S1: u32 = 9;
+
+    #[test]
+    fn writes_drop_the_instructions_they_overlap() {
+        let mut cache = DecodeCache::new();
+        for pc in [0x100, 0x104, 0x108] {
+            cache.insert(pc, decode(addi(A0, 0, pc as i32)));
+        }
+
+        cache.invalidate(0x105, 1);
+        assert!(cache.get(0x104).is_none());
+        assert!(cache.get(0x100).is_some());
+        assert!(cache.get(0x108).is_some());
+
+        // a misaligned write spans two instructions
+        cache.invalidate(0x106, 4);
+        assert!(cache.get(0x108).is_none());
+        assert!(cache.get(0x100).is_some());
+
+        // the same index with another pc isn't the written instruction
+        cache.invalidate(0x100 + 4 * DECODE_CACHE_ENTRIES, 4);
+        assert!(cache.get(0x100).is_some());
+    }
+
+    #[test]
+    fn overwritten_code_is_decoded_again() {
+        // the second iteration runs the instruction stored over the decoded one
+        let mut program = Vec::new();
+        program.extend(li(S0, address_of(5)));
+        program.extend(li(S1, addi(A1, A1, 100)));
+        program.extend([
+            addi(T0, 0, 2),
+            addi(A1, A1, 1),
+            sw(S1, S0, 0),
+            addi(T0, T0, -1),
+            bne(T0, 0, -12),
+        ]);
+        program.extend(exit());
+
+        for enabled in [false, true] {
+            let (reason, vm) = run(&program, |vm| {
+                vm.set_block_cache(false);
+                vm.set_decode_cache(enabled);
+            });
+            assert_eq!(reason, StopReason::Exit(101));
+            if enabled {
+                assert!(vm.get_decode_cache().get_hits() > 0);
+            }
+        }
+    }
+}
@@ -0,0 +1,301 @@
+use crate::{
+    bus::Bus,
+    clint::{CLINT_SIZE, HOST_TIMEBASE},
+    fdt::FdtWriter,
+    plic::{CONTEXTS_PER_HART, MACHINE_CONTEXT, PLIC_SIZE},
+};
+
+// device tree describing the machine the VM is configured as: the hart, the memory regions, the
+// interrupt controllers and the attached devices that have a node
+
+// the interrupt controllers of the harts follow the PLIC
+const PLIC_PHANDLE: u32 = 1;
+const FIRST_CPU_INTC_PHANDLE: u32 = 2;
+
+// local interrupt numbers of the hart
+const IRQ_MACHINE_SOFTWARE: u32 = 3;
+const IRQ_MACHINE_TIMER: u32 = 7;
+const IRQ_SUPERVISOR_EXTERNAL: u32 = 9;
+const IRQ_MACHINE_EXTERNAL: u32 = 11;
+
+const ISA: &str = "rv32ima_zicsr_zifencei";
+const ISA_EXTENSIONS: [&str; 5] = ["i", "m", "a", "zicsr", "zifencei"];
+
+pub enum PropertyValue {
+    // boolean properties, like gpio-controller
+    Empty,
+    U32(u32),
+    String(String),
+}
+
+// node of a device, the bus adds its unit address, reg, interrupts and interrupt-parent
+pub struct DeviceTreeNode {
+    pub name: String,
+    pub compatible: Vec<String>,
+    pub properties: Vec<(String, PropertyValue)>,
+    // memory described instead of the register window, like the pixels of a framebuffer
+    pub reg: Option<(usize, usize)>,
+}
+
+impl DeviceTreeNode {
+    pub fn new(name: &str, compatible: &[&str]) -> Self {
+        Self {
+            name: name.to_string(),
+            compatible: compatible.iter().map(|string| string.to_string()).collect(),
+            properties: Vec::new(),
+            reg: None,
+        }
+    }
+
+    pub fn with_property(mut self, name: &str, value: PropertyValue) -> Self {
+        self.properties.push((name.to_string(), value));
+        self
+    }
+
+    pub fn with_reg(mut self, base: usize, size: usize) -> Self {
+        self.reg = Some((base, size));
+        self
+    }
+}
+
+// what the boot stage tells the guest: the /chosen node and the memory reservation map
+#[derive(Default)]
+pub struct BootInfo {
+    pub bootargs: Option<String>,
+    // start and end of the initramfs
+    pub initrd: Option<(usize, usize)>,
+    // address and size of the memory the guest must leave alone, like the firmware
+    pub reserved: Vec<(usize, usize)>,
+}
+
+// addresses and sizes take two cells, like on the qemu virt machine
+fn reg(base: usize, size: usize) -> Vec<u32> {
+    let (base, size) = (base as u64, size as u64);
+    vec![
+        (base >> 32) as u32,
+        base as u32,
+        (size >> 32) as u32,
+        size as u32,
+    ]
+}
+
+pub fn generate(bus: &Bus, boot: &BootInfo) -> Vec<u8> {
+    let mut fdt = FdtWriter::new();
+    // by address once the nodes giving their own reg are moved there
+    let mut devices: Vec<_> = bus
+        .device_tree_nodes()
+        .into_iter()
+        .map(|(base, size, irq, node)| {
+            let (base, size) = node.reg.unwrap_or((base, size));
+            (base, size, irq, node)
+        })
+        .collect();
+    devices.sort_by_key(|(base, ..)| *base);
+
+    for (address, size) in &boot.reserved {
+        fdt.add_reservation(*address as u64, *size as u64);
+    }
+
+    fdt.begin_node("");
+    fdt.property_u32("#address-cells", 2);
+    fdt.property_u32("#size-cells", 2);
+    fdt.property_string("compatible", "riscv-emulator");
+    fdt.property_string("model", "riscv-emulator");
+
+    fdt.begin_node("chosen");
+    if let Some(bootargs) = &boot.bootargs {
+        fdt.property_string("bootargs", bootargs);
+    }
+    // the console is the first serial port
+    if let Some((base, ..)) = devices.iter().find(|(.., node)| node.name == "serial") {
+        fdt.property_string("stdout-path", &format!("/soc/serial@{base:x}"));
+    }
+    if let Some((start, end)) = boot.initrd {
+        fdt.property_u32("linux,initrd-start", start as u32);
+        fdt.property_u32("linux,initrd-end", end as u32);
+    }
+    fdt.end_node();
+
+    // mtime follows the retired instructions at the same rate as the host clock
+    let clint = bus.get_clint();
+    let harts = clint.get_harts();
+    let cpu_intc_phandle = |hart: usize| FIRST_CPU_INTC_PHANDLE + hart as u32;
+    fdt.begin_node("cpus");
+    fdt.property_u32("#address-cells", 1);
+    fdt.property_u32("#size-cells", 0);
+    fdt.property_u32("timebase-frequency", HOST_TIMEBASE as u32);
+    for hart in 0..harts {
+        fdt.begin_node(&format!("cpu@{hart}"));
+        fdt.property_string("device_type", "cpu");
+        fdt.property_u32("reg", hart as u32);
+        fdt.property_string("status", "okay");
+        fdt.property_string("compatible", "riscv");
+        fdt.property_string("riscv,isa", ISA);
+        fdt.property_string("riscv,isa-base", "rv32i");
+        fdt.property_strings("riscv,isa-extensions", &ISA_EXTENSIONS);
+        fdt.property_string("mmu-type", "riscv,sv32");
+        fdt.begin_node("interrupt-controller");
+        fdt.property_u32("#interrupt-cells", 1);
+        fdt.property_empty("interrupt-controller");
+        fdt.property_string("compatible", "riscv,cpu-intc");
+        fdt.property_u32("phandle", cpu_intc_phandle(hart));
+        fdt.end_node();
+        fdt.end_node();
+    }
+    fdt.end_node();
+
+    // RAM that a device describes, like video memory, isn't system memory
+    let memories = bus.memory_regions();
+    let described = |base: usize| {
+        devices
+            .iter()
+            .any(|(.., node)| node.reg.is_some_and(|(reg_base, _)| reg_base == base))
+    };
+    for (base, size, _) in memories
+        .iter()
+        .filter(|(base, _, read_only)| !read_only && !described(*base))
+    {
+        fdt.begin_node(&format!("memory@{base:x}"));
+        fdt.property_string("device_type", "memory");
+        fdt.property_cells("reg", &reg(*base, *size));
+        fdt.end_node();
+    }
+
+    fdt.begin_node("soc");
+    fdt.property_u32("#address-cells", 2);
+    fdt.property_u32("#size-cells", 2);
+    fdt.property_string("compatible", "simple-bus");
+    fdt.property_empty("ranges");
+
+    let clint_interrupts: Vec<u32> = (0..harts)
+        .flat_map(|hart| {
+            let phandle = cpu_intc_phandle(hart);
+            [phandle, IRQ_MACHINE_SOFTWARE, phandle, IRQ_MACHINE_TIMER]
+        })
+        .collect();
+    fdt.begin_node(&format!("clint@{:x}", clint.get_base()));
+    fdt.property_strings("compatible", &["sifive,clint0", "riscv,clint0"]);
+    fdt.property_cells("reg", &reg(clint.get_base(), CLINT_SIZE));
+    fdt.property_cells("interrupts-extended", &clint_interrupts);
+    fdt.end_node();
+
+    // each hart has a machine and a supervisor context
+    let plic = bus.get_plic();
+    let contexts: Vec<u32> = (0..plic.get_contexts())
+        .flat_map(|context| {
+            let phandle = cpu_intc_phandle(context / CONTEXTS_PER_HART);
+            match context % CONTEXTS_PER_HART {
+                MACHINE_CONTEXT => [phandle, IRQ_MACHINE_EXTERNAL],
+                _ => [phandle, IRQ_SUPERVISOR_EXTERNAL],
+            }
+        })
+        .collect();
+    fdt.begin_node(&format!("plic@{:x}", plic.get_base()));
+    fdt.property_strings("compatible", &["sifive,plic-1.0.0", "riscv,plic0"]);
+    fdt.property_cells("reg", &reg(plic.get_base(), PLIC_SIZE));
+    fdt.property_u32("#address-cells", 0);
+    fdt.property_u32("#interrupt-cells", 1);
+    fdt.property_empty("interrupt-controller");
+    fdt.property_u32("riscv,ndev", plic.get_sources());
+    fdt.property_cells("interrupts-extended", &contexts);
+    fdt.property_u32("phandle", PLIC_PHANDLE);
+    fdt.end_node();
+
+    // read-only memory is mapped like a flash chip
+    for (base, size, _) in memories.iter().filter(|(.., read_only)| *read_only) {
+        fdt.begin_node(&format!("rom@{base:x}"));
+        fdt.property_string("compatible", "mtd-rom");
+        fdt.property_cells("reg", &reg(*base, *size));
+        fdt.property_u32("bank-width", 4);
+        fdt.end_node();
+    }
+
+    for (base, size, irq, node) in &devices {
+        fdt.begin_node(&format!("{}@{base:x}", node.name));
+        let compatible: Vec<&str> = node.compatible.iter().map(String::as_str).collect();
+        fdt.property_strings("compatible", &compatible);
+        fdt.property_cells("reg", &reg(*base, *size));
+        if let Some(irq) = irq {
+            fdt.property_u32("interrupts", *irq);
+            fdt.property_u32("interrupt-parent", PLIC_PHANDLE);
+        }
+        for (name, value) in &node.properties {
+            match value {
+                PropertyValue::Empty => fdt.property_empty(name),
+                PropertyValue::U32(value) => fdt.property_u32(name, *value),
+                PropertyValue::String(value) => fdt.property_string(name, value),
+            }
+        }
+        fdt.end_node();
+    }
+    fdt.end_node();
+
+    fdt.end_node();
+    fdt.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{clint::CLINT_ADDRESS, fdt::tests::properties, vm::VM};
+
+    fn cells(values: &[u32]) -> Vec<u8> {
+        values
+            .iter()
+            .flat_map(|value| value.to_be_bytes())
+            .collect()
+    }
+
+    #[test]
+    fn tree_describes_the_memory_and_interrupt_controllers() {
+        let mut vm = VM::without_memory();
+        vm.get_bus_mut()
+            .add_ram("ram", 0x8000_0000, vec![0; 0x10000])
+            .unwrap();
+        let boot = BootInfo {
+            bootargs: Some("console=hvc0".to_string()),
+            initrd: Some((0x8000_8000, 0x8000_9000)),
+            reserved: vec![(0x8000_0000, 0x1000)],
+        };
+        let properties = properties(&generate(vm.get_bus(), &boot));
+        let find = |path: &str, name: &str| {
+            properties
+                .iter()
+                .find(|(node, property, _)| node == path && property == name)
+                .map(|(.., value)| value.clone())
+        };
+
+        assert_eq!(find("/chosen", "bootargs").unwrap(), b"console=hvc0\0");
+        assert_eq!(
+            find("/chosen", "linux,initrd-start").unwrap(),
+            cells(&[0x8000_8000])
+        );
+        assert_eq!(
+            find("/cpus/cpu@0", "riscv,isa").unwrap(),
+            b"rv32ima_zicsr_zifencei\0"
+        );
+        assert_eq!(
+            find("/memory@80000000", "reg").unwrap(),
+            cells(&[0, 0x8000_0000, 0, 0x10000])
+        );
+        assert_eq!(
+            find(&format!("/soc/clint@{CLINT_ADDRESS:x}"), "reg").unwrap(),
+            cells(&reg(CLINT_ADDRESS, CLINT_SIZE))
+        );
+        // the machine timer and software interrupts of the only hart
+        assert_eq!(
+            find(
+                &format!("/soc/clint@{CLINT_ADDRESS:x}"),
+                "interrupts-extended"
+            )
+            .unwrap(),
+            cells(&[
+                FIRST_CPU_INTC_PHANDLE,
+                IRQ_MACHINE_SOFTWARE,
+                FIRST_CPU_INTC_PHANDLE,
+                IRQ_MACHINE_TIMER
+            ])
+        );
+        assert!(find("/cpus/cpu@1", "reg").is_none());
+    }
+}
@@ -0,0 +1,207 @@
+// minimal ELF32 little-endian reader, only what the emulator needs: loadable segments and symbols
+
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const ELFCLASS32: u8 = 1;
+const ELFDATA2LSB: u8 = 1;
+const EM_RISCV: u16 = 243;
+
+const PT_LOAD: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const STT_FUNC: u8 = 2;
+
+const PHDR_SIZE: usize = 32;
+const SHDR_SIZE: usize = 40;
+const SYM_SIZE: usize = 16;
+
+pub struct Segment {
+    address: u32,
+    // file content zero-extended to the segment memory size
+    data: Vec<u8>,
+}
+
+impl Segment {
+    pub fn get_address(&self) -> u32 {
+        self.address
+    }
+
+    pub fn get_data(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+pub struct Symbol {
+    name: String,
+    address: u32,
+    size: u32,
+    is_function: bool,
+}
+
+impl Symbol {
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn get_address(&self) -> u32 {
+        self.address
+    }
+
+    pub fn get_size(&self) -> u32 {
+        self.size
+    }
+
+    pub fn is_function(&self) -> bool {
+        self.is_function
+    }
+}
+
+pub struct Elf {
+    segments: Vec<Segment>,
+    symbols: Vec<Symbol>,
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Result<u16, String> {
+    data.get(offset..offset + 2)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+        .ok_or_else(|| format!("truncated ELF file at offset {:x}", offset))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, String> {
+    data.get(offset..offset + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or_else(|| format!("truncated ELF file at offset {:x}", offset))
+}
+
+fn read_slice(data: &[u8], offset: usize, size: usize) -> Result<&[u8], String> {
+    data.get(offset..offset + size)
+        .ok_or_else(|| format!("truncated ELF file at offset {:x}", offset))
+}
+
+fn read_str(data: &[u8], offset: usize) -> Result<String, String> {
+    let bytes = data
+        .get(offset..)
+        .ok_or_else(|| format!("invalid string offset {:x}", offset))?;
+    let end = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+
+    Ok(String::from_utf8_lossy(&bytes[..end]).into_owned())
+}
+
+impl Elf {
+    pub fn is_elf(data: &[u8]) -> bool {
+        data.starts_with(&ELF_MAGIC)
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Self, String> {
+        if !Elf::is_elf(data) {
+            return Err("not an ELF file".to_string());
+        }
+        if data.len() < 52 || data[4] != ELFCLASS32 || data[5] != ELFDATA2LSB {
+            return Err("only 32-bit little-endian ELF files are supported".to_string());
+        }
+        if read_u16(data, 18)? != EM_RISCV {
+            return Err("not a RISC-V ELF file".to_string());
+        }
+
+        let phoff = read_u32(data, 28)? as usize;
+        let shoff = read_u32(data, 32)? as usize;
+        let phnum = read_u16(data, 44)? as usize;
+        let shnum = read_u16(data, 48)? as usize;
+
+        let mut segments = Vec::new();
+        for i in 0..phnum {
+            let phdr = phoff + i * PHDR_SIZE;
+            if read_u32(data, phdr)? != PT_LOAD {
+                continue;
+            }
+
+            let offset = read_u32(data, phdr + 4)? as usize;
+            // physical address, that's where the loader places the bytes
+            let address = read_u32(data, phdr + 12)?;
+            let file_size = read_u32(data, phdr + 16)? as usize;
+            let memory_size = read_u32(data, phdr + 20)? as usize;
+
+            if memory_size == 0 {
+                continue;
+            }
+
+            let mut segment_data = read_slice(data, offset, file_size)?.to_vec();
+            segment_data.resize(memory_size.max(file_size), 0);
+
+            segments.push(Segment {
+                address,
+                data: segment_data,
+            });
+        }
+
+        let mut symbols = Vec::new();
+        for i in 0..shnum {
+            let shdr = shoff + i * SHDR_SIZE;
+            if read_u32(data, shdr + 4)? != SHT_SYMTAB {
+                continue;
+            }
+
+            let offset = read_u32(data, shdr + 16)? as usize;
+            let size = read_u32(data, shdr + 20)? as usize;
+            let strtab_index = read_u32(data, shdr + 24)? as usize;
+
+            let strtab_shdr = shoff + strtab_index * SHDR_SIZE;
+            let strtab_offset = read_u32(data, strtab_shdr + 16)? as usize;
+            let strtab_size = read_u32(data, strtab_shdr + 20)? as usize;
+            let strtab = read_slice(data, strtab_offset, strtab_size)?;
+
+            // the first entry is always the null symbol
+            for sym in (offset..offset + size).step_by(SYM_SIZE).skip(1) {
+                let name = read_str(strtab, read_u32(data, sym)? as usize)?;
+                if name.is_empty() {
+                    continue;
+                }
+
+                symbols.push(Symbol {
+                    name,
+                    address: read_u32(data, sym + 4)?,
+                    size: read_u32(data, sym + 8)?,
+                    is_function: read_slice(data, sym + 12, 1)?[0] & 0xf == STT_FUNC,
+                });
+            }
+        }
+
+        Ok(Self { segments, symbols })
+    }
+
+    pub fn get_segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    pub fn get_symbols(&self) -> &[Symbol] {
+        &self.symbols
+    }
+
+    pub fn symbol_address(&self, name: &str) -> Option<u32> {
+        self.symbols
+            .iter()
+            .find(|symbol| symbol.name == name)
+            .map(|symbol| symbol.address)
+    }
+
+    // flat image of all the segments starting at base, as produced by objcopy -O binary
+    pub fn flat_image(&self, base: u32) -> Result<Vec<u8>, String> {
+        let mut image = Vec::new();
+
+        for segment in &self.segments {
+            if segment.address < base {
+                return Err(format!(
+                    "segment at {:x} is below the load address {:x}",
+                    segment.address, base
+                ));
+            }
+
+            let start = (segment.address - base) as usize;
+            let end = start + segment.data.len();
+            if image.len() < end {
+                image.resize(end, 0);
+            }
+            image[start..end].copy_from_slice(&segment.data);
+        }
+
+        Ok(image)
+    }
+}
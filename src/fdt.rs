@@ -0,0 +1,286 @@
+use std::collections::HashMap;
+
+// flattened device tree writer, version 17 of the format: a header, the memory reservation
+// map, the structure block and the strings block. Values are big-endian
+
+const FDT_MAGIC: u32 = 0xd00dfeed;
+const FDT_VERSION: u32 = 17;
+// oldest version the blob is backwards compatible with
+const FDT_LAST_COMPATIBLE_VERSION: u32 = 16;
+const FDT_HEADER_SIZE: usize = 40;
+
+// structure block tokens
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_END: u32 = 9;
+
+#[derive(Default)]
+pub struct FdtWriter {
+    // address and size of the ranges the guest must not use
+    reservations: Vec<(u64, u64)>,
+    structure: Vec<u8>,
+    strings: Vec<u8>,
+    // property names are stored once in the strings block
+    string_offsets: HashMap<String, u32>,
+    // nodes begun and not ended yet
+    depth: usize,
+}
+
+impl FdtWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_reservation(&mut self, address: u64, size: u64) {
+        self.reservations.push((address, size));
+    }
+
+    // the root node has an empty name
+    pub fn begin_node(&mut self, name: &str) {
+        self.push_token(FDT_BEGIN_NODE);
+        self.structure.extend_from_slice(name.as_bytes());
+        self.structure.push(0);
+        self.align_structure();
+        self.depth += 1;
+    }
+
+    pub fn end_node(&mut self) {
+        assert!(self.depth > 0, "no device tree node to end");
+        self.push_token(FDT_END_NODE);
+        self.depth -= 1;
+    }
+
+    pub fn property(&mut self, name: &str, value: &[u8]) {
+        assert!(
+            self.depth > 0,
+            "device tree property {name} outside of a node"
+        );
+        let name_offset = self.string_offset(name);
+
+        self.push_token(FDT_PROP);
+        self.structure
+            .extend_from_slice(&(value.len() as u32).to_be_bytes());
+        self.structure.extend_from_slice(&name_offset.to_be_bytes());
+        self.structure.extend_from_slice(value);
+        self.align_structure();
+    }
+
+    // boolean properties, like interrupt-controller
+    pub fn property_empty(&mut self, name: &str) {
+        self.property(name, &[]);
+    }
+
+    pub fn property_u32(&mut self, name: &str, value: u32) {
+        self.property(name, &value.to_be_bytes());
+    }
+
+    pub fn property_cells(&mut self, name: &str, cells: &[u32]) {
+        let value: Vec<u8> = cells.iter().flat_map(|cell| cell.to_be_bytes()).collect();
+        self.property(name, &value);
+    }
+
+    pub fn property_string(&mut self, name: &str, value: &str) {
+        self.property_strings(name, &[value]);
+    }
+
+    // string lists, like compatible
+    pub fn property_strings(&mut self, name: &str, values: &[&str]) {
+        let mut value = Vec::new();
+        for string in values {
+            value.extend_from_slice(string.as_bytes());
+            value.push(0);
+        }
+        self.property(name, &value);
+    }
+
+    // the blob, every node must have been ended
+    pub fn finish(mut self) -> Vec<u8> {
+        assert!(self.depth == 0, "unterminated device tree node");
+        self.push_token(FDT_END);
+
+        // the reservation map is made of 8-byte aligned pairs ending with an empty one
+        let reservations_offset = FDT_HEADER_SIZE;
+        let structure_offset = reservations_offset + 16 * (self.reservations.len() + 1);
+        let strings_offset = structure_offset + self.structure.len();
+        let total_size = strings_offset + self.strings.len();
+
+        let header = [
+            FDT_MAGIC,
+            total_size as u32,
+            structure_offset as u32,
+            strings_offset as u32,
+            reservations_offset as u32,
+            FDT_VERSION,
+            FDT_LAST_COMPATIBLE_VERSION,
+            // boot_cpuid_phys
+            0,
+            self.strings.len() as u32,
+            self.structure.len() as u32,
+        ];
+
+        let mut blob = Vec::with_capacity(total_size);
+        for field in header {
+            blob.extend_from_slice(&field.to_be_bytes());
+        }
+        for (address, size) in self.reservations.iter().chain([&(0, 0)]) {
+            blob.extend_from_slice(&address.to_be_bytes());
+            blob.extend_from_slice(&size.to_be_bytes());
+        }
+        blob.extend_from_slice(&self.structure);
+        blob.extend_from_slice(&self.strings);
+
+        blob
+    }
+
+    fn push_token(&mut self, token: u32) {
+        self.structure.extend_from_slice(&token.to_be_bytes());
+    }
+
+    // tokens are 4-byte aligned
+    fn align_structure(&mut self) {
+        while !self.structure.len().is_multiple_of(4) {
+            self.structure.push(0);
+        }
+    }
+
+    fn string_offset(&mut self, name: &str) -> u32 {
+        if let Some(offset) = self.string_offsets.get(name) {
+            return *offset;
+        }
+
+        let offset = self.strings.len() as u32;
+        self.strings.extend_from_slice(name.as_bytes());
+        self.strings.push(0);
+        self.string_offsets.insert(name.to_string(), offset);
+        offset
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    fn be_u32(blob: &[u8], offset: usize) -> u32 {
+        u32::from_be_bytes(blob[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn be_u64(blob: &[u8], offset: usize) -> u64 {
+        u64::from_be_bytes(blob[offset..offset + 8].try_into().unwrap())
+    }
+
+    fn c_string(bytes: &[u8]) -> &str {
+        let end = bytes.iter().position(|byte| *byte == 0).unwrap();
+        std::str::from_utf8(&bytes[..end]).unwrap()
+    }
+
+    // reads the blob back as (node path, property name, value), checking the header and tokens
+    pub(crate) fn properties(blob: &[u8]) -> Vec<(String, String, Vec<u8>)> {
+        assert_eq!(be_u32(blob, 0), FDT_MAGIC);
+        assert_eq!(be_u32(blob, 4) as usize, blob.len());
+        let structure = be_u32(blob, 8) as usize;
+        let strings = be_u32(blob, 12) as usize;
+        assert_eq!(be_u32(blob, 20), FDT_VERSION);
+        assert_eq!(be_u32(blob, 32) as usize, blob.len() - strings);
+        assert_eq!(be_u32(blob, 36) as usize, strings - structure);
+
+        let mut properties = Vec::new();
+        let mut path: Vec<String> = Vec::new();
+        let mut offset = structure;
+        loop {
+            let token = be_u32(blob, offset);
+            offset += 4;
+            match token {
+                FDT_BEGIN_NODE => {
+                    let name = c_string(&blob[offset..]);
+                    offset += (name.len() + 1).next_multiple_of(4);
+                    path.push(name.to_string());
+                }
+                FDT_END_NODE => {
+                    path.pop().unwrap();
+                }
+                FDT_PROP => {
+                    let length = be_u32(blob, offset) as usize;
+                    let name = c_string(&blob[strings + be_u32(blob, offset + 4) as usize..]);
+                    let value = blob[offset + 8..offset + 8 + length].to_vec();
+                    offset += 8 + length.next_multiple_of(4);
+                    properties.push((path.join("/"), name.to_string(), value));
+                }
+                FDT_END => break,
+                _ => panic!("unexpected token {token} at {offset:#x}"),
+            }
+        }
+        assert!(path.is_empty());
+        assert_eq!(offset, strings);
+        properties
+    }
+
+    #[test]
+    fn blob_has_the_header_reservations_and_nodes() {
+        let mut fdt = FdtWriter::new();
+        fdt.add_reservation(0x8000_0000, 0x20_0000);
+        fdt.begin_node("");
+        fdt.property_u32("#address-cells", 2);
+        fdt.begin_node("chosen");
+        fdt.property_string("bootargs", "console=ttyS0");
+        fdt.end_node();
+        fdt.begin_node("serial@10000000");
+        fdt.property_strings("compatible", &["ns16550a", "ns16550"]);
+        fdt.property_cells("reg", &[0, 0x1000_0000, 0, 0x100]);
+        fdt.property_empty("interrupt-controller");
+        fdt.end_node();
+        fdt.end_node();
+        let blob = fdt.finish();
+
+        assert_eq!(be_u32(&blob, 16) as usize, FDT_HEADER_SIZE);
+        assert_eq!(be_u32(&blob, 24), FDT_LAST_COMPATIBLE_VERSION);
+        assert_eq!(be_u64(&blob, FDT_HEADER_SIZE), 0x8000_0000);
+        assert_eq!(be_u64(&blob, FDT_HEADER_SIZE + 8), 0x20_0000);
+        // the map ends with an empty pair
+        assert_eq!(be_u64(&blob, FDT_HEADER_SIZE + 16), 0);
+        assert_eq!(be_u64(&blob, FDT_HEADER_SIZE + 24), 0);
+        assert_eq!(be_u32(&blob, 8) as usize, FDT_HEADER_SIZE + 32);
+
+        let property = |path: &str, name: &str, value: &[u8]| {
+            (path.to_string(), name.to_string(), value.to_vec())
+        };
+        assert_eq!(
+            properties(&blob),
+            vec![
+                property("", "#address-cells", &[0, 0, 0, 2]),
+                property("/chosen", "bootargs", b"console=ttyS0\0"),
+                property("/serial@10000000", "compatible", b"ns16550a\0ns16550\0"),
+                property(
+                    "/serial@10000000",
+                    "reg",
+                    &[0, 0, 0, 0, 0x10, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0]
+                ),
+                property("/serial@10000000", "interrupt-controller", &[]),
+            ]
+        );
+    }
+
+    #[test]
+    fn property_names_are_stored_once() {
+        let mut fdt = FdtWriter::new();
+        fdt.begin_node("");
+        for name in ["a", "b", "c"] {
+            fdt.begin_node(name);
+            fdt.property_string("compatible", name);
+            fdt.end_node();
+        }
+        fdt.end_node();
+        let blob = fdt.finish();
+
+        assert_eq!(be_u32(&blob, 32) as usize, "compatible\0".len());
+        assert_eq!(properties(&blob).len(), 3);
+    }
+
+    #[test]
+    #[should_panic(expected = "unterminated device tree node")]
+    fn unterminated_node_panics() {
+        let mut fdt = FdtWriter::new();
+        fdt.begin_node("");
+        fdt.finish();
+    }
+}
@@ -0,0 +1,387 @@
+use std::{fs::File, io, io::Write};
+
+use crate::{
+    bus::{Device, DmaMemory},
+    device_tree::{DeviceTreeNode, PropertyValue},
+    snapshot::{SnapshotReader, SnapshotWriter},
+};
+
+// linear framebuffer. The pixels live in a RAM region of the bus so the guest draws at memory
+// speed, the control registers describe them and presenting a frame saves it on the host
+
+pub const FRAMEBUFFER_CONTROL_ADDRESS: usize = 0x10070000;
+const FRAMEBUFFER_CONTROL_SIZE: usize = 0x100;
+pub const FRAMEBUFFER_ADDRESS: usize = 0x50000000;
+
+// largest width and height, 64 MiB of pixels at most
+pub const FRAMEBUFFER_MAX_SIZE: u32 = 4096;
+
+// register offsets
+const WIDTH: usize = 0x00;
+const HEIGHT: usize = 0x04;
+const FORMAT: usize = 0x08;
+const STRIDE: usize = 0x0c;
+const ADDRESS: usize = 0x10;
+// writing presents the frame, reading returns the number of presented frames
+const PRESENT: usize = 0x14;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    // 32 bits, little endian: blue, green, red and an unused byte
+    Xrgb8888,
+    // 24 bits: red, green and blue bytes
+    Rgb888,
+    // 16 bits, little endian
+    Rgb565,
+}
+
+impl PixelFormat {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "xrgb8888" => Some(PixelFormat::Xrgb8888),
+            "rgb888" => Some(PixelFormat::Rgb888),
+            "rgb565" => Some(PixelFormat::Rgb565),
+            _ => None,
+        }
+    }
+
+    pub fn bytes_per_pixel(&self) -> usize {
+        match self {
+            PixelFormat::Xrgb8888 => 4,
+            PixelFormat::Rgb888 => 3,
+            PixelFormat::Rgb565 => 2,
+        }
+    }
+
+    // format of the simple-framebuffer binding, named from the most significant bits of the
+    // little endian pixel
+    fn device_tree_name(&self) -> &'static str {
+        match self {
+            PixelFormat::Xrgb8888 => "x8r8g8b8",
+            PixelFormat::Rgb888 => "b8g8r8",
+            PixelFormat::Rgb565 => "r5g6b5",
+        }
+    }
+
+    // value of the FORMAT register
+    fn id(&self) -> u32 {
+        match self {
+            PixelFormat::Xrgb8888 => 0,
+            PixelFormat::Rgb888 => 1,
+            PixelFormat::Rgb565 => 2,
+        }
+    }
+
+    fn to_rgb(self, pixel: &[u8]) -> [u8; 3] {
+        match self {
+            PixelFormat::Xrgb8888 => [pixel[2], pixel[1], pixel[0]],
+            PixelFormat::Rgb888 => [pixel[0], pixel[1], pixel[2]],
+            PixelFormat::Rgb565 => {
+                let value = u16::from_le_bytes([pixel[0], pixel[1]]);
+                // the high bits are repeated so that white stays white
+                let red = (value >> 11) as u8 & 0x1f;
+                let green = (value >> 5) as u8 & 0x3f;
+                let blue = value as u8 & 0x1f;
+                [
+                    red << 3 | red >> 2,
+                    green << 2 | green >> 4,
+                    blue << 3 | blue >> 2,
+                ]
+            }
+        }
+    }
+}
+
+// where and how often the presented frames are saved
+pub struct FrameOutput {
+    // '%d' is replaced by the frame number, the extension chooses between .png and .ppm
+    pub pattern: String,
+    // every n-th presented frame is saved
+    pub every: u64,
+}
+
+impl FrameOutput {
+    fn path(&self, frame: u64) -> String {
+        self.pattern.replace("%d", &format!("{frame:05}"))
+    }
+}
+
+pub struct Framebuffer {
+    address: usize,
+    width: u32,
+    height: u32,
+    format: PixelFormat,
+    output: Option<FrameOutput>,
+    frames: u64,
+    present_requested: bool,
+}
+
+impl Framebuffer {
+    // the pixel memory must be mapped as RAM at the address, see memory_size
+    pub fn new(
+        address: usize,
+        width: u32,
+        height: u32,
+        format: PixelFormat,
+        output: Option<FrameOutput>,
+    ) -> Self {
+        Self {
+            address,
+            width,
+            height,
+            format,
+            output,
+            frames: 0,
+            present_requested: false,
+        }
+    }
+
+    pub fn stride(&self) -> usize {
+        self.width as usize * self.format.bytes_per_pixel()
+    }
+
+    pub fn memory_size(&self) -> usize {
+        self.stride() * self.height as usize
+    }
+
+    pub fn get_frames(&self) -> u64 {
+        self.frames
+    }
+
+    fn save(&self, pixels: &[u8], path: &str) -> io::Result<()> {
+        let rgb: Vec<u8> = pixels
+            .chunks(self.format.bytes_per_pixel())
+            .flat_map(|pixel| self.format.to_rgb(pixel))
+            .collect();
+
+        let mut file = File::create(path)?;
+        if path.ends_with(".png") {
+            file.write_all(&png(self.width, self.height, &rgb))
+        } else {
+            write!(file, "P6\n{} {}\n255\n", self.width, self.height)?;
+            file.write_all(&rgb)
+        }
+    }
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                crc >> 1 ^ 0xedb88320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for byte in data {
+        a = (a + *byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    b << 16 | a
+}
+
+// 8-bit RGB image, the zlib stream uses stored blocks so nothing needs to be compressed
+fn png(width: u32, height: u32, rgb: &[u8]) -> Vec<u8> {
+    let mut raw = Vec::new();
+    for row in rgb.chunks(width as usize * 3) {
+        // no filter
+        raw.push(0);
+        raw.extend(row);
+    }
+
+    let mut zlib = vec![0x78, 0x01];
+    let blocks: Vec<&[u8]> = raw.chunks(0xffff).collect();
+    for (i, block) in blocks.iter().enumerate() {
+        zlib.push((i == blocks.len() - 1) as u8);
+        zlib.extend((block.len() as u16).to_le_bytes());
+        zlib.extend((!(block.len() as u16)).to_le_bytes());
+        zlib.extend(*block);
+    }
+    zlib.extend(adler32(&raw).to_be_bytes());
+
+    let mut header = Vec::new();
+    header.extend(width.to_be_bytes());
+    header.extend(height.to_be_bytes());
+    // bit depth, truecolor, default compression, filter and no interlace
+    header.extend([8, 2, 0, 0, 0]);
+
+    let mut image = b"\x89PNG\r\n\x1a\n".to_vec();
+    for (kind, data) in [(b"IHDR", header), (b"IDAT", zlib), (b"IEND", Vec::new())] {
+        image.extend((data.len() as u32).to_be_bytes());
+        let start = image.len();
+        image.extend(kind);
+        image.extend(&data);
+        image.extend(crc32(&image[start..]).to_be_bytes());
+    }
+    image
+}
+
+impl Device for Framebuffer {
+    fn size(&self) -> usize {
+        FRAMEBUFFER_CONTROL_SIZE
+    }
+
+    fn read(&mut self, offset: usize, _nb_bytes: usize, _instret: u64) -> u32 {
+        match offset & !3 {
+            WIDTH => self.width,
+            HEIGHT => self.height,
+            FORMAT => self.format.id(),
+            STRIDE => self.stride() as u32,
+            ADDRESS => self.address as u32,
+            PRESENT => self.frames as u32,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, offset: usize, _nb_bytes: usize, _value: u32, _instret: u64) {
+        if offset & !3 == PRESENT {
+            self.present_requested = true;
+        }
+    }
+
+    fn reset(&mut self) {
+        self.frames = 0;
+        self.present_requested = false;
+    }
+
+    // the frame is saved once the write to PRESENT completes
+    fn dma(&mut self, memory: &mut DmaMemory, _instret: u64) {
+        if !self.present_requested {
+            return;
+        }
+        self.present_requested = false;
+        self.frames += 1;
+
+        let Some(output) = &self.output else {
+            return;
+        };
+        if !self.frames.is_multiple_of(output.every) {
+            return;
+        }
+
+        let path = output.path(self.frames);
+        let result = match memory.read(self.address as u64, self.memory_size()) {
+            Some(pixels) => self.save(&pixels, &path),
+            None => Err(io::Error::other("the pixel memory isn't mapped")),
+        };
+        // the guest keeps running, the missing frame shows up in the comparison
+        if let Err(err) = result {
+            eprintln!("riscv: cannot save frame {}: {path}: {err}", self.frames);
+        }
+    }
+
+    // a simple-framebuffer describing the pixel memory, the control registers are left out
+    fn device_tree_node(&self) -> Option<DeviceTreeNode> {
+        let node = DeviceTreeNode::new("framebuffer", &["simple-framebuffer"])
+            .with_reg(self.address, self.memory_size())
+            .with_property("width", PropertyValue::U32(self.width))
+            .with_property("height", PropertyValue::U32(self.height))
+            .with_property("stride", PropertyValue::U32(self.stride() as u32))
+            .with_property(
+                "format",
+                PropertyValue::String(self.format.device_tree_name().to_string()),
+            );
+        Some(node)
+    }
+
+    // the pixels are in the RAM of the bus, the frame numbers keep counting
+    fn save_state(&self, snapshot: &mut SnapshotWriter) {
+        snapshot.write_u64(self.frames);
+    }
+
+    fn restore_state(&mut self, snapshot: &mut SnapshotReader) -> Result<(), String> {
+        self.frames = snapshot.read_u64()?;
+        self.present_requested = false;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pixels_convert_to_8_bit_rgb() {
+        assert_eq!(PixelFormat::Xrgb8888.to_rgb(&[1, 2, 3, 4]), [3, 2, 1]);
+        assert_eq!(PixelFormat::Rgb888.to_rgb(&[1, 2, 3]), [1, 2, 3]);
+
+        let rgb565 = |value: u16| PixelFormat::Rgb565.to_rgb(&value.to_le_bytes());
+        assert_eq!(rgb565(0xffff), [255, 255, 255]);
+        assert_eq!(rgb565(0xf800), [255, 0, 0]);
+        assert_eq!(rgb565(0x07e0), [0, 255, 0]);
+        assert_eq!(rgb565(0x001f), [0, 0, 255]);
+        assert_eq!(rgb565(0x0000), [0, 0, 0]);
+        // 0b10000 of 5 bits and 0b100000 of 6 bits are halfway
+        assert_eq!(rgb565(0x8410), [132, 130, 132]);
+    }
+
+    // type and data of the chunks, after checking their crc
+    fn chunks(image: &[u8]) -> Vec<(&[u8], &[u8])> {
+        assert_eq!(&image[..8], b"\x89PNG\r\n\x1a\n");
+
+        let mut chunks = Vec::new();
+        let mut rest = &image[8..];
+        while !rest.is_empty() {
+            let len = u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+            let crc = u32::from_be_bytes(rest[8 + len..12 + len].try_into().unwrap());
+            assert_eq!(crc32(&rest[4..8 + len]), crc);
+            chunks.push((&rest[4..8], &rest[8..8 + len]));
+            rest = &rest[12 + len..];
+        }
+        chunks
+    }
+
+    #[test]
+    fn png_stores_the_rows_unfiltered() {
+        // the reference values of the checksums
+        assert_eq!(crc32(b"IEND"), 0xae426082);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e60398);
+
+        // 120 rows of 601 bytes need two stored blocks
+        let (width, height) = (200, 120);
+        let rgb: Vec<u8> = (0..width * height * 3).map(|i| i as u8).collect();
+        let image = png(width, height, &rgb);
+
+        let chunks = chunks(&image);
+        let kinds: Vec<&[u8]> = chunks.iter().map(|(kind, _)| *kind).collect();
+        assert_eq!(kinds, [b"IHDR", b"IDAT", b"IEND"]);
+
+        let header = chunks[0].1;
+        assert_eq!(&header[..4], width.to_be_bytes());
+        assert_eq!(&header[4..8], height.to_be_bytes());
+        assert_eq!(&header[8..], [8, 2, 0, 0, 0]);
+
+        let zlib = chunks[1].1;
+        assert_eq!(&zlib[..2], [0x78, 0x01]);
+        let mut raw = Vec::new();
+        let mut rest = &zlib[2..zlib.len() - 4];
+        loop {
+            let len = u16::from_le_bytes([rest[1], rest[2]]);
+            assert_eq!(u16::from_le_bytes([rest[3], rest[4]]), !len);
+            raw.extend(&rest[5..5 + len as usize]);
+            let last = rest[0] == 1;
+            rest = &rest[5 + len as usize..];
+            if last {
+                break;
+            }
+            assert_eq!(len, 0xffff);
+        }
+        assert!(rest.is_empty());
+        assert_eq!(&zlib[zlib.len() - 4..], adler32(&raw).to_be_bytes());
+
+        let stride = width as usize * 3;
+        assert_eq!(raw.len(), height as usize * (1 + stride));
+        for (row, line) in raw.chunks(1 + stride).enumerate() {
+            assert_eq!(line[0], 0);
+            assert_eq!(&line[1..], &rgb[row * stride..(row + 1) * stride]);
+        }
+    }
+}
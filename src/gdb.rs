@@ -0,0 +1,534 @@
+use std::{
+    io::{self, Read, Write},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::{
+    history::{History, HistoryStop},
+    stop_conditions::{StopConditions, StopReason},
+    vm::VM,
+};
+
+// stub of the GDB remote serial protocol, for `target remote` over TCP. The running hart is shown
+// as the only thread with x0 to x31 and pc, memory addresses are physical. Breakpoints are stop
+// addresses, the guest code isn't patched, and watchpoints stop after a write. The run is kept
+// in a History so that gdb can step and continue backwards (bs and bc packets)
+
+// gdb register numbers, pc comes after x31
+const PC_REGISTER: usize = 32;
+
+const REGISTER_NAMES: [&str; 33] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "fp", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6", "pc",
+];
+
+// signals of the stop replies
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+// the largest packet gdb sends, memory writes are split accordingly
+const PACKET_SIZE: usize = 0x4000;
+
+// register layout of the target, so that gdb doesn't have to be told the architecture
+fn target_description() -> String {
+    let mut xml = String::from(
+        "<?xml version=\"1.0\"?><!DOCTYPE target SYSTEM \"gdb-target.dtd\"><target>\
+         <architecture>riscv:rv32</architecture><feature name=\"org.gnu.gdb.riscv.cpu\">",
+    );
+    for (number, name) in REGISTER_NAMES.iter().enumerate() {
+        let kind = match *name {
+            "ra" | "pc" => "code_ptr",
+            "sp" | "gp" | "tp" | "fp" => "data_ptr",
+            _ => "int",
+        };
+        xml.push_str(&format!(
+            "<reg name=\"{name}\" bitsize=\"32\" type=\"{kind}\" regnum=\"{number}\"/>"
+        ));
+    }
+    xml.push_str("</feature></target>");
+    xml
+}
+
+fn parse_hex(text: &str) -> Option<u32> {
+    u32::from_str_radix(text, 16).ok()
+}
+
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn encode_hex(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+// "addr,length" of the memory and breakpoint packets
+fn parse_range(text: &str) -> Option<(u32, u32)> {
+    let (address, length) = text.split_once(',')?;
+    Some((parse_hex(address)?, parse_hex(length)?))
+}
+
+// register in gdb numbering, value in target byte order
+fn set_register(vm: &mut VM, register: usize, value: &[u8]) {
+    let value = u32::from_le_bytes(value.try_into().unwrap());
+    match register {
+        PC_REGISTER => vm.set_pc(value),
+        _ => vm.set_register(register as u32, value),
+    }
+}
+
+fn stop_reply(stop: HistoryStop) -> String {
+    match stop {
+        HistoryStop::Stopped(StopReason::StopAddress(_)) => format!("T{SIGTRAP:02x}swbreak:;"),
+        HistoryStop::Stopped(StopReason::Watchpoint(address)) => {
+            format!("T{SIGTRAP:02x}watch:{address:x};")
+        }
+        HistoryStop::Stopped(_) => format!("S{SIGTRAP:02x}"),
+        HistoryStop::Begin => format!("T{SIGTRAP:02x}replaylog:begin;"),
+        HistoryStop::End => format!("T{SIGTRAP:02x}replaylog:end;"),
+        HistoryStop::Interrupted => format!("S{SIGINT:02x}"),
+    }
+}
+
+pub struct GdbStub {
+    stream: TcpStream,
+    breakpoints: Vec<u32>,
+    watchpoints: Vec<(u32, u32)>,
+    // reply to the ? packet
+    last_stop: String,
+}
+
+// what the session does once a packet is handled
+enum Session {
+    Reply(String),
+    // the guest exited, the session ends after telling gdb
+    Exit(i32),
+    // the machine runs to the end without the debugger
+    Detach,
+    Kill,
+}
+
+impl GdbStub {
+    // waits for gdb to connect
+    pub fn listen(port: u16) -> io::Result<Self> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        eprintln!("riscv: waiting for gdb on 127.0.0.1:{port}");
+        let (stream, _) = listener.accept()?;
+        stream.set_nodelay(true)?;
+        Ok(Self {
+            stream,
+            breakpoints: Vec::new(),
+            watchpoints: Vec::new(),
+            last_stop: format!("S{SIGTRAP:02x}"),
+        })
+    }
+
+    fn read_byte(&mut self) -> Option<u8> {
+        let mut byte = [0];
+        match self.stream.read(&mut byte) {
+            Ok(1) => Some(byte[0]),
+            _ => None,
+        }
+    }
+
+    // next packet, acknowledged. None when gdb is gone
+    fn read_packet(&mut self) -> Option<String> {
+        loop {
+            // acknowledgments and interrupts while stopped are ignored
+            while self.read_byte()? != b'$' {}
+
+            let mut payload = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    b'#' => break,
+                    byte => payload.push(byte),
+                }
+            }
+            let checksum = [self.read_byte()?, self.read_byte()?];
+            let expected = payload
+                .iter()
+                .fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+
+            if decode_hex(std::str::from_utf8(&checksum).ok()?) == Some(vec![expected]) {
+                self.stream.write_all(b"+").ok()?;
+                return Some(String::from_utf8_lossy(&payload).into_owned());
+            }
+            self.stream.write_all(b"-").ok()?;
+        }
+    }
+
+    fn send(&mut self, payload: &str) -> io::Result<()> {
+        let checksum = payload
+            .bytes()
+            .fold(0u8, |sum, byte| sum.wrapping_add(byte));
+        self.stream
+            .write_all(format!("${payload}#{checksum:02x}").as_bytes())
+    }
+
+    // gdb sends ^C to stop a running target
+    fn interrupted(&mut self) -> bool {
+        let mut byte = [0];
+        let _ = self.stream.set_nonblocking(true);
+        let read = self.stream.read(&mut byte);
+        let _ = self.stream.set_nonblocking(false);
+        matches!(read, Ok(1)) && byte[0] == 0x03
+    }
+
+    // the breakpoints and watchpoints, on top of the stop conditions of the command line when
+    // running forward
+    fn stop_conditions(&self, base: &StopConditions) -> StopConditions {
+        let mut stop_conditions = base.clone();
+        for &address in &self.breakpoints {
+            stop_conditions.add_stop_address(address);
+        }
+        for &(address, length) in &self.watchpoints {
+            stop_conditions.add_watchpoint(address, length);
+        }
+        stop_conditions
+    }
+
+    // serves gdb until it detaches, kills the machine or the guest exits. None when killed
+    pub fn serve(
+        &mut self,
+        vm: &mut VM,
+        stop_conditions: &StopConditions,
+        checkpoint_interval: u64,
+    ) -> Option<StopReason> {
+        let mut history = History::new(vm, checkpoint_interval);
+
+        // gdb being gone is like a detach
+        while let Some(packet) = self.read_packet() {
+            match self.handle(&packet, vm, &mut history, stop_conditions) {
+                Session::Reply(reply) => {
+                    let _ = self.send(&reply);
+                }
+                Session::Exit(exit_code) => {
+                    let _ = self.send(&format!("W{:02x}", exit_code as u8));
+                    return Some(StopReason::Exit(exit_code));
+                }
+                Session::Detach => {
+                    let _ = self.send("OK");
+                    break;
+                }
+                Session::Kill => {
+                    let _ = self.send("OK");
+                    return None;
+                }
+            }
+        }
+
+        loop {
+            if let HistoryStop::Stopped(reason) =
+                history.forward(vm, stop_conditions, &mut || false)
+            {
+                return Some(reason);
+            }
+        }
+    }
+
+    fn handle(
+        &mut self,
+        packet: &str,
+        vm: &mut VM,
+        history: &mut History,
+        base: &StopConditions,
+    ) -> Session {
+        let reply = match packet.split_at_checked(1).unwrap_or_default() {
+            ("?", _) => self.last_stop.clone(),
+            ("g", _) => {
+                let mut registers = vm.get_registers();
+                registers.push(vm.get_pc());
+                registers
+                    .iter()
+                    .map(|value| encode_hex(&value.to_le_bytes()))
+                    .collect()
+            }
+            ("G", values) => match decode_hex(values) {
+                Some(data) if data.len() == 4 * REGISTER_NAMES.len() => {
+                    for (register, value) in data.chunks(4).enumerate() {
+                        set_register(vm, register, value);
+                    }
+                    history.truncate(vm);
+                    "OK".to_string()
+                }
+                _ => "E01".to_string(),
+            },
+            ("p", register) => match parse_hex(register).map(|register| register as usize) {
+                Some(PC_REGISTER) => encode_hex(&vm.get_pc().to_le_bytes()),
+                Some(register) if register < PC_REGISTER => {
+                    encode_hex(&vm.get_registers()[register].to_le_bytes())
+                }
+                _ => "E01".to_string(),
+            },
+            ("P", assignment) => {
+                let register = assignment
+                    .split_once('=')
+                    .and_then(|(register, value)| Some((parse_hex(register)?, decode_hex(value)?)))
+                    .filter(|(register, value)| {
+                        (*register as usize) < REGISTER_NAMES.len() && value.len() == 4
+                    });
+                match register {
+                    Some((register, value)) => {
+                        set_register(vm, register as usize, &value);
+                        history.truncate(vm);
+                        "OK".to_string()
+                    }
+                    None => "E01".to_string(),
+                }
+            }
+            ("m", range) => {
+                let data = parse_range(range).and_then(|(address, length)| {
+                    vm.get_bus()
+                        .read_memory_n(address as usize, length as usize)
+                });
+                match data {
+                    Some(data) => encode_hex(&data),
+                    None => "E14".to_string(),
+                }
+            }
+            ("M", write) => {
+                let data = write.split_once(':').and_then(|(range, data)| {
+                    let (address, length) = parse_range(range)?;
+                    Some((address, decode_hex(data)?))
+                        .filter(|(_, data)| data.len() == length as usize)
+                });
+                if data.is_some_and(|(address, data)| vm.write_memory(address as usize, data)) {
+                    history.truncate(vm);
+                    "OK".to_string()
+                } else {
+                    "E14".to_string()
+                }
+            }
+            ("Z" | "z", point) => self.set_point(packet.starts_with('Z'), point),
+            // both leave the breakpoint at pc, wherever the machine came from
+            ("c", _) => {
+                vm.resume_past_stop_address();
+                let stop_conditions = self.stop_conditions(base);
+                let stop = history.forward(vm, &stop_conditions, &mut || self.interrupted());
+                return self.stopped(stop);
+            }
+            ("s", _) => {
+                vm.resume_past_stop_address();
+                let mut stop_conditions = self.stop_conditions(base);
+                let next = vm.get_instret() + 1;
+                if stop_conditions
+                    .get_max_instructions()
+                    .is_none_or(|max| max > next)
+                {
+                    stop_conditions.set_max_instructions(next);
+                }
+                return self.stopped(history.forward(vm, &stop_conditions, &mut || false));
+            }
+            ("b", "s") => return self.stopped(history.reverse_step(vm)),
+            // the stop conditions of the command line only apply forward
+            ("b", "c") => {
+                let stops = self.stop_conditions(&StopConditions::new());
+                return self.stopped(history.reverse_continue(vm, &stops));
+            }
+            ("D", _) => return Session::Detach,
+            ("k", _) => return Session::Kill,
+            _ if packet.starts_with("vKill") => return Session::Kill,
+            _ => self.query(packet),
+        };
+        Session::Reply(reply)
+    }
+
+    fn stopped(&mut self, stop: HistoryStop) -> Session {
+        if let HistoryStop::Stopped(StopReason::Exit(exit_code)) = stop {
+            return Session::Exit(exit_code);
+        }
+        self.last_stop = stop_reply(stop);
+        Session::Reply(self.last_stop.clone())
+    }
+
+    // Z0 and Z1 add a breakpoint, Z2 a write watchpoint, z removes them
+    fn set_point(&mut self, insert: bool, point: &str) -> String {
+        let Some((kind, range)) = point.split_once(',') else {
+            return "E01".to_string();
+        };
+        // the kind of breakpoint (instruction size) after the address doesn't matter
+        let range = range.split(';').next().unwrap_or_default();
+        let Some((address, length)) = parse_range(range) else {
+            return "E01".to_string();
+        };
+
+        match (kind, insert) {
+            ("0" | "1", true) => self.breakpoints.push(address),
+            ("0" | "1", false) => {
+                if let Some(index) = self.breakpoints.iter().position(|&other| other == address) {
+                    self.breakpoints.remove(index);
+                }
+            }
+            ("2", true) => self.watchpoints.push((address, length)),
+            ("2", false) => {
+                let watchpoint = (address, length);
+                if let Some(index) = self
+                    .watchpoints
+                    .iter()
+                    .position(|&other| other == watchpoint)
+                {
+                    self.watchpoints.remove(index);
+                }
+            }
+            // read and access watchpoints aren't supported
+            _ => return String::new(),
+        }
+        "OK".to_string()
+    }
+
+    // general queries, unknown ones get an empty reply
+    fn query(&mut self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            return format!(
+                "PacketSize={PACKET_SIZE:x};qXfer:features:read+;swbreak+;hwbreak+;\
+                 ReverseStep+;ReverseContinue+"
+            );
+        }
+        if let Some(range) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            let xml = target_description();
+            let Some((offset, length)) = parse_range(range) else {
+                return "E01".to_string();
+            };
+            let start = (offset as usize).min(xml.len());
+            let end = (start + length as usize).min(xml.len());
+            let more = if end < xml.len() { 'm' } else { 'l' };
+            return format!("{more}{}", &xml[start..end]);
+        }
+        match packet {
+            "qAttached" => "1",
+            "qC" => "QC1",
+            "qfThreadInfo" => "m1",
+            "qsThreadInfo" => "l",
+            _ if packet.starts_with('H') || packet.starts_with('T') => "OK",
+            _ => "",
+        }
+        .to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{replay::InputLog, test_utils::*};
+
+    struct Debugged {
+        stub: GdbStub,
+        // gdb's end of the connection, the stub only looks for interrupts on it
+        _gdb: TcpStream,
+        vm: VM,
+        history: History,
+    }
+
+    impl Debugged {
+        // sum_of_reads replaying three reads, stopped at the reset
+        fn new(name: &str) -> Self {
+            let path = reads_recording(name, &[1, 2, 4]);
+            let mut vm = VM::new(flash(&sum_of_reads(3)));
+            vm.set_input_log(InputLog::replay(&path).unwrap());
+            std::fs::remove_file(&path).unwrap();
+            vm.init_execution();
+            let history = History::new(&mut vm, 8);
+
+            let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+            let gdb = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            let (stream, _) = listener.accept().unwrap();
+            let stub = GdbStub {
+                stream,
+                breakpoints: Vec::new(),
+                watchpoints: Vec::new(),
+                last_stop: format!("S{SIGTRAP:02x}"),
+            };
+            Self {
+                stub,
+                _gdb: gdb,
+                vm,
+                history,
+            }
+        }
+
+        // reply to the packet, W and the exit code when the guest exits
+        fn packet(&mut self, packet: &str) -> String {
+            let base = StopConditions::new();
+            match self
+                .stub
+                .handle(packet, &mut self.vm, &mut self.history, &base)
+            {
+                Session::Reply(reply) => reply,
+                Session::Exit(exit_code) => format!("W{exit_code:02x}"),
+                Session::Detach | Session::Kill => panic!("the session ended on {packet}"),
+            }
+        }
+    }
+
+    #[test]
+    fn bs_and_bc_go_back_through_the_breakpoints() {
+        let mut gdb = Debugged::new("gdb-break");
+        let ecall = address_of(6);
+        assert_eq!(gdb.packet(&format!("Z0,{ecall:x},4")), "OK");
+        for n in 0..3 {
+            assert_eq!(gdb.packet("c"), "T05swbreak:;");
+            assert_eq!(gdb.vm.get_instret(), read_instret(n));
+        }
+
+        assert_eq!(gdb.packet("bc"), "T05swbreak:;");
+        assert_eq!(gdb.vm.get_instret(), read_instret(1));
+        assert_eq!(gdb.vm.get_pc(), ecall);
+        assert_eq!(gdb.packet("bs"), "S05");
+        assert_eq!(gdb.vm.get_instret(), read_instret(1) - 1);
+        assert_eq!(gdb.vm.get_pc(), address_of(5));
+        assert_eq!(gdb.packet("bc"), "T05swbreak:;");
+        assert_eq!(gdb.vm.get_instret(), read_instret(0));
+        assert_eq!(gdb.packet("bc"), "T05replaylog:begin;");
+        assert_eq!(gdb.vm.get_instret(), 0);
+        assert_eq!(gdb.packet("bs"), "T05replaylog:begin;");
+        assert_eq!(gdb.packet("?"), "T05replaylog:begin;");
+
+        // forward again, the rerun stops where the run did
+        assert_eq!(gdb.packet("c"), "T05swbreak:;");
+        assert_eq!(gdb.vm.get_instret(), read_instret(0));
+        assert_eq!(gdb.packet(&format!("z0,{ecall:x},4")), "OK");
+        assert_eq!(gdb.packet("c"), "T05replaylog:end;");
+        assert_eq!(gdb.vm.get_instret(), read_instret(2));
+        assert_eq!(gdb.packet("c"), "W07");
+    }
+
+    #[test]
+    fn bc_stops_before_the_last_write_to_a_watchpoint() {
+        let mut gdb = Debugged::new("gdb-watch");
+        let sum = READ_BUFFER + 4;
+        let store = address_of(9);
+        assert_eq!(gdb.packet(&format!("Z2,{sum:x},4")), "OK");
+        assert_eq!(gdb.packet("c"), format!("T05watch:{sum:x};"));
+        assert_eq!(gdb.packet("c"), format!("T05watch:{sum:x};"));
+        assert_eq!(gdb.packet(&format!("m{sum:x},4")), "03000000");
+        let instret = gdb.vm.get_instret();
+
+        assert_eq!(gdb.packet("bc"), format!("T05watch:{sum:x};"));
+        assert_eq!(gdb.vm.get_pc(), store);
+        assert_eq!(gdb.vm.get_instret(), instret - 1);
+        assert_eq!(gdb.packet(&format!("m{sum:x},4")), "01000000");
+        assert_eq!(gdb.packet("bc"), format!("T05watch:{sum:x};"));
+        assert_eq!(gdb.vm.get_instret(), instret - 11);
+        assert_eq!(gdb.packet(&format!("m{sum:x},4")), "00000000");
+        assert_eq!(gdb.packet("bc"), "T05replaylog:begin;");
+    }
+
+    // the debugger changing the machine starts a new history from there
+    #[test]
+    fn writes_forget_the_history_after_them() {
+        let mut gdb = Debugged::new("gdb-write");
+        assert_eq!(gdb.packet(&format!("Z0,{:x},4", address_of(6))), "OK");
+        assert_eq!(gdb.packet("c"), "T05swbreak:;");
+        assert_eq!(gdb.packet("c"), "T05swbreak:;");
+        assert_eq!(gdb.packet("bs"), "S05");
+        // t1 holds the sum so far
+        assert_eq!(gdb.packet("P6=0a000000"), "OK");
+        assert_eq!(gdb.packet("c"), "T05swbreak:;");
+        assert_eq!(gdb.packet("c"), "T05swbreak:;");
+        assert_eq!(gdb.packet(&format!("z0,{:x},4", address_of(6))), "OK");
+        assert_eq!(gdb.packet("c"), "W10");
+    }
+}
@@ -0,0 +1,448 @@
+use std::{cell::RefCell, fs, io::Write, rc::Rc};
+
+use crate::{
+    bus::Device,
+    device_tree::{DeviceTreeNode, PropertyValue},
+    replay::InputChannel,
+    snapshot::{SnapshotReader, SnapshotWriter},
+};
+
+// 32-pin GPIO block. A pin is an output when its direction bit is set, otherwise its level comes
+// from the host: the stimulus file or the GpioInputs handle. Every change of a pin level is
+// logged with the instruction count
+
+pub const GPIO_ADDRESS: usize = 0x10060000;
+const GPIO_SIZE: usize = 0x100;
+
+pub const GPIO_IRQ: u32 = 11;
+
+pub const GPIO_PINS: u32 = 32;
+
+// register offsets
+const INPUT: usize = 0x00;
+const DIRECTION: usize = 0x04;
+const OUTPUT: usize = 0x08;
+const RISE_IE: usize = 0x0c;
+const FALL_IE: usize = 0x10;
+// edges seen on the enabled pins, writing 1 clears a bit
+const IP: usize = 0x14;
+
+// the input pin is driven to the level at the given instruction count
+#[derive(Debug, Clone, Copy)]
+pub struct Stimulus {
+    pub instret: u64,
+    pub pin: u32,
+    pub high: bool,
+}
+
+// drives the input pins from the library, shared with the GPIO
+#[derive(Clone, Default)]
+pub struct GpioInputs {
+    // sorted by instruction count, stable for changes at the same time
+    pending: Rc<RefCell<Vec<Stimulus>>>,
+}
+
+impl GpioInputs {
+    // the change happens at the next access or tick of the GPIO
+    pub fn set(&self, pin: u32, high: bool) {
+        self.schedule(0, pin, high);
+    }
+
+    pub fn schedule(&self, instret: u64, pin: u32, high: bool) {
+        assert!(pin < GPIO_PINS, "Invalid GPIO pin {pin}");
+
+        let mut pending = self.pending.borrow_mut();
+        let index = pending.partition_point(|stimulus| stimulus.instret <= instret);
+        pending.insert(index, Stimulus { instret, pin, high });
+    }
+
+    fn next(&self) -> Option<u64> {
+        self.pending
+            .borrow()
+            .first()
+            .map(|stimulus| stimulus.instret)
+    }
+
+    // removes the changes that are due
+    fn take_due(&self, instret: u64) -> Vec<Stimulus> {
+        let mut pending = self.pending.borrow_mut();
+        let due = pending.partition_point(|stimulus| stimulus.instret <= instret);
+        pending.drain(..due).collect()
+    }
+
+    fn save_state(&self, snapshot: &mut SnapshotWriter) {
+        let pending = self.pending.borrow();
+        snapshot.write_u64(pending.len() as u64);
+        for stimulus in pending.iter() {
+            snapshot.write_u64(stimulus.instret);
+            snapshot.write_u32(stimulus.pin);
+            snapshot.write_bool(stimulus.high);
+        }
+    }
+
+    // the changes still to come replace the scheduled ones
+    fn restore_state(&self, snapshot: &mut SnapshotReader) -> Result<(), String> {
+        let count = snapshot.read_u64()?;
+        let mut pending = Vec::new();
+        for _ in 0..count {
+            let instret = snapshot.read_u64()?;
+            let pin = snapshot.read_u32()?;
+            let high = snapshot.read_bool()?;
+            if pin >= GPIO_PINS {
+                return Err(format!("invalid GPIO pin {pin} in the snapshot"));
+            }
+            pending.push(Stimulus { instret, pin, high });
+        }
+        *self.pending.borrow_mut() = pending;
+        Ok(())
+    }
+}
+
+// one change per line, "<instruction count> <pin> <high | low>", '#' starts a comment
+pub fn parse_stimuli(path: &str) -> Result<Vec<Stimulus>, String> {
+    let text = fs::read_to_string(path).map_err(|err| format!("cannot read {path}: {err}"))?;
+
+    let mut stimuli = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("");
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.is_empty() {
+            continue;
+        }
+
+        let invalid = || format!("{path}:{}: invalid stimulus '{}'", number + 1, line.trim());
+        let [instret, pin, level] = fields[..] else {
+            return Err(invalid());
+        };
+        let instret = instret.parse().map_err(|_| invalid())?;
+        let pin = pin
+            .parse()
+            .ok()
+            .filter(|pin| *pin < GPIO_PINS)
+            .ok_or_else(invalid)?;
+        let high = match level {
+            "high" | "1" => true,
+            "low" | "0" => false,
+            _ => return Err(invalid()),
+        };
+
+        stimuli.push(Stimulus { instret, pin, high });
+    }
+
+    Ok(stimuli)
+}
+
+pub struct Gpio {
+    inputs: GpioInputs,
+    // the levels applied to the input pins can be recorded and replayed
+    host_input: InputChannel,
+    log: Option<Box<dyn Write>>,
+    // levels driven by the host on the input pins
+    input: u32,
+    direction: u32,
+    output: u32,
+    rise_ie: u32,
+    fall_ie: u32,
+    ip: u32,
+}
+
+impl Gpio {
+    pub fn new(log: Option<Box<dyn Write>>) -> Self {
+        Self {
+            inputs: GpioInputs::default(),
+            host_input: InputChannel::default(),
+            log,
+            input: 0,
+            direction: 0,
+            output: 0,
+            rise_ie: 0,
+            fall_ie: 0,
+            ip: 0,
+        }
+    }
+
+    // handle to drive the input pins once the GPIO is attached to the bus
+    pub fn inputs(&self) -> GpioInputs {
+        self.inputs.clone()
+    }
+
+    pub fn add_stimuli(&mut self, stimuli: &[Stimulus]) {
+        for stimulus in stimuli {
+            self.inputs
+                .schedule(stimulus.instret, stimulus.pin, stimulus.high);
+        }
+    }
+
+    fn levels(&self) -> u32 {
+        self.output & self.direction | self.input & !self.direction
+    }
+
+    // runs a register change, logs the pins that changed and latches their edges
+    fn update(&mut self, instret: u64, change: impl FnOnce(&mut Self)) {
+        let old = self.levels();
+        change(self);
+        let new = self.levels();
+
+        self.ip |= new & !old & self.rise_ie | old & !new & self.fall_ie;
+
+        let changed = old ^ new;
+        if changed == 0 {
+            return;
+        }
+        // a rerun already logged its changes
+        if let Some(log) = self.log.as_mut().filter(|_| !self.host_input.is_rerun()) {
+            for pin in (0..GPIO_PINS).filter(|pin| changed & 1 << pin != 0) {
+                let direction = if self.direction & 1 << pin != 0 {
+                    "output"
+                } else {
+                    "input"
+                };
+                let level = if new & 1 << pin != 0 { "high" } else { "low" };
+                let _ = writeln!(log, "{instret}: pin {pin} {direction} {level}");
+            }
+        }
+    }
+
+    // the changes that are due are taken during a replay as well, so that the next event of
+    // the GPIO doesn't move, but the recorded ones are applied
+    fn apply_stimuli(&mut self, instret: u64) {
+        let due = self.inputs.take_due(instret);
+        let changes = self.host_input.read(instret, || {
+            due.iter()
+                .flat_map(|stimulus| [stimulus.pin as u8, stimulus.high as u8])
+                .collect()
+        });
+
+        for change in changes.chunks_exact(2) {
+            let (pin, high) = (change[0] as u32, change[1] != 0);
+            self.update(instret, |gpio| {
+                if high {
+                    gpio.input |= 1 << pin;
+                } else {
+                    gpio.input &= !(1 << pin);
+                }
+            });
+        }
+    }
+
+    fn read_register(&self, offset: usize) -> u32 {
+        match offset {
+            INPUT => self.levels(),
+            DIRECTION => self.direction,
+            OUTPUT => self.output,
+            RISE_IE => self.rise_ie,
+            FALL_IE => self.fall_ie,
+            IP => self.ip,
+            _ => 0,
+        }
+    }
+
+    fn write_register(&mut self, offset: usize, value: u32, instret: u64) {
+        match offset {
+            DIRECTION => self.update(instret, |gpio| gpio.direction = value),
+            OUTPUT => self.update(instret, |gpio| gpio.output = value),
+            RISE_IE => self.rise_ie = value,
+            FALL_IE => self.fall_ie = value,
+            IP => self.ip &= !value,
+            _ => {}
+        }
+    }
+}
+
+impl Device for Gpio {
+    fn size(&self) -> usize {
+        GPIO_SIZE
+    }
+
+    // the registers are 32 bits wide, smaller accesses see a part of them
+    fn read(&mut self, offset: usize, nb_bytes: usize, instret: u64) -> u32 {
+        self.apply_stimuli(instret);
+
+        let shift = (offset & 3) * 8;
+        let value = self.read_register(offset & !3);
+        (value >> shift) & (u32::MAX >> (32 - nb_bytes * 8))
+    }
+
+    fn write(&mut self, offset: usize, nb_bytes: usize, value: u32, instret: u64) {
+        self.apply_stimuli(instret);
+
+        let shift = (offset & 3) * 8;
+        let mask = (u32::MAX >> (32 - nb_bytes * 8)) << shift;
+        // writing 1 to the other bytes of IP would clear them
+        let old = if offset & !3 == IP {
+            0
+        } else {
+            self.read_register(offset & !3)
+        };
+
+        self.write_register(offset & !3, old & !mask | value << shift & mask, instret);
+    }
+
+    // the host keeps driving the input pins
+    fn reset(&mut self) {
+        self.direction = 0;
+        self.output = 0;
+        self.rise_ie = 0;
+        self.fall_ie = 0;
+        self.ip = 0;
+    }
+
+    fn tick(&mut self, instret: u64) {
+        self.apply_stimuli(instret);
+    }
+
+    fn next_event(&self) -> Option<u64> {
+        self.inputs.next()
+    }
+
+    fn interrupt_pending(&self) -> bool {
+        self.ip != 0
+    }
+
+    // the cells are the pin and its flags
+    fn device_tree_node(&self) -> Option<DeviceTreeNode> {
+        let node = DeviceTreeNode::new("gpio", &["riscv-emulator,gpio"])
+            .with_property("gpio-controller", PropertyValue::Empty)
+            .with_property("#gpio-cells", PropertyValue::U32(2))
+            .with_property("ngpios", PropertyValue::U32(GPIO_PINS));
+        Some(node)
+    }
+
+    fn save_state(&self, snapshot: &mut SnapshotWriter) {
+        snapshot.write_u32s(&[
+            self.input,
+            self.direction,
+            self.output,
+            self.rise_ie,
+            self.fall_ie,
+            self.ip,
+        ]);
+        self.inputs.save_state(snapshot);
+    }
+
+    fn restore_state(&mut self, snapshot: &mut SnapshotReader) -> Result<(), String> {
+        let mut registers = [0; 6];
+        snapshot.read_u32s_into("GPIO registers", &mut registers)?;
+        [
+            self.input,
+            self.direction,
+            self.output,
+            self.rise_ie,
+            self.fall_ie,
+            self.ip,
+        ] = registers;
+        self.inputs.restore_state(snapshot)
+    }
+
+    fn set_input(&mut self, input: InputChannel) {
+        self.host_input = input;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(name: &str, text: &str) -> Result<Vec<Stimulus>, String> {
+        let path = std::env::temp_dir()
+            .join(format!("riscv-gpio-{}-{name}", std::process::id()))
+            .to_string_lossy()
+            .into_owned();
+        fs::write(&path, text).unwrap();
+        let stimuli = parse_stimuli(&path);
+        let _ = fs::remove_file(path);
+        stimuli
+    }
+
+    #[test]
+    fn stimuli_are_parsed_line_by_line() {
+        let text = "# pin 3 toggles\n\n100 3 high\n  200 3 0 # back\n300 31 1\n300 0 low\n";
+        let stimuli = parse("valid", text).unwrap();
+        let stimuli: Vec<_> = stimuli
+            .iter()
+            .map(|stimulus| (stimulus.instret, stimulus.pin, stimulus.high))
+            .collect();
+        assert_eq!(
+            stimuli,
+            [
+                (100, 3, true),
+                (200, 3, false),
+                (300, 31, true),
+                (300, 0, false)
+            ]
+        );
+
+        for line in [
+            "100 3",
+            "100 3 high 4",
+            "x 3 high",
+            "100 32 high",
+            "100 3 up",
+        ] {
+            let err = parse("invalid", &format!("# comment\n{line}\n")).unwrap_err();
+            assert!(
+                err.ends_with(&format!(":2: invalid stimulus '{line}'")),
+                "{err}"
+            );
+        }
+
+        assert!(parse_stimuli("/nonexistent/stimuli").is_err());
+    }
+
+    #[test]
+    fn enabled_edges_are_latched_until_cleared() {
+        let mut gpio = Gpio::new(None);
+        let inputs = gpio.inputs();
+        gpio.write(RISE_IE, 4, 0b0101, 0);
+        gpio.write(FALL_IE, 4, 0b0110, 0);
+
+        // pin 0 rises, pin 1 rises without interrupt, pin 3 isn't enabled
+        for pin in [0, 1, 3] {
+            inputs.schedule(10, pin, true);
+        }
+        assert_eq!(gpio.next_event(), Some(10));
+        gpio.tick(9);
+        assert_eq!(gpio.read(IP, 4, 9), 0);
+        gpio.tick(10);
+        assert_eq!(gpio.read(IP, 4, 10), 0b0001);
+        assert!(gpio.interrupt_pending());
+
+        // the edge stays latched after the level goes back
+        inputs.set(1, false);
+        inputs.set(0, false);
+        assert_eq!(gpio.read(INPUT, 4, 11), 0b1000);
+        assert_eq!(gpio.read(IP, 4, 11), 0b0011);
+
+        gpio.write(IP, 4, 0b0001, 12);
+        assert_eq!(gpio.read(IP, 4, 12), 0b0010);
+        gpio.write(IP, 4, 0b0010, 12);
+        assert!(!gpio.interrupt_pending());
+
+        // driving a pin as an output latches its edge too, and so does giving it back to the host
+        gpio.write(OUTPUT, 4, 0b0100, 13);
+        gpio.write(DIRECTION, 4, 0b0100, 13);
+        assert_eq!(gpio.read(IP, 4, 13), 0b0100);
+        gpio.write(IP, 4, 0b0100, 14);
+        gpio.write(DIRECTION, 4, 0, 14);
+        assert_eq!(gpio.read(IP, 4, 14), 0b0100);
+    }
+
+    #[test]
+    fn partial_writes_keep_the_other_bytes() {
+        let mut gpio = Gpio::new(None);
+        gpio.write(OUTPUT, 4, 0x12345678, 0);
+        gpio.write(OUTPUT + 2, 1, 0xab, 0);
+        assert_eq!(gpio.read(OUTPUT, 4, 0), 0x12ab5678);
+        gpio.write(OUTPUT + 2, 2, 0xcdef, 0);
+        assert_eq!(gpio.read(OUTPUT, 4, 0), 0xcdef5678);
+        assert_eq!(gpio.read(OUTPUT + 1, 1, 0), 0x56);
+        assert_eq!(gpio.read(OUTPUT + 2, 2, 0), 0xcdef);
+
+        // the other bytes of IP aren't written with their pending bits, which would clear them
+        gpio.ip = 0x0f0f0f0f;
+        gpio.write(IP + 1, 1, 0xff, 0);
+        assert_eq!(gpio.read(IP, 4, 0), 0x0f0f000f);
+        gpio.write(IP + 2, 2, 0x0100, 0);
+        assert_eq!(gpio.read(IP, 4, 0), 0x0e0f000f);
+    }
+}
@@ -0,0 +1,332 @@
+use crate::{
+    replay::{InputLog, LogPosition},
+    stop_conditions::{StopConditions, StopReason},
+    vm::VM,
+};
+
+// reverse execution: checkpoints of the machine are taken while it runs, going back in time
+// restores the last checkpoint before the target and runs again from there. The inputs from the
+// host are kept by the input log and handed back during the rerun, so it goes exactly like the
+// first time, and the output isn't written twice
+
+// instructions between two checkpoints at first
+pub const DEFAULT_CHECKPOINT_INTERVAL: u64 = 1_000_000;
+
+// when there are more, every other checkpoint is dropped and the interval doubles
+const MAX_CHECKPOINTS: usize = 256;
+
+struct Checkpoint {
+    instret: u64,
+    snapshot: Vec<u8>,
+    position: LogPosition,
+}
+
+// why the execution through the history stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HistoryStop {
+    Stopped(StopReason),
+    // going back reached the first checkpoint
+    Begin,
+    // a rerun is back where the run stopped, the next instructions are new
+    End,
+    // the caller asked to stop, between two checkpoints
+    Interrupted,
+}
+
+pub struct History {
+    log: InputLog,
+    checkpoints: Vec<Checkpoint>,
+    interval: u64,
+    // furthest instruction count reached, a rerun stops there
+    frontier: u64,
+}
+
+impl History {
+    // the first checkpoint is the current state, the machine can't go back further
+    pub fn new(vm: &mut VM, interval: u64) -> Self {
+        let log = vm.get_bus().get_input_log().clone();
+        log.keep_history();
+        // a rerun doesn't stop and resume at the same points as the first run
+        vm.set_exact_ticks(true);
+
+        let mut history = Self {
+            log,
+            checkpoints: Vec::new(),
+            interval,
+            frontier: vm.get_instret(),
+        };
+        history.checkpoint(vm);
+        history
+    }
+
+    fn checkpoint(&mut self, vm: &mut VM) {
+        self.checkpoints.push(Checkpoint {
+            instret: vm.get_instret(),
+            snapshot: vm.save_snapshot(),
+            position: self.log.position(),
+        });
+
+        if self.checkpoints.len() > MAX_CHECKPOINTS {
+            let mut index = 0;
+            self.checkpoints.retain(|_| {
+                index += 1;
+                index % 2 == 1
+            });
+            self.interval *= 2;
+        }
+    }
+
+    fn restore(&mut self, vm: &mut VM, index: usize) {
+        let checkpoint = &self.checkpoints[index];
+        if let Err(err) = vm.restore_snapshot(&checkpoint.snapshot) {
+            panic!(
+                "Cannot restore the checkpoint at instruction {}: {err}",
+                checkpoint.instret
+            );
+        }
+        self.log.rewind(&checkpoint.position);
+        self.caught_up(vm);
+    }
+
+    // the inputs come from the host again once the rerun is back at the frontier
+    fn caught_up(&mut self, vm: &VM) {
+        if vm.get_instret() >= self.frontier {
+            self.frontier = vm.get_instret();
+            self.log.set_rerun(false);
+        }
+    }
+
+    // runs again from the last checkpoint up to the instruction count
+    fn seek(&mut self, vm: &mut VM, instret: u64) {
+        let index = self
+            .checkpoints
+            .iter()
+            .rposition(|checkpoint| checkpoint.instret <= instret)
+            .unwrap_or(0);
+        self.restore(vm, index);
+
+        let mut until = StopConditions::new();
+        until.set_max_instructions(instret);
+        vm.set_stop_conditions(until);
+        let reason = vm.start_execution();
+        if vm.get_instret() != instret {
+            panic!(
+                "Rerun diverged: {reason} at instruction {} instead of reaching {instret}",
+                vm.get_instret()
+            );
+        }
+        self.caught_up(vm);
+    }
+
+    // runs forward until the stop conditions, a rerun stops at the frontier. interrupted is
+    // asked at every checkpoint
+    pub fn forward(
+        &mut self,
+        vm: &mut VM,
+        stop_conditions: &StopConditions,
+        interrupted: &mut dyn FnMut() -> bool,
+    ) -> HistoryStop {
+        loop {
+            let rerun = vm.get_instret() < self.frontier;
+            let limit = if rerun {
+                self.frontier
+            } else {
+                self.checkpoints.last().unwrap().instret + self.interval
+            };
+
+            let mut chunk = stop_conditions.clone();
+            chunk.set_max_instructions(
+                stop_conditions
+                    .get_max_instructions()
+                    .map_or(limit, |max| max.min(limit)),
+            );
+            vm.set_stop_conditions(chunk);
+            let reason = vm.start_execution();
+            self.caught_up(vm);
+
+            let reached_max = stop_conditions
+                .get_max_instructions()
+                .is_some_and(|max| vm.get_instret() >= max);
+            if reason != StopReason::InstructionLimit || vm.get_instret() < limit || reached_max {
+                return HistoryStop::Stopped(reason);
+            }
+            // the first run usually stopped at a breakpoint there
+            if rerun {
+                let pc = vm.get_pc();
+                if stop_conditions.is_stop_address(pc) {
+                    return HistoryStop::Stopped(StopReason::StopAddress(pc));
+                }
+                return HistoryStop::End;
+            }
+
+            self.checkpoint(vm);
+            if interrupted() {
+                return HistoryStop::Interrupted;
+            }
+        }
+    }
+
+    // back one instruction, whichever hart ran it
+    pub fn reverse_step(&mut self, vm: &mut VM) -> HistoryStop {
+        let instret = vm.get_instret();
+        if instret <= self.checkpoints[0].instret {
+            return HistoryStop::Begin;
+        }
+        self.seek(vm, instret - 1);
+        HistoryStop::Stopped(StopReason::InstructionLimit)
+    }
+
+    // back to the last stop address reached or the last write to a watched address, the
+    // machine then stands before the instruction writing to it
+    pub fn reverse_continue(&mut self, vm: &mut VM, stops: &StopConditions) -> HistoryStop {
+        let mut end = vm.get_instret();
+        for index in (0..self.checkpoints.len()).rev() {
+            let start = self.checkpoints[index].instret;
+            if start >= end {
+                continue;
+            }
+
+            self.restore(vm, index);
+            if let Some((instret, reason)) = last_stop(vm, stops, end) {
+                self.seek(vm, instret);
+                return HistoryStop::Stopped(reason);
+            }
+            end = start;
+        }
+
+        self.restore(vm, 0);
+        HistoryStop::Begin
+    }
+
+    // the machine was changed by the debugger, the history after this point is gone
+    pub fn truncate(&mut self, vm: &mut VM) {
+        let instret = vm.get_instret();
+        self.checkpoints
+            .retain(|checkpoint| checkpoint.instret < instret);
+        self.log.truncate();
+        self.log.set_rerun(false);
+        self.frontier = instret;
+        self.checkpoint(vm);
+    }
+}
+
+// instruction count and reason of the last stop between the current instruction count and end
+fn last_stop(vm: &mut VM, stops: &StopConditions, end: u64) -> Option<(u64, StopReason)> {
+    let mut last = None;
+    let mut until_end = stops.clone();
+    until_end.set_max_instructions(end);
+    vm.set_stop_conditions(until_end);
+    loop {
+        match vm.start_execution() {
+            StopReason::StopAddress(pc) => {
+                last = Some((vm.get_instret(), StopReason::StopAddress(pc)));
+            }
+            StopReason::Watchpoint(address) => {
+                last = Some((vm.get_instret() - 1, StopReason::Watchpoint(address)));
+            }
+            _ => return last,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+
+    // sum_of_reads replaying the recording, the history starts at the reset
+    fn machine(path: &str, count: i32, interval: u64) -> (VM, History) {
+        let mut vm = VM::new(flash(&sum_of_reads(count)));
+        vm.set_input_log(InputLog::replay(path).unwrap());
+        vm.init_execution();
+        let history = History::new(&mut vm, interval);
+        (vm, history)
+    }
+
+    fn until(instret: u64) -> StopConditions {
+        let mut stop_conditions = StopConditions::new();
+        stop_conditions.set_max_instructions(instret);
+        stop_conditions
+    }
+
+    #[test]
+    fn reverse_step_restores_the_previous_instruction() {
+        let path = reads_recording("history-step", &[1, 2, 4]);
+        let (mut vm, mut history) = machine(&path, 3, 8);
+        std::fs::remove_file(&path).unwrap();
+
+        let mut states = Vec::new();
+        for instret in 1..=20 {
+            states.push((vm.get_pc(), vm.get_registers()));
+            let stop = history.forward(&mut vm, &until(instret), &mut || false);
+            assert_eq!(stop, HistoryStop::Stopped(StopReason::InstructionLimit));
+        }
+
+        while let Some((pc, registers)) = states.pop() {
+            let stop = history.reverse_step(&mut vm);
+            assert_eq!(stop, HistoryStop::Stopped(StopReason::InstructionLimit));
+            assert_eq!(vm.get_instret(), states.len() as u64);
+            assert_eq!((vm.get_pc(), vm.get_registers()), (pc, registers));
+        }
+        assert_eq!(history.reverse_step(&mut vm), HistoryStop::Begin);
+    }
+
+    // the rerun through the last read gets the recorded byte again, blocks included
+    #[test]
+    fn rerun_reads_the_syscall_inputs_again() {
+        let path = reads_recording("history-rerun", &[1, 2, 4]);
+        let (mut vm, mut history) = machine(&path, 3, 8);
+        std::fs::remove_file(&path).unwrap();
+        let log = vm.get_bus().get_input_log().clone();
+
+        let frontier = read_instret(2) + 4;
+        history.forward(&mut vm, &until(frontier), &mut || false);
+
+        let mut ecall = StopConditions::new();
+        ecall.add_stop_address(address_of(6));
+        let stop = history.reverse_continue(&mut vm, &ecall);
+        assert_eq!(
+            stop,
+            HistoryStop::Stopped(StopReason::StopAddress(address_of(6)))
+        );
+        assert_eq!(vm.get_instret(), read_instret(2));
+        assert!(log.is_rerun());
+
+        let nothing = StopConditions::new();
+        assert_eq!(
+            history.forward(&mut vm, &nothing, &mut || false),
+            HistoryStop::End
+        );
+        assert_eq!(vm.get_instret(), frontier);
+        assert!(!log.is_rerun());
+        assert_eq!(
+            history.forward(&mut vm, &nothing, &mut || false),
+            HistoryStop::Stopped(StopReason::Exit(7))
+        );
+        assert_eq!(log.finish(), Ok(()));
+    }
+
+    #[test]
+    fn checkpoints_are_thinned_out() {
+        let path = reads_recording("history-thin", &[1; 40]);
+        let (mut vm, mut history) = machine(&path, 40, 1);
+        std::fs::remove_file(&path).unwrap();
+
+        // asked at every checkpoint
+        let mut checkpoints = 0;
+        let stop = history.forward(&mut vm, &StopConditions::new(), &mut || {
+            checkpoints += 1;
+            checkpoints == 300
+        });
+        assert_eq!(stop, HistoryStop::Interrupted);
+        assert!(history.checkpoints.len() <= MAX_CHECKPOINTS);
+        assert_eq!(history.interval, 2);
+
+        let instret = vm.get_instret();
+        let registers = vm.get_registers();
+        history.forward(&mut vm, &until(instret + 1), &mut || false);
+        history.reverse_step(&mut vm);
+        assert_eq!(vm.get_instret(), instret);
+        assert_eq!(vm.get_registers(), registers);
+    }
+}
@@ -1,7 +1,8 @@
 use crate::{
     instructions::{
-        BOpcode, BOpcodeHelper, IOpcode, IOpcodeHelper, InstructionFormat, JOpcode, JOpcodeHelper,
-        ROpcode, ROpcodeHelper, SOpcode, SOpcodeHelper, ShamtOrRegister, UOpcode, UOpcodeHelper,
+        AmoOpcode, AmoOpcodeHelper, BOpcode, BOpcodeHelper, CsrOpcode, CsrOpcodeHelper, IOpcode,
+        IOpcodeHelper, InstructionFormat, JOpcode, JOpcodeHelper, ROpcode, ROpcodeHelper, SOpcode,
+        SOpcodeHelper, ShamtOrRegister, UOpcode, UOpcodeHelper,
     },
     utils::{get_bits, sign_extend_number},
 };
@@ -43,6 +44,8 @@ fn decode_r_others(func3: u32, imm: u32, opcode_helper: ROpcodeHelper) -> ROpcod
         ROpcode::Or(opcode_helper)
     } else if func3 == 7 && imm == 0 {
         ROpcode::And(opcode_helper)
+    } else if imm == 1 {
+        decode_r_muldiv(func3, opcode_helper)
     } else {
         panic!(
             "R-type instruction not supported 0b0110011 {} {}",
@@ -51,6 +54,20 @@ fn decode_r_others(func3: u32, imm: u32, opcode_helper: ROpcodeHelper) -> ROpcod
     }
 }
 
+// M extension, funct7 = 1
+fn decode_r_muldiv(func3: u32, opcode_helper: ROpcodeHelper) -> ROpcode {
+    match func3 {
+        0 => ROpcode::Mul(opcode_helper),
+        1 => ROpcode::Mulh(opcode_helper),
+        2 => ROpcode::Mulhsu(opcode_helper),
+        3 => ROpcode::Mulhu(opcode_helper),
+        4 => ROpcode::Div(opcode_helper),
+        5 => ROpcode::Divu(opcode_helper),
+        6 => ROpcode::Rem(opcode_helper),
+        _ => ROpcode::Remu(opcode_helper),
+    }
+}
+
 fn decode_r(instruction: u32) -> ROpcode {
     let opcode = get_bits(instruction, 0, 6);
 
@@ -191,6 +208,60 @@ fn decode_i(instruction: u32) -> IOpcode {
     }
 }
 
+fn decode_amo(instruction: u32) -> AmoOpcode {
+    let func5 = get_bits(instruction, 27, 31);
+
+    let opcode_helper = AmoOpcodeHelper::new(
+        get_bits(instruction, 15, 19),
+        get_bits(instruction, 20, 24),
+        get_bits(instruction, 7, 11),
+    );
+
+    match func5 {
+        0b00010 => AmoOpcode::LrW(opcode_helper),
+        0b00011 => AmoOpcode::ScW(opcode_helper),
+        0b00001 => AmoOpcode::AmoswapW(opcode_helper),
+        0b00000 => AmoOpcode::AmoaddW(opcode_helper),
+        0b00100 => AmoOpcode::AmoxorW(opcode_helper),
+        0b01100 => AmoOpcode::AmoandW(opcode_helper),
+        0b01000 => AmoOpcode::AmoorW(opcode_helper),
+        0b10000 => AmoOpcode::AmominW(opcode_helper),
+        0b10100 => AmoOpcode::AmomaxW(opcode_helper),
+        0b11000 => AmoOpcode::AmominuW(opcode_helper),
+        0b11100 => AmoOpcode::AmomaxuW(opcode_helper),
+        _ => panic!("Atomic instruction not supported {}", func5),
+    }
+}
+
+fn decode_system(instruction: u32) -> InstructionFormat {
+    let func3 = get_bits(instruction, 12, 14);
+    let func12 = get_bits(instruction, 20, 31);
+
+    let opcode_helper = CsrOpcodeHelper::new(
+        get_bits(instruction, 15, 19),
+        get_bits(instruction, 7, 11),
+        func12,
+    );
+
+    match func3 {
+        0 if func12 == 0 => InstructionFormat::ECALL,
+        0 if func12 == 0x302 => InstructionFormat::MRET,
+        0 if func12 == 0x102 => InstructionFormat::SRET,
+        0 if func12 == 0x105 => InstructionFormat::WFI,
+        0 if func12 >> 5 == 0b0001001 => InstructionFormat::SFENCEVMA(
+            get_bits(instruction, 15, 19),
+            get_bits(instruction, 20, 24),
+        ),
+        1 => InstructionFormat::CSR(CsrOpcode::Csrrw(opcode_helper)),
+        2 => InstructionFormat::CSR(CsrOpcode::Csrrs(opcode_helper)),
+        3 => InstructionFormat::CSR(CsrOpcode::Csrrc(opcode_helper)),
+        5 => InstructionFormat::CSR(CsrOpcode::Csrrwi(opcode_helper)),
+        6 => InstructionFormat::CSR(CsrOpcode::Csrrsi(opcode_helper)),
+        7 => InstructionFormat::CSR(CsrOpcode::Csrrci(opcode_helper)),
+        _ => panic!("System instruction not supported {} {:x}", func3, func12),
+    }
+}
+
 pub fn decode(instruction: u32) -> InstructionFormat {
     let opcode = get_bits(instruction, 0, 6);
     let func3 = get_bits(instruction, 12, 14);
@@ -208,7 +279,13 @@ pub fn decode(instruction: u32) -> InstructionFormat {
     } else if opcode == 0b1101111 {
         InstructionFormat::J(decode_j(instruction))
     } else if opcode == 0b1110011 {
-        InstructionFormat::ECALL
+        decode_system(instruction)
+    } else if opcode == 0b0101111 && func3 == 2 {
+        InstructionFormat::AMO(decode_amo(instruction))
+    } else if opcode == 0b0001111 && func3 == 0 {
+        InstructionFormat::FENCE
+    } else if opcode == 0b0001111 && func3 == 1 {
+        InstructionFormat::FENCEI
     } else {
         panic!("Unknown instruction type {}", opcode)
     }
@@ -1,6 +1,6 @@
 use std::fmt::{self};
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct ShamtOrRegister {
     value: u32,
     is_register: bool,
@@ -21,7 +21,7 @@ impl ShamtOrRegister {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct ROpcodeHelper {
     src: u32,
     dest: u32,
@@ -54,7 +54,7 @@ impl ROpcodeHelper {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub enum ROpcode {
     Slli(ROpcodeHelper),
     Srli(ROpcodeHelper),
@@ -69,6 +69,15 @@ pub enum ROpcode {
     Sra(ROpcodeHelper),
     Or(ROpcodeHelper),
     And(ROpcodeHelper),
+    // M extension
+    Mul(ROpcodeHelper),
+    Mulh(ROpcodeHelper),
+    Mulhsu(ROpcodeHelper),
+    Mulhu(ROpcodeHelper),
+    Div(ROpcodeHelper),
+    Divu(ROpcodeHelper),
+    Rem(ROpcodeHelper),
+    Remu(ROpcodeHelper),
 }
 
 impl fmt::Display for ROpcode {
@@ -202,13 +211,93 @@ impl fmt::Display for ROpcode {
                     get_register_name(helper.dest)
                 );
 
+                write!(f, "{}", assembly)
+            }
+            ROpcode::Mul(helper) => {
+                let assembly = format!(
+                    "mul {}, {}, {}",
+                    get_register_name(helper.dest),
+                    get_register_name(helper.src),
+                    get_register_name(helper.value.get_register())
+                );
+
+                write!(f, "{}", assembly)
+            }
+            ROpcode::Mulh(helper) => {
+                let assembly = format!(
+                    "mulh {}, {}, {}",
+                    get_register_name(helper.dest),
+                    get_register_name(helper.src),
+                    get_register_name(helper.value.get_register())
+                );
+
+                write!(f, "{}", assembly)
+            }
+            ROpcode::Mulhsu(helper) => {
+                let assembly = format!(
+                    "mulhsu {}, {}, {}",
+                    get_register_name(helper.dest),
+                    get_register_name(helper.src),
+                    get_register_name(helper.value.get_register())
+                );
+
+                write!(f, "{}", assembly)
+            }
+            ROpcode::Mulhu(helper) => {
+                let assembly = format!(
+                    "mulhu {}, {}, {}",
+                    get_register_name(helper.dest),
+                    get_register_name(helper.src),
+                    get_register_name(helper.value.get_register())
+                );
+
+                write!(f, "{}", assembly)
+            }
+            ROpcode::Div(helper) => {
+                let assembly = format!(
+                    "div {}, {}, {}",
+                    get_register_name(helper.dest),
+                    get_register_name(helper.src),
+                    get_register_name(helper.value.get_register())
+                );
+
+                write!(f, "{}", assembly)
+            }
+            ROpcode::Divu(helper) => {
+                let assembly = format!(
+                    "divu {}, {}, {}",
+                    get_register_name(helper.dest),
+                    get_register_name(helper.src),
+                    get_register_name(helper.value.get_register())
+                );
+
+                write!(f, "{}", assembly)
+            }
+            ROpcode::Rem(helper) => {
+                let assembly = format!(
+                    "rem {}, {}, {}",
+                    get_register_name(helper.dest),
+                    get_register_name(helper.src),
+                    get_register_name(helper.value.get_register())
+                );
+
+                write!(f, "{}", assembly)
+            }
+            ROpcode::Remu(helper) => {
+                let assembly = format!(
+                    "remu {}, {}, {}",
+                    get_register_name(helper.dest),
+                    get_register_name(helper.src),
+                    get_register_name(helper.value.get_register())
+                );
+
                 write!(f, "{}", assembly)
             }
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct IOpcodeHelper {
     src: u32,
     dst: u32,
@@ -233,7 +322,7 @@ impl IOpcodeHelper {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub enum IOpcode {
     Jalr(IOpcodeHelper),
     Lb(IOpcodeHelper),
@@ -359,7 +448,7 @@ impl fmt::Display for IOpcode {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct SOpcodeHelper {
     src: u32,
     base: u32,
@@ -384,7 +473,7 @@ impl SOpcodeHelper {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub enum SOpcode {
     Sb(SOpcodeHelper),
     Sh(SOpcodeHelper),
@@ -428,7 +517,7 @@ impl fmt::Display for SOpcode {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct BOpcodeHelper {
     src1: u32,
     src2: u32,
@@ -451,7 +540,7 @@ impl BOpcodeHelper {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub enum BOpcode {
     Beq(BOpcodeHelper),
     Bne(BOpcodeHelper),
@@ -530,7 +619,7 @@ impl fmt::Display for BOpcode {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct UOpcodeHelper {
     dest: u32,
     imm: u32,
@@ -550,7 +639,7 @@ impl UOpcodeHelper {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub enum UOpcode {
     Lui(UOpcodeHelper),
     Auipc(UOpcodeHelper),
@@ -573,7 +662,7 @@ impl fmt::Display for UOpcode {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct JOpcodeHelper {
     dest: u32,
     offset: u32,
@@ -593,7 +682,7 @@ impl JOpcodeHelper {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub enum JOpcode {
     Jal(JOpcodeHelper),
 }
@@ -610,7 +699,148 @@ impl fmt::Display for JOpcode {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
+pub struct CsrOpcodeHelper {
+    // rs1, or the zero-extended immediate of the i variants
+    src: u32,
+    dst: u32,
+    csr: u32,
+}
+
+impl CsrOpcodeHelper {
+    pub fn new(src: u32, dst: u32, csr: u32) -> Self {
+        Self { src, dst, csr }
+    }
+
+    pub fn get_src(&self) -> u32 {
+        self.src
+    }
+
+    pub fn get_dst(&self) -> u32 {
+        self.dst
+    }
+
+    pub fn get_csr(&self) -> u32 {
+        self.csr
+    }
+}
+
+// Zicsr extension
+#[derive(Debug, Clone, Copy)]
+pub enum CsrOpcode {
+    Csrrw(CsrOpcodeHelper),
+    Csrrs(CsrOpcodeHelper),
+    Csrrc(CsrOpcodeHelper),
+    Csrrwi(CsrOpcodeHelper),
+    Csrrsi(CsrOpcodeHelper),
+    Csrrci(CsrOpcodeHelper),
+}
+
+impl fmt::Display for CsrOpcode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (name, helper, immediate) = match self {
+            CsrOpcode::Csrrw(helper) => ("csrrw", helper, false),
+            CsrOpcode::Csrrs(helper) => ("csrrs", helper, false),
+            CsrOpcode::Csrrc(helper) => ("csrrc", helper, false),
+            CsrOpcode::Csrrwi(helper) => ("csrrwi", helper, true),
+            CsrOpcode::Csrrsi(helper) => ("csrrsi", helper, true),
+            CsrOpcode::Csrrci(helper) => ("csrrci", helper, true),
+        };
+
+        let src = if immediate {
+            helper.src.to_string()
+        } else {
+            get_register_name(helper.src)
+        };
+
+        write!(
+            f,
+            "{} {}, {:#x}, {}",
+            name,
+            get_register_name(helper.dst),
+            helper.csr,
+            src
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct AmoOpcodeHelper {
+    // address register
+    src1: u32,
+    src2: u32,
+    dest: u32,
+}
+
+impl AmoOpcodeHelper {
+    pub fn new(src1: u32, src2: u32, dest: u32) -> Self {
+        Self { src1, src2, dest }
+    }
+
+    pub fn get_src1(&self) -> u32 {
+        self.src1
+    }
+
+    pub fn get_src2(&self) -> u32 {
+        self.src2
+    }
+
+    pub fn get_dest(&self) -> u32 {
+        self.dest
+    }
+}
+
+// A extension, the aq and rl bits are ignored since a single hart is always ordered
+#[derive(Debug, Clone, Copy)]
+pub enum AmoOpcode {
+    LrW(AmoOpcodeHelper),
+    ScW(AmoOpcodeHelper),
+    AmoswapW(AmoOpcodeHelper),
+    AmoaddW(AmoOpcodeHelper),
+    AmoxorW(AmoOpcodeHelper),
+    AmoandW(AmoOpcodeHelper),
+    AmoorW(AmoOpcodeHelper),
+    AmominW(AmoOpcodeHelper),
+    AmomaxW(AmoOpcodeHelper),
+    AmominuW(AmoOpcodeHelper),
+    AmomaxuW(AmoOpcodeHelper),
+}
+
+impl fmt::Display for AmoOpcode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (name, helper) = match self {
+            AmoOpcode::LrW(helper) => {
+                return write!(
+                    f,
+                    "lr.w {}, ({})",
+                    get_register_name(helper.dest),
+                    get_register_name(helper.src1)
+                );
+            }
+            AmoOpcode::ScW(helper) => ("sc.w", helper),
+            AmoOpcode::AmoswapW(helper) => ("amoswap.w", helper),
+            AmoOpcode::AmoaddW(helper) => ("amoadd.w", helper),
+            AmoOpcode::AmoxorW(helper) => ("amoxor.w", helper),
+            AmoOpcode::AmoandW(helper) => ("amoand.w", helper),
+            AmoOpcode::AmoorW(helper) => ("amoor.w", helper),
+            AmoOpcode::AmominW(helper) => ("amomin.w", helper),
+            AmoOpcode::AmomaxW(helper) => ("amomax.w", helper),
+            AmoOpcode::AmominuW(helper) => ("amominu.w", helper),
+            AmoOpcode::AmomaxuW(helper) => ("amomaxu.w", helper),
+        };
+
+        write!(
+            f,
+            "{} {}, {}, ({})",
+            name,
+            get_register_name(helper.dest),
+            get_register_name(helper.src2),
+            get_register_name(helper.src1)
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
 pub enum InstructionFormat {
     R(ROpcode),
     I(IOpcode),
@@ -618,7 +848,16 @@ pub enum InstructionFormat {
     B(BOpcode),
     U(UOpcode),
     J(JOpcode),
+    CSR(CsrOpcode),
+    AMO(AmoOpcode),
     ECALL,
+    MRET,
+    SRET,
+    WFI,
+    // address and asid registers
+    SFENCEVMA(u32, u32),
+    FENCE,
+    FENCEI,
 }
 
 impl fmt::Display for InstructionFormat {
@@ -630,7 +869,20 @@ impl fmt::Display for InstructionFormat {
             InstructionFormat::B(opcode) => write!(f, "{}", opcode),
             InstructionFormat::U(opcode) => write!(f, "{}", opcode),
             InstructionFormat::J(opcode) => write!(f, "{}", opcode),
+            InstructionFormat::CSR(opcode) => write!(f, "{}", opcode),
+            InstructionFormat::AMO(opcode) => write!(f, "{}", opcode),
             InstructionFormat::ECALL => write!(f, "ecall"),
+            InstructionFormat::MRET => write!(f, "mret"),
+            InstructionFormat::SRET => write!(f, "sret"),
+            InstructionFormat::WFI => write!(f, "wfi"),
+            InstructionFormat::SFENCEVMA(address, asid) => write!(
+                f,
+                "sfence.vma {}, {}",
+                get_register_name(*address),
+                get_register_name(*asid)
+            ),
+            InstructionFormat::FENCE => write!(f, "fence"),
+            InstructionFormat::FENCEI => write!(f, "fence.i"),
         }
     }
 }
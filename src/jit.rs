@@ -0,0 +1,717 @@
+// x86-64 code generation for hot translated blocks
+//
+// A compiled block is a sysv64 function taking the VM, the guest register array is found at a
+// fixed offset in it. Guest registers live in memory, every instruction loads its sources into
+// eax/ecx and stores the result back, rbx holds the register array and r12 the VM for the whole
+// block. Memory accesses
+// go through helpers so the region map is honored, device accesses and faults leave the block
+// and the instruction is executed by the interpreter.
+
+#[cfg(not(all(target_arch = "x86_64", unix)))]
+compile_error!("the jit feature needs an x86-64 unix host");
+
+use std::{ffi::c_void, rc::Rc};
+
+use crate::{
+    instructions::{BOpcode, IOpcode, InstructionFormat, JOpcode, ROpcode, SOpcode, UOpcode},
+    utils,
+};
+
+const PROT_READ: i32 = 1;
+const PROT_WRITE: i32 = 2;
+const PROT_EXEC: i32 = 4;
+const MAP_PRIVATE: i32 = 2;
+const MAP_ANONYMOUS: i32 = 0x20;
+const MAP_FAILED: *mut c_void = !0 as *mut c_void;
+
+extern "C" {
+    fn mmap(addr: *mut c_void, len: usize, prot: i32, flags: i32, fd: i32, off: i64)
+        -> *mut c_void;
+    fn mprotect(addr: *mut c_void, len: usize, prot: i32) -> i32;
+    fn munmap(addr: *mut c_void, len: usize) -> i32;
+}
+
+// executions of a block before it gets compiled
+pub const JIT_THRESHOLD: u32 = 50;
+
+// set by the load helpers when the address isn't plain memory
+pub const LOAD_FAULT: u64 = 1 << 63;
+
+// returned by the store helpers
+pub const STORE_OK: u32 = 0;
+pub const STORE_FAULT: u32 = 1;
+// the store overwrote translated code, the block has to stop right after it
+pub const STORE_CODE_CHANGED: u32 = 2;
+
+// a compiled block returns the next pc in the low 32 bits and the number of retired instructions
+// above, this flag asks the interpreter to execute the instruction at pc (ecall, system
+// instruction, device access or fault)
+const EXIT_INTERPRET: u64 = 1 << 63;
+
+// host registers, only the ones the generated code uses
+const EAX: u8 = 0;
+const ECX: u8 = 1;
+const EDX: u8 = 2;
+const ESI: u8 = 6;
+const EDI: u8 = 7;
+
+// x86 condition codes
+const CC_B: u8 = 0x2;
+const CC_AE: u8 = 0x3;
+const CC_E: u8 = 0x4;
+const CC_NE: u8 = 0x5;
+const CC_NS: u8 = 0x9;
+const CC_L: u8 = 0xc;
+const CC_GE: u8 = 0xd;
+
+// /n extension of the 0x81 (immediate) opcode group
+const ALU_ADD: u8 = 0;
+const ALU_OR: u8 = 1;
+const ALU_AND: u8 = 4;
+const ALU_XOR: u8 = 6;
+const ALU_CMP: u8 = 7;
+
+// /n extension of the shift opcode groups
+const SHIFT_SHL: u8 = 4;
+const SHIFT_SHR: u8 = 5;
+const SHIFT_SAR: u8 = 7;
+
+pub type JitEntry = unsafe extern "sysv64" fn(vm: *mut c_void) -> u64;
+
+// addresses of the VM callbacks used by the generated code
+pub struct JitHelpers {
+    // extern "sysv64" fn(vm, address) -> u64, value or LOAD_FAULT
+    pub load_u8: usize,
+    pub load_u16: usize,
+    pub load_u32: usize,
+    // extern "sysv64" fn(vm, address, value) -> u32, one of the STORE_* values
+    pub store_u8: usize,
+    pub store_u16: usize,
+    pub store_u32: usize,
+    // extern "sysv64" fn(vm, pc), prints the trace line of the instruction
+    pub trace: usize,
+}
+
+pub struct JitExit {
+    pub pc: u32,
+    pub retired: u64,
+    pub interpret: bool,
+}
+
+// compilation state of a translated block
+pub enum JitState {
+    // executions so far
+    Cold(u32),
+    Compiled(Rc<JitBlock>),
+    // the first instruction can't be compiled
+    Unsupported,
+}
+
+pub struct JitBlock {
+    memory: *mut c_void,
+    size: usize,
+}
+
+impl JitBlock {
+    fn new(code: &[u8]) -> Option<Self> {
+        let size = code.len().next_multiple_of(0x1000);
+
+        unsafe {
+            let memory = mmap(
+                std::ptr::null_mut(),
+                size,
+                PROT_READ | PROT_WRITE,
+                MAP_PRIVATE | MAP_ANONYMOUS,
+                -1,
+                0,
+            );
+            if memory == MAP_FAILED {
+                return None;
+            }
+
+            std::ptr::copy_nonoverlapping(code.as_ptr(), memory as *mut u8, code.len());
+
+            // never writable and executable at the same time
+            if mprotect(memory, size, PROT_READ | PROT_EXEC) != 0 {
+                munmap(memory, size);
+                return None;
+            }
+
+            Some(Self { memory, size })
+        }
+    }
+
+    /// # Safety
+    ///
+    /// vm has to point to the VM the helpers expect, with the 32 guest registers at the offset
+    /// the block was compiled with.
+    pub unsafe fn run(&self, vm: *mut c_void) -> JitExit {
+        let entry: JitEntry = std::mem::transmute(self.memory);
+        let result = entry(vm);
+
+        JitExit {
+            pc: result as u32,
+            retired: (result & !EXIT_INTERPRET) >> 32,
+            interpret: result & EXIT_INTERPRET != 0,
+        }
+    }
+}
+
+impl Drop for JitBlock {
+    fn drop(&mut self) {
+        unsafe {
+            munmap(self.memory, self.size);
+        }
+    }
+}
+
+extern "sysv64" fn div(a: u32, b: u32) -> u32 {
+    utils::div(a, b)
+}
+
+extern "sysv64" fn divu(a: u32, b: u32) -> u32 {
+    utils::divu(a, b)
+}
+
+extern "sysv64" fn rem(a: u32, b: u32) -> u32 {
+    utils::rem(a, b)
+}
+
+extern "sysv64" fn remu(a: u32, b: u32) -> u32 {
+    utils::remu(a, b)
+}
+
+fn exit_value(pc: u32, retired: usize, interpret: bool) -> u64 {
+    let value = (retired as u64) << 32 | pc as u64;
+    if interpret {
+        value | EXIT_INTERPRET
+    } else {
+        value
+    }
+}
+
+struct Assembler {
+    code: Vec<u8>,
+}
+
+impl Assembler {
+    fn emit(&mut self, bytes: &[u8]) {
+        self.code.extend_from_slice(bytes);
+    }
+
+    fn emit_u32(&mut self, value: u32) {
+        self.emit(&value.to_le_bytes());
+    }
+
+    fn prologue(&mut self, regs_offset: usize) {
+        // push rbx; push r12; push r13 (keeps the stack 16-byte aligned for the helper calls)
+        self.emit(&[0x53, 0x41, 0x54, 0x41, 0x55]);
+        // lea rbx, [rdi + regs_offset]; mov r12, rdi
+        self.emit(&[0x48, 0x8d, 0x9f]);
+        self.emit_u32(regs_offset as u32);
+        self.emit(&[0x49, 0x89, 0xfc]);
+    }
+
+    fn epilogue(&mut self) {
+        // pop r13; pop r12; pop rbx; ret
+        self.emit(&[0x41, 0x5d, 0x41, 0x5c, 0x5b, 0xc3]);
+    }
+
+    // mov rax, value; epilogue
+    fn exit(&mut self, pc: u32, retired: usize, interpret: bool) {
+        self.emit(&[0x48, 0xb8]);
+        self.emit(&exit_value(pc, retired, interpret).to_le_bytes());
+        self.epilogue();
+    }
+
+    // size of the code emitted by exit, to jump over it
+    const EXIT_SIZE: u8 = 16;
+
+    // mov host, [rbx + 4 * reg]
+    fn load_reg(&mut self, host: u8, reg: u32) {
+        if reg == 0 {
+            // xor host, host
+            self.emit(&[0x31, 0xc0 | host << 3 | host]);
+        } else {
+            self.emit(&[0x8b, 0x43 | host << 3, (reg * 4) as u8]);
+        }
+    }
+
+    // mov [rbx + 4 * reg], host
+    fn store_reg(&mut self, reg: u32, host: u8) {
+        if reg != 0 {
+            self.emit(&[0x89, 0x43 | host << 3, (reg * 4) as u8]);
+        }
+    }
+
+    // mov dword [rbx + 4 * reg], value
+    fn store_imm(&mut self, reg: u32, value: u32) {
+        if reg != 0 {
+            self.emit(&[0xc7, 0x43, (reg * 4) as u8]);
+            self.emit_u32(value);
+        }
+    }
+
+    // op eax, ecx
+    fn alu_reg(&mut self, opcode: u8) {
+        self.emit(&[opcode, 0xc8]);
+    }
+
+    // op eax, imm32
+    fn alu_imm(&mut self, extension: u8, value: u32) {
+        self.emit(&[0x81, 0xc0 | extension << 3]);
+        self.emit_u32(value);
+    }
+
+    // shift eax, cl
+    fn shift_cl(&mut self, extension: u8) {
+        self.emit(&[0xd3, 0xc0 | extension << 3]);
+    }
+
+    // shift eax, imm8
+    fn shift_imm(&mut self, extension: u8, amount: u32) {
+        self.emit(&[0xc1, 0xc0 | extension << 3, amount as u8]);
+    }
+
+    // setcc al; movzx eax, al
+    fn set_condition(&mut self, condition: u8) {
+        self.emit(&[0x0f, 0x90 | condition, 0xc0, 0x0f, 0xb6, 0xc0]);
+    }
+
+    // mov dst, src (32 bits)
+    fn mov(&mut self, dst: u8, src: u8) {
+        self.emit(&[0x89, 0xc0 | src << 3 | dst]);
+    }
+
+    // mov rax, address; call rax
+    fn call(&mut self, address: usize) {
+        self.emit(&[0x48, 0xb8]);
+        self.emit(&(address as u64).to_le_bytes());
+        self.emit(&[0xff, 0xd0]);
+    }
+
+    // mov rdi, r12
+    fn vm_argument(&mut self) {
+        self.emit(&[0x4c, 0x89, 0xe7]);
+    }
+
+    // jcc rel8
+    fn jump_if(&mut self, condition: u8, offset: u8) {
+        self.emit(&[0x70 | condition, offset]);
+    }
+
+    // calls the trace helper, eax is kept in r13
+    fn trace(&mut self, helper: usize, pc: u32) {
+        // mov r13d, eax
+        self.emit(&[0x41, 0x89, 0xc5]);
+        self.vm_argument();
+        // mov esi, pc
+        self.emit(&[0xbe]);
+        self.emit_u32(pc);
+        self.call(helper);
+        // mov eax, r13d
+        self.emit(&[0x44, 0x89, 0xe8]);
+    }
+
+    // eax = rs1 + imm
+    fn address(&mut self, base: u32, offset: u32) {
+        self.load_reg(EAX, base);
+        if offset != 0 {
+            self.alu_imm(ALU_ADD, offset);
+        }
+    }
+}
+
+// compiles the instructions of a block, None if not even the first one is supported.
+// regs_offset is the offset of the guest registers in the VM
+pub fn compile(
+    instructions: &[(u32, InstructionFormat)],
+    end: u32,
+    helpers: &JitHelpers,
+    regs_offset: usize,
+    trace: bool,
+) -> Option<JitBlock> {
+    let mut asm = Assembler { code: Vec::new() };
+
+    asm.prologue(regs_offset);
+
+    for (i, (pc, instruction)) in instructions.iter().enumerate() {
+        let pc = *pc;
+
+        // ecall, fence.i, system and atomic instructions have side effects on the whole VM, they
+        // are left to the interpreter
+        if matches!(
+            instruction,
+            InstructionFormat::ECALL
+                | InstructionFormat::FENCEI
+                | InstructionFormat::CSR(_)
+                | InstructionFormat::AMO(_)
+                | InstructionFormat::MRET
+                | InstructionFormat::SRET
+                | InstructionFormat::WFI
+                | InstructionFormat::SFENCEVMA(..)
+        ) {
+            if i == 0 {
+                return None;
+            }
+            asm.exit(pc, i, true);
+            return JitBlock::new(&asm.code);
+        }
+
+        // memory accesses are traced once they didn't leave the block, the interpreter traces them
+        // otherwise
+        let is_memory_access = matches!(
+            instruction,
+            InstructionFormat::S(_)
+                | InstructionFormat::I(
+                    IOpcode::Lb(_)
+                        | IOpcode::Lbu(_)
+                        | IOpcode::Lh(_)
+                        | IOpcode::Lhu(_)
+                        | IOpcode::Lw(_)
+                )
+        );
+        if trace && !is_memory_access {
+            asm.trace(helpers.trace, pc);
+        }
+
+        match instruction {
+            InstructionFormat::R(opcode) => compile_r(&mut asm, opcode),
+            InstructionFormat::I(IOpcode::Jalr(helper)) => {
+                asm.address(helper.get_src(), helper.get_imm());
+                asm.alu_imm(ALU_AND, !1);
+                asm.store_imm(helper.get_dst(), pc + 4);
+                // rax = target | retired << 32, the 32-bit ops above cleared the upper half
+                asm.emit(&[0x48, 0xb9]);
+                asm.emit(&exit_value(0, i + 1, false).to_le_bytes());
+                asm.emit(&[0x48, 0x09, 0xc8]);
+                asm.epilogue();
+                return JitBlock::new(&asm.code);
+            }
+            InstructionFormat::I(opcode) => compile_i(&mut asm, opcode, helpers, trace, pc, i),
+            InstructionFormat::S(opcode) => compile_s(&mut asm, opcode, helpers, trace, pc, i),
+            InstructionFormat::B(opcode) => {
+                let (condition, helper) = match opcode {
+                    BOpcode::Beq(helper) => (CC_E, helper),
+                    BOpcode::Bne(helper) => (CC_NE, helper),
+                    BOpcode::Blt(helper) => (CC_L, helper),
+                    BOpcode::Bge(helper) => (CC_GE, helper),
+                    BOpcode::Bltu(helper) => (CC_B, helper),
+                    BOpcode::Bgeu(helper) => (CC_AE, helper),
+                };
+
+                asm.load_reg(EAX, helper.get_src1());
+                asm.load_reg(ECX, helper.get_src2());
+                // cmp eax, ecx
+                asm.alu_reg(0x39);
+                asm.jump_if(condition, Assembler::EXIT_SIZE);
+                asm.exit(pc + 4, i + 1, false);
+                asm.exit(pc.wrapping_add(helper.get_offset()), i + 1, false);
+                return JitBlock::new(&asm.code);
+            }
+            InstructionFormat::U(UOpcode::Lui(helper)) => {
+                asm.store_imm(helper.get_dest(), helper.get_imm());
+            }
+            InstructionFormat::U(UOpcode::Auipc(helper)) => {
+                asm.store_imm(helper.get_dest(), pc.wrapping_add(helper.get_imm()));
+            }
+            InstructionFormat::J(JOpcode::Jal(helper)) => {
+                asm.store_imm(helper.get_dest(), pc + 4);
+                asm.exit(pc.wrapping_add(helper.get_offset()), i + 1, false);
+                return JitBlock::new(&asm.code);
+            }
+            InstructionFormat::FENCE => {}
+            InstructionFormat::ECALL
+            | InstructionFormat::FENCEI
+            | InstructionFormat::CSR(_)
+            | InstructionFormat::AMO(_)
+            | InstructionFormat::MRET
+            | InstructionFormat::SRET
+            | InstructionFormat::WFI
+            | InstructionFormat::SFENCEVMA(..) => unreachable!(),
+        }
+    }
+
+    asm.exit(end, instructions.len(), false);
+    JitBlock::new(&asm.code)
+}
+
+fn compile_r(asm: &mut Assembler, opcode: &ROpcode) {
+    let helper = match opcode {
+        ROpcode::Slli(helper) | ROpcode::Srli(helper) | ROpcode::Srai(helper) => {
+            let extension = match opcode {
+                ROpcode::Slli(_) => SHIFT_SHL,
+                ROpcode::Srli(_) => SHIFT_SHR,
+                _ => SHIFT_SAR,
+            };
+
+            asm.load_reg(EAX, helper.get_src1());
+            asm.shift_imm(extension, helper.get_shamt());
+            asm.store_reg(helper.get_dest(), EAX);
+            return;
+        }
+        ROpcode::Add(helper)
+        | ROpcode::Sub(helper)
+        | ROpcode::Sll(helper)
+        | ROpcode::Slti(helper)
+        | ROpcode::Sltu(helper)
+        | ROpcode::Xor(helper)
+        | ROpcode::Srl(helper)
+        | ROpcode::Sra(helper)
+        | ROpcode::Or(helper)
+        | ROpcode::And(helper)
+        | ROpcode::Mul(helper)
+        | ROpcode::Mulh(helper)
+        | ROpcode::Mulhsu(helper)
+        | ROpcode::Mulhu(helper)
+        | ROpcode::Div(helper)
+        | ROpcode::Divu(helper)
+        | ROpcode::Rem(helper)
+        | ROpcode::Remu(helper) => helper,
+    };
+
+    // nothing observable happens when the result is discarded
+    if helper.get_dest() == 0 {
+        return;
+    }
+
+    asm.load_reg(EAX, helper.get_src1());
+    asm.load_reg(ECX, helper.get_src2());
+
+    match opcode {
+        ROpcode::Add(_) => asm.alu_reg(0x01),
+        ROpcode::Sub(_) => asm.alu_reg(0x29),
+        ROpcode::Xor(_) => asm.alu_reg(0x31),
+        ROpcode::Or(_) => asm.alu_reg(0x09),
+        ROpcode::And(_) => asm.alu_reg(0x21),
+        // x86 masks the shift amount to 5 bits like RISC-V does
+        ROpcode::Sll(_) => asm.shift_cl(SHIFT_SHL),
+        ROpcode::Srl(_) => asm.shift_cl(SHIFT_SHR),
+        ROpcode::Sra(_) => asm.shift_cl(SHIFT_SAR),
+        ROpcode::Slti(_) => {
+            asm.alu_reg(0x39);
+            asm.set_condition(CC_L);
+        }
+        ROpcode::Sltu(_) => {
+            asm.alu_reg(0x39);
+            asm.set_condition(CC_B);
+        }
+        // imul eax, ecx
+        ROpcode::Mul(_) => asm.emit(&[0x0f, 0xaf, 0xc1]),
+        ROpcode::Mulh(_) => {
+            // imul ecx, the high half ends in edx
+            asm.emit(&[0xf7, 0xe9]);
+            asm.mov(EAX, EDX);
+        }
+        ROpcode::Mulhu(_) => {
+            // mul ecx
+            asm.emit(&[0xf7, 0xe1]);
+            asm.mov(EAX, EDX);
+        }
+        ROpcode::Mulhsu(_) => {
+            // movsxd rax, eax; mov ecx, ecx; imul rax, rcx; shr rax, 32
+            asm.emit(&[0x48, 0x63, 0xc0, 0x89, 0xc9]);
+            asm.emit(&[0x48, 0x0f, 0xaf, 0xc1, 0x48, 0xc1, 0xe8, 0x20]);
+        }
+        ROpcode::Div(_) | ROpcode::Divu(_) | ROpcode::Rem(_) | ROpcode::Remu(_) => {
+            // division by zero and overflow are handled by the helpers
+            let function: extern "sysv64" fn(u32, u32) -> u32 = match opcode {
+                ROpcode::Div(_) => div,
+                ROpcode::Divu(_) => divu,
+                ROpcode::Rem(_) => rem,
+                _ => remu,
+            };
+
+            asm.mov(EDI, EAX);
+            asm.mov(ESI, ECX);
+            asm.call(function as usize);
+        }
+        ROpcode::Slli(_) | ROpcode::Srli(_) | ROpcode::Srai(_) => unreachable!(),
+    }
+
+    asm.store_reg(helper.get_dest(), EAX);
+}
+
+fn compile_i(
+    asm: &mut Assembler,
+    opcode: &IOpcode,
+    helpers: &JitHelpers,
+    trace: bool,
+    pc: u32,
+    index: usize,
+) {
+    let (load, sign_extend, helper) = match opcode {
+        IOpcode::Lb(helper) => (helpers.load_u8, Some([0x0f, 0xbe, 0xc0]), helper),
+        IOpcode::Lbu(helper) => (helpers.load_u8, None, helper),
+        IOpcode::Lh(helper) => (helpers.load_u16, Some([0x0f, 0xbf, 0xc0]), helper),
+        IOpcode::Lhu(helper) => (helpers.load_u16, None, helper),
+        IOpcode::Lw(helper) => (helpers.load_u32, None, helper),
+        IOpcode::Addi(helper)
+        | IOpcode::Slti(helper)
+        | IOpcode::Sltiu(helper)
+        | IOpcode::Xori(helper)
+        | IOpcode::Ori(helper)
+        | IOpcode::Andi(helper) => {
+            if helper.get_dst() == 0 {
+                return;
+            }
+
+            asm.load_reg(EAX, helper.get_src());
+            match opcode {
+                IOpcode::Addi(_) => asm.alu_imm(ALU_ADD, helper.get_imm()),
+                IOpcode::Xori(_) => asm.alu_imm(ALU_XOR, helper.get_imm()),
+                IOpcode::Ori(_) => asm.alu_imm(ALU_OR, helper.get_imm()),
+                IOpcode::Andi(_) => asm.alu_imm(ALU_AND, helper.get_imm()),
+                IOpcode::Slti(_) => {
+                    asm.alu_imm(ALU_CMP, helper.get_imm());
+                    asm.set_condition(CC_L);
+                }
+                _ => {
+                    asm.alu_imm(ALU_CMP, helper.get_imm());
+                    asm.set_condition(CC_B);
+                }
+            }
+            asm.store_reg(helper.get_dst(), EAX);
+            return;
+        }
+        IOpcode::Jalr(_) => unreachable!(),
+    };
+
+    asm.address(helper.get_src(), helper.get_imm());
+    asm.mov(ESI, EAX);
+    asm.vm_argument();
+    asm.call(load);
+
+    // test rax, rax; jns ok; device or unmapped address
+    asm.emit(&[0x48, 0x85, 0xc0]);
+    asm.jump_if(CC_NS, Assembler::EXIT_SIZE);
+    asm.exit(pc, index, true);
+
+    if trace {
+        asm.trace(helpers.trace, pc);
+    }
+
+    if let Some(sign_extend) = sign_extend {
+        asm.emit(&sign_extend);
+    }
+    asm.store_reg(helper.get_dst(), EAX);
+}
+
+fn compile_s(
+    asm: &mut Assembler,
+    opcode: &SOpcode,
+    helpers: &JitHelpers,
+    trace: bool,
+    pc: u32,
+    index: usize,
+) {
+    let (store, helper) = match opcode {
+        SOpcode::Sb(helper) => (helpers.store_u8, helper),
+        SOpcode::Sh(helper) => (helpers.store_u16, helper),
+        SOpcode::Sw(helper) => (helpers.store_u32, helper),
+    };
+
+    asm.address(helper.get_base(), helper.get_offset());
+    asm.load_reg(ECX, helper.get_src());
+    asm.mov(ESI, EAX);
+    asm.mov(EDX, ECX);
+    asm.vm_argument();
+    asm.call(store);
+
+    // cmp eax, STORE_FAULT; jne ok; device or unmapped address
+    asm.emit(&[0x83, 0xf8, STORE_FAULT as u8]);
+    asm.jump_if(CC_NE, Assembler::EXIT_SIZE);
+    asm.exit(pc, index, true);
+
+    if trace {
+        asm.trace(helpers.trace, pc);
+    }
+
+    // test eax, eax; jz ok; the rest of the block may be stale
+    asm.emit(&[0x85, 0xc0]);
+    asm.jump_if(CC_E, Assembler::EXIT_SIZE);
+    asm.exit(pc + 4, index + 1, false);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{clint::CLINT_ADDRESS, stop_conditions::StopReason, test_utils::*};
+
+    const RA: u32 = 1;
+    const SP: u32 = 2;
+    const T0: u32 = 5;
+    const T1: u32 = 6;
+    const T2: u32 = 7;
+    const S0: u32 = 8;
+
+    // arithmetic, byte accesses, a call and a division by zero in a hot loop
+    fn program() -> Vec<u32> {
+        let mut program = vec![
+            addi(T0, 0, 200),
+            addi(A1, 0, 7),
+            addi(S0, SP, -64),
+            mul(T1, A1, T0),
+            srai(T2, T1, 3),
+            xor(A1, A1, T2),
+            divu(T1, A1, T0),
+            add(A1, A1, T1),
+            sb(A1, S0, 0),
+            lbu(T2, S0, 0),
+            add(A1, A1, T2),
+            jal(RA, 16),
+            addi(T0, T0, -1),
+            blt(0, T0, -40),
+            jal(0, 16),
+            divu(T2, A1, 0),
+            add(A1, A1, T2),
+            jalr(0, RA, 0),
+        ];
+        program.extend(exit());
+        program
+    }
+
+    #[test]
+    fn compiled_code_matches_the_interpreter() {
+        let program = program();
+        let (interpreted_reason, interpreted) = run(&program, |vm| vm.set_block_cache(false));
+
+        for jit in [false, true] {
+            let (reason, vm) = run(&program, |vm| {
+                vm.set_block_cache(true);
+                vm.set_jit(jit);
+            });
+
+            assert_eq!(vm.get_jit_compiled() > 0, jit);
+            assert_eq!(reason, interpreted_reason);
+            assert!(matches!(reason, StopReason::Exit(_)));
+            assert_eq!(vm.get_registers(), interpreted.get_registers());
+            assert_eq!(vm.get_instret(), interpreted.get_instret());
+        }
+    }
+
+    #[test]
+    fn device_accesses_leave_the_compiled_code() {
+        // mtime counts the retired instructions, the interpreter has to read it at the exact count
+        let mut program = Vec::new();
+        program.extend(li(S0, CLINT_ADDRESS as u32 + 0xbff8));
+        program.extend([
+            addi(T0, 0, 100),
+            addi(A1, 0, 0),
+            lw(T1, S0, 0),
+            add(A1, A1, T1),
+            addi(T0, T0, -1),
+            bne(T0, 0, -12),
+        ]);
+        program.extend(exit());
+
+        let (interpreted_reason, interpreted) = run(&program, |vm| vm.set_block_cache(false));
+        let (reason, vm) = run(&program, |vm| {
+            vm.set_block_cache(true);
+            vm.set_jit(true);
+        });
+
+        assert!(vm.get_jit_compiled() > 0);
+        assert_eq!(reason, interpreted_reason);
+        assert_eq!(vm.get_registers(), interpreted.get_registers());
+    }
+}
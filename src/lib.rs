@@ -0,0 +1,38 @@
+pub mod block_cache;
+pub mod bus;
+pub mod clint;
+pub mod csr;
+pub mod decode_cache;
+pub mod device_tree;
+pub mod elf;
+pub mod fdt;
+pub mod framebuffer;
+pub mod gdb;
+pub mod gpio;
+pub mod history;
+pub mod instruction_decoder;
+pub mod instructions;
+#[cfg(feature = "jit")]
+pub mod jit;
+mod memory;
+pub mod plic;
+pub mod pmp;
+pub mod profiler;
+mod register;
+pub mod replay;
+pub mod snapshot;
+pub mod stop_conditions;
+mod syscalls;
+pub mod test_finisher;
+#[cfg(test)]
+mod test_utils;
+pub mod tlb;
+pub mod uart;
+mod utils;
+pub mod virt;
+pub mod virtio;
+pub mod virtio_blk;
+pub mod virtio_console;
+pub mod virtio_rng;
+pub mod vm;
+pub mod watchdog;
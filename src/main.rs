@@ -1,26 +1,827 @@
 use std::env;
-use std::fs::File;
-use std::io::Read;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::process::exit;
+use std::time::{Duration, Instant};
 
-use vm::VM;
+use riscv::{
+    clint::{Clint, TimeSource, CLINT_ADDRESS, MAX_HARTS},
+    device_tree::{self, BootInfo},
+    elf::Elf,
+    framebuffer::{
+        FrameOutput, Framebuffer, PixelFormat, FRAMEBUFFER_ADDRESS, FRAMEBUFFER_CONTROL_ADDRESS,
+        FRAMEBUFFER_MAX_SIZE,
+    },
+    gdb::GdbStub,
+    gpio::{parse_stimuli, Gpio, GPIO_ADDRESS, GPIO_IRQ},
+    history::DEFAULT_CHECKPOINT_INTERVAL,
+    plic::{Plic, CONTEXTS_PER_HART, MAX_SOURCES, PLIC_ADDRESS},
+    profiler::Profiler,
+    replay::InputLog,
+    stop_conditions::{exit_status, StopConditions, StopReason},
+    test_finisher::{TestFinisher, TEST_FINISHER_ADDRESS},
+    tlb::{DEFAULT_TLB_ENTRIES, DEFAULT_TLB_WAYS},
+    uart::{Uart, UartBackend, UART_ADDRESS, UART_IRQ},
+    virt::{self, BootImages, DEFAULT_VIRT_RAM_SIZE, VIRT_RAM_ADDRESS},
+    virtio::{VirtioDevice, VirtioMmio, VIRTIO_ADDRESS, VIRTIO_FIRST_IRQ, VIRTIO_STRIDE},
+    virtio_blk::{ImageMode, VirtioBlock},
+    virtio_console::{ConsoleBackend, VirtioConsole},
+    virtio_rng::{EntropySource, VirtioRng},
+    vm::{DEFAULT_QUANTUM, FLASH_ADDRESS, VM},
+    watchdog::{Watchdog, WatchdogAction, WATCHDOG_ADDRESS},
+};
 
-pub mod instruction_decoder;
-pub mod instructions;
-mod memory;
-mod register;
-mod syscalls;
-mod utils;
-mod vm;
+// exit codes reserved for the emulator itself, the guest's exit code goes through exit_status
+const EXIT_STOP_ADDRESS: i32 = 120;
+const EXIT_SELF_LOOP: i32 = 121;
+const EXIT_INSTRUCTION_LIMIT: i32 = 122;
+const EXIT_WATCHDOG: i32 = 123;
+const EXIT_TIMEOUT: i32 = 124;
+const EXIT_EMULATOR_FAULT: i32 = 125;
+const EXIT_USAGE: i32 = 126;
+
+// a1 gets the device tree, as on Linux and U-Boot
+const DTB_REGISTER: u32 = 11;
+
+fn usage() -> ! {
+    eprintln!("usage: riscv [options] <binary or elf>");
+    eprintln!("       riscv --machine virt [options] [firmware]");
+    eprintln!(
+        "  -v                        print emulator diagnostics (exit code, registers) on stderr"
+    );
+    eprintln!("  -vv                       also trace every executed instruction on stderr");
+    eprintln!("  --max-instructions <n>    stop after n retired instructions");
+    eprintln!("  --timeout <seconds>       stop after the given wall-clock time");
+    eprintln!(
+        "  --stop-at <pc | symbol>   stop when reaching the address or ELF symbol, can be repeated"
+    );
+    eprintln!("  --detect-self-loop        stop when an instruction jumps to itself (j .)");
+    eprintln!("  --machine virt            qemu virt-like machine with RAM at 0x80000000 and a UART, booting a firmware or a kernel");
+    eprintln!("  --memory <size>           RAM of the virt machine, with an optional K, M or G suffix (default 128M)");
+    eprintln!(
+        "  --kernel <image>          kernel Image started by the firmware in supervisor mode"
+    );
+    eprintln!("  --initrd <file>           initramfs of the kernel");
+    eprintln!("  --append <bootargs>       kernel command line");
+    eprintln!("  --dtb <address>           place the device tree of the machine in memory and pass its address in a1");
+    eprintln!("  --dtb-register <xN>       register getting the address of the device tree (default x11, a1)");
+    eprintln!(
+        "  --dump-dtb <path>         write the device tree of the machine to the file and exit"
+    );
+    eprintln!("  --harts <n>               number of harts sharing the machine (default 1)");
+    eprintln!(
+        "  --quantum <n>             instructions a hart runs before the next one (default 1000)"
+    );
+    eprintln!("  --clint-base <address>    address of the CLINT (default 0x2000000)");
+    eprintln!("  --clint-time <source>     mtime follows retired 'instructions' (default) or the 'host' clock");
+    eprintln!("  --plic-base <address>     address of the PLIC (default 0xc000000)");
+    eprintln!("  --plic-sources <n>        number of PLIC interrupt sources (default 32)");
+    eprintln!("  --uart <backend>          map a 16550 UART backed by 'stdio', 'file:<path>', 'tcp:<port>' or 'pty'");
+    eprintln!("  --uart-base <address>     address of the UART (default 0x10000000)");
+    eprintln!("  --uart-irq <source>       PLIC source of the UART (default 10)");
+    eprintln!("  --finisher <address>      address of the SiFive test finisher (default 0x100000)");
+    eprintln!("  --virtio-blk <image>      attach a virtio block device, append ',ro' for read-only or ',cow' for copy-on-write");
+    eprintln!(
+        "  --virtio-con <backend>    attach a virtio console backed by 'stdio' or 'file:<path>'"
+    );
+    eprintln!("  --virtio-rng <source>     attach a virtio entropy device fed by a seed (number) or the 'host'");
+    eprintln!("  --gpio                    map a GPIO block at 0x10060000 and log its pin changes on stderr");
+    eprintln!("  --gpio-log <path>         map the GPIO and log its pin changes to the file");
+    eprintln!("  --gpio-stimulus <path>    map the GPIO and drive its input pins from the file");
+    eprintln!("  --framebuffer <w>x<h>     map a framebuffer, append ':rgb888' or ':rgb565' to change the 'xrgb8888' format");
+    eprintln!("  --fb-output <pattern>     file of the presented frames, '%d' is the frame number (default frame-%d.ppm, or .png)");
+    eprintln!("  --fb-every <n>            save every n-th presented frame (default 1)");
+    eprintln!("  --watchdog <action>       map a watchdog that does a 'reset', raises an 'nmi' or 'stop's when it expires");
+    eprintln!("  --watchdog-timeout <n>    arm the watchdog out of reset with a timeout of n instructions");
+    eprintln!("  --tlb-entries <n>         entries of the TLB caching address translations, 0 disables it (default 64)");
+    eprintln!("  --tlb-ways <n>            associativity of the TLB (default 4)");
+    eprintln!("  --no-decode-cache         decode every instruction each time it is executed");
+    eprintln!("  --no-block-cache          interpret instruction by instruction instead of translated blocks");
+    #[cfg(feature = "jit")]
+    eprintln!("  --no-jit                  run translated blocks as micro-ops instead of compiling them to x86-64");
+    eprintln!(
+        "  --stats                   print execution statistics (MIPS, caches, TLB) on stderr"
+    );
+    eprintln!("  --save-snapshot-at <n>    save a snapshot of the machine after n retired instructions, can be repeated");
+    eprintln!("  --snapshot-output <path>  file of the saved snapshots, '%d' is the instruction count (default snapshot-%d.bin)");
+    eprintln!("  --load-snapshot <path>    resume from a snapshot taken with the same options");
+    eprintln!("  --record <path>           record the inputs from the host (stdin, devices, host clock) to the file");
+    eprintln!("  --replay <path>           replay recorded inputs instead of reading the host, with the same options");
+    eprintln!("  --gdb <port>              wait for gdb on 127.0.0.1:port and run under its control, also backwards");
+    eprintln!("  --checkpoint-interval <n> instructions between two checkpoints of reverse execution (default 1000000)");
+    eprintln!("  --profile <path>          write the instructions retired in every function of the ELF file to the file");
+    eprintln!("  --profile-folded <path>   write the instructions retired under every call stack as folded stacks");
+    eprintln!("exit status: the exit code of the guest, or 120-126 when the emulator stops it (see the README),");
+    eprintln!("  127 when the guest exits with 120-126 or a code outside of 0-255");
+    exit(EXIT_USAGE);
+}
+
+fn fail(message: String) -> ! {
+    eprintln!("riscv: {message}");
+    exit(EXIT_USAGE);
+}
+
+fn parse_number(value: &str) -> Option<u64> {
+    match value.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
+// sizes in bytes, or with a binary K, M or G suffix
+fn parse_size(value: &str) -> Option<usize> {
+    let (number, shift) = match value.char_indices().last()? {
+        (i, 'K') => (&value[..i], 10),
+        (i, 'M') => (&value[..i], 20),
+        (i, 'G') => (&value[..i], 30),
+        _ => (value, 0),
+    };
+    let size = parse_number(number)?.checked_shl(shift)?;
+    usize::try_from(size).ok()
+}
+
+fn read_file(path: &str) -> Vec<u8> {
+    let mut data = Vec::new();
+    if let Err(err) = File::open(path).and_then(|mut f| f.read_to_end(&mut data)) {
+        fail(format!("cannot read {path}: {err}"));
+    }
+    data
+}
+
+// written after the run, failing or not, so a write error doesn't change the exit code
+fn write_output(path: &str, write: impl FnOnce(&mut dyn Write) -> io::Result<()>) {
+    let result = File::create(path).and_then(|file| {
+        let mut out = io::BufWriter::new(file);
+        write(&mut out)?;
+        out.flush()
+    });
+    if let Err(err) = result {
+        eprintln!("riscv: cannot write {path}: {err}");
+    }
+}
+
+fn print_stats(vm: &VM, elapsed: Duration) {
+    let instructions = vm.get_instret();
+    let seconds = elapsed.as_secs_f64();
+
+    eprintln!("instructions: {instructions}");
+    eprintln!("time: {seconds:.3}s");
+    eprintln!("MIPS: {:.2}", instructions as f64 / seconds / 1e6);
+
+    let decode_cache = vm.get_decode_cache();
+    eprintln!(
+        "decode cache: {} hits, {} misses",
+        decode_cache.get_hits(),
+        decode_cache.get_misses()
+    );
+
+    let tlb = vm.get_tlb();
+    eprintln!(
+        "tlb: {} hits, {} misses, {} evictions, {} flushes",
+        tlb.get_hits(),
+        tlb.get_misses(),
+        tlb.get_evictions(),
+        tlb.get_flushes()
+    );
+
+    let block_cache = vm.get_block_cache();
+    eprintln!(
+        "block cache: {} blocks translated, {} invalidated",
+        block_cache.get_translated(),
+        block_cache.get_invalidations()
+    );
+
+    #[cfg(feature = "jit")]
+    eprintln!("jit: {} blocks compiled", vm.get_jit_compiled());
+}
+
+// the execution stops at every snapshot point to save the machine, and goes on until one of the
+// stop conditions is met
+fn run(
+    vm: &mut VM,
+    stop_conditions: &StopConditions,
+    snapshot_points: &[u64],
+    snapshot_output: &str,
+) -> StopReason {
+    for &point in snapshot_points {
+        if point < vm.get_instret() {
+            continue;
+        }
+
+        let mut until_point = stop_conditions.clone();
+        let limit = stop_conditions
+            .get_max_instructions()
+            .map_or(point, |max| max.min(point));
+        until_point.set_max_instructions(limit);
+        vm.set_stop_conditions(until_point);
+
+        let reason = vm.start_execution();
+        if reason != StopReason::InstructionLimit || vm.get_instret() < point {
+            return reason;
+        }
+
+        let path = snapshot_output.replace("%d", &point.to_string());
+        if let Err(err) = fs::write(&path, vm.save_snapshot()) {
+            fail(format!("cannot write {path}: {err}"));
+        }
+    }
+
+    vm.set_stop_conditions(stop_conditions.clone());
+    vm.start_execution()
+}
 
 fn main() {
-    let binary = env::args().nth(1).unwrap();
+    let mut verbosity = 0;
+    let mut binary = None;
+    let mut stop_conditions = StopConditions::new();
+    let mut stop_at = Vec::new();
+    let mut tlb_entries = DEFAULT_TLB_ENTRIES;
+    let mut tlb_ways = DEFAULT_TLB_WAYS;
+    let mut decode_cache = true;
+    let mut block_cache = true;
+    #[cfg(feature = "jit")]
+    let mut jit = true;
+    let mut stats = false;
+    let mut harts = 1;
+    let mut quantum = DEFAULT_QUANTUM;
+    let mut clint_base = CLINT_ADDRESS;
+    let mut time_source = TimeSource::Instructions;
+    let mut plic_base = PLIC_ADDRESS;
+    let mut plic_sources = 32;
+    let mut uart_backend = None;
+    let mut uart_base = UART_ADDRESS;
+    let mut uart_irq = UART_IRQ;
+    let mut test_finisher_base = TEST_FINISHER_ADDRESS;
+    let mut virtio_devices: Vec<Box<dyn VirtioDevice>> = Vec::new();
+    let mut console_stdio = false;
+    let mut gpio = false;
+    let mut gpio_log = None;
+    let mut gpio_stimuli = Vec::new();
+    let mut framebuffer = None;
+    let mut watchdog = None;
+    let mut watchdog_timeout = None;
+    let mut virt_machine = false;
+    let mut ram_size = DEFAULT_VIRT_RAM_SIZE;
+    let mut kernel = None;
+    let mut initrd = None;
+    let mut bootargs = None;
+    let mut dtb_address = None;
+    let mut dtb_register = DTB_REGISTER;
+    let mut dump_dtb = None;
+    let mut frame_output = FrameOutput {
+        pattern: "frame-%d.ppm".to_string(),
+        every: 1,
+    };
+    let mut snapshot_points = Vec::new();
+    let mut snapshot_output = "snapshot-%d.bin".to_string();
+    let mut load_snapshot = None;
+    let mut record = None;
+    let mut replay = None;
+    let mut gdb_port = None;
+    let mut checkpoint_interval = DEFAULT_CHECKPOINT_INTERVAL;
+    let mut profile = None;
+    let mut profile_folded = None;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| {
+            args.next()
+                .unwrap_or_else(|| fail(format!("missing value for {name}")))
+        };
+
+        match arg.as_str() {
+            "-v" => verbosity = 1,
+            "-vv" => verbosity = 2,
+            "-h" | "--help" => usage(),
+            "--max-instructions" => {
+                let max = value(&arg);
+                let max = parse_number(&max)
+                    .unwrap_or_else(|| fail(format!("invalid instruction count {max}")));
+                stop_conditions.set_max_instructions(max);
+            }
+            "--timeout" => {
+                let timeout = value(&arg);
+                let seconds = timeout
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|seconds| *seconds >= 0.0)
+                    .unwrap_or_else(|| fail(format!("invalid timeout {timeout}")));
+                stop_conditions.set_timeout(Duration::from_secs_f64(seconds));
+            }
+            "--stop-at" => stop_at.push(value(&arg)),
+            "--detect-self-loop" => stop_conditions.set_detect_self_loop(true),
+            "--machine" => match value(&arg).as_str() {
+                "virt" => virt_machine = true,
+                machine => fail(format!("unknown machine {machine}")),
+            },
+            "--memory" => {
+                let size = value(&arg);
+                ram_size = parse_size(&size)
+                    .unwrap_or_else(|| fail(format!("invalid memory size {size}")));
+            }
+            "--kernel" => kernel = Some(value(&arg)),
+            "--initrd" => initrd = Some(value(&arg)),
+            "--append" => bootargs = Some(value(&arg)),
+            "--dtb" => {
+                let address = value(&arg);
+                dtb_address = Some(
+                    parse_number(&address)
+                        .unwrap_or_else(|| fail(format!("invalid address {address}")))
+                        as usize,
+                );
+            }
+            "--dtb-register" => {
+                let register = value(&arg);
+                dtb_register = register
+                    .strip_prefix('x')
+                    .and_then(|number| number.parse().ok())
+                    .filter(|number| (1..32).contains(number))
+                    .unwrap_or_else(|| fail(format!("invalid register {register}")));
+            }
+            "--dump-dtb" => dump_dtb = Some(value(&arg)),
+            "--harts" => {
+                let count = value(&arg);
+                harts = parse_number(&count)
+                    .filter(|harts| (1..=MAX_HARTS as u64).contains(harts))
+                    .unwrap_or_else(|| fail(format!("invalid number of harts {count}")))
+                    as usize;
+            }
+            "--quantum" => {
+                let count = value(&arg);
+                quantum = parse_number(&count)
+                    .filter(|quantum| *quantum > 0)
+                    .unwrap_or_else(|| fail(format!("invalid quantum {count}")));
+            }
+            "--clint-base" => {
+                let base = value(&arg);
+                clint_base = parse_number(&base)
+                    .unwrap_or_else(|| fail(format!("invalid address {base}")))
+                    as usize;
+            }
+            "--clint-time" => {
+                time_source = match value(&arg).as_str() {
+                    "instructions" => TimeSource::Instructions,
+                    "host" => TimeSource::Host,
+                    source => fail(format!("invalid time source {source}")),
+                }
+            }
+            "--plic-base" => {
+                let base = value(&arg);
+                plic_base = parse_number(&base)
+                    .unwrap_or_else(|| fail(format!("invalid address {base}")))
+                    as usize;
+            }
+            "--plic-sources" => {
+                let sources = value(&arg);
+                plic_sources = parse_number(&sources)
+                    .filter(|sources| *sources <= MAX_SOURCES as u64)
+                    .unwrap_or_else(|| fail(format!("invalid number of sources {sources}")))
+                    as u32;
+            }
+            "--uart" => {
+                let backend = value(&arg);
+                uart_backend = Some(match backend.split_once(':') {
+                    None if backend == "stdio" => UartBackend::Stdio,
+                    None if backend == "pty" => UartBackend::Pty,
+                    Some(("file", path)) => UartBackend::File(path.to_string()),
+                    Some(("tcp", port)) => UartBackend::Tcp(
+                        port.parse()
+                            .unwrap_or_else(|_| fail(format!("invalid port {port}"))),
+                    ),
+                    _ => fail(format!("invalid uart backend {backend}")),
+                });
+            }
+            "--uart-base" => {
+                let base = value(&arg);
+                uart_base = parse_number(&base)
+                    .unwrap_or_else(|| fail(format!("invalid address {base}")))
+                    as usize;
+            }
+            "--uart-irq" => {
+                let irq = value(&arg);
+                uart_irq = parse_number(&irq)
+                    .unwrap_or_else(|| fail(format!("invalid interrupt source {irq}")))
+                    as u32;
+            }
+            "--finisher" => {
+                let base = value(&arg);
+                test_finisher_base = parse_number(&base)
+                    .unwrap_or_else(|| fail(format!("invalid address {base}")))
+                    as usize;
+            }
+            "--virtio-blk" => {
+                let drive = value(&arg);
+                let (path, mode) = match drive.rsplit_once(',') {
+                    Some((path, "ro")) => (path, ImageMode::ReadOnly),
+                    Some((path, "rw")) => (path, ImageMode::ReadWrite),
+                    Some((path, "cow")) => (path, ImageMode::CopyOnWrite),
+                    _ => (drive.as_str(), ImageMode::ReadWrite),
+                };
+                let block = VirtioBlock::new(path, mode)
+                    .unwrap_or_else(|err| fail(format!("cannot open {path}: {err}")));
+                virtio_devices.push(Box::new(block));
+            }
+            "--virtio-con" => {
+                let backend = value(&arg);
+                let backend = match backend.split_once(':') {
+                    None if backend == "stdio" => ConsoleBackend::Stdio,
+                    Some(("file", path)) => ConsoleBackend::File(path.to_string()),
+                    _ => fail(format!("invalid console backend {backend}")),
+                };
+                if matches!(backend, ConsoleBackend::Stdio) {
+                    console_stdio = true;
+                }
+                let console = VirtioConsole::new(&backend)
+                    .unwrap_or_else(|err| fail(format!("cannot open the console backend: {err}")));
+                virtio_devices.push(Box::new(console));
+            }
+            "--virtio-rng" => {
+                let source = value(&arg);
+                let source = match parse_number(&source) {
+                    Some(seed) => EntropySource::Seeded(seed),
+                    None if source == "host" => EntropySource::Host,
+                    None => fail(format!("invalid entropy source {source}")),
+                };
+                let rng = VirtioRng::new(&source)
+                    .unwrap_or_else(|err| fail(format!("cannot open the entropy source: {err}")));
+                virtio_devices.push(Box::new(rng));
+            }
+            "--gpio" => gpio = true,
+            "--gpio-log" => {
+                gpio = true;
+                gpio_log = Some(value(&arg));
+            }
+            "--gpio-stimulus" => {
+                gpio = true;
+                gpio_stimuli = parse_stimuli(&value(&arg)).unwrap_or_else(|err| fail(err));
+            }
+            "--framebuffer" => {
+                let spec = value(&arg);
+                let (size, format) = match spec.split_once(':') {
+                    Some((size, format)) => (size, PixelFormat::parse(format)),
+                    None => (spec.as_str(), Some(PixelFormat::Xrgb8888)),
+                };
+                let format =
+                    format.unwrap_or_else(|| fail(format!("invalid pixel format in {spec}")));
+                let (width, height) = size
+                    .split_once('x')
+                    .and_then(|(width, height)| Some((width.parse().ok()?, height.parse().ok()?)))
+                    .filter(|&(width, height)| {
+                        (1..=FRAMEBUFFER_MAX_SIZE).contains(&width)
+                            && (1..=FRAMEBUFFER_MAX_SIZE).contains(&height)
+                    })
+                    .unwrap_or_else(|| fail(format!("invalid framebuffer size {size}")));
+                framebuffer = Some((width, height, format));
+            }
+            "--fb-output" => frame_output.pattern = value(&arg),
+            "--fb-every" => {
+                let every = value(&arg);
+                frame_output.every = parse_number(&every)
+                    .filter(|every| *every > 0)
+                    .unwrap_or_else(|| fail(format!("invalid frame interval {every}")));
+            }
+            "--watchdog" => {
+                watchdog = Some(match value(&arg).as_str() {
+                    "reset" => WatchdogAction::Reset,
+                    "nmi" => WatchdogAction::Nmi,
+                    "stop" => WatchdogAction::Stop,
+                    action => fail(format!("invalid watchdog action {action}")),
+                });
+            }
+            "--watchdog-timeout" => {
+                let timeout = value(&arg);
+                watchdog_timeout = Some(
+                    parse_number(&timeout)
+                        // 0 would expire again right after every reset
+                        .filter(|timeout| (1..=u32::MAX as u64).contains(timeout))
+                        .unwrap_or_else(|| fail(format!("invalid watchdog timeout {timeout}")))
+                        as u32,
+                );
+            }
+            "--tlb-entries" => {
+                let entries = value(&arg);
+                tlb_entries = parse_number(&entries)
+                    .unwrap_or_else(|| fail(format!("invalid number of TLB entries {entries}")))
+                    as usize;
+            }
+            "--tlb-ways" => {
+                let ways = value(&arg);
+                tlb_ways = parse_number(&ways)
+                    .unwrap_or_else(|| fail(format!("invalid TLB associativity {ways}")))
+                    as usize;
+            }
+            "--no-decode-cache" => decode_cache = false,
+            "--no-block-cache" => block_cache = false,
+            #[cfg(feature = "jit")]
+            "--no-jit" => jit = false,
+            "--stats" => stats = true,
+            "--save-snapshot-at" => {
+                let count = value(&arg);
+                snapshot_points.push(
+                    parse_number(&count)
+                        .unwrap_or_else(|| fail(format!("invalid instruction count {count}"))),
+                );
+            }
+            "--snapshot-output" => snapshot_output = value(&arg),
+            "--load-snapshot" => load_snapshot = Some(value(&arg)),
+            "--record" => record = Some(value(&arg)),
+            "--replay" => replay = Some(value(&arg)),
+            "--gdb" => {
+                let port = value(&arg);
+                gdb_port = Some(
+                    port.parse::<u16>()
+                        .unwrap_or_else(|_| fail(format!("invalid port {port}"))),
+                );
+            }
+            "--checkpoint-interval" => {
+                let count = value(&arg);
+                checkpoint_interval = parse_number(&count)
+                    .filter(|interval| *interval > 0)
+                    .unwrap_or_else(|| fail(format!("invalid instruction count {count}")));
+            }
+            "--profile" => profile = Some(value(&arg)),
+            "--profile-folded" => profile_folded = Some(value(&arg)),
+            _ if arg.starts_with('-') => usage(),
+            _ if binary.is_none() => binary = Some(arg),
+            _ => usage(),
+        }
+    }
+
+    // the binary is optional on the virt machine, which can start the kernel directly
+    if binary.is_none() && !virt_machine {
+        usage();
+    }
+    if !virt_machine && (kernel.is_some() || initrd.is_some() || bootargs.is_some()) {
+        fail("--kernel, --initrd and --append need --machine virt".to_string());
+    }
+    // the virt machine places its device tree itself
+    if virt_machine && dtb_address.is_some() {
+        fail("--dtb can't be used with --machine virt".to_string());
+    }
+
+    // the virt machine always has a UART
+    if virt_machine && uart_backend.is_none() {
+        uart_backend = Some(UartBackend::Stdio);
+    }
+
+    // a recording is replayed with or without the debugger, which needs exact device ticks
+    let exact_ticks = record.is_some() || replay.is_some() || gdb_port.is_some();
+    let input_log = match (record, replay) {
+        (None, None) => InputLog::default(),
+        (Some(path), None) => InputLog::record(&path).unwrap_or_else(|err| fail(err)),
+        (None, Some(path)) => InputLog::replay(&path).unwrap_or_else(|err| fail(err)),
+        (Some(_), Some(_)) => fail("--record and --replay can't be used together".to_string()),
+    };
+    // the debugger decides where the execution stops
+    if gdb_port.is_some() && !snapshot_points.is_empty() {
+        fail("--gdb and --save-snapshot-at can't be used together".to_string());
+    }
+
+    // reruns would count their instructions again
+    let profiling = profile.is_some() || profile_folded.is_some();
+    if gdb_port.is_some() && profiling {
+        fail("--gdb and --profile can't be used together".to_string());
+    }
+
+    // both would compete for the bytes of stdin
+    if console_stdio && matches!(uart_backend, Some(UartBackend::Stdio)) {
+        fail("the uart and the virtio console can't both use stdio".to_string());
+    }
+
+    let load_address = if virt_machine {
+        VIRT_RAM_ADDRESS
+    } else {
+        FLASH_ADDRESS
+    };
+    let mut data = binary.as_deref().map(read_file);
+
+    // ELF files are flattened like objcopy would and keep their symbols around
+    let elf = match (&binary, &mut data) {
+        (Some(binary), Some(data)) if Elf::is_elf(data) => {
+            let elf = Elf::parse(data).unwrap_or_else(|err| fail(format!("{binary}: {err}")));
+            *data = elf
+                .flat_image(load_address as u32)
+                .unwrap_or_else(|err| fail(format!("{binary}: {err}")));
+            Some(elf)
+        }
+        _ => None,
+    };
+
+    for location in stop_at {
+        let address = match parse_number(&location) {
+            Some(address) => address as u32,
+            None => elf
+                .as_ref()
+                .and_then(|elf| elf.symbol_address(&location))
+                .unwrap_or_else(|| fail(format!("unknown symbol {location}"))),
+        };
+        stop_conditions.add_stop_address(address);
+    }
+
+    let mut vm = if virt_machine {
+        virt::new_vm(ram_size).unwrap_or_else(|err| fail(err))
+    } else {
+        VM::new(data.take().unwrap())
+    };
+    // test suites built for HTIF exit through tohost
+    vm.set_tohost(elf.as_ref().and_then(|elf| elf.symbol_address("tohost")));
+    if profiling {
+        let elf = elf
+            .as_ref()
+            .unwrap_or_else(|| fail("--profile needs an ELF file".to_string()));
+        vm.set_profiler(Some(Profiler::new(elf).unwrap_or_else(|err| fail(err))));
+    }
+    vm.set_verbosity(verbosity);
+    vm.set_input_log(input_log.clone());
+    vm.set_exact_ticks(exact_ticks);
+    let bus = vm.get_bus_mut();
+    bus.set_clint(Clint::new(clint_base, time_source, harts))
+        .unwrap_or_else(|err| fail(err));
+    let plic_contexts = harts * CONTEXTS_PER_HART;
+    bus.set_plic(Plic::new(plic_base, plic_sources, plic_contexts))
+        .unwrap_or_else(|err| fail(err));
+    if let Some(backend) = uart_backend {
+        let uart = Uart::new(&backend)
+            .unwrap_or_else(|err| fail(format!("cannot open the uart backend: {err}")));
+        bus.add_device("uart", uart_base, Box::new(uart), Some(uart_irq))
+            .unwrap_or_else(|err| fail(err));
+    }
+    bus.add_device(
+        "test-finisher",
+        test_finisher_base,
+        Box::new(TestFinisher::new()),
+        None,
+    )
+    .unwrap_or_else(|err| fail(err));
+    if gpio {
+        let log: Box<dyn Write> = match &gpio_log {
+            Some(path) => Box::new(
+                File::create(path)
+                    .unwrap_or_else(|err| fail(format!("cannot create {path}: {err}"))),
+            ),
+            None => Box::new(io::stderr()),
+        };
+        let mut device = Gpio::new(Some(log));
+        device.add_stimuli(&gpio_stimuli);
+        bus.add_device("gpio", GPIO_ADDRESS, Box::new(device), Some(GPIO_IRQ))
+            .unwrap_or_else(|err| fail(err));
+    }
+    if let Some((width, height, format)) = framebuffer {
+        let device = Framebuffer::new(
+            FRAMEBUFFER_ADDRESS,
+            width,
+            height,
+            format,
+            Some(frame_output),
+        );
+        bus.add_ram(
+            "framebuffer",
+            FRAMEBUFFER_ADDRESS,
+            vec![0; device.memory_size()],
+        )
+        .unwrap_or_else(|err| fail(err));
+        bus.add_device(
+            "framebuffer-control",
+            FRAMEBUFFER_CONTROL_ADDRESS,
+            Box::new(device),
+            None,
+        )
+        .unwrap_or_else(|err| fail(err));
+    }
+    if watchdog_timeout.is_some() && watchdog.is_none() {
+        fail("--watchdog-timeout needs --watchdog".to_string());
+    }
+    if let Some(action) = watchdog {
+        let device = Watchdog::new(action, watchdog_timeout);
+        bus.add_device("watchdog", WATCHDOG_ADDRESS, Box::new(device), None)
+            .unwrap_or_else(|err| fail(err));
+    }
+    // in the slots of the qemu virt machine, in command line order
+    for (slot, device) in virtio_devices.into_iter().enumerate() {
+        bus.add_device(
+            &format!("virtio{slot}"),
+            VIRTIO_ADDRESS + slot * VIRTIO_STRIDE,
+            Box::new(VirtioMmio::new(device)),
+            Some(VIRTIO_FIRST_IRQ + slot as u32),
+        )
+        .unwrap_or_else(|err| fail(err));
+    }
+    vm.set_harts(harts).unwrap_or_else(|err| fail(err));
+    vm.set_quantum(quantum);
+    // the device tree describes the devices attached above
+    let dtb = if virt_machine {
+        let images = BootImages {
+            firmware: data,
+            kernel: kernel.as_deref().map(read_file),
+            initrd: initrd.as_deref().map(read_file),
+            bootargs,
+        };
+        virt::boot(&mut vm, ram_size, &images).unwrap_or_else(|err| fail(err))
+    } else {
+        device_tree::generate(vm.get_bus(), &BootInfo::default())
+    };
+    if let Some(path) = dump_dtb {
+        if let Err(err) = fs::write(&path, &dtb) {
+            fail(format!("cannot write {path}: {err}"));
+        }
+        exit(0);
+    }
+    if let Some(address) = dtb_address {
+        if !vm.get_bus_mut().write_memory_n(address, dtb) {
+            fail(format!(
+                "the device tree doesn't fit in RAM at {address:#x}"
+            ));
+        }
+        vm.set_boot_register(dtb_register, address as u32);
+    }
+    vm.set_tlb(tlb_entries, tlb_ways)
+        .unwrap_or_else(|err| fail(err));
+    vm.set_decode_cache(decode_cache);
+    vm.set_block_cache(block_cache);
+    #[cfg(feature = "jit")]
+    vm.set_jit(jit);
+    let snapshot = load_snapshot.map(|path| (read_file(&path), path));
+    snapshot_points.sort();
+    snapshot_points.dedup();
+    let mut gdb = gdb_port.map(|port| {
+        GdbStub::listen(port)
+            .unwrap_or_else(|err| fail(format!("cannot wait for gdb on port {port}: {err}")))
+    });
+
+    let start = Instant::now();
+
+    // the default hook already reports the panic on stderr, we only need to map it to an exit code
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        match &snapshot {
+            Some((data, path)) => vm
+                .restore_snapshot(data)
+                .unwrap_or_else(|err| fail(format!("{path}: {err}"))),
+            None => vm.init_execution(),
+        }
+        match &mut gdb {
+            Some(gdb) => gdb.serve(&mut vm, &stop_conditions, checkpoint_interval),
+            None => Some(run(
+                &mut vm,
+                &stop_conditions,
+                &snapshot_points,
+                &snapshot_output,
+            )),
+        }
+    }));
+
+    if stats {
+        print_stats(&vm, start.elapsed());
+    }
+
+    // the profile of a failing run too
+    if let Some(profiler) = vm.get_profiler() {
+        if let Some(path) = &profile {
+            write_output(path, |out| profiler.write_report(out));
+        }
+        if let Some(path) = &profile_folded {
+            write_output(path, |out| profiler.write_folded(out));
+        }
+    }
+
+    // also after a panic, the recording of a failing run is the interesting one
+    if let Err(err) = input_log.finish() {
+        eprintln!("riscv: {err}");
+    }
 
-    let mut f = File::open(binary).unwrap();
-    let mut binary = Vec::new();
-    f.read_to_end(&mut binary).unwrap();
+    let exit_code = match result {
+        // killed from the debugger
+        Ok(None) => 0,
+        Ok(Some(StopReason::Exit(exit_code))) => {
+            if verbosity > 0 {
+                eprintln!("exit({exit_code}) after {} instructions", vm.get_instret());
+                vm.dump_registers();
+            }
+            exit_status(exit_code)
+        }
+        Ok(Some(reason)) => {
+            if verbosity > 0 {
+                eprintln!("stopped: {reason} after {} instructions", vm.get_instret());
+                vm.dump_registers();
+            }
 
-    let mut vm = VM::new(binary);
+            match reason {
+                StopReason::InstructionLimit => EXIT_INSTRUCTION_LIMIT,
+                StopReason::Timeout => EXIT_TIMEOUT,
+                StopReason::StopAddress(_) | StopReason::Watchpoint(_) => EXIT_STOP_ADDRESS,
+                StopReason::SelfLoop(_) => EXIT_SELF_LOOP,
+                StopReason::WatchdogExpired => EXIT_WATCHDOG,
+                StopReason::Exit(_) => unreachable!(),
+            }
+        }
+        Err(_) => {
+            if verbosity > 0 {
+                vm.dump_registers();
+            }
+            EXIT_EMULATOR_FAULT
+        }
+    };
 
-    vm.init_execution();
-    vm.start_execution();
+    let _ = io::stdout().flush();
+    exit(exit_code);
 }
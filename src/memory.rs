@@ -1,11 +1,78 @@
+use crate::snapshot::{SnapshotReader, SnapshotWriter};
+
+// pages of zeros are left out of snapshots, most of the RAM of a machine is never used
+const SNAPSHOT_PAGE_SIZE: usize = 0x1000;
+
 pub struct Memory {
     start: usize,
     data: Vec<u8>,
+    read_only: bool,
 }
 
 impl Memory {
     pub fn new(start: usize, data: Vec<u8>) -> Self {
-        Self { start, data }
+        Self {
+            start,
+            data,
+            read_only: false,
+        }
+    }
+
+    pub fn new_read_only(start: usize, data: Vec<u8>) -> Self {
+        Self {
+            start,
+            data,
+            read_only: true,
+        }
+    }
+
+    pub fn get_start(&self) -> usize {
+        self.start
+    }
+
+    pub fn get_size(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    pub fn save_state(&self, snapshot: &mut SnapshotWriter) {
+        snapshot.write_u64(self.start as u64);
+        snapshot.write_u64(self.data.len() as u64);
+        for page in self.data.chunks(SNAPSHOT_PAGE_SIZE) {
+            let used = page.iter().any(|byte| *byte != 0);
+            snapshot.write_bool(used);
+            if used {
+                snapshot.write_bytes(page);
+            }
+        }
+    }
+
+    pub fn restore_state(&mut self, snapshot: &mut SnapshotReader) -> Result<(), String> {
+        let start = snapshot.read_u64()?;
+        let size = snapshot.read_u64()?;
+        if start != self.start as u64 || size != self.data.len() as u64 {
+            return Err(format!(
+                "the snapshot has memory at {start:#x} (size {size:#x}), the machine at {:#x} (size {:#x})",
+                self.start,
+                self.data.len()
+            ));
+        }
+
+        for page in self.data.chunks_mut(SNAPSHOT_PAGE_SIZE) {
+            if !snapshot.read_bool()? {
+                page.fill(0);
+                continue;
+            }
+            let data = snapshot.read_bytes()?;
+            if data.len() != page.len() {
+                return Err("invalid memory page in the snapshot".to_string());
+            }
+            page.copy_from_slice(data);
+        }
+        Ok(())
     }
 
     pub fn belongs(&self, address: usize, nb_bytes: usize) -> bool {
@@ -132,4 +199,4 @@ impl Memory {
 
     //     bytes
     // }
-}
\ No newline at end of file
+}
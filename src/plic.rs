@@ -0,0 +1,319 @@
+use crate::snapshot::{SnapshotReader, SnapshotWriter};
+
+// platform-level interrupt controller, routes the interrupt lines of the devices to the harts
+//
+// Every source goes through a level-triggered gateway: it becomes pending while its line is
+// asserted and it is not being served, a claim hands the highest priority source to the hart and
+// the completion lets the gateway forward the line again.
+
+pub const PLIC_ADDRESS: usize = 0xc000000;
+pub const PLIC_SIZE: usize = 0x4000000;
+
+// the sifive and qemu plics have 7 priority levels, 0 never interrupts
+const PRIORITY_MASK: u32 = 7;
+pub const MAX_SOURCES: u32 = 1023;
+
+// register offsets
+const PRIORITY: usize = 0;
+const PENDING: usize = 0x1000;
+const ENABLE: usize = 0x2000;
+const ENABLE_STRIDE: usize = 0x80;
+const CONTEXT: usize = 0x200000;
+const CONTEXT_STRIDE: usize = 0x1000;
+const THRESHOLD: usize = 0;
+const CLAIM: usize = 4;
+
+// hart n has the contexts 2n for machine mode and 2n + 1 for supervisor mode
+pub const CONTEXTS_PER_HART: usize = 2;
+pub const MACHINE_CONTEXT: usize = 0;
+pub const SUPERVISOR_CONTEXT: usize = 1;
+
+struct Context {
+    // one bit per source
+    enable: Vec<u32>,
+    threshold: u32,
+}
+
+pub struct Plic {
+    base: usize,
+    // source 0 doesn't exist, its priority is always 0
+    sources: u32,
+    priority: Vec<u32>,
+    pending: Vec<u32>,
+    // claimed and not completed yet
+    in_flight: Vec<u32>,
+    // current level of the interrupt lines
+    lines: Vec<u32>,
+    contexts: Vec<Context>,
+}
+
+fn bit(bits: &[u32], source: u32) -> bool {
+    bits[source as usize / 32] & 1 << (source % 32) != 0
+}
+
+fn set_bit(bits: &mut [u32], source: u32, value: bool) {
+    if value {
+        bits[source as usize / 32] |= 1 << (source % 32);
+    } else {
+        bits[source as usize / 32] &= !(1 << (source % 32));
+    }
+}
+
+impl Plic {
+    pub fn new(base: usize, sources: u32, contexts: usize) -> Self {
+        assert!(
+            sources <= MAX_SOURCES,
+            "The PLIC supports at most {MAX_SOURCES} sources"
+        );
+        assert!(contexts > 0, "The PLIC needs at least one context");
+
+        let words = sources as usize / 32 + 1;
+
+        Self {
+            base,
+            sources,
+            priority: vec![0; sources as usize + 1],
+            pending: vec![0; words],
+            in_flight: vec![0; words],
+            lines: vec![0; words],
+            contexts: (0..contexts)
+                .map(|_| Context {
+                    enable: vec![0; words],
+                    threshold: 0,
+                })
+                .collect(),
+        }
+    }
+
+    // the interrupt lines keep their level
+    pub fn reset(&mut self) {
+        self.priority.fill(0);
+        self.pending.fill(0);
+        self.in_flight.fill(0);
+        for context in &mut self.contexts {
+            context.enable.fill(0);
+            context.threshold = 0;
+        }
+        for source in 1..=self.sources {
+            self.update_gateway(source);
+        }
+    }
+
+    pub fn save_state(&self, snapshot: &mut SnapshotWriter) {
+        snapshot.write_u64(self.sources as u64);
+        snapshot.write_u64(self.contexts.len() as u64);
+        for bits in [&self.priority, &self.pending, &self.in_flight, &self.lines] {
+            snapshot.write_u32s(bits);
+        }
+        for context in &self.contexts {
+            snapshot.write_u32s(&context.enable);
+            snapshot.write_u32(context.threshold);
+        }
+    }
+
+    pub fn restore_state(&mut self, snapshot: &mut SnapshotReader) -> Result<(), String> {
+        snapshot.check("PLIC sources", self.sources as u64)?;
+        snapshot.check("PLIC contexts", self.contexts.len() as u64)?;
+        snapshot.read_u32s_into("PLIC priorities", &mut self.priority)?;
+        for bits in [&mut self.pending, &mut self.in_flight, &mut self.lines] {
+            snapshot.read_u32s_into("PLIC source words", bits)?;
+        }
+        for context in &mut self.contexts {
+            snapshot.read_u32s_into("PLIC source words", &mut context.enable)?;
+            context.threshold = snapshot.read_u32()?;
+        }
+        Ok(())
+    }
+
+    pub fn get_base(&self) -> usize {
+        self.base
+    }
+
+    pub fn get_sources(&self) -> u32 {
+        self.sources
+    }
+
+    pub fn get_contexts(&self) -> usize {
+        self.contexts.len()
+    }
+
+    // asserts or deasserts the interrupt line of a source
+    pub fn set_level(&mut self, source: u32, asserted: bool) {
+        assert!(
+            source > 0 && source <= self.sources,
+            "Invalid interrupt source {source}"
+        );
+
+        set_bit(&mut self.lines, source, asserted);
+        self.update_gateway(source);
+    }
+
+    pub fn is_pending(&self, source: u32) -> bool {
+        bit(&self.pending, source)
+    }
+
+    fn update_gateway(&mut self, source: u32) {
+        let asserted = bit(&self.lines, source);
+
+        if asserted && !bit(&self.in_flight, source) {
+            set_bit(&mut self.pending, source, true);
+        } else if !asserted {
+            // the line went away before being claimed
+            set_bit(&mut self.pending, source, false);
+        }
+    }
+
+    // pending source with the highest priority above the threshold of the context, the lowest id
+    // wins ties
+    fn best_source(&self, context: usize) -> Option<u32> {
+        let context = &self.contexts[context];
+
+        let mut best = None;
+        let mut best_priority = context.threshold;
+
+        for source in 1..=self.sources {
+            let priority = self.priority[source as usize];
+            if priority > best_priority
+                && bit(&self.pending, source)
+                && bit(&context.enable, source)
+            {
+                best = Some(source);
+                best_priority = priority;
+            }
+        }
+
+        best
+    }
+
+    // whether the context has an interrupt to claim, drives mip.MEIP
+    pub fn has_interrupt(&self, context: usize) -> bool {
+        self.best_source(context).is_some()
+    }
+
+    fn claim(&mut self, context: usize) -> u32 {
+        match self.best_source(context) {
+            Some(source) => {
+                set_bit(&mut self.pending, source, false);
+                set_bit(&mut self.in_flight, source, true);
+                source
+            }
+            None => 0,
+        }
+    }
+
+    fn complete(&mut self, context: usize, source: u32) {
+        // completions of sources the context doesn't have enabled are ignored
+        if source == 0 || source > self.sources || !bit(&self.contexts[context].enable, source) {
+            return;
+        }
+
+        set_bit(&mut self.in_flight, source, false);
+        self.update_gateway(source);
+    }
+
+    // the plic registers are 32 bits wide, smaller accesses see a part of them
+    pub fn read(&mut self, address: usize, nb_bytes: usize) -> u32 {
+        let offset = address - self.base;
+        let shift = (offset & 3) * 8;
+
+        let value = self.read_register(offset & !3);
+        (value >> shift) & (u32::MAX >> (32 - nb_bytes * 8))
+    }
+
+    pub fn write(&mut self, address: usize, nb_bytes: usize, value: u32) {
+        let offset = address - self.base;
+        let shift = (offset & 3) * 8;
+        let mask = (u32::MAX >> (32 - nb_bytes * 8)) << shift;
+
+        // reading the claim register to merge a partial write would claim an interrupt
+        let is_claim = offset >= CONTEXT && ((offset - CONTEXT) % CONTEXT_STRIDE) & !3 == CLAIM;
+        let old = if is_claim {
+            0
+        } else {
+            self.read_register(offset & !3)
+        };
+
+        self.write_register(offset & !3, old & !mask | value << shift & mask);
+    }
+
+    // register of the given source group, None past the last source
+    fn word(&self, offset: usize, start: usize) -> Option<usize> {
+        let word = (offset - start) / 4;
+        (word < self.pending.len()).then_some(word)
+    }
+
+    fn read_register(&mut self, offset: usize) -> u32 {
+        if offset < PENDING {
+            let source = (offset - PRIORITY) / 4;
+            return self.priority.get(source).copied().unwrap_or(0);
+        }
+
+        if offset < ENABLE {
+            return self
+                .word(offset, PENDING)
+                .map_or(0, |word| self.pending[word]);
+        }
+
+        if offset < CONTEXT {
+            let context = (offset - ENABLE) / ENABLE_STRIDE;
+            let word = self.word((offset - ENABLE) % ENABLE_STRIDE, 0);
+            return match (self.contexts.get(context), word) {
+                (Some(context), Some(word)) => context.enable[word],
+                _ => 0,
+            };
+        }
+
+        let context = (offset - CONTEXT) / CONTEXT_STRIDE;
+        if context >= self.contexts.len() {
+            return 0;
+        }
+
+        match (offset - CONTEXT) % CONTEXT_STRIDE {
+            THRESHOLD => self.contexts[context].threshold,
+            CLAIM => self.claim(context),
+            _ => 0,
+        }
+    }
+
+    fn write_register(&mut self, offset: usize, value: u32) {
+        if offset < PENDING {
+            let source = (offset - PRIORITY) / 4;
+            // source 0 doesn't exist
+            if source > 0 && source <= self.sources as usize {
+                self.priority[source] = value & PRIORITY_MASK;
+            }
+            return;
+        }
+
+        // the pending bits are read-only
+        if offset < ENABLE {
+            return;
+        }
+
+        if offset < CONTEXT {
+            let context = (offset - ENABLE) / ENABLE_STRIDE;
+            let word = self.word((offset - ENABLE) % ENABLE_STRIDE, 0);
+            if let (Some(context), Some(word)) = (self.contexts.get_mut(context), word) {
+                // bit 0 is source 0, it can't be enabled
+                let mut value = if word == 0 { value & !1 } else { value };
+                // nor can the sources past the last one
+                if word == self.pending.len() - 1 {
+                    value &= u32::MAX >> (31 - self.sources % 32);
+                }
+                context.enable[word] = value;
+            }
+            return;
+        }
+
+        let context = (offset - CONTEXT) / CONTEXT_STRIDE;
+        if context >= self.contexts.len() {
+            return;
+        }
+
+        match (offset - CONTEXT) % CONTEXT_STRIDE {
+            THRESHOLD => self.contexts[context].threshold = value & PRIORITY_MASK,
+            CLAIM => self.complete(context, value),
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,251 @@
+use crate::snapshot::{SnapshotReader, SnapshotWriter};
+
+// physical memory protection, 16 entries configured through pmpcfg0-3 and pmpaddr0-15
+
+pub const PMP_ENTRIES: usize = 16;
+
+// permission bits of a pmpcfg byte
+pub const PMP_R: u8 = 1 << 0;
+pub const PMP_W: u8 = 1 << 1;
+pub const PMP_X: u8 = 1 << 2;
+const PMP_A: u8 = 3 << 3;
+// locked entries ignore writes until reset and apply to machine mode
+const PMP_L: u8 = 1 << 7;
+
+// address matching modes, 0 turns the entry off
+const PMP_TOR: u8 = 1 << 3;
+const PMP_NA4: u8 = 2 << 3;
+const PMP_NAPOT: u8 = 3 << 3;
+
+#[derive(Default)]
+pub struct Pmp {
+    cfg: [u8; PMP_ENTRIES],
+    // bits 33:2 of the addresses
+    addr: [u32; PMP_ENTRIES],
+    // at least one entry is locked, machine mode accesses have to be checked
+    locked: bool,
+    // at least one entry is on. Until then the pmp is disabled and supervisor and user mode can
+    // access everything, so guests that never program it run in those modes
+    active: bool,
+}
+
+impl Pmp {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // pmpcfg<n> holds the configuration of the entries 4n to 4n + 3
+    pub fn read_cfg(&self, index: usize) -> u32 {
+        u32::from_le_bytes(self.cfg[index * 4..index * 4 + 4].try_into().unwrap())
+    }
+
+    pub fn write_cfg(&mut self, index: usize, value: u32) {
+        for (i, byte) in value.to_le_bytes().into_iter().enumerate() {
+            let entry = index * 4 + i;
+            if self.cfg[entry] & PMP_L != 0 {
+                continue;
+            }
+
+            // the reserved bits read as zero, write without read is reserved and drops the write
+            let mut cfg = byte & (PMP_L | PMP_A | PMP_X | PMP_W | PMP_R);
+            if cfg & (PMP_R | PMP_W) == PMP_W {
+                cfg &= !PMP_W;
+            }
+            self.cfg[entry] = cfg;
+        }
+
+        self.update_flags();
+    }
+
+    fn update_flags(&mut self) {
+        self.locked = self.cfg.iter().any(|cfg| cfg & PMP_L != 0);
+        self.active = self.cfg.iter().any(|cfg| cfg & PMP_A != 0);
+    }
+
+    pub fn read_addr(&self, index: usize) -> u32 {
+        self.addr[index]
+    }
+
+    // the top of a locked top-of-range entry is locked as well
+    pub fn write_addr(&mut self, index: usize, value: u32) {
+        let locked = self.cfg[index] & PMP_L != 0;
+        let next_locked = self
+            .cfg
+            .get(index + 1)
+            .is_some_and(|cfg| cfg & PMP_L != 0 && cfg & PMP_A == PMP_TOR);
+
+        if !locked && !next_locked {
+            self.addr[index] = value;
+        }
+    }
+
+    pub fn save_state(&self, snapshot: &mut SnapshotWriter) {
+        snapshot.write_bytes(&self.cfg);
+        snapshot.write_u32s(&self.addr);
+    }
+
+    pub fn restore_state(&mut self, snapshot: &mut SnapshotReader) -> Result<(), String> {
+        let cfg = snapshot.read_bytes()?;
+        self.cfg = cfg
+            .try_into()
+            .map_err(|_| "invalid PMP configuration in the snapshot".to_string())?;
+        snapshot.read_u32s_into("PMP entries", &mut self.addr)?;
+        self.update_flags();
+        Ok(())
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked
+    }
+
+    // byte range [start, end) of the entry, None when it is off
+    fn range(&self, entry: usize) -> Option<(u64, u64)> {
+        let addr = self.addr[entry] as u64;
+        match self.cfg[entry] & PMP_A {
+            PMP_TOR => {
+                let start = match entry {
+                    0 => 0,
+                    _ => (self.addr[entry - 1] as u64) << 2,
+                };
+                Some((start, addr << 2))
+            }
+            PMP_NA4 => Some((addr << 2, (addr << 2) + 4)),
+            // the trailing ones give the size, 8 bytes and more
+            PMP_NAPOT => {
+                let ones = self.addr[entry].trailing_ones() as u64;
+                let size = 8u64 << ones;
+                let start = (addr << 2) & !(size - 1);
+                Some((start, start + size))
+            }
+            _ => None,
+        }
+    }
+
+    // the lowest matching entry decides, an access that only partly matches it fails. Machine mode
+    // is only checked against locked entries, other modes need an entry granting the access once
+    // an entry is on
+    pub fn check(&self, address: u64, nb_bytes: usize, permission: u8, machine: bool) -> bool {
+        if machine && !self.locked || !self.active {
+            return true;
+        }
+
+        let start = address;
+        let end = start + nb_bytes as u64;
+
+        for entry in 0..PMP_ENTRIES {
+            let Some((entry_start, entry_end)) = self.range(entry) else {
+                continue;
+            };
+            if start >= entry_end || end <= entry_start {
+                continue;
+            }
+            if start < entry_start || end > entry_end {
+                return false;
+            }
+
+            let cfg = self.cfg[entry];
+            return machine && cfg & PMP_L == 0 || cfg & permission == permission;
+        }
+
+        machine
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RW: u8 = PMP_R | PMP_W;
+
+    // configures the entries with (cfg, pmpaddr) pairs from entry 0
+    fn configured(entries: &[(u8, u32)]) -> Pmp {
+        let mut pmp = Pmp::new();
+        let mut cfg = [0; PMP_ENTRIES];
+        for (entry, (entry_cfg, addr)) in entries.iter().enumerate() {
+            pmp.write_addr(entry, *addr);
+            cfg[entry] = *entry_cfg;
+        }
+        for index in 0..PMP_ENTRIES / 4 {
+            let value = u32::from_le_bytes(cfg[index * 4..index * 4 + 4].try_into().unwrap());
+            pmp.write_cfg(index, value);
+        }
+        pmp
+    }
+
+    #[test]
+    fn disabled_until_an_entry_is_on() {
+        let pmp = configured(&[]);
+        assert!(pmp.check(0x8000_0000, 4, PMP_W, false));
+
+        let pmp = configured(&[(PMP_NA4 | PMP_R, 0x100)]);
+        assert!(pmp.check(0x400, 4, PMP_R, false));
+        assert!(!pmp.check(0x8000_0000, 4, PMP_R, false));
+        // machine mode isn't restricted by unlocked entries
+        assert!(pmp.check(0x8000_0000, 4, PMP_W, true));
+    }
+
+    #[test]
+    fn tor_matches_from_the_previous_address() {
+        // entry 0 covers [0, 0x1000), entry 1 [0x1000, 0x3000)
+        let pmp = configured(&[(PMP_TOR | PMP_X, 0x400), (PMP_TOR | RW, 0xc00)]);
+
+        assert!(pmp.check(0, 4, PMP_X, false));
+        assert!(!pmp.check(0xffc, 4, PMP_R, false));
+        assert!(pmp.check(0x1000, 4, PMP_W, false));
+        assert!(pmp.check(0x2ffc, 4, PMP_R, false));
+        assert!(!pmp.check(0x2ffc, 4, PMP_X, false));
+        assert!(!pmp.check(0x3000, 4, PMP_R, false));
+    }
+
+    #[test]
+    fn na4_and_napot_sizes() {
+        let pmp = configured(&[
+            // 4 bytes at 0x2000
+            (PMP_NA4 | PMP_R, 0x800),
+            // 8 bytes at 0x3000
+            (PMP_NAPOT | RW, 0xc00),
+            // 4 KiB at 0x4000
+            (PMP_NAPOT | PMP_X, 0x1000 | 0x1ff),
+        ]);
+
+        assert!(pmp.check(0x2000, 4, PMP_R, false));
+        assert!(!pmp.check(0x2004, 4, PMP_R, false));
+        assert!(pmp.check(0x3004, 4, PMP_W, false));
+        assert!(!pmp.check(0x3008, 1, PMP_W, false));
+        assert!(pmp.check(0x4000, 4, PMP_X, false));
+        assert!(pmp.check(0x4ffc, 4, PMP_X, false));
+        assert!(!pmp.check(0x5000, 4, PMP_X, false));
+    }
+
+    #[test]
+    fn lowest_entry_decides_and_partial_matches_fail() {
+        let pmp = configured(&[(PMP_NA4, 0x400), (PMP_NAPOT | RW, 0x400 | 0x1ff)]);
+
+        // the first entry denies its word in the readable page
+        assert!(!pmp.check(0x1000, 4, PMP_R, false));
+        assert!(pmp.check(0x1004, 4, PMP_R, false));
+        // an access straddling the end of the page is only partly matched
+        assert!(!pmp.check(0x1ffe, 4, PMP_R, false));
+    }
+
+    #[test]
+    fn locked_entries_apply_to_machine_mode_and_ignore_writes() {
+        let mut pmp = configured(&[(PMP_TOR | PMP_L | PMP_R, 0x400)]);
+
+        assert!(pmp.is_locked());
+        assert!(pmp.check(0x100, 4, PMP_R, true));
+        assert!(!pmp.check(0x100, 4, PMP_W, true));
+
+        pmp.write_cfg(0, (PMP_TOR | RW) as u32);
+        pmp.write_addr(0, 0x800);
+        assert_eq!(pmp.read_cfg(0), (PMP_TOR | PMP_L | PMP_R) as u32);
+        assert_eq!(pmp.read_addr(0), 0x400);
+    }
+
+    #[test]
+    fn write_only_permission_is_reserved() {
+        let mut pmp = Pmp::new();
+        pmp.write_cfg(0, (PMP_NA4 | PMP_W | PMP_X) as u32);
+        assert_eq!(pmp.read_cfg(0), (PMP_NA4 | PMP_X) as u32);
+    }
+}
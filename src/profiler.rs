@@ -0,0 +1,505 @@
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+use crate::{
+    elf::Elf,
+    instructions::{IOpcode, InstructionFormat, JOpcode},
+};
+
+// every retired instruction is attributed to the function around its pc, and to the functions
+// that called it. Calls and returns are told apart with the link register conventions of the
+// spec: jal and jalr writing ra or t0 call, jalr jumping to ra or t0 without writing them
+// returns
+
+const RA: u32 = 1;
+const T0: u32 = 5;
+
+// instructions outside of every function
+const UNKNOWN: &str = "[unknown]";
+
+struct Function {
+    name: String,
+    start: u32,
+    end: u32,
+}
+
+// node of the call tree, the path from the root is the call stack
+struct Node {
+    function: usize,
+    parent: Option<usize>,
+    // instructions retired with this call stack
+    instructions: u64,
+}
+
+struct Frame {
+    node: usize,
+    // where the function returns, trap handlers return with mret or sret instead
+    return_address: u32,
+    trap: bool,
+}
+
+pub struct Profiler {
+    // sorted by address, the last one is UNKNOWN
+    functions: Vec<Function>,
+    nodes: Vec<Node>,
+    children: HashMap<(Option<usize>, usize), usize>,
+    // call stack of every hart
+    stacks: Vec<Vec<Frame>>,
+}
+
+fn is_link(register: u32) -> bool {
+    register == RA || register == T0
+}
+
+impl Profiler {
+    // the functions of the symbol table, up to the next one when their size is unknown. Without
+    // function symbols, like in assembly programs, every symbol starts a function
+    pub fn new(elf: &Elf) -> Result<Self, String> {
+        let has_functions = elf.get_symbols().iter().any(|symbol| symbol.is_function());
+        let mut symbols: Vec<_> = elf
+            .get_symbols()
+            .iter()
+            .filter(|symbol| symbol.is_function() || !has_functions)
+            .collect();
+        if symbols.is_empty() {
+            return Err("the ELF file has no symbols to profile".to_string());
+        }
+        symbols.sort_by_key(|symbol| symbol.get_address());
+        // aliases are reported under the first name
+        symbols.dedup_by_key(|symbol| symbol.get_address());
+
+        let mut functions: Vec<Function> = symbols
+            .iter()
+            .enumerate()
+            .map(|(i, symbol)| {
+                let next = symbols
+                    .get(i + 1)
+                    .map_or(u32::MAX, |next| next.get_address());
+                let end = match symbol.get_size() {
+                    0 => next,
+                    size => symbol.get_address().saturating_add(size).min(next),
+                };
+                Function {
+                    name: symbol.get_name().to_string(),
+                    start: symbol.get_address(),
+                    end,
+                }
+            })
+            .collect();
+        functions.push(Function {
+            name: UNKNOWN.to_string(),
+            start: 0,
+            end: 0,
+        });
+
+        Ok(Self {
+            functions,
+            nodes: Vec::new(),
+            children: HashMap::new(),
+            stacks: Vec::new(),
+        })
+    }
+
+    fn function_at(&self, pc: u32) -> usize {
+        let unknown = self.functions.len() - 1;
+        let index = self.functions[..unknown].partition_point(|function| function.start <= pc);
+        match index.checked_sub(1) {
+            Some(index) if pc < self.functions[index].end => index,
+            _ => unknown,
+        }
+    }
+
+    fn node(&mut self, parent: Option<usize>, function: usize) -> usize {
+        let nodes = &mut self.nodes;
+        *self.children.entry((parent, function)).or_insert_with(|| {
+            nodes.push(Node {
+                function,
+                parent,
+                instructions: 0,
+            });
+            nodes.len() - 1
+        })
+    }
+
+    fn push(&mut self, hart: usize, target: u32, return_address: u32, trap: bool) {
+        let parent = self.stacks[hart].last().map(|frame| frame.node);
+        let function = self.function_at(target);
+        let node = self.node(parent, function);
+        self.stacks[hart].push(Frame {
+            node,
+            return_address,
+            trap,
+        });
+    }
+
+    // back to the caller returning to target, skipping the frames left by a longjmp. A trap
+    // handler or the first function don't return through a jalr
+    fn pop(&mut self, hart: usize, target: u32) {
+        let stack = &mut self.stacks[hart];
+        let bottom = stack
+            .iter()
+            .rposition(|frame| frame.trap)
+            .map_or(1, |index| index + 1);
+        match stack[bottom..]
+            .iter()
+            .rposition(|frame| frame.return_address == target)
+        {
+            Some(index) => stack.truncate(bottom + index),
+            None if stack.len() > bottom => {
+                stack.pop();
+            }
+            None => {}
+        }
+    }
+
+    // mret and sret leave the last trap handler
+    fn trap_return(&mut self, hart: usize) {
+        let stack = &mut self.stacks[hart];
+        if let Some(index) = stack.iter().rposition(|frame| frame.trap) {
+            stack.truncate(index);
+        }
+    }
+
+    // instruction at pc retired by the hart, next_pc is the next one it executes
+    pub fn retire(&mut self, hart: usize, pc: u32, instruction: &InstructionFormat, next_pc: u32) {
+        if self.stacks.len() <= hart {
+            self.stacks.resize_with(hart + 1, Vec::new);
+        }
+
+        // jumps without a link, tail calls and falling through to the next symbol replace the
+        // current function
+        let current = self.stacks[hart]
+            .last()
+            .map(|frame| self.nodes[frame.node].function);
+        let in_current = current.is_some_and(|function| {
+            let range = self.functions[function].start..self.functions[function].end;
+            range.contains(&pc) || self.function_at(pc) == function
+        });
+        if !in_current {
+            let function = self.function_at(pc);
+            let frame = match self.stacks[hart].pop() {
+                Some(frame) => Frame {
+                    node: self.node(self.nodes[frame.node].parent, function),
+                    ..frame
+                },
+                None => Frame {
+                    node: self.node(None, function),
+                    return_address: 0,
+                    trap: false,
+                },
+            };
+            self.stacks[hart].push(frame);
+        }
+
+        let node = self.stacks[hart].last().unwrap().node;
+        self.nodes[node].instructions += 1;
+
+        let return_address = pc.wrapping_add(4);
+        match instruction {
+            InstructionFormat::J(JOpcode::Jal(helper)) if is_link(helper.get_dest()) => {
+                self.push(hart, next_pc, return_address, false);
+            }
+            InstructionFormat::I(IOpcode::Jalr(helper)) => {
+                let (rd, rs1) = (helper.get_dst(), helper.get_src());
+                // a link in both with different registers is a coroutine switch
+                if is_link(rs1) && (!is_link(rd) || rd != rs1) {
+                    self.pop(hart, next_pc);
+                }
+                if is_link(rd) {
+                    self.push(hart, next_pc, return_address, false);
+                }
+            }
+            InstructionFormat::MRET | InstructionFormat::SRET => self.trap_return(hart),
+            _ => {}
+        }
+    }
+
+    // the trap handler at target runs on top of the interrupted function, until mret or sret
+    pub fn trap(&mut self, hart: usize, target: u32) {
+        if self.stacks.len() <= hart {
+            self.stacks.resize_with(hart + 1, Vec::new);
+        }
+        self.push(hart, target, 0, true);
+    }
+
+    // the harts start again without callers
+    pub fn reset(&mut self) {
+        self.stacks.clear();
+    }
+
+    pub fn get_total(&self) -> u64 {
+        self.nodes.iter().map(|node| node.instructions).sum()
+    }
+
+    // functions of the call stack, from the root
+    fn stack(&self, node: usize) -> Vec<usize> {
+        let mut stack = Vec::new();
+        let mut current = Some(node);
+        while let Some(node) = current {
+            stack.push(self.nodes[node].function);
+            current = self.nodes[node].parent;
+        }
+        stack.reverse();
+        stack
+    }
+
+    // instructions retired in every function itself and with it on the call stack, recursive
+    // calls are only counted once
+    pub fn get_counts(&self) -> Vec<(&str, u64, u64)> {
+        let mut own = vec![0; self.functions.len()];
+        let mut inclusive = vec![0; self.functions.len()];
+        for (index, node) in self.nodes.iter().enumerate() {
+            own[node.function] += node.instructions;
+            let mut stack = self.stack(index);
+            stack.sort();
+            stack.dedup();
+            for function in stack {
+                inclusive[function] += node.instructions;
+            }
+        }
+
+        let mut counts: Vec<_> = self
+            .functions
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| inclusive[*index] > 0)
+            .map(|(index, function)| (function.name.as_str(), own[index], inclusive[index]))
+            .collect();
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then(b.2.cmp(&a.2)).then(a.0.cmp(b.0)));
+        counts
+    }
+
+    // one line per function, by decreasing self count
+    pub fn write_report(&self, out: &mut dyn Write) -> io::Result<()> {
+        let total = self.get_total().max(1) as f64;
+        writeln!(
+            out,
+            "{:>14} {:>7} {:>14} {:>7}  function",
+            "self", "%", "inclusive", "%"
+        )?;
+        for (name, own, inclusive) in self.get_counts() {
+            writeln!(
+                out,
+                "{:>14} {:>6.2}% {:>14} {:>6.2}%  {}",
+                own,
+                own as f64 * 100.0 / total,
+                inclusive,
+                inclusive as f64 * 100.0 / total,
+                name
+            )?;
+        }
+        Ok(())
+    }
+
+    // folded stacks, "caller;callee count" lines as read by flamegraph.pl and inferno
+    pub fn write_folded(&self, out: &mut dyn Write) -> io::Result<()> {
+        let mut lines: Vec<_> = (0..self.nodes.len())
+            .filter(|node| self.nodes[*node].instructions > 0)
+            .map(|node| {
+                let names: Vec<_> = self
+                    .stack(node)
+                    .into_iter()
+                    .map(|function| self.functions[function].name.as_str())
+                    .collect();
+                (names.join(";"), self.nodes[node].instructions)
+            })
+            .collect();
+        lines.sort();
+        for (stack, instructions) in lines {
+            writeln!(out, "{stack} {instructions}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{instruction_decoder::decode, test_utils::*};
+
+    const MAIN: u32 = 0x100;
+    const FOO: u32 = 0x200;
+    const BAR: u32 = 0x300;
+    const HANDLER: u32 = 0x400;
+    const NOP: u32 = 0x13;
+
+    fn profiler() -> Profiler {
+        let mut functions: Vec<_> = [("main", MAIN), ("foo", FOO), ("bar", BAR)]
+            .into_iter()
+            .chain([("handler", HANDLER)])
+            .map(|(name, start)| Function {
+                name: name.to_string(),
+                start,
+                end: start + 0x100,
+            })
+            .collect();
+        functions.push(Function {
+            name: UNKNOWN.to_string(),
+            start: 0,
+            end: 0,
+        });
+        Profiler {
+            functions,
+            nodes: Vec::new(),
+            children: HashMap::new(),
+            stacks: Vec::new(),
+        }
+    }
+
+    // the hart 0 retires the instructions at their pc, each one going to the next pc
+    fn retire(profiler: &mut Profiler, trace: &[(u32, u32)], end: u32) {
+        for (i, &(pc, instruction)) in trace.iter().enumerate() {
+            let next_pc = trace.get(i + 1).map_or(end, |next| next.0);
+            profiler.retire(0, pc, &decode(instruction), next_pc);
+        }
+    }
+
+    fn folded(profiler: &Profiler) -> String {
+        let mut out = Vec::new();
+        profiler.write_folded(&mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn jal_and_jalr_call_and_return_through_the_link_registers() {
+        let mut profiler = profiler();
+        retire(
+            &mut profiler,
+            &[
+                (MAIN, NOP),
+                (MAIN + 4, jal(RA, (FOO - MAIN - 4) as i32)),
+                (FOO, NOP),
+                // alternate link register
+                (FOO + 4, jal(T0, (BAR - FOO - 4) as i32)),
+                (BAR, jalr(0, T0, 0)),
+                (FOO + 8, jalr(0, RA, 0)),
+                (MAIN + 8, NOP),
+                // jumping to a register that isn't a link is neither a call nor a return
+                (MAIN + 12, jalr(0, A0, 0)),
+                (BAR, NOP),
+            ],
+            BAR + 4,
+        );
+
+        assert_eq!(
+            folded(&profiler),
+            "bar 1\nmain 4\nmain;foo 3\nmain;foo;bar 1\n"
+        );
+        assert_eq!(profiler.get_total(), 9);
+        assert_eq!(
+            profiler.get_counts(),
+            [("main", 4, 8), ("foo", 3, 4), ("bar", 2, 2)]
+        );
+    }
+
+    #[test]
+    fn recursive_calls_are_counted_once_inclusively() {
+        let mut profiler = profiler();
+        retire(
+            &mut profiler,
+            &[
+                (MAIN, jal(RA, (FOO - MAIN) as i32)),
+                (FOO, jal(RA, 0)),
+                (FOO, NOP),
+                (FOO + 4, jalr(0, RA, 0)),
+                (FOO + 4, jalr(0, RA, 0)),
+            ],
+            MAIN + 4,
+        );
+
+        assert_eq!(folded(&profiler), "main 1\nmain;foo 2\nmain;foo;foo 2\n");
+        assert_eq!(profiler.get_counts(), [("foo", 4, 4), ("main", 1, 5)]);
+
+        let mut report = Vec::new();
+        profiler.write_report(&mut report).unwrap();
+        let report = String::from_utf8(report).unwrap();
+        let lines: Vec<_> = report.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].ends_with("  function"));
+        assert!(lines[1].contains(" 80.00% ") && lines[1].ends_with("  foo"));
+        assert!(lines[2].contains(" 100.00% ") && lines[2].ends_with("  main"));
+    }
+
+    // jalr ra, t0 and jalr t0, ra swap coroutines, they never nest
+    #[test]
+    fn coroutine_swaps_replace_each_other() {
+        let mut profiler = profiler();
+        retire(
+            &mut profiler,
+            &[
+                (MAIN, jal(RA, (FOO - MAIN) as i32)),
+                (FOO, jalr(RA, T0, 0)),
+                (BAR, jalr(T0, RA, 0)),
+                (FOO + 4, jalr(RA, T0, 0)),
+                (BAR + 4, jalr(T0, RA, 0)),
+                (FOO + 8, NOP),
+            ],
+            FOO + 12,
+        );
+
+        assert_eq!(folded(&profiler), "main 1\nmain;bar 2\nmain;foo 3\n");
+    }
+
+    // a longjmp returns to a caller further down the stack, the frames above it are dropped
+    #[test]
+    fn returns_skip_the_frames_left_by_a_longjmp() {
+        let mut profiler = profiler();
+        retire(
+            &mut profiler,
+            &[
+                (MAIN, jal(RA, (FOO - MAIN) as i32)),
+                (FOO, jal(RA, (BAR - FOO) as i32)),
+                (BAR, jal(RA, 0)),
+                (BAR, jalr(0, RA, 0)),
+                (MAIN + 4, NOP),
+                // a return to an unknown address only leaves the current function
+                (MAIN + 8, jal(RA, (FOO - MAIN - 8) as i32)),
+                (FOO, jal(RA, (BAR - FOO) as i32)),
+                (BAR, jalr(0, RA, 0x40)),
+                (FOO + 0x44, NOP),
+            ],
+            FOO + 0x48,
+        );
+
+        assert_eq!(
+            folded(&profiler),
+            "main 3\nmain;foo 3\nmain;foo;bar 2\nmain;foo;bar;bar 1\n"
+        );
+    }
+
+    // trap handlers run on top of the interrupted function until mret, returns inside them
+    // don't go below the handler
+    #[test]
+    fn trap_handlers_stay_until_mret() {
+        let mut profiler = profiler();
+        retire(
+            &mut profiler,
+            &[(MAIN, jal(RA, (FOO - MAIN) as i32)), (FOO, NOP)],
+            HANDLER,
+        );
+        profiler.trap(0, HANDLER);
+        retire(
+            &mut profiler,
+            &[
+                (HANDLER, jal(RA, BAR as i32 - HANDLER as i32)),
+                (BAR, jalr(0, RA, 0)),
+                // nothing to return to above the handler
+                (HANDLER + 4, jalr(0, RA, 4)),
+                (HANDLER + 8, MRET),
+                (FOO + 4, jalr(0, RA, 0)),
+                (MAIN + 4, NOP),
+            ],
+            MAIN + 8,
+        );
+
+        assert_eq!(
+            folded(&profiler),
+            "main 2\nmain;foo 2\nmain;foo;handler 3\nmain;foo;handler;bar 1\n"
+        );
+
+        // a reset forgets the callers, the next instruction starts a new stack
+        profiler.reset();
+        retire(&mut profiler, &[(BAR, NOP)], BAR + 4);
+        assert!(folded(&profiler).starts_with("bar 1\n"));
+    }
+}
@@ -23,4 +23,8 @@ impl Register {
     pub fn get_value(&self) -> u32 {
         self.value
     }
+
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
 }
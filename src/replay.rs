@@ -0,0 +1,579 @@
+use std::{
+    cell::RefCell,
+    cmp::Ordering,
+    collections::HashMap,
+    fmt,
+    fs::{self, File},
+    io::{BufWriter, Write},
+    rc::Rc,
+};
+
+// record and replay of what the guest gets from the host: the bytes read from stdin, the data
+// received by the devices and the readings of the host clock. The recording lists them with the
+// instruction count they were read at, the replay hands them back at the same points without
+// asking the host, so a run can be reproduced instruction for instruction. The interrupts taken
+// are recorded as well and compared during the replay, to catch a run that went another way
+//
+// file format: the magic and the version, then the events in the order they happened. An event
+// is its input (kind and index), the instruction count, the number of the read at that count and
+// the data preceded by its length. Values are little-endian
+
+const RECORDING_MAGIC: &[u8; 8] = b"RVRECORD";
+// incremented whenever the layout changes, older recordings can't be replayed
+pub const RECORDING_VERSION: u32 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Input {
+    // bytes returned by the ReadInput syscall
+    Syscall,
+    // host clock of the CLINT, in HOST_TIMEBASE ticks
+    HostTime,
+    // received bytes, entropy or input pin levels of the device with this index on the bus
+    Device(u32),
+    // hart and cause of a taken interrupt, only compared during the replay
+    Interrupt,
+}
+
+impl Input {
+    fn encode(self) -> (u8, u32) {
+        match self {
+            Input::Syscall => (0, 0),
+            Input::HostTime => (1, 0),
+            Input::Device(index) => (2, index),
+            Input::Interrupt => (3, 0),
+        }
+    }
+
+    fn decode(kind: u8, index: u32) -> Option<Self> {
+        match kind {
+            0 => Some(Input::Syscall),
+            1 => Some(Input::HostTime),
+            2 => Some(Input::Device(index)),
+            3 => Some(Input::Interrupt),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Input {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Input::Syscall => write!(f, "syscall input"),
+            Input::HostTime => write!(f, "host clock reading"),
+            Input::Device(index) => write!(f, "input of device {index}"),
+            Input::Interrupt => write!(f, "interrupt"),
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Event {
+    input: Input,
+    instret: u64,
+    // reads of the same input at the same instruction count are told apart by their number
+    occurrence: u32,
+    data: Vec<u8>,
+}
+
+enum Mode {
+    // the inputs come from the host
+    Off,
+    Record(BufWriter<File>),
+    // the inputs come from the kept events
+    Replay,
+}
+
+// events kept in memory, by input, with the next one of every input
+#[derive(Default)]
+struct KeptEvents {
+    events: HashMap<Input, Vec<Event>>,
+    cursors: HashMap<Input, usize>,
+}
+
+struct LogState {
+    mode: Mode,
+    // the whole recording when replaying, the inputs read so far when the history of the run is
+    // kept for reverse execution
+    kept: Option<KeptEvents>,
+    // instruction count of the last read of every input and the number of reads at that count
+    last_reads: HashMap<Input, (u64, u32)>,
+    // set while a part of the run that was already executed runs again
+    rerun: bool,
+}
+
+impl LogState {
+    // the inputs come from the kept events rather than from the host
+    fn replayed(&self) -> bool {
+        matches!(self.mode, Mode::Replay) || self.rerun
+    }
+
+    // an input got from the host
+    fn keep(&mut self, input: Input, instret: u64, occurrence: u32, data: &[u8]) {
+        if let Mode::Record(file) = &mut self.mode {
+            write_event(file, input, instret, occurrence, data);
+        }
+        if let Some(kept) = &mut self.kept {
+            let events = kept.events.entry(input).or_default();
+            events.push(Event {
+                input,
+                instret,
+                occurrence,
+                data: data.to_vec(),
+            });
+            kept.cursors.insert(input, events.len());
+        }
+    }
+}
+
+// number of the read among the reads of the input at this instruction count
+fn occurrence(last_reads: &mut HashMap<Input, (u64, u32)>, input: Input, instret: u64) -> u32 {
+    let last = last_reads.entry(input).or_insert((instret, 0));
+    if last.0 == instret {
+        last.1 += 1;
+    } else {
+        *last = (instret, 1);
+    }
+    last.1 - 1
+}
+
+// where the log is in the kept events, saved with the checkpoints of reverse execution
+#[derive(Clone)]
+pub struct LogPosition {
+    cursors: HashMap<Input, usize>,
+    last_reads: HashMap<Input, (u64, u32)>,
+}
+
+// shared by the VM, the CLINT and the devices, it is off unless a recording or a replay is set
+#[derive(Clone)]
+pub struct InputLog {
+    state: Rc<RefCell<LogState>>,
+}
+
+impl Default for InputLog {
+    fn default() -> Self {
+        Self::with_mode(Mode::Off, None)
+    }
+}
+
+fn write_event(
+    file: &mut BufWriter<File>,
+    input: Input,
+    instret: u64,
+    occurrence: u32,
+    data: &[u8],
+) {
+    let (kind, index) = input.encode();
+    let mut event = vec![kind];
+    event.extend_from_slice(&index.to_le_bytes());
+    event.extend_from_slice(&instret.to_le_bytes());
+    event.extend_from_slice(&occurrence.to_le_bytes());
+    event.extend_from_slice(&(data.len() as u64).to_le_bytes());
+    event.extend_from_slice(data);
+
+    if let Err(err) = file.write_all(&event) {
+        panic!("Cannot write the recording: {err}");
+    }
+}
+
+// the next bytes of the recording
+fn take<'a>(rest: &mut &'a [u8], size: usize) -> Result<&'a [u8], String> {
+    let (bytes, remaining) = rest
+        .split_at_checked(size)
+        .ok_or_else(|| "truncated recording".to_string())?;
+    *rest = remaining;
+    Ok(bytes)
+}
+
+fn take_u32(rest: &mut &[u8]) -> Result<u32, String> {
+    Ok(u32::from_le_bytes(take(rest, 4)?.try_into().unwrap()))
+}
+
+fn take_u64(rest: &mut &[u8]) -> Result<u64, String> {
+    Ok(u64::from_le_bytes(take(rest, 8)?.try_into().unwrap()))
+}
+
+fn parse_events(data: &[u8]) -> Result<HashMap<Input, Vec<Event>>, String> {
+    let mut rest = data
+        .strip_prefix(RECORDING_MAGIC)
+        .ok_or_else(|| "not a recording".to_string())?;
+
+    let version = take_u32(&mut rest)?;
+    if version != RECORDING_VERSION {
+        return Err(format!(
+            "recording version {version} isn't supported (expected {RECORDING_VERSION})"
+        ));
+    }
+
+    let mut events: HashMap<Input, Vec<Event>> = HashMap::new();
+    while let Some(&kind) = rest.first() {
+        rest = &rest[1..];
+        let index = take_u32(&mut rest)?;
+        let input = Input::decode(kind, index)
+            .ok_or_else(|| format!("invalid input kind {kind} in the recording"))?;
+        let instret = take_u64(&mut rest)?;
+        let occurrence = take_u32(&mut rest)?;
+        let size = take_u64(&mut rest)?;
+        let size = usize::try_from(size).map_err(|_| "truncated recording".to_string())?;
+        let data = take(&mut rest, size)?.to_vec();
+
+        events.entry(input).or_default().push(Event {
+            input,
+            instret,
+            occurrence,
+            data,
+        });
+    }
+
+    Ok(events)
+}
+
+impl InputLog {
+    fn with_mode(mode: Mode, kept: Option<KeptEvents>) -> Self {
+        Self {
+            state: Rc::new(RefCell::new(LogState {
+                mode,
+                kept,
+                last_reads: HashMap::new(),
+                rerun: false,
+            })),
+        }
+    }
+
+    // the inputs still come from the host and are written to the file as they are read
+    pub fn record(path: &str) -> Result<Self, String> {
+        let file = File::create(path).map_err(|err| format!("cannot create {path}: {err}"))?;
+        let mut file = BufWriter::new(file);
+        let mut header = RECORDING_MAGIC.to_vec();
+        header.extend_from_slice(&RECORDING_VERSION.to_le_bytes());
+        file.write_all(&header)
+            .map_err(|err| format!("cannot write {path}: {err}"))?;
+        Ok(Self::with_mode(Mode::Record(file), None))
+    }
+
+    // the host isn't asked anymore, the machine must be configured like the recorded one
+    pub fn replay(path: &str) -> Result<Self, String> {
+        let data = fs::read(path).map_err(|err| format!("cannot read {path}: {err}"))?;
+        let events = parse_events(&data).map_err(|err| format!("{path}: {err}"))?;
+        let kept = KeptEvents {
+            events,
+            cursors: HashMap::new(),
+        };
+        Ok(Self::with_mode(Mode::Replay, Some(kept)))
+    }
+
+    pub fn channel(&self, input: Input) -> InputChannel {
+        InputChannel {
+            log: self.clone(),
+            input,
+        }
+    }
+
+    // the inputs read from now on are kept in memory, so that the run can go back to a position
+    // and run again with the same inputs
+    pub fn keep_history(&self) {
+        self.state
+            .borrow_mut()
+            .kept
+            .get_or_insert_with(KeptEvents::default);
+    }
+
+    pub fn position(&self) -> LogPosition {
+        let state = self.state.borrow();
+        LogPosition {
+            cursors: state
+                .kept
+                .as_ref()
+                .map(|kept| kept.cursors.clone())
+                .unwrap_or_default(),
+            last_reads: state.last_reads.clone(),
+        }
+    }
+
+    // goes back to a position taken with the machine state being restored, the inputs come from
+    // the kept events until set_rerun(false)
+    pub fn rewind(&self, position: &LogPosition) {
+        let state = &mut *self.state.borrow_mut();
+        if let Some(kept) = &mut state.kept {
+            kept.cursors = position.cursors.clone();
+        }
+        state.last_reads = position.last_reads.clone();
+        state.rerun = true;
+    }
+
+    // cleared when the rerun is back where the run stopped, the inputs come from the host again
+    pub fn set_rerun(&self, rerun: bool) {
+        self.state.borrow_mut().rerun = rerun;
+    }
+
+    // the outputs of a rerun were already written the first time
+    pub fn is_rerun(&self) -> bool {
+        self.state.borrow().rerun
+    }
+
+    // the kept events after the position are dropped, when the run goes another way from there.
+    // A replay keeps the rest of its recording
+    pub fn truncate(&self) {
+        let state = &mut *self.state.borrow_mut();
+        if let (Mode::Off | Mode::Record(_), Some(kept)) = (&state.mode, &mut state.kept) {
+            for (input, events) in kept.events.iter_mut() {
+                events.truncate(kept.cursors.get(input).copied().unwrap_or(0));
+            }
+        }
+    }
+
+    // data of the input at this instruction count, live asks the host unless it is replayed.
+    // Reads that got nothing aren't recorded
+    pub fn read(&self, input: Input, instret: u64, live: impl FnOnce() -> Vec<u8>) -> Vec<u8> {
+        let state = &mut *self.state.borrow_mut();
+        if matches!(state.mode, Mode::Off) && state.kept.is_none() {
+            return live();
+        }
+
+        let occurrence = occurrence(&mut state.last_reads, input, instret);
+        if state.replayed() {
+            return take_event(&mut state.kept, input, instret, occurrence)
+                .map(|event| event.data)
+                .unwrap_or_default();
+        }
+
+        let data = live();
+        if !data.is_empty() {
+            state.keep(input, instret, occurrence, &data);
+        }
+        data
+    }
+
+    // an event computed by the machine itself, the replay stops when it doesn't match the
+    // recording
+    pub fn check(&self, input: Input, instret: u64, value: u64) {
+        let state = &mut *self.state.borrow_mut();
+        if matches!(state.mode, Mode::Off) && state.kept.is_none() {
+            return;
+        }
+
+        let occurrence = occurrence(&mut state.last_reads, input, instret);
+        let data = value.to_le_bytes();
+        if state.replayed() {
+            match take_event(&mut state.kept, input, instret, occurrence) {
+                Some(event) if event.data == data => {}
+                _ => panic!("Replay diverged: {input} at instruction {instret} wasn't recorded"),
+            }
+        } else {
+            state.keep(input, instret, occurrence, &data);
+        }
+    }
+
+    // the recording is complete once flushed. A replay that stopped before the end of the
+    // recording reports the first input it didn't read
+    pub fn finish(&self) -> Result<(), String> {
+        let state = &mut *self.state.borrow_mut();
+        match (&mut state.mode, &state.kept) {
+            (Mode::Record(file), _) => file
+                .flush()
+                .map_err(|err| format!("cannot write the recording: {err}")),
+            (Mode::Replay, Some(kept)) => match kept
+                .events
+                .iter()
+                .filter_map(|(input, events)| {
+                    events.get(kept.cursors.get(input).copied().unwrap_or(0))
+                })
+                .min_by_key(|event| (event.instret, event.occurrence))
+            {
+                Some(event) => Err(format!(
+                    "the replay stopped before the {} recorded at instruction {}",
+                    event.input, event.instret
+                )),
+                None => Ok(()),
+            },
+            _ => Ok(()),
+        }
+    }
+}
+
+// next kept event of the input if it happened at this read. A recorded read that was skipped
+// means the replay went another way than the recording
+fn take_event(
+    kept: &mut Option<KeptEvents>,
+    input: Input,
+    instret: u64,
+    occurrence: u32,
+) -> Option<Event> {
+    let kept = kept.as_mut()?;
+    let cursor = kept.cursors.entry(input).or_insert(0);
+    let next = kept.events.get(&input)?.get(*cursor)?;
+    match (next.instret, next.occurrence).cmp(&(instret, occurrence)) {
+        Ordering::Equal => {
+            *cursor += 1;
+            Some(next.clone())
+        }
+        Ordering::Greater => None,
+        Ordering::Less => panic!(
+            "Replay diverged: the {input} recorded at instruction {} wasn't read",
+            next.instret
+        ),
+    }
+}
+
+// an input of the log, given to the CLINT and the devices when they are attached to the bus
+#[derive(Clone)]
+pub struct InputChannel {
+    log: InputLog,
+    input: Input,
+}
+
+// not recorded, for the devices that aren't attached yet
+impl Default for InputChannel {
+    fn default() -> Self {
+        InputLog::default().channel(Input::Device(0))
+    }
+}
+
+impl InputChannel {
+    pub fn read(&self, instret: u64, live: impl FnOnce() -> Vec<u8>) -> Vec<u8> {
+        self.log.read(self.input, instret, live)
+    }
+
+    // the device doesn't write its output again during a rerun
+    pub fn is_rerun(&self) -> bool {
+        self.log.is_rerun()
+    }
+
+    // for inputs that are always read, like the host clock
+    pub fn read_u64(&self, instret: u64, live: impl FnOnce() -> u64) -> u64 {
+        let data = self.read(instret, || live().to_le_bytes().to_vec());
+        match data.try_into() {
+            Ok(bytes) => u64::from_le_bytes(bytes),
+            Err(_) => panic!(
+                "Replay diverged: the {} at instruction {instret} wasn't recorded",
+                self.input
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{stop_conditions::StopReason, test_utils::*};
+
+    // a recording of two syscall reads, a clock reading and an interrupt
+    fn recording(name: &str) -> String {
+        let path = std::env::temp_dir()
+            .join(format!("riscv-replay-{}-{name}", std::process::id()))
+            .to_string_lossy()
+            .into_owned();
+
+        let log = InputLog::record(&path).unwrap();
+        assert_eq!(log.read(Input::Syscall, 10, || b"ab".to_vec()), b"ab");
+        // nothing was read, it isn't recorded
+        assert!(log.read(Input::Syscall, 20, Vec::new).is_empty());
+        assert_eq!(log.read(Input::Syscall, 20, || b"c".to_vec()), b"c");
+        let clock = log.channel(Input::HostTime);
+        assert_eq!(clock.read_u64(30, || 1234), 1234);
+        log.check(Input::Interrupt, 40, 7);
+        log.finish().unwrap();
+        path
+    }
+
+    fn host() -> Vec<u8> {
+        panic!("the host was asked during the replay")
+    }
+
+    #[test]
+    fn replay_returns_the_recorded_inputs() {
+        let path = recording("inputs");
+        let log = InputLog::replay(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(log.read(Input::Syscall, 10, host), b"ab");
+        assert!(log.read(Input::Syscall, 20, host).is_empty());
+        assert_eq!(log.read(Input::Syscall, 20, host), b"c");
+        assert_eq!(log.channel(Input::HostTime).read_u64(30, || 0), 1234);
+        assert!(log.finish().is_err());
+        log.check(Input::Interrupt, 40, 7);
+        assert_eq!(log.finish(), Ok(()));
+    }
+
+    #[test]
+    #[should_panic(expected = "Replay diverged: the syscall input recorded at instruction 10")]
+    fn skipped_input_diverges() {
+        let path = recording("skipped");
+        let log = InputLog::replay(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        log.read(Input::Syscall, 11, host);
+    }
+
+    #[test]
+    #[should_panic(expected = "Replay diverged: interrupt at instruction 40")]
+    fn other_interrupt_diverges() {
+        let path = recording("interrupt");
+        let log = InputLog::replay(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        log.check(Input::Interrupt, 40, 3);
+    }
+
+    #[test]
+    fn rerun_reads_the_kept_inputs_again() {
+        let log = InputLog::default();
+        log.keep_history();
+        let start = log.position();
+        assert_eq!(log.read(Input::Device(1), 5, || b"x".to_vec()), b"x");
+        let middle = log.position();
+        assert_eq!(log.read(Input::Device(1), 8, || b"y".to_vec()), b"y");
+
+        log.rewind(&start);
+        assert!(log.is_rerun());
+        assert_eq!(log.read(Input::Device(1), 5, host), b"x");
+        assert_eq!(log.read(Input::Device(1), 8, host), b"y");
+
+        // going another way from the middle forgets what came after it
+        log.rewind(&middle);
+        log.truncate();
+        log.set_rerun(false);
+        assert_eq!(log.read(Input::Device(1), 9, || b"z".to_vec()), b"z");
+        log.rewind(&start);
+        assert_eq!(log.read(Input::Device(1), 5, host), b"x");
+        assert_eq!(log.read(Input::Device(1), 9, host), b"z");
+    }
+
+    #[test]
+    fn invalid_recordings_are_rejected() {
+        assert_eq!(parse_events(b"RVSNAPSH").err().unwrap(), "not a recording");
+
+        let mut data = RECORDING_MAGIC.to_vec();
+        data.extend_from_slice(&(RECORDING_VERSION + 1).to_le_bytes());
+        assert!(parse_events(&data)
+            .err()
+            .unwrap()
+            .contains("isn't supported"));
+
+        let mut data = RECORDING_MAGIC.to_vec();
+        data.extend_from_slice(&RECORDING_VERSION.to_le_bytes());
+        data.push(0);
+        assert_eq!(parse_events(&data).err().unwrap(), "truncated recording");
+        data[RECORDING_MAGIC.len() + 4] = 9;
+        data.extend_from_slice(&0u32.to_le_bytes());
+        assert!(parse_events(&data)
+            .err()
+            .unwrap()
+            .contains("invalid input kind"));
+    }
+
+    // the second and third reads run from a translated block
+    #[test]
+    fn syscall_inputs_replay_with_and_without_blocks() {
+        let path = reads_recording("blocks", &[1, 2, 4]);
+        for blocks in [false, true] {
+            let log = InputLog::replay(&path).unwrap();
+            let (reason, _) = run(&sum_of_reads(3), |vm| {
+                vm.set_block_cache(blocks);
+                vm.set_input_log(log.clone());
+            });
+            assert_eq!(reason, StopReason::Exit(7));
+            assert_eq!(log.finish(), Ok(()));
+        }
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,249 @@
+// snapshot file format: a header with the magic and the version, then the state of every part of
+// the machine in a fixed order. Each part starts with its name so that a snapshot taken on a
+// differently configured machine is rejected instead of being misread. Values are little-endian
+
+const SNAPSHOT_MAGIC: &[u8; 8] = b"RVSNAPSH";
+// incremented whenever the layout changes, older snapshots can't be restored
+pub const SNAPSHOT_VERSION: u32 = 2;
+
+pub struct SnapshotWriter {
+    data: Vec<u8>,
+}
+
+impl Default for SnapshotWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SnapshotWriter {
+    pub fn new() -> Self {
+        let mut writer = Self {
+            data: SNAPSHOT_MAGIC.to_vec(),
+        };
+        writer.write_u32(SNAPSHOT_VERSION);
+        writer
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.data
+    }
+
+    // starts the state of a part of the machine
+    pub fn section(&mut self, name: &str) {
+        self.write_string(name);
+    }
+
+    pub fn write_u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.write_u8(value as u8);
+    }
+
+    pub fn write_u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_option_u64(&mut self, value: Option<u64>) {
+        self.write_bool(value.is_some());
+        self.write_u64(value.unwrap_or(0));
+    }
+
+    // preceded by their length
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.write_u64(bytes.len() as u64);
+        self.data.extend_from_slice(bytes);
+    }
+
+    pub fn write_u32s(&mut self, values: &[u32]) {
+        self.write_u64(values.len() as u64);
+        values.iter().for_each(|value| self.write_u32(*value));
+    }
+
+    pub fn write_string(&mut self, value: &str) {
+        self.write_bytes(value.as_bytes());
+    }
+}
+
+pub struct SnapshotReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> SnapshotReader<'a> {
+    pub fn new(data: &'a [u8]) -> Result<Self, String> {
+        if !data.starts_with(SNAPSHOT_MAGIC) {
+            return Err("not a snapshot".to_string());
+        }
+
+        let mut reader = Self {
+            data,
+            position: SNAPSHOT_MAGIC.len(),
+        };
+        let version = reader.read_u32()?;
+        if version != SNAPSHOT_VERSION {
+            return Err(format!(
+                "snapshot version {version} isn't supported (expected {SNAPSHOT_VERSION})"
+            ));
+        }
+
+        Ok(reader)
+    }
+
+    // everything was restored
+    pub fn finish(&self) -> Result<(), String> {
+        if self.position != self.data.len() {
+            return Err("unexpected data at the end of the snapshot".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn section(&mut self, name: &str) -> Result<(), String> {
+        let found = self.read_string()?;
+        if found != name {
+            return Err(format!("expected the {name} state, found {found}"));
+        }
+        Ok(())
+    }
+
+    // a value that depends on the configuration of the machine, which must be the same
+    pub fn check(&mut self, what: &str, expected: u64) -> Result<(), String> {
+        let found = self.read_u64()?;
+        if found != expected {
+            return Err(format!(
+                "the snapshot has {found} {what}, the machine has {expected}"
+            ));
+        }
+        Ok(())
+    }
+
+    fn take(&mut self, size: usize) -> Result<&'a [u8], String> {
+        let end = self
+            .position
+            .checked_add(size)
+            .filter(|end| *end <= self.data.len())
+            .ok_or_else(|| "truncated snapshot".to_string())?;
+        let bytes = &self.data[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, String> {
+        match self.read_u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            value => Err(format!("invalid boolean {value} in the snapshot")),
+        }
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub fn read_option_u64(&mut self) -> Result<Option<u64>, String> {
+        let present = self.read_bool()?;
+        let value = self.read_u64()?;
+        Ok(present.then_some(value))
+    }
+
+    pub fn read_bytes(&mut self) -> Result<&'a [u8], String> {
+        let size = self.read_u64()?;
+        let size = usize::try_from(size).map_err(|_| "truncated snapshot".to_string())?;
+        self.take(size)
+    }
+
+    // into a slice of the configured size
+    pub fn read_u32s_into(&mut self, what: &str, values: &mut [u32]) -> Result<(), String> {
+        self.check(what, values.len() as u64)?;
+        for value in values {
+            *value = self.read_u32()?;
+        }
+        Ok(())
+    }
+
+    pub fn read_string(&mut self) -> Result<String, String> {
+        let bytes = self.read_bytes()?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| "invalid string in the snapshot".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values_read_back_in_order() {
+        let mut writer = SnapshotWriter::new();
+        writer.section("part");
+        writer.write_u8(7);
+        writer.write_bool(true);
+        writer.write_u32(0xdead_beef);
+        writer.write_u64(u64::MAX - 1);
+        writer.write_option_u64(None);
+        writer.write_option_u64(Some(42));
+        writer.write_bytes(&[1, 2, 3]);
+        writer.write_u32s(&[4, 5]);
+        writer.write_string("name");
+        let data = writer.finish();
+
+        let mut reader = SnapshotReader::new(&data).unwrap();
+        reader.section("part").unwrap();
+        assert_eq!(reader.read_u8(), Ok(7));
+        assert_eq!(reader.read_bool(), Ok(true));
+        assert_eq!(reader.read_u32(), Ok(0xdead_beef));
+        assert_eq!(reader.read_u64(), Ok(u64::MAX - 1));
+        assert_eq!(reader.read_option_u64(), Ok(None));
+        assert_eq!(reader.read_option_u64(), Ok(Some(42)));
+        assert_eq!(reader.read_bytes(), Ok(&[1, 2, 3][..]));
+        let mut values = [0; 2];
+        reader.read_u32s_into("values", &mut values).unwrap();
+        assert_eq!(values, [4, 5]);
+        assert_eq!(reader.read_string().as_deref(), Ok("name"));
+        assert_eq!(reader.finish(), Ok(()));
+    }
+
+    #[test]
+    fn mismatching_snapshots_are_rejected() {
+        assert!(SnapshotReader::new(b"not a snapshot").is_err());
+
+        let mut data = SnapshotWriter::new().finish();
+        data[SNAPSHOT_MAGIC.len()] += 1;
+        assert!(SnapshotReader::new(&data)
+            .err()
+            .unwrap()
+            .contains("isn't supported"));
+
+        let mut writer = SnapshotWriter::new();
+        writer.section("uart");
+        writer.write_u32s(&[1, 2, 3]);
+        writer.write_u8(2);
+        let data = writer.finish();
+
+        let mut reader = SnapshotReader::new(&data).unwrap();
+        assert!(reader.section("plic").is_err());
+        let mut reader = SnapshotReader::new(&data).unwrap();
+        reader.section("uart").unwrap();
+        assert!(reader.read_u32s_into("values", &mut [0; 2]).is_err());
+
+        let mut reader = SnapshotReader::new(&data).unwrap();
+        reader.section("uart").unwrap();
+        reader.read_u32s_into("values", &mut [0; 3]).unwrap();
+        assert!(reader.finish().is_err());
+        assert!(reader.read_bool().is_err());
+        assert_eq!(reader.read_u8(), Err("truncated snapshot".to_string()));
+    }
+}
@@ -0,0 +1,131 @@
+use std::{fmt, time::Duration};
+
+// why start_execution returned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    // the guest called the exit syscall
+    Exit(i32),
+    // the maximum number of retired instructions was reached
+    InstructionLimit,
+    // the wall-clock timeout expired
+    Timeout,
+    // the pc reached one of the stop addresses
+    StopAddress(u32),
+    // an instruction jumped to itself (j .), the guest can't make progress anymore
+    SelfLoop(u32),
+    // a watchdog wasn't kicked in time
+    WatchdogExpired,
+    // an instruction wrote to a watched address, the execution stops after it
+    Watchpoint(u32),
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StopReason::Exit(exit_code) => write!(f, "exit({})", exit_code),
+            StopReason::InstructionLimit => write!(f, "instruction limit reached"),
+            StopReason::Timeout => write!(f, "timeout"),
+            StopReason::StopAddress(pc) => write!(f, "stop address {:x} reached", pc),
+            StopReason::SelfLoop(pc) => write!(f, "self loop at {:x}", pc),
+            StopReason::WatchdogExpired => write!(f, "watchdog expired"),
+            StopReason::Watchpoint(address) => write!(f, "write to watched address {:x}", address),
+        }
+    }
+}
+
+// exit status of a guest whose exit code can't be passed on: outside of 0-255, where the status
+// would be truncated (256 would pass as 0), or one of the codes 120-126 of the emulator
+pub const GUEST_FAILURE_EXIT_STATUS: i32 = 127;
+
+// exit status of the emulator for the exit code of the guest
+pub fn exit_status(exit_code: i32) -> i32 {
+    match exit_code {
+        0..=119 | 127..=255 => exit_code,
+        _ => GUEST_FAILURE_EXIT_STATUS,
+    }
+}
+
+#[derive(Default, Clone)]
+pub struct StopConditions {
+    max_instructions: Option<u64>,
+    timeout: Option<Duration>,
+    stop_addresses: Vec<u32>,
+    // start and length of the watched ranges of virtual addresses
+    watchpoints: Vec<(u32, u32)>,
+    detect_self_loop: bool,
+}
+
+impl StopConditions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_max_instructions(&mut self, max_instructions: u64) {
+        self.max_instructions = Some(max_instructions);
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = Some(timeout);
+    }
+
+    pub fn add_stop_address(&mut self, address: u32) {
+        self.stop_addresses.push(address);
+    }
+
+    // the instructions run one by one while something is watched
+    pub fn add_watchpoint(&mut self, address: u32, length: u32) {
+        self.watchpoints.push((address, length));
+    }
+
+    pub fn set_detect_self_loop(&mut self, detect_self_loop: bool) {
+        self.detect_self_loop = detect_self_loop;
+    }
+
+    pub fn get_max_instructions(&self) -> Option<u64> {
+        self.max_instructions
+    }
+
+    pub fn get_timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    pub fn is_stop_address(&self, address: u32) -> bool {
+        self.stop_addresses.contains(&address)
+    }
+
+    pub fn get_stop_addresses(&self) -> &[u32] {
+        &self.stop_addresses
+    }
+
+    pub fn has_watchpoints(&self) -> bool {
+        !self.watchpoints.is_empty()
+    }
+
+    // start of the watched range written by a store of nb_bytes at address
+    pub fn watched_address(&self, address: u32, nb_bytes: usize) -> Option<u32> {
+        let (address, end) = (address as u64, address as u64 + nb_bytes as u64);
+        self.watchpoints
+            .iter()
+            .find(|(start, length)| address < *start as u64 + *length as u64 && end > *start as u64)
+            .map(|(start, _)| *start)
+    }
+
+    pub fn get_detect_self_loop(&self) -> bool {
+        self.detect_self_loop
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn guest_exit_codes_pass_unless_they_are_not_a_status() {
+        for exit_code in [0, 1, 119, 127, 128, 255] {
+            assert_eq!(exit_status(exit_code), exit_code);
+        }
+        for exit_code in [120, 126, 256, 512, -1, i32::MIN, i32::MAX] {
+            assert_eq!(exit_status(exit_code), GUEST_FAILURE_EXIT_STATUS);
+        }
+    }
+}
@@ -2,6 +2,7 @@ pub enum Syscalls {
     ReadInput,
     Exit,
     Puts,
+    Eputs,
 }
 
 impl Syscalls {
@@ -11,6 +12,7 @@ impl Syscalls {
             0 => Syscalls::ReadInput,
             1 => Syscalls::Exit,
             2 => Syscalls::Puts,
+            3 => Syscalls::Eputs,
             _ => panic!("Syscall id {} not supported", syscall_id),
         }
     }
@@ -0,0 +1,126 @@
+use crate::{bus::Device, device_tree::DeviceTreeNode, stop_conditions::GUEST_FAILURE_EXIT_STATUS};
+
+// SiFive test device, test suites and bare-metal runtimes power the machine off through it
+
+pub const TEST_FINISHER_ADDRESS: usize = 0x100000;
+const TEST_FINISHER_SIZE: usize = 0x1000;
+
+// written to the low 16 bits, a failure has its code in the upper 16 bits
+const FINISHER_FAIL: u32 = 0x3333;
+const FINISHER_PASS: u32 = 0x5555;
+
+pub struct TestFinisher {
+    exit_code: Option<i32>,
+}
+
+impl Default for TestFinisher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TestFinisher {
+    pub fn new() -> Self {
+        Self { exit_code: None }
+    }
+}
+
+impl Device for TestFinisher {
+    fn size(&self) -> usize {
+        TEST_FINISHER_SIZE
+    }
+
+    fn read(&mut self, _offset: usize, _nb_bytes: usize, _instret: u64) -> u32 {
+        0
+    }
+
+    // other commands, like the qemu reset, are ignored
+    fn write(&mut self, offset: usize, nb_bytes: usize, value: u32, _instret: u64) {
+        if offset != 0 || nb_bytes != 4 {
+            return;
+        }
+
+        match value & 0xffff {
+            FINISHER_PASS => self.exit_code = Some(0),
+            // a failure never passes, even without a code
+            FINISHER_FAIL => {
+                self.exit_code = match value >> 16 {
+                    0 => Some(GUEST_FAILURE_EXIT_STATUS),
+                    code => Some(code as i32),
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn reset(&mut self) {
+        self.exit_code = None;
+    }
+
+    fn exit_code(&self) -> Option<i32> {
+        self.exit_code
+    }
+
+    // without the reset command of sifive,test1
+    fn device_tree_node(&self) -> Option<DeviceTreeNode> {
+        Some(DeviceTreeNode::new("test", &["sifive,test0"]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        stop_conditions::{exit_status, StopReason},
+        test_utils::*,
+    };
+
+    fn finish(value: u32) -> Option<i32> {
+        let mut finisher = TestFinisher::new();
+        finisher.write(0, 4, value, 0);
+        finisher.exit_code()
+    }
+
+    #[test]
+    fn failures_never_exit_with_0() {
+        assert_eq!(finish(FINISHER_PASS), Some(0));
+        assert_eq!(finish(FINISHER_FAIL | 3 << 16), Some(3));
+        assert_eq!(finish(FINISHER_FAIL), Some(GUEST_FAILURE_EXIT_STATUS));
+
+        let code = finish(FINISHER_FAIL | 0x100 << 16).unwrap();
+        assert_eq!(exit_status(code), GUEST_FAILURE_EXIT_STATUS);
+
+        // the reset command of sifive,test1 and narrow writes aren't exits
+        assert_eq!(finish(0x7777), None);
+        let mut finisher = TestFinisher::new();
+        finisher.write(0, 2, FINISHER_PASS, 0);
+        assert_eq!(finisher.exit_code(), None);
+    }
+
+    #[test]
+    fn tohost_failures_never_exit_with_0() {
+        const TOHOST: u32 = 0xfffff000;
+        const T0: u32 = 5;
+        const T1: u32 = 6;
+
+        for (command, exit_code) in [(1, 0), (3 << 1 | 1, 3), (0x100 << 1 | 1, 0x100)] {
+            let mut program = Vec::new();
+            program.extend(li(T0, TOHOST));
+            program.extend(li(T1, command));
+            // the command without bit 0 isn't an exit
+            program.extend([
+                addi(T1, T1, -1),
+                sw(T1, T0, 0),
+                addi(T1, T1, 1),
+                sw(T1, T0, 0),
+            ]);
+            // not reached
+            program.push(addi(A1, 0, 99));
+            program.extend(exit());
+
+            let (reason, _) = run(&program, |vm| vm.set_tohost(Some(TOHOST)));
+            assert_eq!(reason, StopReason::Exit(exit_code));
+            assert_eq!(exit_status(exit_code) == 0, command == 1);
+        }
+    }
+}
@@ -0,0 +1,213 @@
+// encoders of the instructions used by the unit tests, and flash images running them. Not every
+// build uses every encoder
+#![allow(dead_code)]
+
+use crate::{
+    replay::{Input, InputLog},
+    stop_conditions::StopReason,
+    vm::{FLASH_ADDRESS, VM},
+};
+
+pub const A0: u32 = 10;
+pub const A1: u32 = 11;
+
+// buffer of the syscall reads of sum_of_reads, in the stack ram of the flash machine
+pub const READ_BUFFER: u32 = 0xfffff000;
+
+fn r(funct7: u32, rs2: u32, rs1: u32, funct3: u32, rd: u32, opcode: u32) -> u32 {
+    funct7 << 25 | rs2 << 20 | rs1 << 15 | funct3 << 12 | rd << 7 | opcode
+}
+
+fn i(imm: i32, rs1: u32, funct3: u32, rd: u32, opcode: u32) -> u32 {
+    (imm as u32) << 20 | rs1 << 15 | funct3 << 12 | rd << 7 | opcode
+}
+
+fn s(imm: i32, rs2: u32, rs1: u32, funct3: u32) -> u32 {
+    let imm = imm as u32;
+    (imm >> 5 & 0x7f) << 25 | rs2 << 20 | rs1 << 15 | funct3 << 12 | (imm & 0x1f) << 7 | 0x23
+}
+
+fn b(offset: i32, rs2: u32, rs1: u32, funct3: u32) -> u32 {
+    let offset = offset as u32;
+    (offset >> 12 & 1) << 31
+        | (offset >> 5 & 0x3f) << 25
+        | rs2 << 20
+        | rs1 << 15
+        | funct3 << 12
+        | (offset >> 1 & 0xf) << 8
+        | (offset >> 11 & 1) << 7
+        | 0x63
+}
+
+pub fn add(rd: u32, rs1: u32, rs2: u32) -> u32 {
+    r(0, rs2, rs1, 0, rd, 0x33)
+}
+
+pub fn sub(rd: u32, rs1: u32, rs2: u32) -> u32 {
+    r(0x20, rs2, rs1, 0, rd, 0x33)
+}
+
+pub fn xor(rd: u32, rs1: u32, rs2: u32) -> u32 {
+    r(0, rs2, rs1, 4, rd, 0x33)
+}
+
+pub fn mul(rd: u32, rs1: u32, rs2: u32) -> u32 {
+    r(1, rs2, rs1, 0, rd, 0x33)
+}
+
+pub fn divu(rd: u32, rs1: u32, rs2: u32) -> u32 {
+    r(1, rs2, rs1, 5, rd, 0x33)
+}
+
+pub fn addi(rd: u32, rs1: u32, imm: i32) -> u32 {
+    i(imm, rs1, 0, rd, 0x13)
+}
+
+pub fn slli(rd: u32, rs1: u32, shift: u32) -> u32 {
+    i(shift as i32, rs1, 1, rd, 0x13)
+}
+
+pub fn srai(rd: u32, rs1: u32, shift: u32) -> u32 {
+    i(0x400 | shift as i32, rs1, 5, rd, 0x13)
+}
+
+pub fn lw(rd: u32, rs1: u32, offset: i32) -> u32 {
+    i(offset, rs1, 2, rd, 0x03)
+}
+
+pub fn lbu(rd: u32, rs1: u32, offset: i32) -> u32 {
+    i(offset, rs1, 4, rd, 0x03)
+}
+
+pub fn sw(rs2: u32, rs1: u32, offset: i32) -> u32 {
+    s(offset, rs2, rs1, 2)
+}
+
+pub fn sb(rs2: u32, rs1: u32, offset: i32) -> u32 {
+    s(offset, rs2, rs1, 0)
+}
+
+pub fn bne(rs1: u32, rs2: u32, offset: i32) -> u32 {
+    b(offset, rs2, rs1, 1)
+}
+
+pub fn blt(rs1: u32, rs2: u32, offset: i32) -> u32 {
+    b(offset, rs2, rs1, 4)
+}
+
+pub fn lui(rd: u32, imm: u32) -> u32 {
+    imm & 0xfffff000 | rd << 7 | 0x37
+}
+
+pub fn jal(rd: u32, offset: i32) -> u32 {
+    let offset = offset as u32;
+    (offset >> 20 & 1) << 31
+        | (offset >> 1 & 0x3ff) << 21
+        | (offset >> 11 & 1) << 20
+        | (offset >> 12 & 0xff) << 12
+        | rd << 7
+        | 0x6f
+}
+
+pub fn jalr(rd: u32, rs1: u32, offset: i32) -> u32 {
+    i(offset, rs1, 0, rd, 0x67)
+}
+
+pub fn lr_w(rd: u32, rs1: u32) -> u32 {
+    r(0b0001000, 0, rs1, 2, rd, 0x2f)
+}
+
+pub fn sc_w(rd: u32, rs2: u32, rs1: u32) -> u32 {
+    r(0b0001100, rs2, rs1, 2, rd, 0x2f)
+}
+
+pub const ECALL: u32 = 0x73;
+pub const WFI: u32 = 0x10500073;
+pub const MRET: u32 = 0x30200073;
+
+// csrrs rd, csr, zero
+pub fn csrr(rd: u32, csr: u32) -> u32 {
+    i(csr as i32, 0, 2, rd, 0x73)
+}
+
+// csrrw zero, csr, rs1
+pub fn csrw(csr: u32, rs1: u32) -> u32 {
+    i(csr as i32, rs1, 1, 0, 0x73)
+}
+
+// rd = value, with lui and addi
+pub fn li(rd: u32, value: u32) -> [u32; 2] {
+    let low = (value << 20) as i32 >> 20;
+    [lui(rd, value.wrapping_sub(low as u32)), addi(rd, rd, low)]
+}
+
+// exit syscall with the code in a1
+pub fn exit() -> [u32; 2] {
+    [addi(A0, 0, 1), ECALL]
+}
+
+// flash image starting the program right after the reset vector
+pub fn flash(program: &[u32]) -> Vec<u8> {
+    let mut data = (FLASH_ADDRESS as u32 + 4).to_le_bytes().to_vec();
+    for instruction in program {
+        data.extend_from_slice(&instruction.to_le_bytes());
+    }
+    data
+}
+
+// address of the instruction at the index of the program given to flash
+pub fn address_of(index: usize) -> u32 {
+    FLASH_ADDRESS as u32 + 4 + 4 * index as u32
+}
+
+// runs the program on the flash machine, configured by the closure before it starts
+pub fn run(program: &[u32], configure: impl FnOnce(&mut VM)) -> (StopReason, VM) {
+    let mut vm = VM::new(flash(program));
+    configure(&mut vm);
+    vm.init_execution();
+    let reason = vm.start_execution();
+    (reason, vm)
+}
+
+// reads a byte with the syscall count times, stores the running sum after the byte read and exits
+// with it. The loop starts at address_of(2) with the ecall at address_of(6), and runs from
+// translated blocks after the first time
+pub fn sum_of_reads(count: i32) -> Vec<u32> {
+    const T0: u32 = 5;
+    const T1: u32 = 6;
+    const T2: u32 = 7;
+    let mut program = vec![addi(T0, 0, count), addi(T1, 0, 0)];
+    program.extend(li(A1, READ_BUFFER));
+    program.extend([
+        addi(12, 0, 1),
+        addi(A0, 0, 0),
+        ECALL,
+        lbu(T2, A1, 0),
+        add(T1, T1, T2),
+        sw(T1, A1, 4),
+        addi(T0, T0, -1),
+        bne(T0, 0, -36),
+        addi(A1, T1, 0),
+    ]);
+    program.extend(exit());
+    program
+}
+
+// instruction count of the nth ecall of sum_of_reads, as the interpreter retires it
+pub fn read_instret(n: usize) -> u64 {
+    6 + 10 * n as u64
+}
+
+// recording of the bytes read by sum_of_reads, in a temporary file removed by the caller
+pub fn reads_recording(name: &str, data: &[u8]) -> String {
+    let path = std::env::temp_dir()
+        .join(format!("riscv-reads-{}-{name}", std::process::id()))
+        .to_string_lossy()
+        .into_owned();
+    let log = InputLog::record(&path).unwrap();
+    for (n, byte) in data.iter().enumerate() {
+        log.read(Input::Syscall, read_instret(n), || vec![*byte]);
+    }
+    log.finish().unwrap();
+    path
+}
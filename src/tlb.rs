@@ -0,0 +1,312 @@
+use crate::snapshot::{SnapshotReader, SnapshotWriter};
+
+// software TLB in front of the sv32 page table walker, set-associative with round-robin
+// replacement in each set
+
+pub const DEFAULT_TLB_ENTRIES: usize = 64;
+pub const DEFAULT_TLB_WAYS: usize = 4;
+
+// translation of a 4 KiB page, megapages are cached one page at a time
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TlbEntry {
+    pub vpn: u32,
+    pub asid: u32,
+    // matches every asid
+    pub global: bool,
+    pub megapage: bool,
+    // stores through a clean entry miss, so the walk sets the dirty bit
+    pub dirty: bool,
+    // leaf page table entry, its permissions are checked on every access
+    pub pte: u32,
+    // physical address of the page
+    pub page: u32,
+}
+
+impl TlbEntry {
+    fn matches_address(&self, vpn: u32) -> bool {
+        if self.megapage {
+            self.vpn >> 10 == vpn >> 10
+        } else {
+            self.vpn == vpn
+        }
+    }
+}
+
+pub struct Tlb {
+    entries: Vec<Option<TlbEntry>>,
+    ways: usize,
+    // next way replaced in each set
+    victims: Vec<usize>,
+    hits: u64,
+    misses: u64,
+    evictions: u64,
+    flushes: u64,
+}
+
+impl Default for Tlb {
+    fn default() -> Self {
+        Self::new(DEFAULT_TLB_ENTRIES, DEFAULT_TLB_WAYS).unwrap()
+    }
+}
+
+impl Tlb {
+    // no entries disables the TLB, every access walks the page tables
+    pub fn new(entries: usize, ways: usize) -> Result<Self, String> {
+        if entries > 0 && (ways == 0 || !entries.is_multiple_of(ways)) {
+            return Err(format!(
+                "the {entries} TLB entries can't be split in sets of {ways} ways"
+            ));
+        }
+
+        Ok(Self {
+            entries: vec![None; entries],
+            ways,
+            victims: vec![0; entries.checked_div(ways).unwrap_or(0)],
+            hits: 0,
+            misses: 0,
+            evictions: 0,
+            flushes: 0,
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.entries.is_empty()
+    }
+
+    fn set(&self, vpn: u32) -> usize {
+        vpn as usize % self.victims.len()
+    }
+
+    fn ways_of(&mut self, set: usize) -> &mut [Option<TlbEntry>] {
+        &mut self.entries[set * self.ways..(set + 1) * self.ways]
+    }
+
+    pub fn lookup(&mut self, vpn: u32, asid: u32, store: bool) -> Option<TlbEntry> {
+        if !self.is_enabled() {
+            return None;
+        }
+
+        let set = self.set(vpn);
+        let found = self.ways_of(set).iter().flatten().copied().find(|entry| {
+            entry.vpn == vpn && (entry.global || entry.asid == asid) && (entry.dirty || !store)
+        });
+
+        if found.is_some() {
+            self.hits += 1;
+        } else {
+            self.misses += 1;
+        }
+        found
+    }
+
+    // replaces the entry of the same page, otherwise a free way or the next victim of the set
+    pub fn insert(&mut self, entry: TlbEntry) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        let set = self.set(entry.vpn);
+        let ways = self.ways_of(set);
+        let same_page = ways.iter().position(|way| {
+            way.is_some_and(|way| way.vpn == entry.vpn && (way.global || way.asid == entry.asid))
+        });
+        let way = match same_page.or_else(|| ways.iter().position(|way| way.is_none())) {
+            Some(way) => way,
+            None => {
+                let victim = self.victims[set];
+                self.victims[set] = (victim + 1) % self.ways;
+                self.evictions += 1;
+                victim
+            }
+        };
+
+        self.ways_of(set)[way] = Some(entry);
+    }
+
+    // sfence.vma: without an address every page is flushed, without an asid every address space.
+    // Global mappings are kept when an asid is given
+    pub fn flush(&mut self, address: Option<u32>, asid: Option<u32>) {
+        self.flushes += 1;
+
+        for way in &mut self.entries {
+            let flushed = way.is_some_and(|entry| {
+                address.is_none_or(|address| entry.matches_address(address >> 12))
+                    && asid.is_none_or(|asid| !entry.global && entry.asid == asid)
+            });
+            if flushed {
+                *way = None;
+            }
+        }
+    }
+
+    pub fn flush_all(&mut self) {
+        self.flush(None, None);
+    }
+
+    // the cached translations are saved as well, a guest may rely on stale ones until sfence.vma
+    pub fn save_state(&self, snapshot: &mut SnapshotWriter) {
+        snapshot.write_u64(self.entries.len() as u64);
+        snapshot.write_u64(self.ways as u64);
+        for way in &self.entries {
+            snapshot.write_bool(way.is_some());
+            let entry = way.unwrap_or_default();
+            snapshot.write_u32(entry.vpn);
+            snapshot.write_u32(entry.asid);
+            snapshot.write_bool(entry.global);
+            snapshot.write_bool(entry.megapage);
+            snapshot.write_bool(entry.dirty);
+            snapshot.write_u32(entry.pte);
+            snapshot.write_u32(entry.page);
+        }
+        for victim in &self.victims {
+            snapshot.write_u64(*victim as u64);
+        }
+        for counter in [self.hits, self.misses, self.evictions, self.flushes] {
+            snapshot.write_u64(counter);
+        }
+    }
+
+    pub fn restore_state(&mut self, snapshot: &mut SnapshotReader) -> Result<(), String> {
+        snapshot.check("TLB entries", self.entries.len() as u64)?;
+        snapshot.check("TLB ways", self.ways as u64)?;
+        for way in &mut self.entries {
+            let valid = snapshot.read_bool()?;
+            let entry = TlbEntry {
+                vpn: snapshot.read_u32()?,
+                asid: snapshot.read_u32()?,
+                global: snapshot.read_bool()?,
+                megapage: snapshot.read_bool()?,
+                dirty: snapshot.read_bool()?,
+                pte: snapshot.read_u32()?,
+                page: snapshot.read_u32()?,
+            };
+            *way = valid.then_some(entry);
+        }
+        for victim in &mut self.victims {
+            *victim = snapshot.read_u64()? as usize % self.ways;
+        }
+        for counter in [
+            &mut self.hits,
+            &mut self.misses,
+            &mut self.evictions,
+            &mut self.flushes,
+        ] {
+            *counter = snapshot.read_u64()?;
+        }
+        Ok(())
+    }
+
+    pub fn get_entries(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn get_ways(&self) -> usize {
+        self.ways
+    }
+
+    pub fn get_hits(&self) -> u64 {
+        self.hits
+    }
+
+    pub fn get_misses(&self) -> u64 {
+        self.misses
+    }
+
+    // entries replaced to make room for a new translation
+    pub fn get_evictions(&self) -> u64 {
+        self.evictions
+    }
+
+    pub fn get_flushes(&self) -> u64 {
+        self.flushes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(vpn: u32, asid: u32, global: bool) -> TlbEntry {
+        TlbEntry {
+            vpn,
+            asid,
+            global,
+            megapage: false,
+            dirty: true,
+            pte: 0,
+            page: vpn << 12,
+        }
+    }
+
+    #[test]
+    fn full_set_evicts_round_robin() {
+        // 2 sets of 2 ways, even pages share the first set
+        let mut tlb = Tlb::new(4, 2).unwrap();
+        tlb.insert(entry(0, 0, false));
+        tlb.insert(entry(2, 0, false));
+        tlb.insert(entry(1, 0, false));
+        tlb.insert(entry(4, 0, false));
+
+        assert_eq!(tlb.get_evictions(), 1);
+        assert!(tlb.lookup(0, 0, false).is_none());
+        assert!(tlb.lookup(1, 0, false).is_some());
+        assert!(tlb.lookup(2, 0, false).is_some());
+        assert!(tlb.lookup(4, 0, false).is_some());
+        assert_eq!((tlb.get_hits(), tlb.get_misses()), (3, 1));
+
+        tlb.insert(entry(6, 0, false));
+        assert!(tlb.lookup(2, 0, false).is_none());
+        assert!(tlb.lookup(4, 0, false).is_some());
+    }
+
+    #[test]
+    fn entries_match_their_asid_unless_global() {
+        let mut tlb = Tlb::new(8, 2).unwrap();
+        tlb.insert(entry(1, 5, false));
+        tlb.insert(entry(2, 5, true));
+
+        assert!(tlb.lookup(1, 6, false).is_none());
+        assert!(tlb.lookup(2, 6, false).is_some());
+
+        // stores miss clean entries, so the walk sets the dirty bit
+        tlb.insert(TlbEntry {
+            dirty: false,
+            ..entry(3, 5, false)
+        });
+        assert!(tlb.lookup(3, 5, false).is_some());
+        assert!(tlb.lookup(3, 5, true).is_none());
+    }
+
+    #[test]
+    fn flush_by_address_and_asid() {
+        let mut tlb = Tlb::new(8, 2).unwrap();
+        tlb.insert(entry(1, 5, false));
+        tlb.insert(entry(2, 5, false));
+        tlb.insert(entry(3, 5, true));
+        tlb.insert(entry(4, 6, false));
+
+        tlb.flush(Some(0x1abc), None);
+        assert!(tlb.lookup(1, 5, false).is_none());
+        assert!(tlb.lookup(2, 5, false).is_some());
+
+        // global mappings survive a flush of their asid
+        tlb.flush(None, Some(5));
+        assert!(tlb.lookup(2, 5, false).is_none());
+        assert!(tlb.lookup(3, 5, false).is_some());
+        assert!(tlb.lookup(4, 6, false).is_some());
+
+        tlb.flush_all();
+        assert!(tlb.lookup(3, 5, false).is_none());
+        assert!(tlb.lookup(4, 6, false).is_none());
+        assert_eq!(tlb.get_flushes(), 3);
+    }
+
+    #[test]
+    fn no_entries_disable_the_tlb() {
+        let mut tlb = Tlb::new(0, 0).unwrap();
+        tlb.insert(entry(1, 0, false));
+        assert!(!tlb.is_enabled());
+        assert!(tlb.lookup(1, 0, false).is_none());
+        assert!(Tlb::new(6, 4).is_err());
+    }
+}
@@ -0,0 +1,577 @@
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{self, Read, Write},
+    net::TcpListener,
+    sync::mpsc::{self, Receiver},
+    thread,
+    time::Duration,
+};
+
+use crate::{
+    bus::Device,
+    device_tree::{DeviceTreeNode, PropertyValue},
+    replay::InputChannel,
+    snapshot::{SnapshotReader, SnapshotWriter},
+};
+
+// NS16550A compatible UART, the registers are 8 bits wide and 1 byte apart. Wider accesses see the
+// register at their address, zero-extended
+
+pub const UART_ADDRESS: usize = 0x10000000;
+const UART_SIZE: usize = 0x100;
+
+// PLIC source of the UART on the qemu virt machine
+pub const UART_IRQ: u32 = 10;
+
+const FIFO_SIZE: usize = 16;
+
+// input clock given to the drivers, the baud rate has no effect
+const UART_CLOCK: u32 = 3686400;
+
+// register offsets, the divisor latch replaces RBR/THR and IER while LCR.DLAB is set
+const RBR_THR: usize = 0;
+const IER: usize = 1;
+const IIR_FCR: usize = 2;
+const LCR: usize = 3;
+const MCR: usize = 4;
+const LSR: usize = 5;
+const MSR: usize = 6;
+const SCR: usize = 7;
+
+const IER_RX: u8 = 1;
+const IER_THRE: u8 = 2;
+const IER_MASK: u8 = 0x0f;
+
+// interrupt identification, by decreasing priority
+const IIR_NONE: u8 = 1;
+const IIR_RX: u8 = 4;
+const IIR_RX_TIMEOUT: u8 = 0xc;
+const IIR_THRE: u8 = 2;
+const IIR_FIFO_ENABLED: u8 = 0xc0;
+
+const FCR_FIFO_ENABLE: u8 = 1;
+const FCR_CLEAR_RX: u8 = 2;
+
+const LCR_DLAB: u8 = 0x80;
+
+const MCR_LOOPBACK: u8 = 0x10;
+const MCR_MASK: u8 = 0x1f;
+
+const LSR_DATA_READY: u8 = 1;
+// the transmitter sends instantly, it is always empty
+const LSR_TX_EMPTY: u8 = 0x60;
+
+// CTS, DSR and DCD, the other end is always connected
+const MSR_CONNECTED: u8 = 0xb0;
+
+// host side of the serial line
+pub enum UartBackend {
+    Stdio,
+    // transmitted bytes are written to the file, nothing is received
+    File(String),
+    // waits for a connection on 127.0.0.1 before starting
+    Tcp(u16),
+    // a pseudo-terminal, its path is printed on stderr
+    Pty,
+}
+
+pub struct Uart {
+    output: Box<dyn Write>,
+    // bytes received by the host, read by a thread so that the guest never blocks
+    input: Option<Receiver<u8>>,
+    // stdin is shared with the ReadInput syscall, it is only read once the guest looks at the
+    // receiver
+    stdin_unread: bool,
+    // the received bytes can be recorded and replayed
+    host_input: InputChannel,
+    rx: VecDeque<u8>,
+    // the last poll of the host brought nothing, data below the trigger level times out
+    rx_idle: bool,
+    thre_interrupt: bool,
+    ier: u8,
+    lcr: u8,
+    mcr: u8,
+    scr: u8,
+    dll: u8,
+    dlm: u8,
+    fifo_enabled: bool,
+    rx_trigger: usize,
+}
+
+pub(crate) fn spawn_reader(
+    mut source: impl Read + Send + 'static,
+    retry_errors: bool,
+) -> Receiver<u8> {
+    let (sender, receiver) = mpsc::channel();
+
+    thread::spawn(move || {
+        let mut buffer = [0; 256];
+        loop {
+            match source.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(n) => {
+                    if buffer[..n].iter().any(|byte| sender.send(*byte).is_err()) {
+                        break;
+                    }
+                }
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                // a pty fails while no terminal is attached to it
+                Err(_) if retry_errors => thread::sleep(Duration::from_millis(10)),
+                Err(_) => break,
+            }
+        }
+    });
+
+    receiver
+}
+
+#[cfg(target_os = "linux")]
+fn open_pty() -> io::Result<(File, String)> {
+    use std::{
+        ffi::{c_char, c_void, CStr},
+        os::fd::FromRawFd,
+    };
+
+    const O_RDWR: i32 = 2;
+    const O_NOCTTY: i32 = 0x100;
+    const O_NONBLOCK: i32 = 0x800;
+    const F_GETFL: i32 = 3;
+    const F_SETFL: i32 = 4;
+    const TCSANOW: i32 = 0;
+
+    extern "C" {
+        fn posix_openpt(flags: i32) -> i32;
+        fn grantpt(fd: i32) -> i32;
+        fn unlockpt(fd: i32) -> i32;
+        fn ptsname(fd: i32) -> *const c_char;
+        fn fcntl(fd: i32, cmd: i32, ...) -> i32;
+        fn tcgetattr(fd: i32, termios: *mut c_void) -> i32;
+        fn tcsetattr(fd: i32, actions: i32, termios: *const c_void) -> i32;
+        fn cfmakeraw(termios: *mut c_void);
+    }
+
+    unsafe {
+        let fd = posix_openpt(O_RDWR | O_NOCTTY);
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let master = File::from_raw_fd(fd);
+
+        // writes are dropped instead of blocking the guest while nobody reads the terminal
+        let flags = fcntl(fd, F_GETFL);
+        if grantpt(fd) != 0 || unlockpt(fd) != 0 || fcntl(fd, F_SETFL, flags | O_NONBLOCK) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // without echo and line editing, otherwise the guest output comes back as input
+        // struct termios is 60 bytes on linux, it is only handled through the libc functions
+        let mut termios = [0u32; 16];
+        if tcgetattr(fd, termios.as_mut_ptr() as *mut c_void) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        cfmakeraw(termios.as_mut_ptr() as *mut c_void);
+        if tcsetattr(fd, TCSANOW, termios.as_ptr() as *const c_void) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let name = ptsname(fd);
+        if name.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok((master, CStr::from_ptr(name).to_string_lossy().into_owned()))
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn open_pty() -> io::Result<(File, String)> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "pseudo-terminals are only supported on linux",
+    ))
+}
+
+impl Uart {
+    pub fn new(backend: &UartBackend) -> io::Result<Self> {
+        let mut stdin_unread = false;
+
+        let (output, input): (Box<dyn Write>, _) = match backend {
+            UartBackend::Stdio => {
+                stdin_unread = true;
+                (Box::new(io::stdout()), None)
+            }
+            UartBackend::File(path) => (Box::new(File::create(path)?), None),
+            UartBackend::Tcp(port) => {
+                let listener = TcpListener::bind(("127.0.0.1", *port))?;
+                eprintln!("riscv: uart waiting for a connection on 127.0.0.1:{port}");
+                let (stream, _) = listener.accept()?;
+                stream.set_nodelay(true)?;
+                let input = spawn_reader(stream.try_clone()?, false);
+                (Box::new(stream), Some(input))
+            }
+            UartBackend::Pty => {
+                let (master, name) = open_pty()?;
+                eprintln!("riscv: uart connected to {name}");
+                let input = spawn_reader(master.try_clone()?, true);
+                (Box::new(master), Some(input))
+            }
+        };
+
+        Ok(Self {
+            output,
+            input,
+            stdin_unread,
+            host_input: InputChannel::default(),
+            rx: VecDeque::new(),
+            rx_idle: false,
+            thre_interrupt: false,
+            ier: 0,
+            lcr: 0,
+            mcr: 0,
+            scr: 0,
+            dll: 0,
+            dlm: 0,
+            fifo_enabled: false,
+            rx_trigger: 1,
+        })
+    }
+
+    fn rx_capacity(&self) -> usize {
+        if self.fifo_enabled {
+            FIFO_SIZE
+        } else {
+            1
+        }
+    }
+
+    // moves the bytes received by the host to the receiver, as long as there is room
+    fn poll(&mut self, instret: u64) {
+        if self.ier & IER_RX != 0 {
+            self.start_stdin();
+        }
+
+        let Some(input) = &self.input else {
+            return;
+        };
+
+        let room = self.rx_capacity() - self.rx.len();
+        let received = self
+            .host_input
+            .read(instret, || input.try_iter().take(room).collect());
+
+        self.rx_idle = received.is_empty();
+        self.rx.extend(received);
+    }
+
+    fn start_stdin(&mut self) {
+        if self.stdin_unread {
+            self.stdin_unread = false;
+            self.input = Some(spawn_reader(io::stdin(), false));
+        }
+    }
+
+    fn interrupt_id(&self) -> u8 {
+        if self.ier & IER_RX != 0 && !self.rx.is_empty() {
+            if self.rx.len() >= self.rx_trigger {
+                return IIR_RX;
+            }
+            if self.rx_idle {
+                return IIR_RX_TIMEOUT;
+            }
+        }
+
+        if self.ier & IER_THRE != 0 && self.thre_interrupt {
+            return IIR_THRE;
+        }
+
+        IIR_NONE
+    }
+
+    fn transmit(&mut self, byte: u8) {
+        if self.mcr & MCR_LOOPBACK != 0 {
+            if self.rx.len() < self.rx_capacity() {
+                self.rx.push_back(byte);
+                self.rx_idle = false;
+            }
+        } else if !self.host_input.is_rerun() {
+            // like a real serial line, bytes are lost when the other end is gone
+            let _ = self.output.write_all(&[byte]);
+            let _ = self.output.flush();
+        }
+
+        // the byte is already sent, the holding register is empty again
+        self.thre_interrupt = true;
+    }
+
+    fn read_register(&mut self, offset: usize, instret: u64) -> u8 {
+        let dlab = self.lcr & LCR_DLAB != 0;
+
+        match offset {
+            RBR_THR if dlab => self.dll,
+            RBR_THR => {
+                self.start_stdin();
+                self.poll(instret);
+                self.rx_idle = false;
+                self.rx.pop_front().unwrap_or(0)
+            }
+            IER if dlab => self.dlm,
+            IER => self.ier,
+            IIR_FCR => {
+                let id = self.interrupt_id();
+                // reading the identification acknowledges the transmitter interrupt
+                if id == IIR_THRE {
+                    self.thre_interrupt = false;
+                }
+                if self.fifo_enabled {
+                    id | IIR_FIFO_ENABLED
+                } else {
+                    id
+                }
+            }
+            LCR => self.lcr,
+            MCR => self.mcr,
+            LSR => {
+                self.start_stdin();
+                self.poll(instret);
+                let data_ready = if self.rx.is_empty() {
+                    0
+                } else {
+                    LSR_DATA_READY
+                };
+                data_ready | LSR_TX_EMPTY
+            }
+            MSR if self.mcr & MCR_LOOPBACK != 0 => {
+                // RTS, DTR, OUT1 and OUT2 are looped back to CTS, DSR, RI and DCD
+                let mcr = self.mcr;
+                (mcr & 2) << 3 | (mcr & 1) << 5 | (mcr & 4) << 4 | (mcr & 8) << 4
+            }
+            MSR => MSR_CONNECTED,
+            SCR => self.scr,
+            _ => 0,
+        }
+    }
+
+    fn write_register(&mut self, offset: usize, value: u8) {
+        let dlab = self.lcr & LCR_DLAB != 0;
+
+        match offset {
+            RBR_THR if dlab => self.dll = value,
+            RBR_THR => self.transmit(value),
+            IER if dlab => self.dlm = value,
+            IER => {
+                // enabling the transmitter interrupt reports the empty holding register
+                if value & IER_THRE != 0 && self.ier & IER_THRE == 0 {
+                    self.thre_interrupt = true;
+                }
+                self.ier = value & IER_MASK;
+            }
+            IIR_FCR => {
+                let fifo_enabled = value & FCR_FIFO_ENABLE != 0;
+                if fifo_enabled != self.fifo_enabled || value & FCR_CLEAR_RX != 0 {
+                    self.rx.clear();
+                }
+                self.fifo_enabled = fifo_enabled;
+                self.rx_trigger = if fifo_enabled {
+                    [1, 4, 8, 14][value as usize >> 6]
+                } else {
+                    1
+                };
+            }
+            LCR => self.lcr = value,
+            MCR => self.mcr = value & MCR_MASK,
+            SCR => self.scr = value,
+            // LSR and MSR are read-only
+            _ => {}
+        }
+    }
+}
+
+impl Device for Uart {
+    fn size(&self) -> usize {
+        UART_SIZE
+    }
+
+    fn read(&mut self, offset: usize, _nb_bytes: usize, instret: u64) -> u32 {
+        self.read_register(offset, instret) as u32
+    }
+
+    fn write(&mut self, offset: usize, _nb_bytes: usize, value: u32, _instret: u64) {
+        self.write_register(offset, value as u8)
+    }
+
+    // the backend stays connected, the bytes still waiting in the host are kept
+    fn reset(&mut self) {
+        self.rx.clear();
+        self.rx_idle = false;
+        self.thre_interrupt = false;
+        self.ier = 0;
+        self.lcr = 0;
+        self.mcr = 0;
+        self.scr = 0;
+        self.dll = 0;
+        self.dlm = 0;
+        self.fifo_enabled = false;
+        self.rx_trigger = 1;
+    }
+
+    fn tick(&mut self, instret: u64) {
+        self.poll(instret);
+    }
+
+    fn interrupt_pending(&self) -> bool {
+        self.interrupt_id() != IIR_NONE
+    }
+
+    fn device_tree_node(&self) -> Option<DeviceTreeNode> {
+        let node = DeviceTreeNode::new("serial", &["ns16550a"])
+            .with_property("clock-frequency", PropertyValue::U32(UART_CLOCK));
+        Some(node)
+    }
+
+    // the bytes still in the host aren't part of the snapshot
+    fn save_state(&self, snapshot: &mut SnapshotWriter) {
+        snapshot.write_bytes(&self.rx.iter().copied().collect::<Vec<u8>>());
+        snapshot.write_bool(self.rx_idle);
+        snapshot.write_bool(self.thre_interrupt);
+        for register in [self.ier, self.lcr, self.mcr, self.scr, self.dll, self.dlm] {
+            snapshot.write_u8(register);
+        }
+        snapshot.write_bool(self.fifo_enabled);
+        snapshot.write_u64(self.rx_trigger as u64);
+    }
+
+    fn restore_state(&mut self, snapshot: &mut SnapshotReader) -> Result<(), String> {
+        self.rx = snapshot.read_bytes()?.iter().copied().collect();
+        self.rx_idle = snapshot.read_bool()?;
+        self.thre_interrupt = snapshot.read_bool()?;
+        for register in [
+            &mut self.ier,
+            &mut self.lcr,
+            &mut self.mcr,
+            &mut self.scr,
+            &mut self.dll,
+            &mut self.dlm,
+        ] {
+            *register = snapshot.read_u8()?;
+        }
+        self.fifo_enabled = snapshot.read_bool()?;
+        self.rx_trigger = snapshot.read_u64()? as usize;
+        Ok(())
+    }
+
+    fn set_input(&mut self, input: InputChannel) {
+        self.host_input = input;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::Sender;
+
+    // uart transmitting to a temporary file, with an empty host input
+    fn uart(name: &str) -> (Uart, Sender<u8>) {
+        let path = std::env::temp_dir()
+            .join(format!("riscv-uart-{}-{name}", std::process::id()))
+            .to_string_lossy()
+            .into_owned();
+        let mut uart = Uart::new(&UartBackend::File(path.clone())).unwrap();
+        let _ = std::fs::remove_file(path);
+
+        let (sender, receiver) = mpsc::channel();
+        uart.input = Some(receiver);
+        (uart, sender)
+    }
+
+    #[test]
+    fn divisor_latch_replaces_the_data_and_interrupt_enable_registers() {
+        let (mut uart, _host) = uart("dlab");
+        uart.write_register(IER, IER_RX);
+
+        uart.write_register(LCR, LCR_DLAB | 3);
+        uart.write_register(RBR_THR, 0x12);
+        uart.write_register(IER, 0x34);
+        assert_eq!(uart.read_register(RBR_THR, 0), 0x12);
+        assert_eq!(uart.read_register(IER, 0), 0x34);
+
+        uart.write_register(LCR, 3);
+        assert_eq!(uart.read_register(IER, 0), IER_RX);
+        uart.write_register(IER, IER_RX | IER_THRE);
+
+        uart.write_register(LCR, LCR_DLAB | 3);
+        assert_eq!(uart.read_register(RBR_THR, 0), 0x12);
+        assert_eq!(uart.read_register(IER, 0), 0x34);
+    }
+
+    #[test]
+    fn receiver_interrupts_at_the_trigger_level_or_when_idle() {
+        let (mut uart, host) = uart("trigger");
+        uart.write_register(IER, IER_RX);
+        // fifo with a trigger level of 8 bytes
+        uart.write_register(IIR_FCR, 0x80 | FCR_FIFO_ENABLE);
+
+        for byte in 0..7 {
+            host.send(byte).unwrap();
+        }
+        uart.poll(0);
+        assert_eq!(uart.interrupt_id(), IIR_NONE);
+
+        host.send(7).unwrap();
+        uart.poll(0);
+        assert_eq!(uart.read_register(IIR_FCR, 0), IIR_RX | IIR_FIFO_ENABLED);
+
+        // below the trigger level, the interrupt comes once the host has nothing more
+        assert_eq!(uart.read_register(RBR_THR, 0), 0);
+        assert_eq!(uart.interrupt_id(), IIR_NONE);
+        uart.tick(0);
+        assert_eq!(uart.interrupt_id(), IIR_RX_TIMEOUT);
+
+        // without the fifo every byte interrupts
+        uart.write_register(IIR_FCR, 0);
+        host.send(8).unwrap();
+        uart.poll(0);
+        assert_eq!(uart.read_register(IIR_FCR, 0), IIR_RX);
+    }
+
+    #[test]
+    fn reading_the_identification_acknowledges_the_transmitter_interrupt() {
+        let (mut uart, _host) = uart("thre");
+        uart.write_register(MCR, MCR_LOOPBACK);
+        assert_eq!(uart.read_register(IIR_FCR, 0), IIR_NONE);
+
+        uart.write_register(IER, IER_THRE);
+        assert_eq!(uart.read_register(IIR_FCR, 0), IIR_THRE);
+        assert_eq!(uart.read_register(IIR_FCR, 0), IIR_NONE);
+
+        uart.write_register(RBR_THR, b'a');
+        assert!(uart.interrupt_pending());
+        assert_eq!(uart.read_register(IIR_FCR, 0), IIR_THRE);
+        assert!(!uart.interrupt_pending());
+        // the looped back byte
+        assert_eq!(uart.read_register(RBR_THR, 0), b'a');
+
+        // the receiver has the priority and reading it doesn't acknowledge the transmitter
+        uart.write_register(IER, IER_RX | IER_THRE);
+        uart.write_register(RBR_THR, b'b');
+        assert_eq!(uart.read_register(IIR_FCR, 0), IIR_RX);
+        assert_eq!(uart.read_register(RBR_THR, 0), b'b');
+        assert_eq!(uart.read_register(IIR_FCR, 0), IIR_THRE);
+    }
+
+    #[test]
+    fn loopback_connects_the_modem_control_to_the_modem_status() {
+        let (mut uart, _host) = uart("msr");
+        assert_eq!(uart.read_register(MSR, 0), MSR_CONNECTED);
+
+        // DTR and RTS to DSR and CTS
+        uart.write_register(MCR, MCR_LOOPBACK | 3);
+        assert_eq!(uart.read_register(MSR, 0), 0x30);
+        // OUT1 and OUT2 to RI and DCD
+        uart.write_register(MCR, MCR_LOOPBACK | 0xc);
+        assert_eq!(uart.read_register(MSR, 0), 0xc0);
+
+        uart.write_register(MCR, 3);
+        assert_eq!(uart.read_register(MSR, 0), MSR_CONNECTED);
+    }
+}
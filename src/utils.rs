@@ -20,4 +20,42 @@ pub fn sign_extend_number(number: u32, nb_bits: u32) -> u32 {
         }
     }
     number
-}
\ No newline at end of file
+}
+// M extension arithmetic, division by zero and overflow don't trap on RISC-V
+
+pub fn mulh(a: u32, b: u32) -> u32 {
+    ((a as i32 as i64 * b as i32 as i64) >> 32) as u32
+}
+
+pub fn mulhsu(a: u32, b: u32) -> u32 {
+    ((a as i32 as i64 * b as i64) >> 32) as u32
+}
+
+pub fn mulhu(a: u32, b: u32) -> u32 {
+    ((a as u64 * b as u64) >> 32) as u32
+}
+
+pub fn div(a: u32, b: u32) -> u32 {
+    if b == 0 {
+        u32::MAX
+    } else {
+        // i32::MIN / -1 wraps to i32::MIN
+        (a as i32).wrapping_div(b as i32) as u32
+    }
+}
+
+pub fn divu(a: u32, b: u32) -> u32 {
+    a.checked_div(b).unwrap_or(u32::MAX)
+}
+
+pub fn rem(a: u32, b: u32) -> u32 {
+    if b == 0 {
+        a
+    } else {
+        (a as i32).wrapping_rem(b as i32) as u32
+    }
+}
+
+pub fn remu(a: u32, b: u32) -> u32 {
+    a.checked_rem(b).unwrap_or(a)
+}
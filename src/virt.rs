@@ -0,0 +1,324 @@
+use crate::{
+    device_tree::{self, BootInfo},
+    vm::VM,
+};
+
+// qemu virt-like machine: RAM at 0x80000000, the CLINT, the PLIC, the UART, the test finisher and
+// the virtio slots at their usual addresses. A firmware, a kernel and an initramfs are placed in
+// RAM where qemu puts them, with a device tree generated from the configured machine
+
+pub const VIRT_RAM_ADDRESS: usize = 0x80000000;
+pub const DEFAULT_VIRT_RAM_SIZE: usize = 128 << 20;
+// RAM above 3 GiB isn't in the linear mapping of rv32 kernels
+const LOWMEM_END: usize = 0xc0000000;
+
+// the kernel follows the firmware on a 4 MiB boundary, where fw_jump expects it on rv32
+const KERNEL_ALIGN: usize = 4 << 20;
+// the initramfs starts half the RAM after the kernel, at most 128 MiB
+const MAX_INITRD_OFFSET: usize = 128 << 20;
+// the device tree is at the end of the low memory on a 2 MiB boundary
+const FDT_ALIGN: usize = 2 << 20;
+
+// fw_dynamic_info handed to OpenSBI in a2, the next stage is the kernel in supervisor mode
+const FW_DYNAMIC_INFO_MAGIC: u32 = 0x4942534f;
+const FW_DYNAMIC_INFO_VERSION: u32 = 2;
+const FW_DYNAMIC_INFO_SIZE: usize = 24;
+const NEXT_MODE_SUPERVISOR: u32 = 1;
+
+// boot protocol registers
+const A0: u32 = 10;
+const A1: u32 = 11;
+const A2: u32 = 12;
+
+#[derive(Default)]
+pub struct BootImages {
+    // started in machine mode at the start of RAM, usually OpenSBI
+    pub firmware: Option<Vec<u8>>,
+    // raw kernel Image, started directly in machine mode without a firmware
+    pub kernel: Option<Vec<u8>>,
+    pub initrd: Option<Vec<u8>>,
+    pub bootargs: Option<String>,
+}
+
+// the devices are attached to its bus before booting
+pub fn new_vm(ram_size: usize) -> Result<VM, String> {
+    if ram_size == 0 || ram_size > u32::MAX as usize - VIRT_RAM_ADDRESS + 1 {
+        return Err(format!("invalid RAM size {ram_size:#x}"));
+    }
+
+    let mut vm = VM::without_memory();
+    vm.get_bus_mut()
+        .add_ram("ram", VIRT_RAM_ADDRESS, vec![0; ram_size])?;
+    Ok(vm)
+}
+
+fn align_up(value: usize, align: usize) -> usize {
+    value.div_ceil(align) * align
+}
+
+fn load(vm: &mut VM, name: &str, address: usize, data: &[u8]) -> Result<(), String> {
+    if !vm.get_bus_mut().write_memory_n(address, data.to_vec()) {
+        return Err(format!(
+            "the {name} ({:#x} bytes at {address:#x}) doesn't fit in RAM",
+            data.len()
+        ));
+    }
+    Ok(())
+}
+
+// places the images and the device tree in RAM and sets the entry point of every hart: a0 is the
+// hart id, a1 the address of the device tree and a2 the fw_dynamic_info of OpenSBI. Returns the
+// device tree
+pub fn boot(vm: &mut VM, ram_size: usize, images: &BootImages) -> Result<Vec<u8>, String> {
+    let kernel_address = match &images.firmware {
+        Some(firmware) => {
+            load(vm, "firmware", VIRT_RAM_ADDRESS, firmware)?;
+            align_up(VIRT_RAM_ADDRESS + firmware.len(), KERNEL_ALIGN)
+        }
+        None if images.kernel.is_some() => VIRT_RAM_ADDRESS,
+        None => return Err("the virt machine needs a firmware or a kernel".to_string()),
+    };
+
+    let mut images_end = kernel_address;
+    if let Some(kernel) = &images.kernel {
+        load(vm, "kernel", kernel_address, kernel)?;
+        images_end = kernel_address + kernel.len();
+    }
+
+    // the kernel must not reuse the memory of the firmware below it
+    let mut boot = BootInfo {
+        bootargs: images.bootargs.clone(),
+        initrd: None,
+        reserved: Vec::new(),
+    };
+    if images.firmware.is_some() {
+        boot.reserved
+            .push((VIRT_RAM_ADDRESS, kernel_address - VIRT_RAM_ADDRESS));
+    }
+    if let Some(initrd) = &images.initrd {
+        if images.kernel.is_none() {
+            return Err("an initramfs needs a kernel".to_string());
+        }
+        let start = kernel_address + (ram_size / 2).min(MAX_INITRD_OFFSET);
+        if start < images_end {
+            return Err("the kernel overlaps the initramfs".to_string());
+        }
+        load(vm, "initramfs", start, initrd)?;
+        images_end = start + initrd.len();
+        boot.initrd = Some((start, images_end));
+    }
+
+    let fdt = device_tree::generate(vm.get_bus(), &boot);
+    let top = (VIRT_RAM_ADDRESS + ram_size).min(LOWMEM_END);
+    let fdt_size = align_up(fdt.len(), 8);
+    let fdt_address = top
+        .checked_sub(fdt_size + FW_DYNAMIC_INFO_SIZE)
+        .map(|address| address / FDT_ALIGN * FDT_ALIGN)
+        .filter(|address| *address >= images_end)
+        .ok_or("the device tree doesn't fit in RAM after the images")?;
+    load(vm, "device tree", fdt_address, &fdt)?;
+
+    vm.set_hart_id_register(A0);
+    vm.set_boot_register(A1, fdt_address as u32);
+
+    if images.firmware.is_some() {
+        let info_address = fdt_address + fdt_size;
+        let info: Vec<u8> = [
+            FW_DYNAMIC_INFO_MAGIC,
+            FW_DYNAMIC_INFO_VERSION,
+            kernel_address as u32,
+            NEXT_MODE_SUPERVISOR,
+            // options
+            0,
+            // boot hart
+            0,
+        ]
+        .iter()
+        .flat_map(|field| field.to_le_bytes())
+        .collect();
+        load(vm, "fw_dynamic_info", info_address, &info)?;
+        vm.set_boot_register(A2, info_address as u32);
+        vm.set_entry(VIRT_RAM_ADDRESS as u32);
+    } else {
+        vm.set_entry(kernel_address as u32);
+    }
+
+    Ok(fdt)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fdt::tests::properties;
+
+    const MIB: usize = 1 << 20;
+    const A0_INDEX: usize = A0 as usize;
+    const A1_INDEX: usize = A1 as usize;
+    const A2_INDEX: usize = A2 as usize;
+
+    fn images(firmware: usize, kernel: usize, initrd: usize) -> BootImages {
+        let image = |size: usize, byte: u8| (size > 0).then(|| vec![byte; size]);
+        BootImages {
+            firmware: image(firmware, 0xf1),
+            kernel: image(kernel, 0x4b),
+            initrd: image(initrd, 0x1d),
+            bootargs: None,
+        }
+    }
+
+    // boots the images on a fresh machine, the registers are set once the execution starts
+    fn boot_images(ram_size: usize, images: &BootImages) -> Result<(VM, Vec<u8>), String> {
+        let mut vm = new_vm(ram_size)?;
+        let fdt = boot(&mut vm, ram_size, images)?;
+        vm.init_execution();
+        Ok((vm, fdt))
+    }
+
+    fn chosen_u32(fdt: &[u8], name: &str) -> Option<u32> {
+        properties(fdt)
+            .into_iter()
+            .find(|(path, property, _)| path == "/chosen" && property == name)
+            .map(|(.., value)| u32::from_be_bytes(value.try_into().unwrap()))
+    }
+
+    // the memory reservation block, up to its terminating empty entry
+    fn reservations(fdt: &[u8]) -> Vec<(u64, u64)> {
+        let be_u64 =
+            |offset: usize| u64::from_be_bytes(fdt[offset..offset + 8].try_into().unwrap());
+        let mut offset = u32::from_be_bytes(fdt[16..20].try_into().unwrap()) as usize;
+        let mut reservations = Vec::new();
+        while be_u64(offset) != 0 || be_u64(offset + 8) != 0 {
+            reservations.push((be_u64(offset), be_u64(offset + 8)));
+            offset += 16;
+        }
+        reservations
+    }
+
+    #[test]
+    fn firmware_boots_the_kernel_through_fw_dynamic_info() {
+        let images = images(0x12345, 0x2000, 0x300);
+        let (vm, fdt) = boot_images(DEFAULT_VIRT_RAM_SIZE, &images).unwrap();
+        let bus = vm.get_bus();
+        let registers = vm.get_registers();
+
+        assert_eq!(vm.get_pc(), VIRT_RAM_ADDRESS as u32);
+        assert_eq!(registers[A0_INDEX], 0);
+        assert_eq!(
+            bus.read_memory_n(VIRT_RAM_ADDRESS, 0x12345),
+            images.firmware
+        );
+
+        // the kernel on the next 4 MiB boundary, the firmware below it is reserved
+        let kernel = VIRT_RAM_ADDRESS + 4 * MIB;
+        assert_eq!(bus.read_memory_n(kernel, 0x2000), images.kernel);
+        assert_eq!(
+            reservations(&fdt),
+            [(VIRT_RAM_ADDRESS as u64, 4 * MIB as u64)]
+        );
+
+        // the initramfs half the RAM after the kernel
+        let initrd = kernel + 64 * MIB;
+        assert_eq!(bus.read_memory_n(initrd, 0x300), images.initrd);
+        assert_eq!(chosen_u32(&fdt, "linux,initrd-start"), Some(initrd as u32));
+        assert_eq!(
+            chosen_u32(&fdt, "linux,initrd-end"),
+            Some(initrd as u32 + 0x300)
+        );
+
+        // the device tree in the last 2 MiB of RAM, followed by fw_dynamic_info
+        let fdt_address = registers[A1_INDEX] as usize;
+        assert_eq!(
+            fdt_address,
+            VIRT_RAM_ADDRESS + DEFAULT_VIRT_RAM_SIZE - 2 * MIB
+        );
+        assert_eq!(bus.read_memory_n(fdt_address, fdt.len()), Some(fdt.clone()));
+
+        let info_address = registers[A2_INDEX] as usize;
+        assert_eq!(info_address, fdt_address + align_up(fdt.len(), 8));
+        let info: Vec<u32> = (0..6)
+            .map(|i| bus.read_memory_u32(info_address + 4 * i).unwrap())
+            .collect();
+        assert_eq!(
+            info,
+            [
+                FW_DYNAMIC_INFO_MAGIC,
+                FW_DYNAMIC_INFO_VERSION,
+                kernel as u32,
+                NEXT_MODE_SUPERVISOR,
+                0,
+                0
+            ]
+        );
+    }
+
+    #[test]
+    fn kernel_alone_starts_at_the_start_of_ram() {
+        let (vm, fdt) = boot_images(16 * MIB, &images(0, 0x1000, 0x10)).unwrap();
+        let registers = vm.get_registers();
+
+        assert_eq!(vm.get_pc(), VIRT_RAM_ADDRESS as u32);
+        assert_eq!(registers[A2_INDEX], 0);
+        assert!(reservations(&fdt).is_empty());
+        assert_eq!(
+            chosen_u32(&fdt, "linux,initrd-start"),
+            Some((VIRT_RAM_ADDRESS + 8 * MIB) as u32)
+        );
+        assert_eq!(
+            registers[A1_INDEX] as usize,
+            VIRT_RAM_ADDRESS + 16 * MIB - 2 * MIB
+        );
+    }
+
+    #[test]
+    fn initramfs_and_device_tree_stay_in_low_memory() {
+        // 1.25 GiB: the initramfs at most 128 MiB after the kernel, the device tree below 3 GiB
+        let ram_size = 1280 * MIB;
+        let (vm, fdt) = boot_images(ram_size, &images(0x1000, 0x1000, 0x10)).unwrap();
+
+        let kernel = VIRT_RAM_ADDRESS + 4 * MIB;
+        assert_eq!(
+            chosen_u32(&fdt, "linux,initrd-start"),
+            Some((kernel + 128 * MIB) as u32)
+        );
+        assert_eq!(vm.get_registers()[A1_INDEX] as usize, LOWMEM_END - 2 * MIB);
+    }
+
+    #[test]
+    fn overlapping_images_are_rejected() {
+        let error =
+            |ram_size: usize, images: BootImages| boot_images(ram_size, &images).err().unwrap();
+
+        assert_eq!(
+            error(16 * MIB, images(0, 0, 0)),
+            "the virt machine needs a firmware or a kernel"
+        );
+        assert_eq!(
+            error(16 * MIB, images(0x1000, 0, 0x10)),
+            "an initramfs needs a kernel"
+        );
+        // the initramfs goes 8 MiB after the kernel
+        assert_eq!(
+            error(16 * MIB, images(0, 9 * MIB, 0x10)),
+            "the kernel overlaps the initramfs"
+        );
+        assert_eq!(
+            error(16 * MIB, images(0, 8 * MIB, 9 * MIB)),
+            format!(
+                "the initramfs ({:#x} bytes at 0x80800000) doesn't fit in RAM",
+                9 * MIB
+            )
+        );
+        // the last 2 MiB boundary below the device tree is inside the kernel
+        assert_eq!(
+            error(16 * MIB, images(0, 15 * MIB, 0)),
+            "the device tree doesn't fit in RAM after the images"
+        );
+        assert_eq!(
+            error(16 * MIB, images(17 * MIB, 0, 0)),
+            format!(
+                "the firmware ({:#x} bytes at 0x80000000) doesn't fit in RAM",
+                17 * MIB
+            )
+        );
+    }
+}
@@ -0,0 +1,789 @@
+use crate::{
+    bus::{Device, DmaMemory},
+    device_tree::DeviceTreeNode,
+    replay::InputChannel,
+    snapshot::{SnapshotReader, SnapshotWriter},
+};
+
+// virtio-mmio transport (version 2) with split virtqueues, the device types implement
+// VirtioDevice
+
+// first slot and spacing of the virtio devices on the qemu virt machine, their PLIC sources start
+// at 1
+pub const VIRTIO_ADDRESS: usize = 0x10001000;
+pub const VIRTIO_STRIDE: usize = 0x1000;
+pub const VIRTIO_FIRST_IRQ: u32 = 1;
+const VIRTIO_SIZE: usize = 0x200;
+
+pub const DEVICE_ID_BLOCK: u32 = 2;
+pub const DEVICE_ID_CONSOLE: u32 = 3;
+pub const DEVICE_ID_RNG: u32 = 4;
+
+const MAGIC_VALUE: u32 = 0x74726976;
+const VERSION: u32 = 2;
+const VENDOR_ID: u32 = 0x554d4551;
+
+// register offsets
+const MAGIC: usize = 0x000;
+const VERSION_REGISTER: usize = 0x004;
+const DEVICE_ID: usize = 0x008;
+const VENDOR: usize = 0x00c;
+const DEVICE_FEATURES: usize = 0x010;
+const DEVICE_FEATURES_SEL: usize = 0x014;
+const DRIVER_FEATURES: usize = 0x020;
+const DRIVER_FEATURES_SEL: usize = 0x024;
+const QUEUE_SEL: usize = 0x030;
+const QUEUE_NUM_MAX: usize = 0x034;
+const QUEUE_NUM: usize = 0x038;
+const QUEUE_READY: usize = 0x044;
+const QUEUE_NOTIFY: usize = 0x050;
+const INTERRUPT_STATUS: usize = 0x060;
+const INTERRUPT_ACK: usize = 0x064;
+const STATUS: usize = 0x070;
+const QUEUE_DESC_LOW: usize = 0x080;
+const QUEUE_DESC_HIGH: usize = 0x084;
+const QUEUE_DRIVER_LOW: usize = 0x090;
+const QUEUE_DRIVER_HIGH: usize = 0x094;
+const QUEUE_DEVICE_LOW: usize = 0x0a0;
+const QUEUE_DEVICE_HIGH: usize = 0x0a4;
+const CONFIG_GENERATION: usize = 0x0fc;
+const CONFIG: usize = 0x100;
+
+// offered with the features of every device, required by the modern interface
+const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+const INTERRUPT_USED_BUFFER: u32 = 1;
+const INTERRUPT_CONFIG_CHANGE: u32 = 2;
+
+const STATUS_DRIVER_OK: u32 = 4;
+const STATUS_NEEDS_RESET: u32 = 0x40;
+
+const QUEUE_SIZE_MAX: u16 = 256;
+
+pub(crate) const DESC_F_NEXT: u16 = 1;
+pub(crate) const DESC_F_WRITE: u16 = 2;
+
+pub struct Descriptor {
+    pub address: u64,
+    pub len: u32,
+    // written by the device, read by the driver
+    pub writable: bool,
+}
+
+// buffers of a request, the driver-readable ones come first
+pub struct Chain {
+    head: u16,
+    pub descriptors: Vec<Descriptor>,
+}
+
+impl Chain {
+    // concatenation of the driver-written buffers
+    pub fn read(&self, memory: &DmaMemory) -> Option<Vec<u8>> {
+        let mut data = Vec::new();
+        for descriptor in self.descriptors.iter().filter(|d| !d.writable) {
+            data.extend(memory.read(descriptor.address, descriptor.len as usize)?);
+        }
+        Some(data)
+    }
+
+    pub fn writable_len(&self) -> usize {
+        self.descriptors
+            .iter()
+            .filter(|descriptor| descriptor.writable)
+            .map(|descriptor| descriptor.len as usize)
+            .sum()
+    }
+
+    // scatters the data in the device-writable buffers, returns the number of bytes written
+    pub fn write(&self, memory: &mut DmaMemory, data: &[u8]) -> Option<u32> {
+        let mut written = 0;
+        for descriptor in self.descriptors.iter().filter(|d| d.writable) {
+            if written == data.len() {
+                break;
+            }
+            let size = (descriptor.len as usize).min(data.len() - written);
+            if !memory.write(descriptor.address, &data[written..written + size]) {
+                return None;
+            }
+            written += size;
+        }
+        Some(written as u32)
+    }
+}
+
+pub struct Queue {
+    size: u16,
+    ready: bool,
+    desc: u64,
+    driver: u64,
+    device: u64,
+    // next entry of the available ring to handle
+    last_available: u16,
+    // set when the driver handed over malformed descriptors
+    broken: bool,
+}
+
+impl Queue {
+    fn new() -> Self {
+        Self {
+            size: QUEUE_SIZE_MAX,
+            ready: false,
+            desc: 0,
+            driver: 0,
+            device: 0,
+            last_available: 0,
+            broken: false,
+        }
+    }
+
+    fn save_state(&self, snapshot: &mut SnapshotWriter) {
+        snapshot.write_u32(self.size as u32);
+        snapshot.write_bool(self.ready);
+        snapshot.write_u64(self.desc);
+        snapshot.write_u64(self.driver);
+        snapshot.write_u64(self.device);
+        snapshot.write_u32(self.last_available as u32);
+        snapshot.write_bool(self.broken);
+    }
+
+    fn restore_state(&mut self, snapshot: &mut SnapshotReader) -> Result<(), String> {
+        self.size = snapshot.read_u32()? as u16;
+        self.ready = snapshot.read_bool()?;
+        self.desc = snapshot.read_u64()?;
+        self.driver = snapshot.read_u64()?;
+        self.device = snapshot.read_u64()?;
+        self.last_available = snapshot.read_u32()? as u16;
+        self.broken = snapshot.read_bool()?;
+        if self.size > QUEUE_SIZE_MAX {
+            let size = self.size;
+            return Err(format!("invalid virtqueue size {size} in the snapshot"));
+        }
+        Ok(())
+    }
+
+    fn is_usable(&self) -> bool {
+        self.ready && !self.broken && self.size > 0
+    }
+
+    pub fn has_available(&self, memory: &DmaMemory) -> bool {
+        self.is_usable() && memory.read_u16(self.driver + 2) != Some(self.last_available)
+    }
+
+    // next request of the driver, None when there is none or the queue is broken
+    pub fn pop(&mut self, memory: &DmaMemory) -> Option<Chain> {
+        if !self.has_available(memory) {
+            return None;
+        }
+
+        let chain = self.read_chain(memory);
+        if chain.is_none() {
+            self.broken = true;
+        }
+        self.last_available = self.last_available.wrapping_add(1);
+        chain
+    }
+
+    fn read_chain(&self, memory: &DmaMemory) -> Option<Chain> {
+        let slot = (self.last_available % self.size) as u64;
+        let head = memory.read_u16(self.driver + 4 + 2 * slot)?;
+
+        let mut descriptors = Vec::new();
+        let mut index = head;
+        loop {
+            // a chain can't be longer than the table, longer ones loop
+            if index >= self.size || descriptors.len() == self.size as usize {
+                return None;
+            }
+
+            let entry = memory.read(self.desc.checked_add(16 * index as u64)?, 16)?;
+            let flags = u16::from_le_bytes([entry[12], entry[13]]);
+            let descriptor = Descriptor {
+                address: u64::from_le_bytes(entry[0..8].try_into().unwrap()),
+                len: u32::from_le_bytes(entry[8..12].try_into().unwrap()),
+                writable: flags & DESC_F_WRITE != 0,
+            };
+
+            // the buffers must be in guest memory, so that the device never allocates more than
+            // the guest has
+            if !memory.is_accessible(
+                descriptor.address,
+                descriptor.len as usize,
+                descriptor.writable,
+            ) {
+                return None;
+            }
+
+            // the device-writable buffers come after the readable ones
+            if !descriptor.writable && descriptors.last().is_some_and(|d: &Descriptor| d.writable) {
+                return None;
+            }
+            descriptors.push(descriptor);
+
+            if flags & DESC_F_NEXT == 0 {
+                break;
+            }
+            index = u16::from_le_bytes([entry[14], entry[15]]);
+        }
+
+        Some(Chain { head, descriptors })
+    }
+
+    // gives the request back to the driver with the number of bytes written in its buffers
+    pub fn push_used(&mut self, memory: &mut DmaMemory, chain: &Chain, len: u32) {
+        let Some(index) = memory.read_u16(self.device + 2) else {
+            self.broken = true;
+            return;
+        };
+
+        let element = self.device + 4 + 8 * (index % self.size) as u64;
+        if !memory.write_u32(element, chain.head as u32)
+            || !memory.write_u32(element + 4, len)
+            || !memory.write_u16(self.device + 2, index.wrapping_add(1))
+        {
+            self.broken = true;
+        }
+    }
+}
+
+pub trait VirtioDevice {
+    fn device_id(&self) -> u32;
+
+    // device specific feature bits
+    fn features(&self) -> u64;
+
+    fn queue_count(&self) -> usize;
+
+    // device configuration space
+    fn read_config(&self, offset: usize) -> u8;
+
+    fn write_config(&mut self, _offset: usize, _value: u8) {}
+
+    // handles the requests of a queue, called when the driver notifies it and on every tick.
+    // Returns whether buffers were given back to the driver
+    fn process_queue(
+        &mut self,
+        index: usize,
+        queue: &mut Queue,
+        memory: &mut DmaMemory,
+        instret: u64,
+    ) -> bool;
+
+    // back to the power-on state
+    fn reset(&mut self) {}
+
+    // called regularly with the retired instructions, to poll the host
+    fn tick(&mut self, _instret: u64) {}
+
+    // state of the device in a snapshot, the transport saves the queues
+    fn save_state(&self, _snapshot: &mut SnapshotWriter) {}
+
+    fn restore_state(&mut self, _snapshot: &mut SnapshotReader) -> Result<(), String> {
+        Ok(())
+    }
+
+    // what the device gets from the host goes through the channel, see Device::set_input
+    fn set_input(&mut self, _input: InputChannel) {}
+}
+
+pub struct VirtioMmio {
+    device: Box<dyn VirtioDevice>,
+    queues: Vec<Queue>,
+    status: u32,
+    interrupt_status: u32,
+    device_features_sel: u32,
+    driver_features_sel: u32,
+    driver_features: u64,
+    queue_sel: u32,
+    // queues notified by the driver since the last dma
+    notified: Vec<bool>,
+    ticked: bool,
+}
+
+impl VirtioMmio {
+    pub fn new(device: Box<dyn VirtioDevice>) -> Self {
+        let queue_count = device.queue_count();
+
+        Self {
+            device,
+            queues: (0..queue_count).map(|_| Queue::new()).collect(),
+            status: 0,
+            interrupt_status: 0,
+            device_features_sel: 0,
+            driver_features_sel: 0,
+            driver_features: 0,
+            queue_sel: 0,
+            notified: vec![false; queue_count],
+            ticked: false,
+        }
+    }
+
+    fn features(&self) -> u64 {
+        self.device.features() | VIRTIO_F_VERSION_1
+    }
+
+    fn selected_queue(&mut self) -> Option<&mut Queue> {
+        self.queues.get_mut(self.queue_sel as usize)
+    }
+
+    fn read_register(&self, offset: usize) -> u32 {
+        let queue = self.queues.get(self.queue_sel as usize);
+
+        match offset {
+            MAGIC => MAGIC_VALUE,
+            VERSION_REGISTER => VERSION,
+            DEVICE_ID => self.device.device_id(),
+            VENDOR => VENDOR_ID,
+            DEVICE_FEATURES => match self.device_features_sel {
+                0 => self.features() as u32,
+                1 => (self.features() >> 32) as u32,
+                _ => 0,
+            },
+            QUEUE_NUM_MAX => queue.map_or(0, |_| QUEUE_SIZE_MAX as u32),
+            QUEUE_READY => queue.map_or(0, |queue| queue.ready as u32),
+            INTERRUPT_STATUS => self.interrupt_status,
+            STATUS => self.status,
+            CONFIG_GENERATION => 0,
+            _ => 0,
+        }
+    }
+
+    fn write_register(&mut self, offset: usize, value: u32) {
+        match offset {
+            DEVICE_FEATURES_SEL => self.device_features_sel = value,
+            DRIVER_FEATURES => {
+                let shift = match self.driver_features_sel {
+                    0 => 0,
+                    1 => 32,
+                    _ => return,
+                };
+                self.driver_features &= !(0xffffffff << shift);
+                self.driver_features |= (value as u64) << shift;
+            }
+            DRIVER_FEATURES_SEL => self.driver_features_sel = value,
+            QUEUE_SEL => self.queue_sel = value,
+            QUEUE_NOTIFY => {
+                if let Some(notified) = self.notified.get_mut(value as usize) {
+                    *notified = true;
+                }
+            }
+            INTERRUPT_ACK => self.interrupt_status &= !value,
+            STATUS if value == 0 => self.reset_transport(),
+            STATUS => self.status = value,
+            _ => self.write_queue_register(offset, value),
+        }
+    }
+
+    // the queue layout can only change while the queue is not ready
+    fn write_queue_register(&mut self, offset: usize, value: u32) {
+        let Some(queue) = self.selected_queue() else {
+            return;
+        };
+
+        let low = |address: u64| address & !0xffffffff | value as u64;
+        let high = |address: u64| address & 0xffffffff | (value as u64) << 32;
+
+        match offset {
+            QUEUE_READY => queue.ready = value & 1 != 0,
+            _ if queue.ready => {}
+            QUEUE_NUM if value <= QUEUE_SIZE_MAX as u32 => queue.size = value as u16,
+            QUEUE_DESC_LOW => queue.desc = low(queue.desc),
+            QUEUE_DESC_HIGH => queue.desc = high(queue.desc),
+            QUEUE_DRIVER_LOW => queue.driver = low(queue.driver),
+            QUEUE_DRIVER_HIGH => queue.driver = high(queue.driver),
+            QUEUE_DEVICE_LOW => queue.device = low(queue.device),
+            QUEUE_DEVICE_HIGH => queue.device = high(queue.device),
+            _ => {}
+        }
+    }
+
+    fn reset_transport(&mut self) {
+        self.device.reset();
+        self.queues
+            .iter_mut()
+            .for_each(|queue| *queue = Queue::new());
+        self.notified
+            .iter_mut()
+            .for_each(|notified| *notified = false);
+        self.status = 0;
+        self.interrupt_status = 0;
+        self.device_features_sel = 0;
+        self.driver_features_sel = 0;
+        self.driver_features = 0;
+        self.queue_sel = 0;
+    }
+}
+
+impl Device for VirtioMmio {
+    fn size(&self) -> usize {
+        VIRTIO_SIZE
+    }
+
+    // the registers are 32 bits wide, the configuration space can be accessed with any width
+    fn read(&mut self, offset: usize, nb_bytes: usize, _instret: u64) -> u32 {
+        if offset >= CONFIG {
+            return (0..nb_bytes).fold(0, |value, i| {
+                value | (self.device.read_config(offset - CONFIG + i) as u32) << (8 * i)
+            });
+        }
+
+        self.read_register(offset & !3)
+    }
+
+    fn write(&mut self, offset: usize, nb_bytes: usize, value: u32, _instret: u64) {
+        if offset >= CONFIG {
+            for i in 0..nb_bytes {
+                self.device
+                    .write_config(offset - CONFIG + i, (value >> (8 * i)) as u8);
+            }
+            return;
+        }
+
+        self.write_register(offset & !3, value)
+    }
+
+    fn reset(&mut self) {
+        self.reset_transport();
+    }
+
+    fn tick(&mut self, instret: u64) {
+        self.device.tick(instret);
+        self.ticked = true;
+    }
+
+    fn interrupt_pending(&self) -> bool {
+        self.interrupt_status != 0
+    }
+
+    fn dma(&mut self, memory: &mut DmaMemory, instret: u64) {
+        if self.status & STATUS_DRIVER_OK == 0 {
+            return;
+        }
+
+        for index in 0..self.queues.len() {
+            if !self.notified[index] && !self.ticked {
+                continue;
+            }
+            self.notified[index] = false;
+
+            let queue = &mut self.queues[index];
+            if !queue.is_usable() {
+                continue;
+            }
+
+            if self.device.process_queue(index, queue, memory, instret) {
+                self.interrupt_status |= INTERRUPT_USED_BUFFER;
+            }
+
+            if queue.broken {
+                self.status |= STATUS_NEEDS_RESET;
+                self.interrupt_status |= INTERRUPT_CONFIG_CHANGE;
+            }
+        }
+
+        self.ticked = false;
+    }
+
+    fn device_tree_node(&self) -> Option<DeviceTreeNode> {
+        Some(DeviceTreeNode::new("virtio_mmio", &["virtio,mmio"]))
+    }
+
+    fn set_input(&mut self, input: InputChannel) {
+        self.device.set_input(input);
+    }
+
+    fn save_state(&self, snapshot: &mut SnapshotWriter) {
+        snapshot.write_u64(self.device.device_id() as u64);
+        snapshot.write_u64(self.queues.len() as u64);
+        for (queue, notified) in self.queues.iter().zip(&self.notified) {
+            queue.save_state(snapshot);
+            snapshot.write_bool(*notified);
+        }
+        snapshot.write_u32s(&[
+            self.status,
+            self.interrupt_status,
+            self.device_features_sel,
+            self.driver_features_sel,
+            self.queue_sel,
+        ]);
+        snapshot.write_u64(self.driver_features);
+        snapshot.write_bool(self.ticked);
+        self.device.save_state(snapshot);
+    }
+
+    fn restore_state(&mut self, snapshot: &mut SnapshotReader) -> Result<(), String> {
+        snapshot.check("as virtio device id", self.device.device_id() as u64)?;
+        snapshot.check("virtqueues", self.queues.len() as u64)?;
+        for (queue, notified) in self.queues.iter_mut().zip(&mut self.notified) {
+            queue.restore_state(snapshot)?;
+            *notified = snapshot.read_bool()?;
+        }
+        let mut registers = [0; 5];
+        snapshot.read_u32s_into("virtio registers", &mut registers)?;
+        [
+            self.status,
+            self.interrupt_status,
+            self.device_features_sel,
+            self.driver_features_sel,
+            self.queue_sel,
+        ] = registers;
+        self.driver_features = snapshot.read_u64()?;
+        self.ticked = snapshot.read_bool()?;
+        self.device.restore_state(snapshot)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::memory::Memory;
+
+    // split queue of 8 entries in the first page of a 64 KiB memory at 0
+    const MEMORY_SIZE: usize = 0x10000;
+    const DESC: u64 = 0x000;
+    const DRIVER: u64 = 0x100;
+    pub(crate) const DEVICE: u64 = 0x200;
+    pub(crate) const SIZE: u16 = 8;
+
+    pub(crate) fn queue() -> Queue {
+        Queue {
+            size: SIZE,
+            ready: true,
+            desc: DESC,
+            driver: DRIVER,
+            device: DEVICE,
+            ..Queue::new()
+        }
+    }
+
+    pub(crate) fn set_descriptor(
+        memory: &mut DmaMemory,
+        index: u16,
+        address: u64,
+        len: u32,
+        flags: u16,
+        next: u16,
+    ) {
+        let entry = DESC + 16 * index as u64;
+        assert!(memory.write(entry, &address.to_le_bytes()));
+        assert!(memory.write_u32(entry + 8, len));
+        assert!(memory.write_u16(entry + 12, flags));
+        assert!(memory.write_u16(entry + 14, next));
+    }
+
+    // puts the chain in the available ring
+    pub(crate) fn offer(memory: &mut DmaMemory, head: u16) {
+        let index = memory.read_u16(DRIVER + 2).unwrap();
+        assert!(memory.write_u16(DRIVER + 4 + 2 * (index % SIZE) as u64, head));
+        assert!(memory.write_u16(DRIVER + 2, index.wrapping_add(1)));
+    }
+
+    pub(crate) fn with_memory(test: impl FnOnce(&mut DmaMemory)) {
+        let mut memories = vec![Memory::new(0, vec![0; MEMORY_SIZE])];
+        let mut written = Vec::new();
+        test(&mut DmaMemory::new(&mut memories, &mut written));
+    }
+
+    // length written by the device in the n-th element of the used ring
+    pub(crate) fn used_len(memory: &DmaMemory, index: u16) -> u32 {
+        memory
+            .read_u32(DEVICE + 8 + 8 * (index % SIZE) as u64)
+            .unwrap()
+    }
+
+    #[test]
+    fn chains_follow_the_next_descriptors() {
+        with_memory(|memory| {
+            let mut queue = queue();
+            assert!(memory.write(0x1000, b"request"));
+            set_descriptor(memory, 2, 0x1000, 7, DESC_F_NEXT, 5);
+            set_descriptor(memory, 5, 0x2000, 0x200, DESC_F_NEXT | DESC_F_WRITE, 0);
+            set_descriptor(memory, 0, 0x3000, 1, DESC_F_WRITE, 0);
+            assert!(!queue.has_available(memory));
+
+            offer(memory, 2);
+            let chain = queue.pop(memory).unwrap();
+            assert_eq!(chain.head, 2);
+            assert_eq!(chain.descriptors.len(), 3);
+            assert_eq!(chain.read(memory).unwrap(), b"request");
+            assert_eq!(chain.writable_len(), 0x201);
+
+            // the data is scattered in the writable buffers in order
+            assert_eq!(chain.write(memory, &[7; 0x201]), Some(0x201));
+            assert_eq!(memory.read(0x21ff, 1).unwrap(), [7]);
+            assert_eq!(memory.read(0x3000, 2).unwrap(), [7, 0]);
+
+            assert!(!queue.has_available(memory));
+            assert!(!queue.broken);
+        });
+    }
+
+    #[test]
+    fn readable_buffers_after_writable_ones_break_the_queue() {
+        with_memory(|memory| {
+            let mut queue = queue();
+            set_descriptor(memory, 0, 0x1000, 16, DESC_F_NEXT | DESC_F_WRITE, 1);
+            set_descriptor(memory, 1, 0x2000, 16, 0, 0);
+            offer(memory, 0);
+
+            assert!(queue.pop(memory).is_none());
+            assert!(queue.broken);
+
+            // nothing is handled anymore, even valid chains
+            set_descriptor(memory, 1, 0x2000, 16, DESC_F_WRITE, 0);
+            offer(memory, 0);
+            assert!(!queue.has_available(memory));
+            assert!(queue.pop(memory).is_none());
+        });
+    }
+
+    #[test]
+    fn chains_outside_of_memory_or_looping_break_the_queue() {
+        let chains: [&[(u64, u32, u16)]; 4] = [
+            // past the end of the memory
+            &[(MEMORY_SIZE as u64 - 0x100, 0x200, DESC_F_WRITE)],
+            // a huge writable buffer the device would have to allocate
+            &[(0x1000, u32::MAX, DESC_F_WRITE)],
+            // wrapping around the address space
+            &[(u64::MAX - 0xf, 0x20, 0)],
+            // back to the head
+            &[(0x1000, 16, DESC_F_NEXT), (0x2000, 16, DESC_F_NEXT)],
+        ];
+
+        for chain in chains {
+            with_memory(|memory| {
+                let mut queue = queue();
+                for (index, &(address, len, flags)) in chain.iter().enumerate() {
+                    let next = (index as u16 + 1) % chain.len() as u16;
+                    set_descriptor(memory, index as u16, address, len, flags, next);
+                }
+                offer(memory, 0);
+
+                assert!(queue.pop(memory).is_none());
+                assert!(queue.broken);
+            });
+        }
+
+        // the descriptor index must be in the table
+        with_memory(|memory| {
+            let mut queue = queue();
+            offer(memory, SIZE);
+            assert!(queue.pop(memory).is_none());
+            assert!(queue.broken);
+        });
+    }
+
+    #[test]
+    fn rings_wrap_around() {
+        with_memory(|memory| {
+            let mut queue = queue();
+            queue.last_available = u16::MAX;
+            assert!(memory.write_u16(DRIVER + 2, u16::MAX));
+            assert!(memory.write_u16(DEVICE + 2, u16::MAX));
+
+            for head in [3, 4] {
+                set_descriptor(memory, head, 0x1000, 16, DESC_F_WRITE, 0);
+                offer(memory, head);
+                let chain = queue.pop(memory).unwrap();
+                assert_eq!(chain.head, head);
+                queue.push_used(memory, &chain, 16);
+            }
+
+            // the last element of the used ring, then the first one
+            let element = |index: u64| memory.read_u32(DEVICE + 4 + 8 * index).unwrap();
+            assert_eq!(element(SIZE as u64 - 1), 3);
+            assert_eq!(element(0), 4);
+            assert_eq!(memory.read_u16(DEVICE + 2), Some(1));
+            assert_eq!(queue.last_available, 1);
+        });
+    }
+
+    // gives every request back without writing anything
+    struct Discard;
+
+    impl VirtioDevice for Discard {
+        fn device_id(&self) -> u32 {
+            DEVICE_ID_RNG
+        }
+
+        fn features(&self) -> u64 {
+            0
+        }
+
+        fn queue_count(&self) -> usize {
+            1
+        }
+
+        fn read_config(&self, _offset: usize) -> u8 {
+            0
+        }
+
+        fn process_queue(
+            &mut self,
+            _index: usize,
+            queue: &mut Queue,
+            memory: &mut DmaMemory,
+            _instret: u64,
+        ) -> bool {
+            let mut used = false;
+            while let Some(chain) = queue.pop(memory) {
+                queue.push_used(memory, &chain, 0);
+                used = true;
+            }
+            used
+        }
+    }
+
+    #[test]
+    fn broken_queue_needs_a_reset() {
+        let mut mmio = VirtioMmio::new(Box::new(Discard));
+        let setup = |mmio: &mut VirtioMmio| {
+            for (offset, value) in [
+                (QUEUE_SEL, 0),
+                (QUEUE_NUM, SIZE as u32),
+                (QUEUE_DESC_LOW, DESC as u32),
+                (QUEUE_DRIVER_LOW, DRIVER as u32),
+                (QUEUE_DEVICE_LOW, DEVICE as u32),
+                (QUEUE_READY, 1),
+                (STATUS, STATUS_DRIVER_OK),
+                (QUEUE_NOTIFY, 0),
+            ] {
+                mmio.write(offset, 4, value, 0);
+            }
+        };
+
+        with_memory(|memory| {
+            setup(&mut mmio);
+            set_descriptor(memory, 0, 0x1000, 16, 0, 0);
+            offer(memory, 0);
+            mmio.dma(memory, 0);
+            assert_eq!(mmio.read(INTERRUPT_STATUS, 4, 0), INTERRUPT_USED_BUFFER);
+            mmio.write(INTERRUPT_ACK, 4, INTERRUPT_USED_BUFFER, 0);
+
+            set_descriptor(memory, 0, 0x1000, 16, DESC_F_NEXT, 0);
+            offer(memory, 0);
+            mmio.write(QUEUE_NOTIFY, 4, 0, 0);
+            mmio.dma(memory, 0);
+            assert_eq!(
+                mmio.read(STATUS, 4, 0),
+                STATUS_DRIVER_OK | STATUS_NEEDS_RESET
+            );
+            assert_eq!(mmio.read(INTERRUPT_STATUS, 4, 0), INTERRUPT_CONFIG_CHANGE);
+            assert!(mmio.interrupt_pending());
+
+            // the driver resets the device and sets the queue up again
+            mmio.write(STATUS, 4, 0, 0);
+            assert_eq!(mmio.read(STATUS, 4, 0), 0);
+            assert!(!mmio.interrupt_pending());
+            assert!(!mmio.queues[0].broken);
+        });
+
+        with_memory(|memory| {
+            setup(&mut mmio);
+            set_descriptor(memory, 0, 0x1000, 16, 0, 0);
+            offer(memory, 0);
+            mmio.dma(memory, 0);
+            assert_eq!(mmio.read(INTERRUPT_STATUS, 4, 0), INTERRUPT_USED_BUFFER);
+            assert_eq!(mmio.read(STATUS, 4, 0), STATUS_DRIVER_OK);
+        });
+    }
+}
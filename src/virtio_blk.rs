@@ -0,0 +1,253 @@
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+};
+
+use crate::{
+    bus::DmaMemory,
+    snapshot::{SnapshotReader, SnapshotWriter},
+    virtio::{Chain, Queue, VirtioDevice, DEVICE_ID_BLOCK},
+};
+
+// virtio block device backed by a disk image of the host
+
+const SECTOR_SIZE: usize = 512;
+
+const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+
+// request types
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+const VIRTIO_BLK_T_GET_ID: u32 = 8;
+
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_IOERR: u8 = 1;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+// type, reserved and sector
+const HEADER_SIZE: usize = 16;
+const ID_SIZE: usize = 20;
+const DEVICE_NAME: &[u8] = b"riscv-emulator";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageMode {
+    ReadOnly,
+    ReadWrite,
+    // the guest sees its writes but they are kept in memory, the image is never modified
+    CopyOnWrite,
+}
+
+pub struct VirtioBlock {
+    image: File,
+    mode: ImageMode,
+    sectors: u64,
+    // sectors written in copy-on-write mode
+    overlay: HashMap<u64, Vec<u8>>,
+}
+
+impl VirtioBlock {
+    // a trailing partial sector of the image is ignored
+    pub fn new(path: &str, mode: ImageMode) -> io::Result<Self> {
+        let image = OpenOptions::new()
+            .read(true)
+            .write(mode == ImageMode::ReadWrite)
+            .open(path)?;
+        let sectors = image.metadata()?.len() / SECTOR_SIZE as u64;
+
+        Ok(Self {
+            image,
+            mode,
+            sectors,
+            overlay: HashMap::new(),
+        })
+    }
+
+    fn read_sectors(&mut self, sector: u64, data: &mut [u8]) -> io::Result<()> {
+        self.image
+            .seek(SeekFrom::Start(sector * SECTOR_SIZE as u64))?;
+        self.image.read_exact(data)?;
+
+        for (i, chunk) in data.chunks_mut(SECTOR_SIZE).enumerate() {
+            if let Some(written) = self.overlay.get(&(sector + i as u64)) {
+                chunk.copy_from_slice(&written[..chunk.len()]);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn write_sectors(&mut self, sector: u64, data: &[u8]) -> io::Result<()> {
+        match self.mode {
+            ImageMode::ReadOnly => Err(io::Error::from(io::ErrorKind::PermissionDenied)),
+            ImageMode::ReadWrite => {
+                self.image
+                    .seek(SeekFrom::Start(sector * SECTOR_SIZE as u64))?;
+                self.image.write_all(data)
+            }
+            ImageMode::CopyOnWrite => {
+                for (i, chunk) in data.chunks(SECTOR_SIZE).enumerate() {
+                    let mut written = chunk.to_vec();
+                    // a partial sector keeps the rest of its current content
+                    if written.len() < SECTOR_SIZE {
+                        let mut current = vec![0; SECTOR_SIZE];
+                        self.read_sectors(sector + i as u64, &mut current)?;
+                        current[..written.len()].copy_from_slice(&written);
+                        written = current;
+                    }
+                    self.overlay.insert(sector + i as u64, written);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn in_range(&self, sector: u64, len: usize) -> bool {
+        let sectors = len.div_ceil(SECTOR_SIZE) as u64;
+        sector
+            .checked_add(sectors)
+            .is_some_and(|end| end <= self.sectors)
+    }
+
+    // executes the request, returns what goes to the device-writable buffers with the status
+    // byte last
+    fn handle_request(&mut self, chain: &Chain, memory: &DmaMemory) -> Vec<u8> {
+        let Some(readable) = chain.read(memory) else {
+            return vec![VIRTIO_BLK_S_IOERR];
+        };
+        if readable.len() < HEADER_SIZE || chain.writable_len() == 0 {
+            return vec![VIRTIO_BLK_S_IOERR];
+        }
+
+        let request_type = u32::from_le_bytes(readable[0..4].try_into().unwrap());
+        let sector = u64::from_le_bytes(readable[8..16].try_into().unwrap());
+        // what can be returned before the status byte
+        let data_len = chain.writable_len() - 1;
+
+        let (mut data, status) = match request_type {
+            // the buffers are only allocated for requests within the disk
+            VIRTIO_BLK_T_IN if !self.in_range(sector, data_len) => (Vec::new(), VIRTIO_BLK_S_IOERR),
+            VIRTIO_BLK_T_IN => {
+                let mut data = vec![0; data_len];
+                let status = if self.read_sectors(sector, &mut data).is_ok() {
+                    VIRTIO_BLK_S_OK
+                } else {
+                    VIRTIO_BLK_S_IOERR
+                };
+                (data, status)
+            }
+            VIRTIO_BLK_T_OUT => {
+                let data = &readable[HEADER_SIZE..];
+                let status = if self.in_range(sector, data.len())
+                    && self.write_sectors(sector, data).is_ok()
+                {
+                    VIRTIO_BLK_S_OK
+                } else {
+                    VIRTIO_BLK_S_IOERR
+                };
+                (Vec::new(), status)
+            }
+            VIRTIO_BLK_T_FLUSH => {
+                let status = match self.mode {
+                    ImageMode::ReadWrite if self.image.sync_data().is_err() => VIRTIO_BLK_S_IOERR,
+                    _ => VIRTIO_BLK_S_OK,
+                };
+                (Vec::new(), status)
+            }
+            VIRTIO_BLK_T_GET_ID => {
+                let mut id = DEVICE_NAME.to_vec();
+                id.resize(ID_SIZE.min(data_len), 0);
+                (id, VIRTIO_BLK_S_OK)
+            }
+            _ => (Vec::new(), VIRTIO_BLK_S_UNSUPP),
+        };
+
+        data.push(status);
+        data
+    }
+}
+
+impl VirtioDevice for VirtioBlock {
+    fn device_id(&self) -> u32 {
+        DEVICE_ID_BLOCK
+    }
+
+    fn features(&self) -> u64 {
+        match self.mode {
+            ImageMode::ReadOnly => VIRTIO_BLK_F_RO | VIRTIO_BLK_F_FLUSH,
+            _ => VIRTIO_BLK_F_FLUSH,
+        }
+    }
+
+    fn queue_count(&self) -> usize {
+        1
+    }
+
+    // capacity in 512-byte sectors
+    fn read_config(&self, offset: usize) -> u8 {
+        match offset {
+            0..=7 => (self.sectors >> (8 * offset)) as u8,
+            _ => 0,
+        }
+    }
+
+    // requests complete immediately
+    fn process_queue(
+        &mut self,
+        _index: usize,
+        queue: &mut Queue,
+        memory: &mut DmaMemory,
+        _instret: u64,
+    ) -> bool {
+        let mut used = false;
+
+        while let Some(chain) = queue.pop(memory) {
+            let response = self.handle_request(&chain, memory);
+
+            // the status byte goes to the last writable byte, after the data
+            let mut written = 0;
+            if response.len() > 1 {
+                written = chain
+                    .write(memory, &response[..response.len() - 1])
+                    .unwrap_or(0);
+            }
+            let status = *response.last().unwrap();
+            if let Some(last) = chain.descriptors.last().filter(|d| d.writable && d.len > 0) {
+                memory.write(last.address + last.len as u64 - 1, &[status]);
+                written += 1;
+            }
+
+            queue.push_used(memory, &chain, written);
+            used = true;
+        }
+
+        used
+    }
+
+    // the image itself isn't saved, only the sectors written in copy-on-write mode
+    fn save_state(&self, snapshot: &mut SnapshotWriter) {
+        let mut sectors: Vec<_> = self.overlay.keys().copied().collect();
+        sectors.sort();
+        snapshot.write_u64(sectors.len() as u64);
+        for sector in sectors {
+            snapshot.write_u64(sector);
+            snapshot.write_bytes(&self.overlay[&sector]);
+        }
+    }
+
+    fn restore_state(&mut self, snapshot: &mut SnapshotReader) -> Result<(), String> {
+        let count = snapshot.read_u64()?;
+        self.overlay.clear();
+        for _ in 0..count {
+            let sector = snapshot.read_u64()?;
+            let data = snapshot.read_bytes()?;
+            if sector >= self.sectors || data.len() != SECTOR_SIZE {
+                return Err(format!("invalid disk sector {sector} in the snapshot"));
+            }
+            self.overlay.insert(sector, data.to_vec());
+        }
+        Ok(())
+    }
+}
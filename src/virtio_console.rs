@@ -0,0 +1,258 @@
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{self, Write},
+    sync::mpsc::Receiver,
+};
+
+use crate::{
+    bus::DmaMemory,
+    replay::InputChannel,
+    snapshot::{SnapshotReader, SnapshotWriter},
+    uart::spawn_reader,
+    virtio::{Queue, VirtioDevice, DEVICE_ID_CONSOLE},
+};
+
+// virtio console with a single port, without the multiport and resize features
+
+const VIRTIO_CONSOLE_F_EMERG_WRITE: u64 = 1 << 2;
+
+const RECEIVE_QUEUE: usize = 0;
+const TRANSMIT_QUEUE: usize = 1;
+
+// cols, rows and max_nr_ports come before it in the configuration space
+const EMERG_WR: usize = 8;
+
+pub enum ConsoleBackend {
+    Stdio,
+    // the output is written to the file, nothing is received
+    File(String),
+}
+
+pub struct VirtioConsole {
+    output: Box<dyn Write>,
+    input: Option<Receiver<u8>>,
+    // stdin is shared with the ReadInput syscall, it is only read once the guest posts receive
+    // buffers
+    stdin_unread: bool,
+    // the received bytes can be recorded and replayed
+    host_input: InputChannel,
+    // received by the host, waiting for a receive buffer
+    pending: VecDeque<u8>,
+}
+
+impl VirtioConsole {
+    pub fn new(backend: &ConsoleBackend) -> io::Result<Self> {
+        let (output, stdin_unread): (Box<dyn Write>, _) = match backend {
+            ConsoleBackend::Stdio => (Box::new(io::stdout()), true),
+            ConsoleBackend::File(path) => (Box::new(File::create(path)?), false),
+        };
+
+        Ok(Self {
+            output,
+            input: None,
+            stdin_unread,
+            host_input: InputChannel::default(),
+            pending: VecDeque::new(),
+        })
+    }
+
+    fn poll(&mut self, instret: u64) {
+        if let Some(input) = &self.input {
+            let received = self.host_input.read(instret, || input.try_iter().collect());
+            self.pending.extend(received);
+        }
+    }
+
+    fn output(&mut self, data: &[u8]) {
+        if self.host_input.is_rerun() {
+            return;
+        }
+        // like the uart, the output is lost when the other end is gone
+        let _ = self.output.write_all(data);
+        let _ = self.output.flush();
+    }
+
+    fn receive(&mut self, queue: &mut Queue, memory: &mut DmaMemory, instret: u64) -> bool {
+        if self.stdin_unread {
+            self.stdin_unread = false;
+            self.input = Some(spawn_reader(io::stdin(), false));
+        }
+        self.poll(instret);
+
+        let mut used = false;
+        while !self.pending.is_empty() {
+            let Some(chain) = queue.pop(memory) else {
+                break;
+            };
+
+            let size = chain.writable_len().min(self.pending.len());
+            let data: Vec<u8> = self.pending.drain(..size).collect();
+            let written = chain.write(memory, &data).unwrap_or(0);
+
+            queue.push_used(memory, &chain, written);
+            used = true;
+        }
+
+        used
+    }
+
+    fn transmit(&mut self, queue: &mut Queue, memory: &mut DmaMemory) -> bool {
+        let mut used = false;
+
+        while let Some(chain) = queue.pop(memory) {
+            if let Some(data) = chain.read(memory) {
+                self.output(&data);
+            }
+            queue.push_used(memory, &chain, 0);
+            used = true;
+        }
+
+        used
+    }
+}
+
+impl VirtioDevice for VirtioConsole {
+    fn device_id(&self) -> u32 {
+        DEVICE_ID_CONSOLE
+    }
+
+    fn features(&self) -> u64 {
+        VIRTIO_CONSOLE_F_EMERG_WRITE
+    }
+
+    // receiveq0 and transmitq0
+    fn queue_count(&self) -> usize {
+        2
+    }
+
+    // the size isn't reported and there is a single port
+    fn read_config(&self, _offset: usize) -> u8 {
+        0
+    }
+
+    // the emergency write outputs a character without going through the queues
+    fn write_config(&mut self, offset: usize, value: u8) {
+        if offset == EMERG_WR {
+            self.output(&[value]);
+        }
+    }
+
+    fn process_queue(
+        &mut self,
+        index: usize,
+        queue: &mut Queue,
+        memory: &mut DmaMemory,
+        instret: u64,
+    ) -> bool {
+        match index {
+            RECEIVE_QUEUE => self.receive(queue, memory, instret),
+            TRANSMIT_QUEUE => self.transmit(queue, memory),
+            _ => false,
+        }
+    }
+
+    // bytes received while the driver was resetting are dropped, the guest never sees them so
+    // they aren't recorded
+    fn reset(&mut self) {
+        if let Some(input) = &self.input {
+            input.try_iter().for_each(drop);
+        }
+        self.pending.clear();
+    }
+
+    // the bytes received and not given to the guest yet
+    fn save_state(&self, snapshot: &mut SnapshotWriter) {
+        snapshot.write_bytes(&self.pending.iter().copied().collect::<Vec<u8>>());
+    }
+
+    fn restore_state(&mut self, snapshot: &mut SnapshotReader) -> Result<(), String> {
+        self.pending = snapshot.read_bytes()?.iter().copied().collect();
+        Ok(())
+    }
+
+    fn set_input(&mut self, input: InputChannel) {
+        self.host_input = input;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::virtio::{
+        tests::{offer, queue, set_descriptor, used_len, with_memory},
+        DESC_F_NEXT, DESC_F_WRITE,
+    };
+    use std::sync::mpsc::{self, Sender};
+
+    // console writing to a temporary file, with the host input given to the test
+    fn console(name: &str) -> (VirtioConsole, Sender<u8>, String) {
+        let path = std::env::temp_dir()
+            .join(format!("riscv-console-{}-{name}", std::process::id()))
+            .to_string_lossy()
+            .into_owned();
+        let mut console = VirtioConsole::new(&ConsoleBackend::File(path.clone())).unwrap();
+
+        let (sender, receiver) = mpsc::channel();
+        console.input = Some(receiver);
+        (console, sender, path)
+    }
+
+    fn send(host: &Sender<u8>, data: &[u8]) {
+        data.iter().for_each(|byte| host.send(*byte).unwrap());
+    }
+
+    #[test]
+    fn received_bytes_wait_for_receive_buffers() {
+        let (mut console, host, path) = console("receive");
+        let _ = std::fs::remove_file(path);
+
+        with_memory(|memory| {
+            let mut queue = queue();
+            assert!(!console.process_queue(RECEIVE_QUEUE, &mut queue, memory, 0));
+
+            send(&host, b"hello");
+            set_descriptor(memory, 0, 0x1000, 3, DESC_F_WRITE, 0);
+            set_descriptor(memory, 1, 0x2000, 16, DESC_F_WRITE, 0);
+            offer(memory, 0);
+            assert!(console.process_queue(RECEIVE_QUEUE, &mut queue, memory, 0));
+            assert_eq!(used_len(memory, 0), 3);
+            assert_eq!(memory.read(0x1000, 3).unwrap(), b"hel");
+            assert_eq!(console.pending, b"lo");
+
+            offer(memory, 1);
+            assert!(console.process_queue(RECEIVE_QUEUE, &mut queue, memory, 0));
+            assert_eq!(used_len(memory, 1), 2);
+            assert_eq!(memory.read(0x2000, 2).unwrap(), b"lo");
+
+            // a reset drops what the guest hasn't received
+            send(&host, b"lost");
+            console.poll(0);
+            console.reset();
+            assert!(console.pending.is_empty());
+        });
+    }
+
+    #[test]
+    fn transmitted_buffers_and_emergency_writes_are_output() {
+        let (mut console, _host, path) = console("transmit");
+
+        with_memory(|memory| {
+            let mut queue = queue();
+            assert!(memory.write(0x1000, b"ab"));
+            assert!(memory.write(0x2000, b"cd"));
+            set_descriptor(memory, 0, 0x1000, 2, DESC_F_NEXT, 1);
+            set_descriptor(memory, 1, 0x2000, 2, 0, 0);
+            offer(memory, 0);
+            assert!(console.process_queue(TRANSMIT_QUEUE, &mut queue, memory, 0));
+            assert_eq!(used_len(memory, 0), 0);
+        });
+        console.write_config(EMERG_WR, b'e');
+        // the other fields of the configuration space are read-only
+        console.write_config(0, b'x');
+
+        let output = std::fs::read(&path).unwrap();
+        let _ = std::fs::remove_file(path);
+        assert_eq!(output, b"abcde");
+    }
+}
@@ -0,0 +1,209 @@
+use std::{
+    fs::File,
+    io::{self, Read},
+};
+
+use crate::{
+    bus::DmaMemory,
+    replay::InputChannel,
+    snapshot::{SnapshotReader, SnapshotWriter},
+    virtio::{Queue, VirtioDevice, DEVICE_ID_RNG},
+};
+
+// virtio entropy device
+
+// entropy given per request, the driver asks again for more. Bounds what a request allocates and
+// records from the host
+const MAX_REQUEST_SIZE: usize = 0x1000;
+
+pub enum EntropySource {
+    // xorshift64* generator, the same seed gives the same bytes on every run
+    Seeded(u64),
+    Host,
+}
+
+pub struct VirtioRng {
+    host: Option<File>,
+    // the host entropy can be recorded and replayed
+    host_input: InputChannel,
+    seed: u64,
+    state: u64,
+}
+
+// xorshift gets stuck on 0
+fn initial_state(seed: u64) -> u64 {
+    if seed == 0 {
+        0x9e3779b97f4a7c15
+    } else {
+        seed
+    }
+}
+
+impl VirtioRng {
+    pub fn new(source: &EntropySource) -> io::Result<Self> {
+        let (host, seed) = match source {
+            EntropySource::Seeded(seed) => (None, *seed),
+            EntropySource::Host => (Some(File::open("/dev/urandom")?), 0),
+        };
+
+        Ok(Self {
+            host,
+            host_input: InputChannel::default(),
+            seed,
+            state: initial_state(seed),
+        })
+    }
+
+    fn next(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545f4914f6cdd1d)
+    }
+
+    fn fill(&mut self, data: &mut [u8], instret: u64) -> bool {
+        match &mut self.host {
+            Some(host) => {
+                // a failed read gets nothing
+                let size = data.len();
+                let entropy = self.host_input.read(instret, || {
+                    let mut entropy = vec![0; size];
+                    match host.read_exact(&mut entropy) {
+                        Ok(()) => entropy,
+                        Err(_) => Vec::new(),
+                    }
+                });
+                if entropy.len() != size {
+                    return false;
+                }
+                data.copy_from_slice(&entropy);
+                true
+            }
+            None => {
+                for chunk in data.chunks_mut(8) {
+                    let value = self.next().to_le_bytes();
+                    chunk.copy_from_slice(&value[..chunk.len()]);
+                }
+                true
+            }
+        }
+    }
+}
+
+impl VirtioDevice for VirtioRng {
+    fn device_id(&self) -> u32 {
+        DEVICE_ID_RNG
+    }
+
+    fn features(&self) -> u64 {
+        0
+    }
+
+    // requestq
+    fn queue_count(&self) -> usize {
+        1
+    }
+
+    fn read_config(&self, _offset: usize) -> u8 {
+        0
+    }
+
+    // the buffers are filled completely, up to MAX_REQUEST_SIZE bytes per request
+    fn process_queue(
+        &mut self,
+        _index: usize,
+        queue: &mut Queue,
+        memory: &mut DmaMemory,
+        instret: u64,
+    ) -> bool {
+        let mut used = false;
+
+        while let Some(chain) = queue.pop(memory) {
+            let mut data = vec![0; chain.writable_len().min(MAX_REQUEST_SIZE)];
+            let written = if self.fill(&mut data, instret) {
+                chain.write(memory, &data).unwrap_or(0)
+            } else {
+                0
+            };
+
+            queue.push_used(memory, &chain, written);
+            used = true;
+        }
+
+        used
+    }
+
+    // the sequence starts over, so a reset machine sees the same bytes again
+    fn reset(&mut self) {
+        self.state = initial_state(self.seed);
+    }
+
+    // where the generator is in its sequence, host entropy stays random
+    fn save_state(&self, snapshot: &mut SnapshotWriter) {
+        snapshot.write_u64(self.state);
+    }
+
+    fn restore_state(&mut self, snapshot: &mut SnapshotReader) -> Result<(), String> {
+        self.state = snapshot.read_u64()?;
+        Ok(())
+    }
+
+    fn set_input(&mut self, input: InputChannel) {
+        self.host_input = input;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::virtio::{
+        tests::{offer, queue, set_descriptor, used_len, with_memory},
+        DESC_F_WRITE,
+    };
+
+    // the bytes given to one request of the size
+    fn request(rng: &mut VirtioRng, size: usize) -> Vec<u8> {
+        let mut data = Vec::new();
+        with_memory(|memory| {
+            let mut queue = queue();
+            set_descriptor(memory, 0, 0x1000, size as u32, DESC_F_WRITE, 0);
+            offer(memory, 0);
+            assert!(rng.process_queue(0, &mut queue, memory, 0));
+
+            let len = used_len(memory, 0) as usize;
+            data = memory.read(0x1000, len).unwrap();
+        });
+        data
+    }
+
+    #[test]
+    fn seeded_bytes_are_the_same_on_every_run_and_after_a_reset() {
+        let mut rng = VirtioRng::new(&EntropySource::Seeded(42)).unwrap();
+        let first = request(&mut rng, 13);
+        let second = request(&mut rng, 64);
+        assert_eq!(first.len(), 13);
+        assert_ne!(first, second[..13]);
+
+        let mut other = VirtioRng::new(&EntropySource::Seeded(42)).unwrap();
+        assert_eq!(request(&mut other, 13), first);
+        let mut other = VirtioRng::new(&EntropySource::Seeded(43)).unwrap();
+        assert_ne!(request(&mut other, 13), first);
+
+        rng.reset();
+        assert_eq!(request(&mut rng, 13), first);
+        assert_eq!(request(&mut rng, 64), second);
+
+        // a zero seed doesn't get stuck
+        let mut zero = VirtioRng::new(&EntropySource::Seeded(0)).unwrap();
+        assert!(request(&mut zero, 16).iter().any(|byte| *byte != 0));
+    }
+
+    #[test]
+    fn requests_get_at_most_a_page() {
+        let mut rng = VirtioRng::new(&EntropySource::Seeded(1)).unwrap();
+        assert_eq!(
+            request(&mut rng, 3 * MAX_REQUEST_SIZE).len(),
+            MAX_REQUEST_SIZE
+        );
+    }
+}
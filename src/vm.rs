@@ -1,58 +1,340 @@
 use std::{
-    io::{self, Read},
-    process::exit,
+    io::{self, Read, Write},
+    rc::Rc,
+    time::Instant,
 };
 
 use crate::{
+    block_cache::{Block, BlockCache, MicroOp},
+    bus::{Bus, MachineRequest},
+    clint::{Clint, TimeSource, CLINT_ADDRESS},
+    csr::{self, Csrs, Exception, Privilege},
+    decode_cache::DecodeCache,
     instruction_decoder::decode,
-    instructions::{BOpcode, IOpcode, InstructionFormat, JOpcode, ROpcode, SOpcode, UOpcode},
-    memory::Memory,
+    instructions::{
+        AmoOpcode, BOpcode, CsrOpcode, IOpcode, InstructionFormat, JOpcode, ROpcode, SOpcode,
+        UOpcode,
+    },
+    plic::{Plic, CONTEXTS_PER_HART, MACHINE_CONTEXT, PLIC_ADDRESS, SUPERVISOR_CONTEXT},
+    profiler::Profiler,
     register::Register,
+    replay::{Input, InputLog},
+    stop_conditions::{StopConditions, StopReason},
     syscalls::Syscalls,
-    utils::sign_extend_number,
+    tlb::Tlb,
+    utils::{div, divu, mulh, mulhsu, mulhu, rem, remu, sign_extend_number},
 };
 
+use self::{
+    harts::Hart,
+    micro_ops::Terminator,
+    mmu::{crosses_page, Access},
+};
+
+pub use self::harts::DEFAULT_QUANTUM;
+
+mod harts;
+#[cfg(feature = "jit")]
+mod jit_helpers;
+mod micro_ops;
+mod mmu;
+mod snapshot;
+
 const MEMORY_SIZE: usize = 0x4000;
 
-const FLASH_ADDRESS: usize = 0x40000;
+pub const FLASH_ADDRESS: usize = 0x40000;
 const FLASH_INTERRUPT_TABLE_ADDRESS: usize = FLASH_ADDRESS;
 const FLASH_INTERRUPT_TABLE_RESET_ADDRESS: usize = FLASH_INTERRUPT_TABLE_ADDRESS;
 
 // 16 byte aligned
 const STACK_ADDRESS: usize = 0xfffffff0;
 
+// the timeout is only checked every so many instructions, reading the clock is not free
+const TIMEOUT_CHECK_INTERVAL: u64 = 0x1000;
+
+// longer straight-line sequences are split in several blocks
+const MAX_BLOCK_SIZE: usize = 64;
+
+// interrupt sources of the default PLIC
+const PLIC_SOURCES: u32 = 32;
+
+// the devices are ticked this often
+const DEVICE_TICK_INTERVAL: u64 = 0x1000;
+
 pub struct VM {
-    regs: Vec<Register>,
+    // registers, csrs, privilege, reservation and tlb of the running hart
+    hart: usize,
+    // x0 is kept at zero by set_register_value
+    regs: [u32; 32],
     pc: Register,
-    flash: Memory,
-    stack: Memory,
+    // flash, stack, interrupt controllers and devices
+    bus: Bus,
+    // set by the exit syscall, a power-off device or tohost, stops the execution loop
+    exit_code: Option<i32>,
+    // HTIF mailbox in plain memory, its writes go through device_write
+    tohost: Option<usize>,
+    // first instruction when the machine has no reset vector
+    entry: Option<u32>,
+    // registers set at every reset, for the boot protocols passing arguments to the firmware
+    boot_registers: Vec<(u32, u32)>,
+    hart_id_register: Option<u32>,
+    // number of instructions retired by all the harts, the time of the machine
+    instret: u64,
+    // instructions retired by the other harts, the running hart retired the rest
+    instret_offset: u64,
+    // instruction count of the next regular device tick, kept across start_execution calls so a
+    // run goes the same way however it is stopped and resumed
+    next_regular_tick: u64,
+    // regular ticks at their exact instruction count rather than after the block running then
+    exact_ticks: bool,
+    stop_conditions: StopConditions,
+    // stop address the execution stopped at, it doesn't stop there again when resumed
+    resumed_stop: Option<u32>,
+    // watched address written by the last instruction
+    watch_hit: Option<u32>,
+    csrs: Csrs,
+    privilege: Privilege,
+    // physical address reserved by lr.w
+    reservation: Option<usize>,
+    // translations of supervisor and user mode
+    tlb: Tlb,
+    // parked harts, indexed by hart id
+    harts: Vec<Hart>,
+    quantum: u64,
+    // instruction count at which the next hart runs
+    next_switch: u64,
+    decode_cache: DecodeCache,
+    block_cache: BlockCache,
+    // block being discovered by step, translated once complete
+    recording_start: u32,
+    recording: Vec<MicroOp>,
+    // set when translated code has to return to the execution loop, device accesses and csr
+    // instructions are executed there so they see an exact instruction count
+    leave_blocks: bool,
+    // 0: guest output only, 1: emulator diagnostics, 2: instruction trace
+    verbosity: u8,
+    // attributes the retired instructions to functions, the execution is interpreted then
+    profiler: Option<Profiler>,
+    #[cfg(feature = "jit")]
+    jit_enabled: bool,
+    #[cfg(feature = "jit")]
+    jit_compiled: u64,
 }
 
 impl VM {
     pub fn new(flash_data: Vec<u8>) -> Self {
         assert!(flash_data.len() < MEMORY_SIZE);
 
-        let mut registers = Vec::<Register>::new();
-        for i in 0..32 {
-            registers.push(Register::new(0, i, format!("x{}", i)));
-        }
+        let mut vm = Self::without_memory();
+        vm.bus.add_ram("flash", FLASH_ADDRESS, flash_data).unwrap();
+        vm.bus
+            .add_ram("stack", STACK_ADDRESS - MEMORY_SIZE, vec![0; MEMORY_SIZE])
+            .unwrap();
+        vm
+    }
 
-        let stack = vec![0; MEMORY_SIZE];
+    // machine with the interrupt controllers only, its memory is added through the bus and it
+    // starts at the address given to set_entry instead of the reset vector of the flash
+    pub fn without_memory() -> Self {
+        let clint = Clint::new(CLINT_ADDRESS, TimeSource::Instructions, 1);
+        let plic = Plic::new(PLIC_ADDRESS, PLIC_SOURCES, CONTEXTS_PER_HART);
+        let bus = Bus::new(clint, plic).unwrap();
+        let tlb = Tlb::default();
 
         Self {
-            regs: registers,
+            hart: 0,
+            regs: [0; 32],
             pc: Register::new(0, 90, "pc".to_string()),
-            flash: Memory::new(FLASH_ADDRESS, flash_data),
-            stack: Memory::new(STACK_ADDRESS - MEMORY_SIZE, stack),
+            bus,
+            exit_code: None,
+            tohost: None,
+            entry: None,
+            boot_registers: Vec::new(),
+            hart_id_register: None,
+            instret: 0,
+            instret_offset: 0,
+            next_regular_tick: 0,
+            exact_ticks: false,
+            stop_conditions: StopConditions::new(),
+            resumed_stop: None,
+            watch_hit: None,
+            csrs: Csrs::new(0),
+            privilege: Privilege::Machine,
+            reservation: None,
+            harts: vec![Hart::new(0, &tlb)],
+            tlb,
+            quantum: DEFAULT_QUANTUM,
+            next_switch: 0,
+            decode_cache: DecodeCache::new(),
+            block_cache: BlockCache::new(),
+            recording_start: 0,
+            recording: Vec::new(),
+            leave_blocks: false,
+            verbosity: 0,
+            profiler: None,
+            #[cfg(feature = "jit")]
+            jit_enabled: true,
+            #[cfg(feature = "jit")]
+            jit_compiled: 0,
+        }
+    }
+
+    pub fn set_stop_conditions(&mut self, stop_conditions: StopConditions) {
+        // blocks end before stop addresses, they have to be translated again
+        if stop_conditions.get_stop_addresses() != self.stop_conditions.get_stop_addresses() {
+            self.block_cache.flush();
+            self.recording.clear();
+        }
+        self.stop_conditions = stop_conditions;
+    }
+
+    pub fn get_bus(&self) -> &Bus {
+        &self.bus
+    }
+
+    // memory, interrupt controllers and devices are configured through the bus
+    pub fn get_bus_mut(&mut self) -> &mut Bus {
+        &mut self.bus
+    }
+
+    // address of the 64-bit tohost variable, usually found in the ELF symbols
+    pub fn set_tohost(&mut self, tohost: Option<u32>) {
+        self.tohost = tohost.map(|tohost| tohost as usize);
+    }
+
+    pub fn set_entry(&mut self, entry: u32) {
+        self.entry = Some(entry);
+    }
+
+    pub fn set_boot_register(&mut self, register: u32, value: u32) {
+        self.boot_registers.retain(|(other, _)| *other != register);
+        self.boot_registers.push((register, value));
+    }
+
+    // asserts or deasserts an interrupt line of the PLIC, for interrupt sources that aren't
+    // attached devices
+    pub fn set_interrupt_line(&mut self, source: u32, asserted: bool) {
+        self.bus.get_plic_mut().set_level(source, asserted);
+    }
+
+    // records the inputs of the host or replays a recording, set before the execution starts
+    pub fn set_input_log(&mut self, log: InputLog) {
+        self.bus.set_input_log(log);
+    }
+
+    // the execution then goes the same way whatever the engine and wherever it stops, at the cost
+    // of interpreting the end of a block before every tick
+    pub fn set_exact_ticks(&mut self, exact: bool) {
+        self.exact_ticks = exact;
+    }
+
+    pub fn set_decode_cache(&mut self, enabled: bool) {
+        self.decode_cache.set_enabled(enabled);
+    }
+
+    pub fn get_decode_cache(&self) -> &DecodeCache {
+        &self.decode_cache
+    }
+
+    pub fn set_tlb(&mut self, entries: usize, ways: usize) -> Result<(), String> {
+        self.set_hart_tlbs(entries, ways)
+    }
+
+    // tlb of the running hart
+    pub fn get_tlb(&self) -> &Tlb {
+        &self.tlb
+    }
+
+    pub fn set_block_cache(&mut self, enabled: bool) {
+        self.block_cache.set_enabled(enabled);
+        self.recording.clear();
+    }
+
+    pub fn get_block_cache(&self) -> &BlockCache {
+        &self.block_cache
+    }
+
+    // compiled code lives in the translated blocks, they are dropped with it
+    #[cfg(feature = "jit")]
+    pub fn set_jit(&mut self, enabled: bool) {
+        self.jit_enabled = enabled;
+        self.block_cache.flush();
+        self.recording.clear();
+    }
+
+    #[cfg(feature = "jit")]
+    pub fn get_jit_compiled(&self) -> u64 {
+        self.jit_compiled
+    }
+
+    pub fn get_pc(&self) -> u32 {
+        self.pc.get_value()
+    }
+
+    pub fn get_registers(&self) -> Vec<u32> {
+        self.regs.to_vec()
+    }
+
+    // registers of the running hart changed by a debugger, x0 stays zero
+    pub fn set_register(&mut self, register: u32, value: u32) {
+        self.set_register_value(register, value);
+    }
+
+    pub fn set_pc(&mut self, pc: u32) {
+        self.pc.set_value(pc);
+    }
+
+    // the next start_execution doesn't stop at a stop address at the current pc, for debuggers
+    // resuming from a breakpoint they didn't stop at
+    pub fn resume_past_stop_address(&mut self) {
+        self.resumed_stop = Some(self.pc.get_value());
+    }
+
+    pub fn get_instret(&self) -> u64 {
+        self.instret
+    }
+
+    pub fn get_privilege(&self) -> Privilege {
+        self.privilege
+    }
+
+    pub fn set_verbosity(&mut self, verbosity: u8) {
+        self.verbosity = verbosity;
+        // the trace is compiled in the blocks
+        self.block_cache.flush();
+        self.recording.clear();
+    }
+
+    pub fn set_profiler(&mut self, profiler: Option<Profiler>) {
+        self.profiler = profiler;
+    }
+
+    pub fn get_profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_ref()
+    }
+
+    pub fn dump_registers(&self) {
+        for hart in 0..self.harts.len() {
+            if self.harts.len() > 1 {
+                eprintln!("hart {hart}:");
+            }
+            let (pc, regs) = self.hart_registers(hart);
+            eprintln!("{} = {:08x}", self.pc.get_name(), pc);
+            for (i, value) in regs.iter().enumerate() {
+                eprintln!("{:>3} = {:08x}", format!("x{i}"), value);
+            }
         }
     }
 
     fn get_register_value(&self, register_index: u32) -> u32 {
-        self.regs[register_index as usize].get_value()
+        self.regs[register_index as usize]
     }
 
     fn set_register_value(&mut self, register_index: u32, value: u32) {
-        self.regs[register_index as usize].set_value(value);
+        // x0 is always zero, jalr sets the result in x0 as a way of ignoring
+        if register_index != 0 {
+            self.regs[register_index as usize] = value;
+        }
     }
 
     fn execute_instruction_r(&mut self, opcode: ROpcode) -> bool {
@@ -161,10 +443,74 @@ impl VM {
 
                 self.set_register_value(helper.get_dest(), result as u32)
             }
+            ROpcode::Mul(helper) => {
+                let src1_value = self.get_register_value(helper.get_src1());
+                let src2_value = self.get_register_value(helper.get_src2());
+
+                let result = src1_value.wrapping_mul(src2_value);
+
+                self.set_register_value(helper.get_dest(), result)
+            }
+            ROpcode::Mulh(helper) => {
+                let src1_value = self.get_register_value(helper.get_src1());
+                let src2_value = self.get_register_value(helper.get_src2());
+
+                let result = mulh(src1_value, src2_value);
+
+                self.set_register_value(helper.get_dest(), result)
+            }
+            ROpcode::Mulhsu(helper) => {
+                let src1_value = self.get_register_value(helper.get_src1());
+                let src2_value = self.get_register_value(helper.get_src2());
+
+                let result = mulhsu(src1_value, src2_value);
+
+                self.set_register_value(helper.get_dest(), result)
+            }
+            ROpcode::Mulhu(helper) => {
+                let src1_value = self.get_register_value(helper.get_src1());
+                let src2_value = self.get_register_value(helper.get_src2());
+
+                let result = mulhu(src1_value, src2_value);
+
+                self.set_register_value(helper.get_dest(), result)
+            }
+            ROpcode::Div(helper) => {
+                let src1_value = self.get_register_value(helper.get_src1());
+                let src2_value = self.get_register_value(helper.get_src2());
+
+                let result = div(src1_value, src2_value);
+
+                self.set_register_value(helper.get_dest(), result)
+            }
+            ROpcode::Divu(helper) => {
+                let src1_value = self.get_register_value(helper.get_src1());
+                let src2_value = self.get_register_value(helper.get_src2());
+
+                let result = divu(src1_value, src2_value);
+
+                self.set_register_value(helper.get_dest(), result)
+            }
+            ROpcode::Rem(helper) => {
+                let src1_value = self.get_register_value(helper.get_src1());
+                let src2_value = self.get_register_value(helper.get_src2());
+
+                let result = rem(src1_value, src2_value);
+
+                self.set_register_value(helper.get_dest(), result)
+            }
+            ROpcode::Remu(helper) => {
+                let src1_value = self.get_register_value(helper.get_src1());
+                let src2_value = self.get_register_value(helper.get_src2());
+
+                let result = remu(src1_value, src2_value);
+
+                self.set_register_value(helper.get_dest(), result)
+            }
         }
         false
     }
-    fn execute_instruction_i(&mut self, opcode: IOpcode) -> bool {
+    fn execute_instruction_i(&mut self, opcode: IOpcode) -> Result<bool, Exception> {
         let mut pc_changed = false;
 
         match opcode {
@@ -217,7 +563,7 @@ impl VM {
                 let imm_value = helper.get_imm();
 
                 let address = src_value.overflowing_add(imm_value).0;
-                let result = self.read_u32(address as usize);
+                let result = self.load(address, 4)?;
 
                 self.set_register_value(helper.get_dst(), result)
             }
@@ -226,7 +572,7 @@ impl VM {
                 let imm_value = helper.get_imm();
 
                 let address = src_value.overflowing_add(imm_value).0;
-                let result = sign_extend_number(self.read_u16(address as usize) as u32, 16);
+                let result = sign_extend_number(self.load(address, 2)?, 16);
 
                 self.set_register_value(helper.get_dst(), result)
             }
@@ -235,7 +581,7 @@ impl VM {
                 let imm_value = helper.get_imm();
 
                 let address = src_value.overflowing_add(imm_value).0;
-                let result = self.read_u16(address as usize) as u32;
+                let result = self.load(address, 2)?;
 
                 self.set_register_value(helper.get_dst(), result)
             }
@@ -244,7 +590,7 @@ impl VM {
                 let imm_value = helper.get_imm();
 
                 let address = src_value.overflowing_add(imm_value).0;
-                let result = sign_extend_number(self.read_u8(address as usize) as u32, 8);
+                let result = sign_extend_number(self.load(address, 1)?, 8);
 
                 self.set_register_value(helper.get_dst(), result)
             }
@@ -253,7 +599,7 @@ impl VM {
                 let imm_value = helper.get_imm();
 
                 let address = src_value.overflowing_add(imm_value).0;
-                let result = self.read_u8(address as usize) as u32;
+                let result = self.load(address, 1)?;
 
                 self.set_register_value(helper.get_dst(), result)
             }
@@ -265,7 +611,7 @@ impl VM {
                 // save return address
                 self.set_register_value(helper.get_dst(), self.pc.get_value() + 4);
 
-                let target = src_value + imm_value;
+                let target = src_value.overflowing_add(imm_value).0;
                 // set the least-significant bit to zero
                 let target = target & !1;
 
@@ -275,9 +621,9 @@ impl VM {
             }
         }
 
-        pc_changed
+        Ok(pc_changed)
     }
-    fn execute_instruction_s(&mut self, opcode: SOpcode) -> bool {
+    fn execute_instruction_s(&mut self, opcode: SOpcode) -> Result<bool, Exception> {
         match opcode {
             SOpcode::Sw(helper) => {
                 let src_value = self.get_register_value(helper.get_src());
@@ -286,7 +632,7 @@ impl VM {
                 let imm_value = helper.get_offset();
 
                 let address = base_value.overflowing_add(imm_value).0;
-                self.write_u32(address as usize, src_value)
+                self.store(address, 4, src_value)?
             }
             SOpcode::Sh(helper) => {
                 let src_value = self.get_register_value(helper.get_src()) & 0xffff;
@@ -294,8 +640,8 @@ impl VM {
                 let base_value = self.get_register_value(helper.get_base());
                 let imm_value = helper.get_offset();
 
-                let address = base_value + imm_value;
-                self.write_u16(address as usize, src_value as u16)
+                let address = base_value.overflowing_add(imm_value).0;
+                self.store(address, 2, src_value)?
             }
             SOpcode::Sb(helper) => {
                 let src_value = self.get_register_value(helper.get_src()) & 0xff;
@@ -303,11 +649,86 @@ impl VM {
                 let base_value = self.get_register_value(helper.get_base());
                 let imm_value = helper.get_offset();
 
-                let address = base_value + imm_value;
-                self.write_u8(address as usize, src_value as u8)
+                let address = base_value.overflowing_add(imm_value).0;
+                self.store(address, 1, src_value)?
             }
         }
-        false
+        Ok(false)
+    }
+
+    // the reservation of lr.w is a physical address, sc.w only succeeds on it and always clears it
+    fn execute_instruction_amo(&mut self, opcode: AmoOpcode) -> Result<bool, Exception> {
+        let (AmoOpcode::LrW(helper)
+        | AmoOpcode::ScW(helper)
+        | AmoOpcode::AmoswapW(helper)
+        | AmoOpcode::AmoaddW(helper)
+        | AmoOpcode::AmoxorW(helper)
+        | AmoOpcode::AmoandW(helper)
+        | AmoOpcode::AmoorW(helper)
+        | AmoOpcode::AmominW(helper)
+        | AmoOpcode::AmomaxW(helper)
+        | AmoOpcode::AmominuW(helper)
+        | AmoOpcode::AmomaxuW(helper)) = opcode;
+
+        let address = self.get_register_value(helper.get_src1());
+        let src_value = self.get_register_value(helper.get_src2());
+
+        // atomics are never split, misaligned ones can't be emulated
+        if !address.is_multiple_of(4) {
+            let cause = match opcode {
+                AmoOpcode::LrW(_) => csr::CAUSE_LOAD_ADDRESS_MISALIGNED,
+                _ => csr::CAUSE_STORE_ADDRESS_MISALIGNED,
+            };
+            return Err(Exception::new(cause, address));
+        }
+
+        let value = match opcode {
+            AmoOpcode::LrW(_) => {
+                let physical = self.translate(address, 4, Access::Load)?;
+                let value = self
+                    .read_u32(physical)
+                    .ok_or(Exception::new(csr::CAUSE_LOAD_ACCESS_FAULT, address))?;
+                self.reservation = Some(physical);
+                value
+            }
+            AmoOpcode::ScW(_) => {
+                let physical = self.translate(address, 4, Access::Store)?;
+                let reserved = self.reservation.take() == Some(physical);
+                if reserved {
+                    if !self.write_u32(physical, src_value) {
+                        return Err(Exception::new(csr::CAUSE_STORE_ACCESS_FAULT, address));
+                    }
+                    self.watch_store(address, 4);
+                }
+                !reserved as u32
+            }
+            _ => {
+                let physical = self.translate(address, 4, Access::Store)?;
+                // amos fault as stores, even on the read
+                let fault = Exception::new(csr::CAUSE_STORE_ACCESS_FAULT, address);
+                let old = self.read_u32(physical).ok_or(fault)?;
+                let new = match opcode {
+                    AmoOpcode::AmoaddW(_) => old.wrapping_add(src_value),
+                    AmoOpcode::AmoxorW(_) => old ^ src_value,
+                    AmoOpcode::AmoandW(_) => old & src_value,
+                    AmoOpcode::AmoorW(_) => old | src_value,
+                    AmoOpcode::AmominW(_) => (old as i32).min(src_value as i32) as u32,
+                    AmoOpcode::AmomaxW(_) => (old as i32).max(src_value as i32) as u32,
+                    AmoOpcode::AmominuW(_) => old.min(src_value),
+                    AmoOpcode::AmomaxuW(_) => old.max(src_value),
+                    _ => src_value,
+                };
+                if !self.write_u32(physical, new) {
+                    return Err(fault);
+                }
+                self.watch_store(address, 4);
+                old
+            }
+        };
+
+        self.set_register_value(helper.get_dest(), value);
+
+        Ok(false)
     }
     fn execute_instruction_b(&mut self, opcode: BOpcode) -> bool {
         let mut pc_changed = false;
@@ -330,7 +751,7 @@ impl VM {
 
                 if src1_value != src2_value {
                     let offset_value = helper.get_offset();
-                    let target = self.pc.get_value() + offset_value;
+                    let target = self.pc.get_value().overflowing_add(offset_value).0;
                     self.pc.set_value(target);
 
                     pc_changed = true;
@@ -342,7 +763,7 @@ impl VM {
 
                 if src1_value >= src2_value {
                     let offset_value = helper.get_offset();
-                    let target = self.pc.get_value() + offset_value;
+                    let target = self.pc.get_value().overflowing_add(offset_value).0;
                     self.pc.set_value(target);
 
                     pc_changed = true;
@@ -354,7 +775,7 @@ impl VM {
 
                 if src1_value >= src2_value {
                     let offset_value = helper.get_offset();
-                    let target = self.pc.get_value() + offset_value;
+                    let target = self.pc.get_value().overflowing_add(offset_value).0;
                     self.pc.set_value(target);
 
                     pc_changed = true;
@@ -366,7 +787,7 @@ impl VM {
 
                 if src1_value < src2_value {
                     let offset_value = helper.get_offset();
-                    let target = self.pc.get_value() + offset_value;
+                    let target = self.pc.get_value().overflowing_add(offset_value).0;
                     self.pc.set_value(target);
 
                     pc_changed = true;
@@ -378,7 +799,7 @@ impl VM {
 
                 if src1_value < src2_value {
                     let offset_value = helper.get_offset();
-                    let target = self.pc.get_value() + offset_value;
+                    let target = self.pc.get_value().overflowing_add(offset_value).0;
                     self.pc.set_value(target);
 
                     pc_changed = true;
@@ -392,7 +813,7 @@ impl VM {
             // todo these might be buggy, not sure
             UOpcode::Auipc(helper) => {
                 let imm = helper.get_imm();
-                let target = self.pc.get_value() + imm;
+                let target = self.pc.get_value().overflowing_add(imm).0;
 
                 self.set_register_value(helper.get_dest(), target);
             }
@@ -443,160 +864,1052 @@ impl VM {
     }
 
     fn execute_ecall(&mut self) -> bool {
-        let syscall_id = self.regs[10].get_value();
+        let syscall_id = self.regs[10];
 
         match Syscalls::from_u32(syscall_id) {
             Syscalls::ReadInput => {
-                let address = self.regs[11].get_value() as usize;
-                let size = self.regs[12].get_value();
+                let address = self.regs[11] as usize;
+                let size = self.regs[12];
 
                 // sanitity check
                 if size as usize > MEMORY_SIZE {
                     panic!("Requesting more data than the memory can hold");
                 }
 
-                let input = VM::read_input(size);
+                let input = self
+                    .bus
+                    .get_input_log()
+                    .read(Input::Syscall, self.instret, || VM::read_input(size));
                 self.write_n(address, input);
             }
             Syscalls::Exit => {
-                let exit_code = self.regs[11].get_value() as i32;
-                println!("exit({exit_code})");
-                exit(exit_code);
+                let exit_code = self.regs[11] as i32;
+                self.exit_code = Some(exit_code);
             }
+            // a rerun already wrote its output
+            Syscalls::Puts | Syscalls::Eputs if self.bus.get_input_log().is_rerun() => {}
             Syscalls::Puts => {
-                let address = self.regs[11].get_value() as usize;
-                let size = self.regs[12].get_value() as usize;
+                let address = self.regs[11] as usize;
+                let size = self.regs[12] as usize;
 
                 let data = self.read_n(address, size);
 
-                for b in data {
-                    print!("{}", b as char);
-                }
+                // guest output is written as is, flushed so it interleaves correctly with stderr
+                let mut stdout = io::stdout();
+                stdout.write_all(&data).unwrap();
+                stdout.flush().unwrap();
+            }
+            Syscalls::Eputs => {
+                let address = self.regs[11] as usize;
+                let size = self.regs[12] as usize;
+
+                let data = self.read_n(address, size);
 
-                println!();
+                io::stderr().write_all(&data).unwrap();
             }
         }
 
         false
     }
 
+    // csrs above the current privilege, satp when trapped by tvm and the counters not enabled by
+    // counteren raise illegal instruction exceptions
+    fn check_csr_access(&self, csr: u32) -> Result<(), Exception> {
+        let mstatus = self.csrs.get_mstatus();
+        let trapped = match csr {
+            csr::SATP => self.privilege == Privilege::Supervisor && mstatus & csr::MSTATUS_TVM != 0,
+            csr::CYCLE..=0xc1f | csr::CYCLEH..=0xc9f => {
+                !self.csrs.counter_enabled(csr, self.privilege)
+            }
+            _ => false,
+        };
+
+        if self.privilege < Privilege::of_csr(csr) || trapped {
+            return Err(Exception::illegal_instruction());
+        }
+        Ok(())
+    }
+
+    fn read_csr(&mut self, csr: u32) -> Result<u32, Exception> {
+        self.check_csr_access(csr)?;
+
+        let value = match csr {
+            csr::MCYCLE | csr::CYCLE => self.instret as u32,
+            csr::MCYCLEH | csr::CYCLEH => (self.instret >> 32) as u32,
+            csr::MINSTRET | csr::INSTRET => self.get_hart_instret() as u32,
+            csr::MINSTRETH | csr::INSTRETH => (self.get_hart_instret() >> 32) as u32,
+            csr::TIME => self.bus.get_clint().read_mtime(self.instret) as u32,
+            csr::TIMEH => (self.bus.get_clint().read_mtime(self.instret) >> 32) as u32,
+            csr::MIP | csr::SIP => {
+                self.update_pending_interrupts();
+                self.csrs.read(csr).unwrap()
+            }
+            _ => match self.csrs.read(csr) {
+                Some(value) => value,
+                None => return Err(self.invalid_csr(csr)),
+            },
+        };
+
+        Ok(value)
+    }
+
+    // unknown csrs and writes to read-only ones are illegal instructions
+    fn invalid_csr(&self, csr: u32) -> Exception {
+        if self.verbosity > 0 {
+            eprintln!(
+                "riscv: invalid access to csr {:x} at {:x}",
+                csr,
+                self.pc.get_value()
+            );
+        }
+        Exception::illegal_instruction()
+    }
+
+    fn write_csr(&mut self, csr: u32, value: u32) -> Result<(), Exception> {
+        if Csrs::is_read_only(csr) {
+            return Err(self.invalid_csr(csr));
+        }
+
+        match csr {
+            // one cycle per instruction, the counters follow instret and ignore writes
+            csr::MCYCLE | csr::MINSTRET | csr::MCYCLEH | csr::MINSTRETH => {}
+            _ => {
+                if !self.csrs.write(csr, value) {
+                    return Err(self.invalid_csr(csr));
+                }
+            }
+        }
+
+        // the cached translations belong to the previous address space
+        if csr == csr::SATP {
+            self.tlb.flush_all();
+        }
+
+        Ok(())
+    }
+
+    fn execute_csr(&mut self, opcode: CsrOpcode) -> Result<bool, Exception> {
+        let (helper, value, write) = match opcode {
+            CsrOpcode::Csrrw(helper) | CsrOpcode::Csrrs(helper) | CsrOpcode::Csrrc(helper) => {
+                // csrrs and csrrc with x0 only read
+                let write = matches!(opcode, CsrOpcode::Csrrw(_)) || helper.get_src() != 0;
+                (helper, self.get_register_value(helper.get_src()), write)
+            }
+            CsrOpcode::Csrrwi(helper) | CsrOpcode::Csrrsi(helper) | CsrOpcode::Csrrci(helper) => {
+                let write = matches!(opcode, CsrOpcode::Csrrwi(_)) || helper.get_src() != 0;
+                (helper, helper.get_src(), write)
+            }
+        };
+
+        let old = self.read_csr(helper.get_csr())?;
+
+        if write {
+            let new = match opcode {
+                CsrOpcode::Csrrw(_) | CsrOpcode::Csrrwi(_) => value,
+                CsrOpcode::Csrrs(_) | CsrOpcode::Csrrsi(_) => old | value,
+                CsrOpcode::Csrrc(_) | CsrOpcode::Csrrci(_) => old & !value,
+            };
+            self.write_csr(helper.get_csr(), new)?;
+        }
+
+        self.set_register_value(helper.get_dst(), old);
+
+        Ok(false)
+    }
+
+    // machine mode ecalls are the syscalls of the emulator, the other modes trap to their
+    // handler
+    fn execute_ecall_instruction(&mut self) -> Result<bool, Exception> {
+        match self.privilege {
+            Privilege::Machine => Ok(self.execute_ecall()),
+            Privilege::Supervisor => Err(Exception::new(csr::CAUSE_SUPERVISOR_ECALL, 0)),
+            Privilege::User => Err(Exception::new(csr::CAUSE_USER_ECALL, 0)),
+        }
+    }
+
+    fn execute_mret(&mut self) -> Result<bool, Exception> {
+        if self.privilege != Privilege::Machine {
+            return Err(Exception::illegal_instruction());
+        }
+
+        let mstatus = self.csrs.get_mstatus();
+
+        // mie is restored from mpie, mpie is set
+        let mut restored = mstatus & !csr::MSTATUS_MIE | csr::MSTATUS_MPIE;
+        if mstatus & csr::MSTATUS_MPIE != 0 {
+            restored |= csr::MSTATUS_MIE;
+        }
+
+        // the privilege comes from mpp, which goes back to user mode
+        let previous = self.csrs.get_mpp();
+        if previous != Privilege::Machine {
+            restored &= !csr::MSTATUS_MPRV;
+        }
+        self.csrs.set_mstatus(restored);
+        self.csrs.set_mpp(Privilege::User);
+        self.privilege = previous;
+
+        self.pc.set_value(self.csrs.get_mepc());
+
+        Ok(true)
+    }
+
+    fn execute_sret(&mut self) -> Result<bool, Exception> {
+        let mstatus = self.csrs.get_mstatus();
+        let trapped = self.privilege == Privilege::Supervisor && mstatus & csr::MSTATUS_TSR != 0;
+        if self.privilege == Privilege::User || trapped {
+            return Err(Exception::illegal_instruction());
+        }
+
+        // sie is restored from spie, spie is set and spp goes back to user mode
+        let mut restored = mstatus & !(csr::MSTATUS_SIE | csr::MSTATUS_SPP | csr::MSTATUS_MPRV)
+            | csr::MSTATUS_SPIE;
+        if mstatus & csr::MSTATUS_SPIE != 0 {
+            restored |= csr::MSTATUS_SIE;
+        }
+        self.csrs.set_mstatus(restored);
+
+        self.privilege = if mstatus & csr::MSTATUS_SPP != 0 {
+            Privilege::Supervisor
+        } else {
+            Privilege::User
+        };
+
+        self.pc.set_value(self.csrs.get_sepc());
+
+        Ok(true)
+    }
+
+    // wfi completes at once and lets the next hart run, tw makes it trap below machine mode
+    fn execute_wfi(&mut self) -> Result<bool, Exception> {
+        let trapped = self.csrs.get_mstatus() & csr::MSTATUS_TW != 0;
+        if self.privilege != Privilege::Machine && trapped {
+            return Err(Exception::illegal_instruction());
+        }
+        self.yield_hart();
+        Ok(false)
+    }
+
+    // x0 as the address or asid register selects every address or address space
+    fn execute_sfence_vma(&mut self, address: u32, asid: u32) -> Result<bool, Exception> {
+        let trapped = self.csrs.get_mstatus() & csr::MSTATUS_TVM != 0;
+        if self.privilege == Privilege::User || self.privilege == Privilege::Supervisor && trapped {
+            return Err(Exception::illegal_instruction());
+        }
+
+        let address = (address != 0).then(|| self.get_register_value(address));
+        let asid_mask = csr::SATP_ASID >> csr::SATP_ASID_SHIFT;
+        let asid = (asid != 0).then(|| self.get_register_value(asid) & asid_mask);
+        self.tlb.flush(address, asid);
+
+        Ok(false)
+    }
+
+    // mip of the running hart follows the interrupt sources
+    fn update_pending_interrupts(&mut self) {
+        let clint = self.bus.get_clint();
+        let timer_pending = clint.timer_pending(self.hart, self.instret);
+        let software_pending = clint.get_msip(self.hart);
+        self.csrs.set_pending(csr::MIP_MTIP, timer_pending);
+        self.csrs.set_pending(csr::MIP_MSIP, software_pending);
+        // software can raise seip as well, the plic only drives the contexts it has
+        let plic = self.bus.get_plic();
+        let context_pending = |context: usize| {
+            let context = self.hart * CONTEXTS_PER_HART + context;
+            plic.get_contexts() > context && plic.has_interrupt(context)
+        };
+        let external_pending = context_pending(MACHINE_CONTEXT);
+        let supervisor_external_pending = context_pending(SUPERVISOR_CONTEXT);
+        self.csrs.set_pending(csr::MIP_MEIP, external_pending);
+        self.csrs
+            .set_pending(csr::MIP_SEIP, supervisor_external_pending);
+    }
+
+    // highest priority interrupt that is both pending and enabled, the interrupts handled in
+    // machine mode come first
+    fn pending_interrupt(&mut self) -> Option<u32> {
+        let enabled = self.csrs.enabled_interrupts(self.privilege);
+        if enabled == 0 {
+            return None;
+        }
+
+        self.update_pending_interrupts();
+
+        let pending = self.csrs.get_mip() & enabled;
+        let delegated = self.csrs.get_mideleg();
+        [pending & !delegated, pending & delegated]
+            .into_iter()
+            .find_map(|pending| {
+                csr::INTERRUPT_PRIORITIES
+                    .into_iter()
+                    .find(|cause| pending & (1 << cause) != 0)
+            })
+    }
+
+    // instructions that can run before an enabled interrupt may become pending
+    fn interrupt_budget(&self) -> u64 {
+        let enabled = self.csrs.enabled_interrupts(self.privilege);
+        let clint = self.bus.get_clint();
+        // the host clock is read whenever pending interrupts are checked
+        let timer_checked = match clint.get_time_source() {
+            TimeSource::Instructions => enabled & csr::MIP_MTIP != 0,
+            TimeSource::Host => enabled != 0,
+        };
+        if !timer_checked {
+            return u64::MAX;
+        }
+
+        clint.instructions_until_timer(self.hart, self.instret)
+    }
+
+    // enters the trap handler, at stvec for the exceptions and interrupts delegated to supervisor
+    // mode and at mtvec otherwise. The interrupted instruction is resumed by sret or mret
+    fn take_trap(&mut self, cause: u32, tval: u32) {
+        let interrupt = cause & csr::CAUSE_INTERRUPT != 0;
+        let code = cause & !csr::CAUSE_INTERRUPT;
+        let delegated = if interrupt {
+            self.csrs.get_mideleg()
+        } else {
+            self.csrs.get_medeleg()
+        };
+
+        let mstatus = self.csrs.get_mstatus();
+        let tvec;
+
+        // traps never go to a lower privilege
+        if self.privilege != Privilege::Machine && delegated & (1 << code) != 0 {
+            let mut saved = mstatus & !(csr::MSTATUS_SIE | csr::MSTATUS_SPIE | csr::MSTATUS_SPP);
+            if mstatus & csr::MSTATUS_SIE != 0 {
+                saved |= csr::MSTATUS_SPIE;
+            }
+            if self.privilege == Privilege::Supervisor {
+                saved |= csr::MSTATUS_SPP;
+            }
+            self.csrs.set_mstatus(saved);
+
+            self.csrs.set_sepc(self.pc.get_value());
+            self.csrs.set_scause(cause);
+            self.csrs.set_stval(tval);
+            self.privilege = Privilege::Supervisor;
+            tvec = self.csrs.get_stvec();
+        } else {
+            let mut saved = mstatus & !(csr::MSTATUS_MIE | csr::MSTATUS_MPIE);
+            if mstatus & csr::MSTATUS_MIE != 0 {
+                saved |= csr::MSTATUS_MPIE;
+            }
+            self.csrs.set_mstatus(saved);
+            self.csrs.set_mpp(self.privilege);
+
+            self.csrs.set_mepc(self.pc.get_value());
+            self.csrs.set_mcause(cause);
+            self.csrs.set_mtval(tval);
+            self.privilege = Privilege::Machine;
+            tvec = self.csrs.get_mtvec();
+        }
+
+        let base = tvec & !3;
+        // vectored mode, interrupts jump to base + 4 * cause
+        let target = if tvec & 1 != 0 && interrupt {
+            base + 4 * code
+        } else {
+            base
+        };
+
+        self.pc.set_value(target);
+        if let Some(profiler) = &mut self.profiler {
+            profiler.trap(self.hart, target);
+        }
+    }
+
     // whether executing instruction changed the pc
-    pub fn execute_instruction(&mut self, instruction: InstructionFormat) -> bool {
+    pub fn execute_instruction(
+        &mut self,
+        instruction: InstructionFormat,
+    ) -> Result<bool, Exception> {
         match instruction {
-            InstructionFormat::R(opcode) => self.execute_instruction_r(opcode),
+            InstructionFormat::R(opcode) => Ok(self.execute_instruction_r(opcode)),
             InstructionFormat::I(opcode) => self.execute_instruction_i(opcode),
             InstructionFormat::S(opcode) => self.execute_instruction_s(opcode),
-            InstructionFormat::B(opcode) => self.execute_instruction_b(opcode),
-            InstructionFormat::U(opcode) => self.execute_instruction_u(opcode),
-            InstructionFormat::J(opcode) => self.execute_instruction_j(opcode),
-            InstructionFormat::ECALL => self.execute_ecall(),
+            InstructionFormat::B(opcode) => Ok(self.execute_instruction_b(opcode)),
+            InstructionFormat::U(opcode) => Ok(self.execute_instruction_u(opcode)),
+            InstructionFormat::J(opcode) => Ok(self.execute_instruction_j(opcode)),
+            InstructionFormat::CSR(opcode) => self.execute_csr(opcode),
+            InstructionFormat::AMO(opcode) => self.execute_instruction_amo(opcode),
+            InstructionFormat::ECALL => self.execute_ecall_instruction(),
+            InstructionFormat::MRET => self.execute_mret(),
+            InstructionFormat::SRET => self.execute_sret(),
+            // interrupts are checked after every instruction anyway
+            InstructionFormat::WFI => self.execute_wfi(),
+            InstructionFormat::SFENCEVMA(address, asid) => self.execute_sfence_vma(address, asid),
+            // the harts take turns and every engine accesses memory directly, without caches or
+            // write buffers, so memory is always ordered. Only code caches need fence.i
+            InstructionFormat::FENCE => Ok(false),
+            InstructionFormat::FENCEI => {
+                self.flush_code_caches();
+                Ok(false)
+            }
         }
     }
 
-    fn write_u8(&mut self, address: usize, value: u8) {
-        if self.flash.belongs(address, 1) {
-            self.flash.write_8(address, value)
-        } else if self.stack.belongs(address, 1) {
-            self.stack.write_8(address, value)
-        } else {
-            panic!("Invalid 1-byte write address {:x}", address)
+    // accesses to plain memory, None or false for read-only memory, devices and unmapped addresses
+
+    fn ram_write_u8(&mut self, address: usize, value: u8) -> bool {
+        if self.is_tohost(address, 1) || !self.bus.write_memory_u8(address, value) {
+            return false;
         }
+        self.invalidate_code(address, 1);
+        self.invalidate_reservations(address, 1);
+        true
     }
 
-    fn read_u8(&self, address: usize) -> u8 {
-        if self.flash.belongs(address, 1) {
-            self.flash.read_u8(address)
-        } else if self.stack.belongs(address, 1) {
-            self.stack.read_u8(address)
-        } else {
-            panic!("Invalid 1-byte read address {:x}", address)
+    fn ram_read_u8(&self, address: usize) -> Option<u8> {
+        self.bus.read_memory_u8(address)
+    }
+
+    fn ram_write_u16(&mut self, address: usize, value: u16) -> bool {
+        if self.is_tohost(address, 2) || !self.bus.write_memory_u16(address, value) {
+            return false;
         }
+        self.invalidate_code(address, 2);
+        self.invalidate_reservations(address, 2);
+        true
     }
 
-    fn write_u16(&mut self, address: usize, value: u16) {
-        if self.flash.belongs(address, 2) {
-            self.flash.write_16(address, value)
-        } else if self.stack.belongs(address, 2) {
-            self.stack.write_16(address, value)
-        } else {
-            panic!("Invalid 2-byte write address {:x}", address)
+    fn ram_read_u16(&self, address: usize) -> Option<u16> {
+        self.bus.read_memory_u16(address)
+    }
+
+    fn ram_write_u32(&mut self, address: usize, value: u32) -> bool {
+        if self.is_tohost(address, 4) || !self.bus.write_memory_u32(address, value) {
+            return false;
         }
+        self.invalidate_code(address, 4);
+        self.invalidate_reservations(address, 4);
+        true
     }
 
-    fn read_u16(&self, address: usize) -> u16 {
-        if self.flash.belongs(address, 2) {
-            self.flash.read_u16(address)
-        } else if self.stack.belongs(address, 2) {
-            self.stack.read_u16(address)
-        } else {
-            panic!("Invalid 2-byte read address {:x}", address)
+    fn ram_read_u32(&self, address: usize) -> Option<u32> {
+        self.bus.read_memory_u32(address)
+    }
+
+    // the devices, interrupt controllers included, see the current instruction count. false or
+    // None for unmapped addresses and read-only memory, the access faults
+
+    fn device_write(&mut self, address: usize, nb_bytes: usize, value: u32) -> bool {
+        if self.is_tohost(address, nb_bytes) {
+            self.write_tohost(address, nb_bytes, value);
+            return true;
+        }
+
+        if !self
+            .bus
+            .write_device(address, nb_bytes, value, self.instret)
+        {
+            return false;
         }
+
+        if let Some(exit_code) = self.bus.get_exit_code() {
+            self.exit_code = Some(exit_code);
+        }
+        self.invalidate_dma_writes();
+        true
     }
 
-    fn write_u32(&mut self, address: usize, value: u32) {
-        if self.flash.belongs(address, 4) {
-            self.flash.write_u32(address, value)
-        } else if self.stack.belongs(address, 4) {
-            self.stack.write_u32(address, value)
-        } else {
-            panic!("Invalid 4-byte write address {:x}", address)
+    // devices may have written over translated code or reserved words
+    fn invalidate_dma_writes(&mut self) {
+        for (address, size) in self.bus.take_dma_writes() {
+            self.invalidate_code(address, size);
+            self.invalidate_all_reservations(address, size);
         }
     }
 
-    pub fn read_u32(&self, address: usize) -> u32 {
-        if self.flash.belongs(address, 4) {
-            self.flash.read_u32(address)
-        } else if self.stack.belongs(address, 4) {
-            self.stack.read_u32(address)
-        } else {
-            panic!("Invalid 4-byte read address {:x}", address)
+    fn is_tohost(&self, address: usize, nb_bytes: usize) -> bool {
+        self.tohost
+            .is_some_and(|tohost| address < tohost + 8 && address + nb_bytes > tohost)
+    }
+
+    // a value with bit 0 set in the low word of tohost is an exit request with the code in the
+    // other bits, riscv-tests write 1 on success and the failed test number << 1 | 1. Other
+    // commands are ignored
+    fn write_tohost(&mut self, address: usize, nb_bytes: usize, value: u32) {
+        let written = match nb_bytes {
+            1 => self.bus.write_memory_u8(address, value as u8),
+            2 => self.bus.write_memory_u16(address, value as u16),
+            _ => self.bus.write_memory_u32(address, value),
+        };
+        if !written {
+            panic!("Invalid {}-byte write address {:x}", nb_bytes, address)
+        }
+
+        let tohost = self.tohost.unwrap();
+        if address < tohost + 4 {
+            let command = self.ram_read_u32(tohost).unwrap();
+            if command & 1 != 0 {
+                self.exit_code = Some((command >> 1) as i32);
+            }
         }
     }
 
+    fn device_read(&mut self, address: usize, nb_bytes: usize) -> Option<u32> {
+        self.bus.read_device(address, nb_bytes, self.instret)
+    }
+
+    fn write_u8(&mut self, address: usize, value: u8) -> bool {
+        self.ram_write_u8(address, value) || self.device_write(address, 1, value as u32)
+    }
+
+    fn read_u8(&mut self, address: usize) -> Option<u8> {
+        match self.ram_read_u8(address) {
+            Some(value) => Some(value),
+            None => self.device_read(address, 1).map(|value| value as u8),
+        }
+    }
+
+    fn write_u16(&mut self, address: usize, value: u16) -> bool {
+        self.ram_write_u16(address, value) || self.device_write(address, 2, value as u32)
+    }
+
+    fn read_u16(&mut self, address: usize) -> Option<u16> {
+        match self.ram_read_u16(address) {
+            Some(value) => Some(value),
+            None => self.device_read(address, 2).map(|value| value as u16),
+        }
+    }
+
+    fn write_u32(&mut self, address: usize, value: u32) -> bool {
+        self.ram_write_u32(address, value) || self.device_write(address, 4, value)
+    }
+
+    pub fn read_u32(&mut self, address: usize) -> Option<u32> {
+        match self.ram_read_u32(address) {
+            Some(value) => Some(value),
+            None => self.device_read(address, 4),
+        }
+    }
+
+    // physical accesses of the loads and stores, access faults report the virtual address
+
+    fn read_physical(&mut self, physical: usize, nb_bytes: usize) -> Option<u32> {
+        match nb_bytes {
+            1 => self.read_u8(physical).map(|value| value as u32),
+            2 => self.read_u16(physical).map(|value| value as u32),
+            _ => self.read_u32(physical),
+        }
+    }
+
+    fn write_physical(&mut self, physical: usize, nb_bytes: usize, value: u32) -> bool {
+        match nb_bytes {
+            1 => self.write_u8(physical, value as u8),
+            2 => self.write_u16(physical, value as u16),
+            _ => self.write_u32(physical, value),
+        }
+    }
+
+    // loads and stores of the instructions, with virtual addresses
+
+    fn load(&mut self, address: u32, nb_bytes: usize) -> Result<u32, Exception> {
+        if self.translates(Access::Load) && crosses_page(address, nb_bytes) {
+            let mut value = 0;
+            for i in 0..nb_bytes as u32 {
+                value |= self.load(address.wrapping_add(i), 1)? << (8 * i);
+            }
+            return Ok(value);
+        }
+
+        let physical = self.translate(address, nb_bytes, Access::Load)?;
+        self.read_physical(physical, nb_bytes)
+            .ok_or(Exception::new(csr::CAUSE_LOAD_ACCESS_FAULT, address))
+    }
+
+    fn store(&mut self, address: u32, nb_bytes: usize, value: u32) -> Result<(), Exception> {
+        // both pages have to be writable before anything is written
+        if self.translates(Access::Store) && crosses_page(address, nb_bytes) {
+            let physical = (0..nb_bytes as u32)
+                .map(|i| self.translate(address.wrapping_add(i), 1, Access::Store))
+                .collect::<Result<Vec<_>, _>>()?;
+            for (i, physical) in physical.into_iter().enumerate() {
+                if !self.write_u8(physical, (value >> (8 * i)) as u8) {
+                    let faulting = address.wrapping_add(i as u32);
+                    return Err(Exception::new(csr::CAUSE_STORE_ACCESS_FAULT, faulting));
+                }
+            }
+            self.watch_store(address, nb_bytes);
+            return Ok(());
+        }
+
+        let physical = self.translate(address, nb_bytes, Access::Store)?;
+        if !self.write_physical(physical, nb_bytes, value) {
+            return Err(Exception::new(csr::CAUSE_STORE_ACCESS_FAULT, address));
+        }
+        self.watch_store(address, nb_bytes);
+        Ok(())
+    }
+
+    // the execution stops after an instruction writing to a watched address
+    fn watch_store(&mut self, address: u32, nb_bytes: usize) {
+        if self.stop_conditions.has_watchpoints() && self.watch_hit.is_none() {
+            self.watch_hit = self.stop_conditions.watched_address(address, nb_bytes);
+        }
+    }
+
+    // syscall buffers, only in plain memory
+
     fn write_n(&mut self, address: usize, data: Vec<u8>) {
         let nb_bytes = data.len();
-        if self.flash.belongs(address, nb_bytes) {
-            self.flash.write_n(address, data)
-        } else if self.stack.belongs(address, nb_bytes) {
-            self.stack.write_n(address, data)
-        } else {
+        if !self.write_memory(address, data) {
             panic!("Invalid {}-byte write address {:x}", nb_bytes, address)
         }
     }
 
+    // also used by debuggers, false when it isn't writable memory
+    pub fn write_memory(&mut self, address: usize, data: Vec<u8>) -> bool {
+        let nb_bytes = data.len();
+        self.invalidate_code(address, nb_bytes);
+        self.invalidate_all_reservations(address, nb_bytes);
+        self.bus.write_memory_n(address, data)
+    }
+
     pub fn read_n(&mut self, address: usize, size: usize) -> Vec<u8> {
-        if self.flash.belongs(address, size) {
-            self.flash.read_n(address, size)
-        } else if self.stack.belongs(address, size) {
-            self.stack.read_n(address, size)
-        } else {
-            panic!("Invalid {}-byte read address {:x}", size, address)
+        match self.bus.read_memory_n(address, size) {
+            Some(data) => data,
+            None => panic!("Invalid {}-byte read address {:x}", size, address),
+        }
+    }
+
+    // instruction count of the next device tick and whether it is a device event
+    fn schedule_device_tick(&self, next_regular_tick: u64) -> (u64, bool) {
+        match self.bus.next_event() {
+            Some(event) if event < next_regular_tick => (event.max(self.instret + 1), true),
+            _ => (next_regular_tick, false),
+        }
+    }
+
+    // warm reset: the harts, the interrupt controllers and the devices go back to their power-on
+    // state, memory is kept and the instruction count keeps running
+    pub fn reset(&mut self) {
+        self.for_each_hart(|vm, hart| {
+            vm.regs = [0; 32];
+            vm.csrs = Csrs::new(hart as u32);
+            vm.privilege = Privilege::Machine;
+            vm.reservation = None;
+            vm.tlb.flush_all();
+        });
+        self.bus.reset_interrupt_controllers();
+        if let Some(profiler) = &mut self.profiler {
+            profiler.reset();
         }
+        self.init_execution();
     }
 
+    // every hart starts at the same address, with the same stack on the flash machine
     pub fn init_execution(&mut self) {
-        let reset_handler_entry = FLASH_INTERRUPT_TABLE_RESET_ADDRESS;
-        let reset_handler = self.flash.read_u32(reset_handler_entry);
+        self.for_each_hart(|vm, hart| {
+            match vm.entry {
+                Some(entry) => vm.pc.set_value(entry),
+                None => {
+                    let reset_handler_entry = FLASH_INTERRUPT_TABLE_RESET_ADDRESS;
+                    let reset_handler = vm
+                        .ram_read_u32(reset_handler_entry)
+                        .expect("Invalid reset vector address");
+
+                    vm.pc.set_value(reset_handler);
+
+                    // x2 is the stack register
+                    vm.set_register_value(2, STACK_ADDRESS as u32);
+                }
+            }
 
-        self.pc.set_value(reset_handler);
+            for (register, value) in vm.boot_registers.clone() {
+                vm.set_register_value(register, value);
+            }
+            if let Some(register) = vm.hart_id_register {
+                vm.set_register_value(register, hart as u32);
+            }
+        });
+        self.resumed_stop = None;
+
+        self.bus.reset_devices();
+    }
 
-        // x2 is the stack register
-        self.regs[2].set_value(STACK_ADDRESS as u32);
+    // instructions are only executed from plain memory
+    fn fetch(&self, pc: u32) -> u32 {
+        match self.ram_read_u32(pc as usize) {
+            Some(instruction) => instruction,
+            None => panic!("Invalid instruction fetch address {:x}", pc),
+        }
     }
 
-    pub fn start_execution(&mut self) {
+    // the decode cache is indexed by physical address, stores invalidate it without translation
+    fn decode_at(&mut self, address: u32) -> InstructionFormat {
+        match self.decode_cache.get(address) {
+            Some(decoded_instruction) => decoded_instruction,
+            None => {
+                let decoded_instruction = decode(self.fetch(address));
+                self.decode_cache.insert(address, decoded_instruction);
+                decoded_instruction
+            }
+        }
+    }
+
+    fn fetch_decode(&mut self, pc: u32) -> Result<InstructionFormat, Exception> {
+        let physical = self.translate(pc, 4, Access::Fetch)?;
+        Ok(self.decode_at(physical as u32))
+    }
+
+    // translated blocks and compiled code address memory physically and leave privileged
+    // checks to the interpreter, they only run in machine mode without translation or locked
+    // pmp entries. They don't check the watchpoints nor report to the profiler either
+    fn runs_blocks(&self) -> bool {
+        self.block_cache.is_enabled()
+            && self.privilege == Privilege::Machine
+            && !self.translates(Access::Load)
+            && !self.csrs.get_pmp().is_locked()
+            && !self.stop_conditions.has_watchpoints()
+            && self.profiler.is_none()
+    }
+
+    // appends the instruction to the block being discovered, the block is translated once it
+    // reaches a branch, jal, jalr or ecall
+    fn record_instruction(&mut self, pc: u32, instruction: &InstructionFormat) {
+        let expected = self.recording_start + 4 * self.recording.len() as u32;
+        if self.recording.is_empty() || pc != expected {
+            self.recording.clear();
+            self.recording_start = pc;
+        }
+
+        let (op, terminator) = micro_ops::translate(instruction, pc);
+        self.recording.push(op);
+
+        let next = pc.wrapping_add(4);
+        if terminator != Terminator::No
+            || self.recording.len() >= MAX_BLOCK_SIZE
+            || self.stop_conditions.is_stop_address(next)
+            || !self.bus.is_memory(next as usize, 4)
+        {
+            self.finish_recording(terminator);
+        }
+    }
+
+    fn finish_recording(&mut self, terminator: Terminator) {
+        let ops = std::mem::take(&mut self.recording);
+        let block = Block::new(
+            self.recording_start,
+            ops,
+            terminator != Terminator::No,
+            // falling through is as static as a direct jump
+            terminator != Terminator::Indirect,
+        );
+        self.block_cache.insert(block);
+    }
+
+    fn flush_code_caches(&mut self) {
+        self.decode_cache.flush();
+        self.block_cache.flush();
+        self.recording.clear();
+    }
+
+    // forgets everything translated or decoded from the written bytes
+    fn invalidate_code(&mut self, address: usize, size: usize) {
+        self.decode_cache.invalidate(address, size);
+        self.block_cache.invalidate(address, size);
+
+        let recording_start = self.recording_start as usize;
+        let recording_end = recording_start + 4 * self.recording.len();
+        if address < recording_end && address + size > recording_start {
+            self.recording.clear();
+        }
+    }
+
+    // only used for the blocks
+    fn trace_instruction(&mut self, pc: u32) {
+        let decoded_instruction = self.decode_at(pc);
+        eprintln!("{:x} {}", pc, decoded_instruction);
+    }
+
+    // executes a single instruction
+    pub fn step(&mut self) {
+        let pc = self.pc.get_value();
+
+        let decoded_instruction = match self.fetch_decode(pc) {
+            Ok(decoded_instruction) => decoded_instruction,
+            Err(exception) => return self.raise(exception),
+        };
+
+        if self.verbosity > 1 {
+            eprintln!("{:x} {}", pc, decoded_instruction);
+        }
+
+        if self.runs_blocks() {
+            self.record_instruction(pc, &decoded_instruction);
+        }
+
+        self.retire(pc, decoded_instruction);
+    }
+
+    // executes the instruction at pc, the trace is up to the caller. An instruction raising an
+    // exception doesn't retire
+    fn retire(&mut self, pc: u32, instruction: InstructionFormat) {
+        match self.execute_instruction(instruction) {
+            Ok(pc_changed) => {
+                if !pc_changed {
+                    self.pc.set_value(pc + 4);
+                }
+
+                self.instret += 1;
+                if let Some(profiler) = &mut self.profiler {
+                    profiler.retire(self.hart, pc, &instruction, self.pc.get_value());
+                }
+            }
+            Err(exception) => self.raise(exception),
+        }
+    }
+
+    // traps with the pc of the faulting instruction, exceptions are part of the trace
+    fn raise(&mut self, exception: Exception) {
+        if self.verbosity > 1 {
+            eprintln!(
+                "riscv: exception {} at {:x}, tval {:x}",
+                exception.cause,
+                self.pc.get_value(),
+                exception.tval
+            );
+        }
+        self.take_trap(exception.cause, exception.tval);
+    }
+
+    // called by the micro-ops instead of executing their instruction, it is executed by the
+    // interpreter and the block is left
+    fn leave_block(&mut self) {
+        self.leave_blocks = true;
+    }
+
+    // runs the translated block, the retired instructions are added to instret
+    fn execute_block(&mut self, block: &Block) {
+        self.leave_blocks = false;
+        self.block_cache.clear_invalidated();
+
+        #[cfg(feature = "jit")]
+        if self.execute_jit(block) {
+            return;
+        }
+
+        let ops = block.get_ops();
+        let trace = self.verbosity > 1;
+
+        for (i, op) in ops.iter().enumerate() {
+            if trace {
+                self.trace_instruction(op.pc);
+            }
+
+            (op.handler)(self, op);
+
+            if op.side_exit {
+                if self.leave_blocks {
+                    self.instret += i as u64;
+                    self.pc.set_value(op.pc);
+                    let instruction = self.decode_at(op.pc);
+                    self.retire(op.pc, instruction);
+                    return;
+                }
+
+                // a store changed translated code, the rest of the block may be stale
+                if self.block_cache.was_invalidated() {
+                    let is_last = i + 1 == ops.len();
+                    if !(is_last && block.ends_with_jump()) {
+                        self.pc.set_value(op.pc + 4);
+                    }
+                    self.instret += i as u64 + 1;
+                    return;
+                }
+            }
+        }
+
+        if !block.ends_with_jump() {
+            self.pc.set_value(block.get_end());
+        }
+
+        self.instret += ops.len() as u64;
+    }
+
+    // translated block starting at pc, chained to the previously executed block when possible
+    fn lookup_block(&mut self, pc: u32, previous: Option<&Rc<Block>>) -> Option<Rc<Block>> {
+        if !self.runs_blocks() {
+            return None;
+        }
+
+        let block = self.block_cache.get(pc)?;
+        if let Some(previous) = previous {
+            previous.add_link(&block);
+        }
+
+        // the block being discovered runs into an existing one, it ends here
+        if !self.recording.is_empty() {
+            if self.recording_start + 4 * self.recording.len() as u32 == pc {
+                self.finish_recording(Terminator::No);
+            } else {
+                self.recording.clear();
+            }
+        }
+
+        Some(block)
+    }
+
+    // runs the block and the blocks chained to it until one of them has no chained successor or
+    // the budget of instructions is spent, returns the last executed block and the pc of the
+    // last executed instruction
+    fn run_blocks(&mut self, block: Rc<Block>, budget: u64) -> (Rc<Block>, u32) {
+        let start_instret = self.instret;
+        let detect_self_loop = self.stop_conditions.get_detect_self_loop();
+        let mut block = block;
+
         loop {
-            let instruction = self.flash.read_u32(self.pc.get_value() as usize);
+            let block_instret = self.instret;
+            self.execute_block(&block);
+
+            // blocks are straight-line code, even when left early. An instruction raising an
+            // exception doesn't retire, the previous one is the last executed
+            let retired = self.instret - block_instret;
+            let last_pc = block.get_start() + 4 * retired.saturating_sub(1) as u32;
+            let pc = self.pc.get_value();
+            let executed = self.instret - start_instret;
+
+            if self.exit_code.is_some()
+                || self.leave_blocks
+                || self.block_cache.was_invalidated()
+                || (detect_self_loop && pc == last_pc)
+            {
+                return (block, last_pc);
+            }
+
+            match block.get_link(pc) {
+                Some(next) if executed + next.get_ops().len() as u64 <= budget => block = next,
+                _ => return (block, last_pc),
+            }
+        }
+    }
+
+    // runs until the guest exits or one of the stop conditions is met
+    pub fn start_execution(&mut self) -> StopReason {
+        let start = Instant::now();
+        let mut next_timeout_check = self.instret;
+        let (mut next_device_tick, mut device_event) =
+            self.schedule_device_tick(self.next_regular_tick);
+        let mut previous_block: Option<Rc<Block>> = None;
+
+        loop {
+            if let Some(exit_code) = self.exit_code {
+                return StopReason::Exit(exit_code);
+            }
 
-            let decoded_instruction = decode(instruction);
+            if self.bus.has_devices() {
+                if self.instret >= next_device_tick {
+                    self.bus.tick_devices(self.instret);
+                    self.invalidate_dma_writes();
+                    if self.instret >= self.next_regular_tick {
+                        self.next_regular_tick = self.instret + DEVICE_TICK_INTERVAL;
+                    }
+                    (next_device_tick, device_event) =
+                        self.schedule_device_tick(self.next_regular_tick);
+                } else if self.bus.take_events_changed() {
+                    (next_device_tick, device_event) =
+                        self.schedule_device_tick(self.next_regular_tick);
+                }
+            }
+
+            if self.bus.has_requests() {
+                for request in self.bus.take_requests() {
+                    match request {
+                        MachineRequest::Reset => self.reset(),
+                        MachineRequest::Nmi => {
+                            self.take_trap(csr::CAUSE_INTERRUPT | csr::CAUSE_NMI, 0)
+                        }
+                        MachineRequest::Stop(reason) => return reason,
+                    }
+                }
+                previous_block = None;
+                // the devices were reset or their events moved
+                next_device_tick = self.instret;
+                continue;
+            }
+
+            if self.schedule_harts() {
+                previous_block = None;
+            }
+
+            if let Some(cause) = self.pending_interrupt() {
+                let taken = (self.hart as u64) << 32 | cause as u64;
+                self.bus
+                    .get_input_log()
+                    .check(Input::Interrupt, self.instret, taken);
+                self.take_trap(csr::CAUSE_INTERRUPT | cause, 0);
+                previous_block = None;
+            }
+
+            let pc = self.pc.get_value();
+
+            // instructions that can run before the next check of the stop conditions
+            let mut budget = self.interrupt_budget().min(self.quantum_budget());
+            // regular ticks can be a few instructions late, a whole block always fits
+            if self.bus.has_devices() {
+                let until_tick = next_device_tick.saturating_sub(self.instret);
+                if device_event || self.exact_ticks {
+                    budget = budget.min(until_tick);
+                } else {
+                    budget = budget.min(until_tick.max(MAX_BLOCK_SIZE as u64));
+                }
+            }
+
+            if let Some(max_instructions) = self.stop_conditions.get_max_instructions() {
+                if self.instret >= max_instructions {
+                    return StopReason::InstructionLimit;
+                }
+                budget = budget.min(max_instructions - self.instret);
+            }
+
+            if let Some(timeout) = self.stop_conditions.get_timeout() {
+                if self.instret >= next_timeout_check {
+                    if start.elapsed() >= timeout {
+                        return StopReason::Timeout;
+                    }
+                    next_timeout_check = self.instret + TIMEOUT_CHECK_INTERVAL;
+                }
+                budget = budget.min(next_timeout_check - self.instret);
+            }
+
+            if self.resumed_stop != Some(pc) && self.stop_conditions.is_stop_address(pc) {
+                self.resumed_stop = Some(pc);
+                return StopReason::StopAddress(pc);
+            }
 
-            eprintln!("{:x} {}", self.pc.get_value(), decoded_instruction);
+            // pc of the last executed instruction
+            let last_pc;
 
-            // execute instruction
-            let pc_changed = self.execute_instruction(decoded_instruction);
+            let block = self.lookup_block(pc, previous_block.as_ref());
+
+            match block.filter(|block| block.get_ops().len() as u64 <= budget) {
+                Some(block) => {
+                    let (block, block_last_pc) = self.run_blocks(block, budget);
+                    last_pc = block_last_pc;
+                    previous_block = Some(block);
+                }
+                None => {
+                    self.step();
+                    last_pc = pc;
+                    previous_block = None;
+                }
+            }
+            // the execution moved on, the stop address stops it again when it comes back
+            self.resumed_stop = None;
+
+            if let Some(address) = self.watch_hit.take() {
+                return StopReason::Watchpoint(address);
+            }
 
-            if !pc_changed {
-                self.pc.set_value(self.pc.get_value() + 4);
+            if self.stop_conditions.get_detect_self_loop()
+                && self.pc.get_value() == last_pc
+                && self.exit_code.is_none()
+            {
+                return StopReason::SelfLoop(last_pc);
             }
         }
     }
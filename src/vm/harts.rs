@@ -0,0 +1,408 @@
+// several harts sharing the bus. The running hart uses the registers, csrs, reservation and tlb
+// fields of the VM, the translated blocks and compiled code only know about those. The others are
+// parked and swapped in by a deterministic round-robin scheduler, every hart runs for a quantum
+// of retired instructions
+
+use std::mem;
+
+use crate::{
+    clint::MAX_HARTS,
+    csr::{Csrs, Privilege},
+    snapshot::{SnapshotReader, SnapshotWriter},
+    tlb::Tlb,
+};
+
+use super::VM;
+
+pub const DEFAULT_QUANTUM: u64 = 1000;
+
+// state of a parked hart, the slot of the running hart is stale
+pub(super) struct Hart {
+    regs: [u32; 32],
+    pc: u32,
+    csrs: Csrs,
+    privilege: Privilege,
+    reservation: Option<usize>,
+    tlb: Tlb,
+    // instructions retired by the hart, minstret
+    retired: u64,
+}
+
+impl Hart {
+    pub(super) fn new(hart_id: usize, tlb: &Tlb) -> Self {
+        Self {
+            regs: [0; 32],
+            pc: 0,
+            csrs: Csrs::new(hart_id as u32),
+            privilege: Privilege::Machine,
+            reservation: None,
+            tlb: Tlb::new(tlb.get_entries(), tlb.get_ways()).unwrap(),
+            retired: 0,
+        }
+    }
+
+    fn save_state(&self, snapshot: &mut SnapshotWriter) {
+        snapshot.write_u32s(&self.regs);
+        snapshot.write_u32(self.pc);
+        self.csrs.save_state(snapshot);
+        snapshot.write_u8(self.privilege as u8);
+        snapshot.write_option_u64(self.reservation.map(|address| address as u64));
+        self.tlb.save_state(snapshot);
+        snapshot.write_u64(self.retired);
+    }
+
+    fn restore_state(&mut self, snapshot: &mut SnapshotReader) -> Result<(), String> {
+        snapshot.read_u32s_into("registers", &mut self.regs)?;
+        self.regs[0] = 0;
+        self.pc = snapshot.read_u32()?;
+        self.csrs.restore_state(snapshot)?;
+        self.privilege = match snapshot.read_u8()? {
+            0 => Privilege::User,
+            1 => Privilege::Supervisor,
+            3 => Privilege::Machine,
+            privilege => return Err(format!("invalid privilege {privilege} in the snapshot")),
+        };
+        self.reservation = snapshot.read_option_u64()?.map(|address| address as usize);
+        self.tlb.restore_state(snapshot)?;
+        self.retired = snapshot.read_u64()?;
+        Ok(())
+    }
+}
+
+fn overlaps(reservation: Option<usize>, address: usize, size: usize) -> bool {
+    reservation.is_some_and(|reserved| address < reserved + 4 && address + size > reserved)
+}
+
+impl VM {
+    // every hart needs its msip and mtimecmp in the CLINT, the PLIC contexts of the harts that
+    // don't have any never see an external interrupt
+    pub fn set_harts(&mut self, harts: usize) -> Result<(), String> {
+        let clint_harts = self.bus.get_clint().get_harts();
+        if harts == 0 || harts > MAX_HARTS {
+            return Err(format!("invalid number of harts {harts}"));
+        }
+        if harts > clint_harts {
+            return Err(format!(
+                "the CLINT only has the registers of {clint_harts} harts"
+            ));
+        }
+
+        self.switch_hart(0);
+        self.harts = (0..harts).map(|hart| Hart::new(hart, &self.tlb)).collect();
+        Ok(())
+    }
+
+    pub fn get_harts(&self) -> usize {
+        self.harts.len()
+    }
+
+    // id of the running hart
+    pub fn get_hart(&self) -> usize {
+        self.hart
+    }
+
+    // instructions each hart runs before the next one gets its turn
+    pub fn set_quantum(&mut self, quantum: u64) {
+        assert!(quantum > 0, "The quantum must be at least one instruction");
+        self.quantum = quantum;
+    }
+
+    // register set to the id of its hart at every reset, a0 in most boot protocols
+    pub fn set_hart_id_register(&mut self, register: u32) {
+        self.hart_id_register = Some(register);
+    }
+
+    // the minstret of the running hart
+    pub(super) fn get_hart_instret(&self) -> u64 {
+        self.instret - self.instret_offset
+    }
+
+    // exchanges the running state with the slot of the hart
+    fn swap_hart(&mut self, hart: usize) {
+        let parked = &mut self.harts[hart];
+        mem::swap(&mut self.regs, &mut parked.regs);
+        let pc = self.pc.get_value();
+        self.pc.set_value(parked.pc);
+        parked.pc = pc;
+        mem::swap(&mut self.csrs, &mut parked.csrs);
+        mem::swap(&mut self.privilege, &mut parked.privilege);
+        mem::swap(&mut self.reservation, &mut parked.reservation);
+        mem::swap(&mut self.tlb, &mut parked.tlb);
+
+        // the other harts kept retiring instructions while this one was parked
+        let retired = self.instret - self.instret_offset;
+        self.instret_offset = self.instret - parked.retired;
+        parked.retired = retired;
+    }
+
+    // parks the running hart and runs the given one
+    pub(super) fn switch_hart(&mut self, hart: usize) {
+        if hart == self.hart {
+            return;
+        }
+
+        self.swap_hart(self.hart);
+        self.swap_hart(hart);
+        self.hart = hart;
+        // the block being discovered was executed by the previous hart
+        self.recording.clear();
+    }
+
+    // called by the execution loop, the next hart runs once the quantum is spent. Returns
+    // whether the running hart changed
+    pub(super) fn schedule_harts(&mut self) -> bool {
+        if self.harts.len() < 2 || self.instret < self.next_switch {
+            return false;
+        }
+
+        self.switch_hart((self.hart + 1) % self.harts.len());
+        self.next_switch = self.instret + self.quantum;
+        true
+    }
+
+    // instructions the running hart can retire before the scheduler runs again
+    pub(super) fn quantum_budget(&self) -> u64 {
+        if self.harts.len() < 2 {
+            return u64::MAX;
+        }
+        self.next_switch.saturating_sub(self.instret)
+    }
+
+    // a hart waiting for an interrupt gives the rest of its quantum to the next one
+    pub(super) fn yield_hart(&mut self) {
+        self.next_switch = self.instret;
+    }
+
+    // a store to a reserved word makes the sc.w of the other harts fail
+    pub(super) fn invalidate_reservations(&mut self, address: usize, size: usize) {
+        if self.harts.len() < 2 {
+            return;
+        }
+
+        for (hart, parked) in self.harts.iter_mut().enumerate() {
+            if hart != self.hart && overlaps(parked.reservation, address, size) {
+                parked.reservation = None;
+            }
+        }
+    }
+
+    // a write of a device or a debugger makes the sc.w of every hart fail, the running one too
+    pub(super) fn invalidate_all_reservations(&mut self, address: usize, size: usize) {
+        if overlaps(self.reservation, address, size) {
+            self.reservation = None;
+        }
+        self.invalidate_reservations(address, size);
+    }
+
+    // calls f with every hart running in turn, hart 0 runs first afterwards with a full quantum
+    pub(super) fn for_each_hart(&mut self, f: impl Fn(&mut Self, usize)) {
+        for hart in (0..self.harts.len()).rev() {
+            self.switch_hart(hart);
+            f(self, hart);
+        }
+        self.next_switch = self.instret + self.quantum;
+    }
+
+    // every hart, the running one is parked while it is saved
+    pub(super) fn save_harts(&mut self, snapshot: &mut SnapshotWriter) {
+        self.swap_hart(self.hart);
+        snapshot.write_u64(self.harts.len() as u64);
+        snapshot.write_u64(self.hart as u64);
+        snapshot.write_u64(self.next_switch);
+        for parked in &self.harts {
+            parked.save_state(snapshot);
+        }
+        self.swap_hart(self.hart);
+    }
+
+    // the instruction count must be restored first
+    pub(super) fn restore_harts(&mut self, snapshot: &mut SnapshotReader) -> Result<(), String> {
+        snapshot.check("harts", self.harts.len() as u64)?;
+        let running = snapshot.read_u64()? as usize;
+        if running >= self.harts.len() {
+            return Err(format!("invalid running hart {running} in the snapshot"));
+        }
+        self.next_switch = snapshot.read_u64()?;
+
+        // every slot is overwritten, then the running hart is swapped in
+        for parked in &mut self.harts {
+            parked.restore_state(snapshot)?;
+        }
+        self.hart = running;
+        self.swap_hart(running);
+        Ok(())
+    }
+
+    // the tlb geometry applies to every hart
+    pub(super) fn set_hart_tlbs(&mut self, entries: usize, ways: usize) -> Result<(), String> {
+        for parked in &mut self.harts {
+            parked.tlb = Tlb::new(entries, ways)?;
+        }
+        self.tlb = Tlb::new(entries, ways)?;
+        Ok(())
+    }
+
+    // pc and registers of the hart, parked or running
+    pub(super) fn hart_registers(&self, hart: usize) -> (u32, &[u32; 32]) {
+        if hart == self.hart {
+            (self.pc.get_value(), &self.regs)
+        } else {
+            (self.harts[hart].pc, &self.harts[hart].regs)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        bus::{Device, DmaMemory},
+        clint::{Clint, TimeSource, CLINT_ADDRESS},
+        csr::{MCAUSE, MHARTID, MIE, MIP_MSIP, MSTATUS, MSTATUS_MIE, MTVEC},
+        stop_conditions::{StopConditions, StopReason},
+        test_utils::*,
+    };
+
+    const T0: u32 = 5;
+    const T1: u32 = 6;
+    const T2: u32 = 7;
+    const S0: u32 = 8;
+    // reserved word in the stack RAM, with a flag after it
+    const RESERVED: u32 = 0xfffff000;
+    const FLAG: i32 = 0x100;
+
+    fn with_harts(vm: &mut VM, harts: usize) {
+        vm.get_bus_mut()
+            .set_clint(Clint::new(CLINT_ADDRESS, TimeSource::Instructions, harts))
+            .unwrap();
+        vm.set_harts(harts).unwrap();
+    }
+
+    // value of the register in every hart, the running one included
+    fn registers(vm: &VM, register: u32) -> Vec<u32> {
+        (0..vm.harts.len())
+            .map(|hart| match hart == vm.hart {
+                true => vm.regs[register as usize],
+                false => vm.harts[hart].regs[register as usize],
+            })
+            .collect()
+    }
+
+    #[test]
+    fn harts_take_turns_for_a_quantum() {
+        // every hart reads its id, then counts its loop iterations
+        let program = [csrr(T0, MHARTID), addi(T1, T1, 1), jal(0, -4)];
+        let (reason, vm) = run(&program, |vm| {
+            with_harts(vm, 3);
+            vm.set_quantum(10);
+            let mut stop_conditions = StopConditions::new();
+            stop_conditions.set_max_instructions(95);
+            vm.set_stop_conditions(stop_conditions);
+        });
+
+        assert_eq!(reason, StopReason::InstructionLimit);
+        assert_eq!(registers(&vm, T0), [0, 1, 2]);
+        // hart 0 ran 4 times, 35 instructions with the csrr, the others 3 times
+        assert_eq!(registers(&vm, T1), [17, 15, 15]);
+        assert_eq!(vm.get_hart(), 0);
+    }
+
+    #[test]
+    fn software_interrupts_reach_the_hart_they_are_sent_to() {
+        // hart 0 sets the msip of hart 1, which waits for it and exits from the handler with
+        // its id and the cause
+        let mut program = vec![csrr(T0, MHARTID), bne(T0, 0, 24)];
+        program.extend(li(T1, CLINT_ADDRESS as u32 + 4));
+        program.extend([addi(T2, 0, 1), sw(T2, T1, 0), jal(0, 0)]);
+        program.extend(li(T1, address_of(16)));
+        program.extend([csrw(MTVEC, T1), addi(T1, 0, MIP_MSIP as i32), csrw(MIE, T1)]);
+        program.extend([
+            addi(T1, 0, MSTATUS_MIE as i32),
+            csrw(MSTATUS, T1),
+            WFI,
+            jal(0, -4),
+        ]);
+        program.extend([csrr(T1, MCAUSE), slli(T1, T1, 4), add(A1, T1, T0)]);
+        program.extend(exit());
+
+        let (reason, _) = run(&program, |vm| with_harts(vm, 2));
+        // cause 3 shifted out of the interrupt bit, and hart 1
+        assert_eq!(reason, StopReason::Exit(0x31));
+    }
+
+    // hart 0 reserves the word and waits for hart 1 to store at the offset of the word and set
+    // the flag, then exits with the result of its sc.w
+    fn store_between_lr_and_sc(offset: i32) -> StopReason {
+        let mut program = vec![csrr(T0, MHARTID)];
+        program.extend(li(S0, RESERVED));
+        program.extend([bne(T0, 0, 32), lr_w(T1, S0)]);
+        program.extend([lw(T2, S0, FLAG), bne(T2, 0, 8), jal(0, -8)]);
+        program.push(sc_w(A1, T1, S0));
+        program.extend(exit());
+        program.extend([sw(T0, S0, offset), sw(T0, S0, FLAG), jal(0, 0)]);
+
+        run(&program, |vm| with_harts(vm, 2)).0
+    }
+
+    #[test]
+    fn stores_of_other_harts_break_the_reservation() {
+        assert_eq!(store_between_lr_and_sc(0), StopReason::Exit(1));
+        assert_eq!(store_between_lr_and_sc(4), StopReason::Exit(0));
+    }
+
+    // writes the word at the address given to it to memory, after a write to its register
+    struct DmaWriter {
+        address: Option<u64>,
+    }
+
+    impl Device for DmaWriter {
+        fn size(&self) -> usize {
+            4
+        }
+
+        fn read(&mut self, _offset: usize, _nb_bytes: usize, _instret: u64) -> u32 {
+            0
+        }
+
+        fn write(&mut self, _offset: usize, _nb_bytes: usize, value: u32, _instret: u64) {
+            self.address = Some(value as u64);
+        }
+
+        fn dma(&mut self, memory: &mut DmaMemory, _instret: u64) {
+            if let Some(address) = self.address.take() {
+                assert!(memory.write_u32(address, 0));
+            }
+        }
+    }
+
+    #[test]
+    fn dma_writes_break_the_reservation_of_the_running_hart() {
+        const DMA_ADDRESS: u32 = 0x10000000;
+
+        for (harts, written) in [(1, RESERVED), (2, RESERVED), (1, RESERVED + 4)] {
+            let mut program = Vec::new();
+            program.extend(li(S0, RESERVED));
+            program.extend(li(T0, DMA_ADDRESS));
+            program.extend(li(T1, written));
+            program.extend([lr_w(T2, S0), sw(T1, T0, 0), sc_w(A1, T2, S0)]);
+            program.extend(exit());
+
+            let (reason, _) = run(&program, |vm| {
+                with_harts(vm, harts);
+                // hart 1 doesn't get a turn before hart 0 exits
+                vm.set_quantum(100);
+                let device = DmaWriter { address: None };
+                vm.get_bus_mut()
+                    .add_device("dma", DMA_ADDRESS as usize, Box::new(device), None)
+                    .unwrap();
+            });
+            assert_eq!(reason, StopReason::Exit((written == RESERVED) as i32));
+        }
+
+        // so do the writes of a debugger
+        let mut vm = VM::new(flash(&[]));
+        vm.reservation = Some(RESERVED as usize);
+        assert!(vm.write_memory(RESERVED as usize + 3, vec![0]));
+        assert_eq!(vm.reservation, None);
+    }
+}
@@ -0,0 +1,155 @@
+use std::{ffi::c_void, mem::offset_of, rc::Rc};
+
+use crate::{
+    block_cache::Block,
+    jit::{
+        self, JitHelpers, JitState, JIT_THRESHOLD, LOAD_FAULT, STORE_CODE_CHANGED, STORE_FAULT,
+        STORE_OK,
+    },
+};
+
+use super::VM;
+
+// callbacks of the compiled code, they only access plain memory. Device accesses and faults are
+// reported back so that the interpreter executes the instruction, with the exact instruction
+// count, and fails exactly like it always does
+
+extern "sysv64" fn load_u8(vm: *mut VM, address: u32) -> u64 {
+    let vm = unsafe { &mut *vm };
+    match vm.ram_read_u8(address as usize) {
+        Some(value) => value as u64,
+        None => LOAD_FAULT,
+    }
+}
+
+extern "sysv64" fn load_u16(vm: *mut VM, address: u32) -> u64 {
+    let vm = unsafe { &mut *vm };
+    match vm.ram_read_u16(address as usize) {
+        Some(value) => value as u64,
+        None => LOAD_FAULT,
+    }
+}
+
+extern "sysv64" fn load_u32(vm: *mut VM, address: u32) -> u64 {
+    let vm = unsafe { &mut *vm };
+    match vm.ram_read_u32(address as usize) {
+        Some(value) => value as u64,
+        None => LOAD_FAULT,
+    }
+}
+
+fn store_status(vm: &VM) -> u32 {
+    if vm.block_cache.was_invalidated() {
+        STORE_CODE_CHANGED
+    } else {
+        STORE_OK
+    }
+}
+
+extern "sysv64" fn store_u8(vm: *mut VM, address: u32, value: u32) -> u32 {
+    let vm = unsafe { &mut *vm };
+    if !vm.ram_write_u8(address as usize, value as u8) {
+        return STORE_FAULT;
+    }
+    store_status(vm)
+}
+
+extern "sysv64" fn store_u16(vm: *mut VM, address: u32, value: u32) -> u32 {
+    let vm = unsafe { &mut *vm };
+    if !vm.ram_write_u16(address as usize, value as u16) {
+        return STORE_FAULT;
+    }
+    store_status(vm)
+}
+
+extern "sysv64" fn store_u32(vm: *mut VM, address: u32, value: u32) -> u32 {
+    let vm = unsafe { &mut *vm };
+    if !vm.ram_write_u32(address as usize, value) {
+        return STORE_FAULT;
+    }
+    store_status(vm)
+}
+
+extern "sysv64" fn trace(vm: *mut VM, pc: u32) {
+    let vm = unsafe { &mut *vm };
+    vm.trace_instruction(pc);
+}
+
+impl VM {
+    fn compile_block(&mut self, block: &Block) -> Option<jit::JitBlock> {
+        let instructions: Vec<_> = (block.get_start()..block.get_end())
+            .step_by(4)
+            .map(|pc| (pc, self.decode_at(pc)))
+            .collect();
+
+        let helpers = JitHelpers {
+            load_u8: load_u8 as *const () as usize,
+            load_u16: load_u16 as *const () as usize,
+            load_u32: load_u32 as *const () as usize,
+            store_u8: store_u8 as *const () as usize,
+            store_u16: store_u16 as *const () as usize,
+            store_u32: store_u32 as *const () as usize,
+            trace: trace as *const () as usize,
+        };
+
+        jit::compile(
+            &instructions,
+            block.get_end(),
+            &helpers,
+            offset_of!(VM, regs),
+            self.verbosity > 1,
+        )
+    }
+
+    // runs the compiled code of the block, compiling it once it is hot, false while the block
+    // has to go through the micro-ops
+    pub(super) fn execute_jit(&mut self, block: &Block) -> bool {
+        if !self.jit_enabled {
+            return false;
+        }
+
+        let mut state = block.get_jit().borrow_mut();
+
+        if let JitState::Cold(executions) = *state {
+            if executions + 1 < JIT_THRESHOLD {
+                *state = JitState::Cold(executions + 1);
+                return false;
+            }
+
+            *state = match self.compile_block(block) {
+                Some(code) => {
+                    self.jit_compiled += 1;
+                    JitState::Compiled(Rc::new(code))
+                }
+                None => JitState::Unsupported,
+            };
+        }
+
+        let JitState::Compiled(code) = &*state else {
+            return false;
+        };
+        // the helpers may invalidate the block while it runs
+        let code = Rc::clone(code);
+        drop(state);
+
+        // the compiled code reaches the registers and, through the helpers, the VM from this one
+        // pointer
+        let vm: *mut VM = self;
+        let exit = unsafe { code.run(vm as *mut c_void) };
+
+        self.pc.set_value(exit.pc);
+        self.instret += exit.retired;
+
+        // ecall, fence.i, system instructions, device accesses and faults
+        if exit.interpret {
+            let instruction = self.decode_at(exit.pc);
+            if self.verbosity > 1 {
+                self.trace_instruction(exit.pc);
+            }
+            self.retire(exit.pc, instruction);
+            self.leave_blocks = true;
+        }
+
+        true
+    }
+}
@@ -0,0 +1,465 @@
+// translation of decoded instructions into micro-ops for the block cache, every handler works on
+// operands resolved at translation time so executing a block is a straight sequence of calls
+
+use crate::{
+    block_cache::{MicroOp, MicroOpHandler},
+    instructions::{BOpcode, IOpcode, InstructionFormat, JOpcode, ROpcode, SOpcode, UOpcode},
+    utils::{self, sign_extend_number},
+};
+
+use super::VM;
+
+// how an instruction ends a block
+#[derive(PartialEq, Eq)]
+pub enum Terminator {
+    // execution continues with the next instruction
+    No,
+    // branches and jal, their successors are known at translation time
+    Direct,
+    // jalr, ecall, fence.i and system instructions
+    Indirect,
+}
+
+// register indexes are masked so the compiler can drop the bounds checks
+fn rs1(vm: &VM, op: &MicroOp) -> u32 {
+    vm.regs[(op.rs1 & 31) as usize]
+}
+
+fn rs2(vm: &VM, op: &MicroOp) -> u32 {
+    vm.regs[(op.rs2 & 31) as usize]
+}
+
+// arithmetic ops writing x0 are translated to nop, rd is never x0 here
+fn set_rd(vm: &mut VM, op: &MicroOp, value: u32) {
+    vm.regs[(op.rd & 31) as usize] = value
+}
+
+// loads and jumps still execute with rd = x0
+fn set_rd_checked(vm: &mut VM, op: &MicroOp, value: u32) {
+    vm.set_register_value(op.rd as u32, value)
+}
+
+fn nop(_vm: &mut VM, _op: &MicroOp) {}
+
+fn add(vm: &mut VM, op: &MicroOp) {
+    set_rd(vm, op, rs1(vm, op).wrapping_add(rs2(vm, op)))
+}
+
+fn sub(vm: &mut VM, op: &MicroOp) {
+    set_rd(vm, op, rs1(vm, op).wrapping_sub(rs2(vm, op)))
+}
+
+fn slt(vm: &mut VM, op: &MicroOp) {
+    set_rd(vm, op, ((rs1(vm, op) as i32) < (rs2(vm, op) as i32)) as u32)
+}
+
+fn sltu(vm: &mut VM, op: &MicroOp) {
+    set_rd(vm, op, (rs1(vm, op) < rs2(vm, op)) as u32)
+}
+
+fn xor(vm: &mut VM, op: &MicroOp) {
+    set_rd(vm, op, rs1(vm, op) ^ rs2(vm, op))
+}
+
+fn or(vm: &mut VM, op: &MicroOp) {
+    set_rd(vm, op, rs1(vm, op) | rs2(vm, op))
+}
+
+fn and(vm: &mut VM, op: &MicroOp) {
+    set_rd(vm, op, rs1(vm, op) & rs2(vm, op))
+}
+
+fn sll(vm: &mut VM, op: &MicroOp) {
+    set_rd(vm, op, rs1(vm, op).wrapping_shl(rs2(vm, op)))
+}
+
+fn srl(vm: &mut VM, op: &MicroOp) {
+    set_rd(vm, op, rs1(vm, op).wrapping_shr(rs2(vm, op)))
+}
+
+fn sra(vm: &mut VM, op: &MicroOp) {
+    set_rd(
+        vm,
+        op,
+        (rs1(vm, op) as i32).wrapping_shr(rs2(vm, op)) as u32,
+    )
+}
+
+fn mul(vm: &mut VM, op: &MicroOp) {
+    set_rd(vm, op, rs1(vm, op).wrapping_mul(rs2(vm, op)))
+}
+
+fn mulh(vm: &mut VM, op: &MicroOp) {
+    set_rd(vm, op, utils::mulh(rs1(vm, op), rs2(vm, op)))
+}
+
+fn mulhsu(vm: &mut VM, op: &MicroOp) {
+    set_rd(vm, op, utils::mulhsu(rs1(vm, op), rs2(vm, op)))
+}
+
+fn mulhu(vm: &mut VM, op: &MicroOp) {
+    set_rd(vm, op, utils::mulhu(rs1(vm, op), rs2(vm, op)))
+}
+
+fn div(vm: &mut VM, op: &MicroOp) {
+    set_rd(vm, op, utils::div(rs1(vm, op), rs2(vm, op)))
+}
+
+fn divu(vm: &mut VM, op: &MicroOp) {
+    set_rd(vm, op, utils::divu(rs1(vm, op), rs2(vm, op)))
+}
+
+fn rem(vm: &mut VM, op: &MicroOp) {
+    set_rd(vm, op, utils::rem(rs1(vm, op), rs2(vm, op)))
+}
+
+fn remu(vm: &mut VM, op: &MicroOp) {
+    set_rd(vm, op, utils::remu(rs1(vm, op), rs2(vm, op)))
+}
+
+fn addi(vm: &mut VM, op: &MicroOp) {
+    set_rd(vm, op, rs1(vm, op).wrapping_add(op.imm))
+}
+
+fn slti(vm: &mut VM, op: &MicroOp) {
+    set_rd(vm, op, ((rs1(vm, op) as i32) < (op.imm as i32)) as u32)
+}
+
+fn sltiu(vm: &mut VM, op: &MicroOp) {
+    set_rd(vm, op, (rs1(vm, op) < op.imm) as u32)
+}
+
+fn xori(vm: &mut VM, op: &MicroOp) {
+    set_rd(vm, op, rs1(vm, op) ^ op.imm)
+}
+
+fn ori(vm: &mut VM, op: &MicroOp) {
+    set_rd(vm, op, rs1(vm, op) | op.imm)
+}
+
+fn andi(vm: &mut VM, op: &MicroOp) {
+    set_rd(vm, op, rs1(vm, op) & op.imm)
+}
+
+fn slli(vm: &mut VM, op: &MicroOp) {
+    set_rd(vm, op, rs1(vm, op).wrapping_shl(op.imm))
+}
+
+fn srli(vm: &mut VM, op: &MicroOp) {
+    set_rd(vm, op, rs1(vm, op).wrapping_shr(op.imm))
+}
+
+fn srai(vm: &mut VM, op: &MicroOp) {
+    set_rd(vm, op, (rs1(vm, op) as i32).wrapping_shr(op.imm) as u32)
+}
+
+// lui and auipc, the value is computed at translation time
+fn li(vm: &mut VM, op: &MicroOp) {
+    set_rd(vm, op, op.imm)
+}
+
+fn lb(vm: &mut VM, op: &MicroOp) {
+    let address = rs1(vm, op).wrapping_add(op.imm) as usize;
+    let Some(value) = vm.ram_read_u8(address) else {
+        return vm.leave_block();
+    };
+    set_rd_checked(vm, op, sign_extend_number(value as u32, 8))
+}
+
+fn lbu(vm: &mut VM, op: &MicroOp) {
+    let address = rs1(vm, op).wrapping_add(op.imm) as usize;
+    let Some(value) = vm.ram_read_u8(address) else {
+        return vm.leave_block();
+    };
+    set_rd_checked(vm, op, value as u32)
+}
+
+fn lh(vm: &mut VM, op: &MicroOp) {
+    let address = rs1(vm, op).wrapping_add(op.imm) as usize;
+    let Some(value) = vm.ram_read_u16(address) else {
+        return vm.leave_block();
+    };
+    set_rd_checked(vm, op, sign_extend_number(value as u32, 16))
+}
+
+fn lhu(vm: &mut VM, op: &MicroOp) {
+    let address = rs1(vm, op).wrapping_add(op.imm) as usize;
+    let Some(value) = vm.ram_read_u16(address) else {
+        return vm.leave_block();
+    };
+    set_rd_checked(vm, op, value as u32)
+}
+
+fn lw(vm: &mut VM, op: &MicroOp) {
+    let address = rs1(vm, op).wrapping_add(op.imm) as usize;
+    let Some(value) = vm.ram_read_u32(address) else {
+        return vm.leave_block();
+    };
+    set_rd_checked(vm, op, value)
+}
+
+fn sb(vm: &mut VM, op: &MicroOp) {
+    let address = rs1(vm, op).wrapping_add(op.imm) as usize;
+    if !vm.ram_write_u8(address, rs2(vm, op) as u8) {
+        vm.leave_block()
+    }
+}
+
+fn sh(vm: &mut VM, op: &MicroOp) {
+    let address = rs1(vm, op).wrapping_add(op.imm) as usize;
+    if !vm.ram_write_u16(address, rs2(vm, op) as u16) {
+        vm.leave_block()
+    }
+}
+
+fn sw(vm: &mut VM, op: &MicroOp) {
+    let address = rs1(vm, op).wrapping_add(op.imm) as usize;
+    if !vm.ram_write_u32(address, rs2(vm, op)) {
+        vm.leave_block()
+    }
+}
+
+// branches have their target resolved in imm
+fn branch(vm: &mut VM, op: &MicroOp, taken: bool) {
+    let target = if taken { op.imm } else { op.pc + 4 };
+    vm.pc.set_value(target)
+}
+
+fn beq(vm: &mut VM, op: &MicroOp) {
+    branch(vm, op, rs1(vm, op) == rs2(vm, op))
+}
+
+fn bne(vm: &mut VM, op: &MicroOp) {
+    branch(vm, op, rs1(vm, op) != rs2(vm, op))
+}
+
+fn blt(vm: &mut VM, op: &MicroOp) {
+    branch(vm, op, (rs1(vm, op) as i32) < (rs2(vm, op) as i32))
+}
+
+fn bge(vm: &mut VM, op: &MicroOp) {
+    branch(vm, op, (rs1(vm, op) as i32) >= (rs2(vm, op) as i32))
+}
+
+fn bltu(vm: &mut VM, op: &MicroOp) {
+    branch(vm, op, rs1(vm, op) < rs2(vm, op))
+}
+
+fn bgeu(vm: &mut VM, op: &MicroOp) {
+    branch(vm, op, rs1(vm, op) >= rs2(vm, op))
+}
+
+fn jal(vm: &mut VM, op: &MicroOp) {
+    set_rd_checked(vm, op, op.pc + 4);
+    vm.pc.set_value(op.imm)
+}
+
+fn jalr(vm: &mut VM, op: &MicroOp) {
+    // the source has to be read before rd is written, they can be the same register
+    let target = rs1(vm, op).wrapping_add(op.imm) & !1;
+    set_rd_checked(vm, op, op.pc + 4);
+    vm.pc.set_value(target)
+}
+
+fn fence_i(vm: &mut VM, op: &MicroOp) {
+    vm.flush_code_caches();
+    vm.pc.set_value(op.pc + 4)
+}
+
+// ecall, csr instructions, mret, sret, wfi and sfence.vma. The syscalls record their inputs at
+// the exact instruction count, which only the interpreter keeps
+fn system(vm: &mut VM, _op: &MicroOp) {
+    vm.leave_block()
+}
+
+fn micro_op(handler: MicroOpHandler, rd: u32, rs1: u32, rs2: u32, imm: u32, pc: u32) -> MicroOp {
+    MicroOp {
+        handler,
+        rd: rd as u8,
+        rs1: rs1 as u8,
+        rs2: rs2 as u8,
+        imm,
+        pc,
+        side_exit: false,
+    }
+}
+
+fn translate_r(opcode: &ROpcode, pc: u32) -> MicroOp {
+    let (handler, helper): (MicroOpHandler, _) = match opcode {
+        ROpcode::Add(helper) => (add, helper),
+        ROpcode::Sub(helper) => (sub, helper),
+        ROpcode::Slti(helper) => (slt, helper),
+        ROpcode::Sltu(helper) => (sltu, helper),
+        ROpcode::Xor(helper) => (xor, helper),
+        ROpcode::Or(helper) => (or, helper),
+        ROpcode::And(helper) => (and, helper),
+        ROpcode::Sll(helper) => (sll, helper),
+        ROpcode::Srl(helper) => (srl, helper),
+        ROpcode::Sra(helper) => (sra, helper),
+        ROpcode::Mul(helper) => (mul, helper),
+        ROpcode::Mulh(helper) => (mulh, helper),
+        ROpcode::Mulhsu(helper) => (mulhsu, helper),
+        ROpcode::Mulhu(helper) => (mulhu, helper),
+        ROpcode::Div(helper) => (div, helper),
+        ROpcode::Divu(helper) => (divu, helper),
+        ROpcode::Rem(helper) => (rem, helper),
+        ROpcode::Remu(helper) => (remu, helper),
+        ROpcode::Slli(helper) => {
+            let shamt = helper.get_shamt();
+            return micro_op(slli, helper.get_dest(), helper.get_src1(), 0, shamt, pc);
+        }
+        ROpcode::Srli(helper) => {
+            let shamt = helper.get_shamt();
+            return micro_op(srli, helper.get_dest(), helper.get_src1(), 0, shamt, pc);
+        }
+        ROpcode::Srai(helper) => {
+            let shamt = helper.get_shamt();
+            return micro_op(srai, helper.get_dest(), helper.get_src1(), 0, shamt, pc);
+        }
+    };
+
+    let (dest, src1, src2) = (helper.get_dest(), helper.get_src1(), helper.get_src2());
+    micro_op(handler, dest, src1, src2, 0, pc)
+}
+
+fn translate_i(opcode: &IOpcode, pc: u32) -> MicroOp {
+    let (handler, helper): (MicroOpHandler, _) = match opcode {
+        IOpcode::Addi(helper) => (addi, helper),
+        IOpcode::Slti(helper) => (slti, helper),
+        IOpcode::Sltiu(helper) => (sltiu, helper),
+        IOpcode::Xori(helper) => (xori, helper),
+        IOpcode::Ori(helper) => (ori, helper),
+        IOpcode::Andi(helper) => (andi, helper),
+        IOpcode::Lb(helper) => (lb, helper),
+        IOpcode::Lbu(helper) => (lbu, helper),
+        IOpcode::Lh(helper) => (lh, helper),
+        IOpcode::Lhu(helper) => (lhu, helper),
+        IOpcode::Lw(helper) => (lw, helper),
+        IOpcode::Jalr(helper) => (jalr, helper),
+    };
+
+    let is_load = matches!(
+        opcode,
+        IOpcode::Lb(_) | IOpcode::Lbu(_) | IOpcode::Lh(_) | IOpcode::Lhu(_) | IOpcode::Lw(_)
+    );
+
+    MicroOp {
+        side_exit: is_load,
+        ..micro_op(
+            handler,
+            helper.get_dst(),
+            helper.get_src(),
+            0,
+            helper.get_imm(),
+            pc,
+        )
+    }
+}
+
+fn translate_s(opcode: &SOpcode, pc: u32) -> MicroOp {
+    let (handler, helper): (MicroOpHandler, _) = match opcode {
+        SOpcode::Sb(helper) => (sb, helper),
+        SOpcode::Sh(helper) => (sh, helper),
+        SOpcode::Sw(helper) => (sw, helper),
+    };
+
+    MicroOp {
+        side_exit: true,
+        ..micro_op(
+            handler,
+            0,
+            helper.get_base(),
+            helper.get_src(),
+            helper.get_offset(),
+            pc,
+        )
+    }
+}
+
+fn translate_b(opcode: &BOpcode, pc: u32) -> MicroOp {
+    let (handler, helper): (MicroOpHandler, _) = match opcode {
+        BOpcode::Beq(helper) => (beq, helper),
+        BOpcode::Bne(helper) => (bne, helper),
+        BOpcode::Blt(helper) => (blt, helper),
+        BOpcode::Bge(helper) => (bge, helper),
+        BOpcode::Bltu(helper) => (bltu, helper),
+        BOpcode::Bgeu(helper) => (bgeu, helper),
+    };
+
+    let target = pc.wrapping_add(helper.get_offset());
+    micro_op(handler, 0, helper.get_src1(), helper.get_src2(), target, pc)
+}
+
+pub fn translate(instruction: &InstructionFormat, pc: u32) -> (MicroOp, Terminator) {
+    let (op, terminator) = match instruction {
+        InstructionFormat::R(opcode) => (translate_r(opcode, pc), Terminator::No),
+        InstructionFormat::I(opcode @ IOpcode::Jalr(_)) => {
+            (translate_i(opcode, pc), Terminator::Indirect)
+        }
+        InstructionFormat::I(opcode) => (translate_i(opcode, pc), Terminator::No),
+        InstructionFormat::S(opcode) => (translate_s(opcode, pc), Terminator::No),
+        InstructionFormat::B(opcode) => (translate_b(opcode, pc), Terminator::Direct),
+        InstructionFormat::U(UOpcode::Lui(helper)) => (
+            micro_op(li, helper.get_dest(), 0, 0, helper.get_imm(), pc),
+            Terminator::No,
+        ),
+        InstructionFormat::U(UOpcode::Auipc(helper)) => (
+            micro_op(
+                li,
+                helper.get_dest(),
+                0,
+                0,
+                pc.wrapping_add(helper.get_imm()),
+                pc,
+            ),
+            Terminator::No,
+        ),
+        InstructionFormat::J(JOpcode::Jal(helper)) => (
+            micro_op(
+                jal,
+                helper.get_dest(),
+                0,
+                0,
+                pc.wrapping_add(helper.get_offset()),
+                pc,
+            ),
+            Terminator::Direct,
+        ),
+        InstructionFormat::ECALL
+        | InstructionFormat::CSR(_)
+        | InstructionFormat::AMO(_)
+        | InstructionFormat::MRET
+        | InstructionFormat::SRET
+        | InstructionFormat::WFI
+        | InstructionFormat::SFENCEVMA(..) => (
+            MicroOp {
+                side_exit: true,
+                ..micro_op(system, 0, 0, 0, 0, pc)
+            },
+            Terminator::Indirect,
+        ),
+        InstructionFormat::FENCE => (micro_op(nop, 0, 0, 0, 0, pc), Terminator::No),
+        InstructionFormat::FENCEI => (micro_op(fence_i, 0, 0, 0, 0, pc), Terminator::Indirect),
+    };
+
+    // writes to x0 are discarded, loads still have to perform their access
+    let discardable = match instruction {
+        InstructionFormat::R(_) | InstructionFormat::U(_) => true,
+        InstructionFormat::I(opcode) => matches!(
+            opcode,
+            IOpcode::Addi(_)
+                | IOpcode::Slti(_)
+                | IOpcode::Sltiu(_)
+                | IOpcode::Xori(_)
+                | IOpcode::Ori(_)
+                | IOpcode::Andi(_)
+        ),
+        _ => false,
+    };
+
+    if discardable && op.rd == 0 {
+        return (micro_op(nop, 0, 0, 0, 0, pc), terminator);
+    }
+
+    (op, terminator)
+}
@@ -0,0 +1,390 @@
+// sv32 address translation and physical memory protection, the page tables are read and
+// updated in plain memory
+
+use crate::{
+    csr::{self, Exception, Privilege},
+    pmp::{PMP_R, PMP_W, PMP_X},
+    tlb::TlbEntry,
+};
+
+use super::VM;
+
+const PAGE_SIZE: u64 = 0x1000;
+
+// page table entry bits
+const PTE_V: u32 = 1 << 0;
+const PTE_R: u32 = 1 << 1;
+const PTE_W: u32 = 1 << 2;
+const PTE_X: u32 = 1 << 3;
+const PTE_U: u32 = 1 << 4;
+const PTE_G: u32 = 1 << 5;
+const PTE_A: u32 = 1 << 6;
+const PTE_D: u32 = 1 << 7;
+const PTE_PPN_SHIFT: u32 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Fetch,
+    Load,
+    Store,
+}
+
+impl Access {
+    fn page_fault(self, address: u32) -> Exception {
+        let cause = match self {
+            Access::Fetch => csr::CAUSE_INSTRUCTION_PAGE_FAULT,
+            Access::Load => csr::CAUSE_LOAD_PAGE_FAULT,
+            Access::Store => csr::CAUSE_STORE_PAGE_FAULT,
+        };
+        Exception::new(cause, address)
+    }
+
+    pub fn access_fault(self, address: u32) -> Exception {
+        let cause = match self {
+            Access::Fetch => csr::CAUSE_INSTRUCTION_ACCESS_FAULT,
+            Access::Load => csr::CAUSE_LOAD_ACCESS_FAULT,
+            Access::Store => csr::CAUSE_STORE_ACCESS_FAULT,
+        };
+        Exception::new(cause, address)
+    }
+}
+
+// an access crossing a page boundary is translated one byte at a time
+pub fn crosses_page(address: u32, nb_bytes: usize) -> bool {
+    (address as u64 % PAGE_SIZE) + nb_bytes as u64 > PAGE_SIZE
+}
+
+impl VM {
+    // privilege the access is checked with, mprv gives the loads and stores of machine mode the
+    // privilege in mpp
+    fn access_privilege(&self, access: Access) -> Privilege {
+        if access != Access::Fetch
+            && self.privilege == Privilege::Machine
+            && self.csrs.get_mstatus() & csr::MSTATUS_MPRV != 0
+        {
+            self.csrs.get_mpp()
+        } else {
+            self.privilege
+        }
+    }
+
+    // machine mode always uses physical addresses
+    pub(super) fn translates(&self, access: Access) -> bool {
+        self.csrs.get_satp() & csr::SATP_MODE != 0
+            && self.access_privilege(access) != Privilege::Machine
+    }
+
+    // physical address of the access, checked against the pmp. Faults are raised with the
+    // virtual address
+    pub(super) fn translate(
+        &mut self,
+        address: u32,
+        nb_bytes: usize,
+        access: Access,
+    ) -> Result<usize, Exception> {
+        let physical = if self.translates(access) {
+            self.translate_page(address, access)?
+        } else {
+            address as usize
+        };
+
+        let privilege = self.access_privilege(access);
+        if !self.pmp_allows(physical as u64, nb_bytes, access, privilege) {
+            return Err(access.access_fault(address));
+        }
+        Ok(physical)
+    }
+
+    fn pmp_allows(
+        &self,
+        physical: u64,
+        nb_bytes: usize,
+        access: Access,
+        privilege: Privilege,
+    ) -> bool {
+        let permission = match access {
+            Access::Fetch => PMP_X,
+            Access::Load => PMP_R,
+            Access::Store => PMP_W,
+        };
+        let machine = privilege == Privilege::Machine;
+        self.csrs
+            .get_pmp()
+            .check(physical, nb_bytes, permission, machine)
+    }
+
+    // the TLB is looked up first, the permissions of its entries are checked again on every
+    // access since the privilege, sum and mxr can change without a flush
+    fn translate_page(&mut self, address: u32, access: Access) -> Result<usize, Exception> {
+        let asid = (self.csrs.get_satp() & csr::SATP_ASID) >> csr::SATP_ASID_SHIFT;
+        let store = access == Access::Store;
+        let entry = match self.tlb.lookup(address >> 12, asid, store) {
+            Some(entry) => {
+                self.check_leaf(entry.pte, address, access)?;
+                entry
+            }
+            None => {
+                let entry = self.walk_page_table(address, access)?;
+                self.tlb.insert(entry);
+                entry
+            }
+        };
+
+        Ok((entry.page | address & 0xfff) as usize)
+    }
+
+    // the walk is done by the hart with supervisor privilege, a page table outside of plain
+    // memory or denied by the pmp is an access fault
+    fn read_pte(&self, pte_address: u64, access: Access, address: u32) -> Result<u32, Exception> {
+        if pte_address > u32::MAX as u64
+            || !self.pmp_allows(pte_address, 4, Access::Load, Privilege::Supervisor)
+        {
+            return Err(access.access_fault(address));
+        }
+
+        self.ram_read_u32(pte_address as usize)
+            .ok_or(access.access_fault(address))
+    }
+
+    fn check_leaf(&self, pte: u32, address: u32, access: Access) -> Result<(), Exception> {
+        let privilege = self.access_privilege(access);
+        let mstatus = self.csrs.get_mstatus();
+
+        let permitted = match access {
+            Access::Fetch => pte & PTE_X != 0,
+            Access::Load => pte & PTE_R != 0 || mstatus & csr::MSTATUS_MXR != 0 && pte & PTE_X != 0,
+            Access::Store => pte & PTE_W != 0,
+        };
+        // supervisor mode never executes user pages and only accesses them with sum
+        let user_page = pte & PTE_U != 0;
+        let privileged = match privilege {
+            Privilege::User => user_page,
+            Privilege::Supervisor => {
+                !user_page || access != Access::Fetch && mstatus & csr::MSTATUS_SUM != 0
+            }
+            Privilege::Machine => true,
+        };
+
+        if !permitted || !privileged {
+            return Err(access.page_fault(address));
+        }
+        Ok(())
+    }
+
+    fn walk_page_table(&mut self, address: u32, access: Access) -> Result<TlbEntry, Exception> {
+        let vpn = [address >> 12 & 0x3ff, address >> 22];
+
+        let mut table = (self.csrs.get_satp() & csr::SATP_PPN) as u64 * PAGE_SIZE;
+        let mut level = 1;
+        // a global pointer makes the whole subtree global
+        let mut global = false;
+        let (pte_address, pte) = loop {
+            let pte_address = table + vpn[level] as u64 * 4;
+            let pte = self.read_pte(pte_address, access, address)?;
+
+            // writable pages must be readable
+            if pte & PTE_V == 0 || pte & (PTE_R | PTE_W) == PTE_W {
+                return Err(access.page_fault(address));
+            }
+            global |= pte & PTE_G != 0;
+            if pte & (PTE_R | PTE_X) != 0 {
+                break (pte_address, pte);
+            }
+            if level == 0 {
+                return Err(access.page_fault(address));
+            }
+
+            level -= 1;
+            table = (pte >> PTE_PPN_SHIFT) as u64 * PAGE_SIZE;
+        };
+
+        self.check_leaf(pte, address, access)?;
+        let ppn = pte >> PTE_PPN_SHIFT;
+        // megapages are aligned on 4 MiB
+        if level == 1 && ppn & 0x3ff != 0 {
+            return Err(access.page_fault(address));
+        }
+
+        // accessed and dirty bits are set by the walk instead of raising page faults
+        let mut updated = pte | PTE_A;
+        if access == Access::Store {
+            updated |= PTE_D;
+        }
+        if updated != pte
+            && (!self.pmp_allows(pte_address, 4, Access::Store, Privilege::Supervisor)
+                || !self.ram_write_u32(pte_address as usize, updated))
+        {
+            return Err(access.access_fault(address));
+        }
+
+        // physical address of the 4 KiB page
+        let page = if level == 1 {
+            (ppn as u64 >> 10) << 22 | (address & 0x3ff000) as u64
+        } else {
+            (ppn as u64) << 12
+        };
+
+        // sv32 has 34-bit physical addresses, the bus decodes 32 bits
+        if page > u32::MAX as u64 {
+            return Err(access.access_fault(address));
+        }
+
+        Ok(TlbEntry {
+            vpn: address >> 12,
+            asid: (self.csrs.get_satp() & csr::SATP_ASID) >> csr::SATP_ASID_SHIFT,
+            global,
+            megapage: level == 1,
+            dirty: updated & PTE_D != 0,
+            pte: updated,
+            page: page as u32,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        csr::{PMPADDR0, PMPCFG0, SATP, SATP_MODE},
+        tlb::DEFAULT_TLB_WAYS,
+    };
+
+    const ROOT_TABLE: u32 = 0x8000_0000;
+    const LEAF_TABLE: u32 = 0x8000_1000;
+    const PAGE: u32 = 0x8000_2000;
+    const OTHER_PAGE: u32 = 0x8000_3000;
+    const MEGAPAGE: u32 = 0x8040_0000;
+
+    const VIRTUAL_PAGE: u32 = 0x0040_0000;
+    const VIRTUAL_MEGAPAGE: u32 = 0x0080_0000;
+
+    fn pte(physical: u32, flags: u32) -> u32 {
+        (physical >> 12) << PTE_PPN_SHIFT | flags
+    }
+
+    // supervisor mode with VIRTUAL_PAGE mapped to PAGE and VIRTUAL_MEGAPAGE to MEGAPAGE
+    fn sv32_vm(tlb_entries: usize) -> VM {
+        let mut vm = VM::new(vec![0; 4]);
+        let bus = vm.get_bus_mut();
+        bus.add_ram("ram", ROOT_TABLE as usize, vec![0; 0x4000])
+            .unwrap();
+        bus.add_ram("megapage", MEGAPAGE as usize, vec![0; 0x1000])
+            .unwrap();
+        vm.set_tlb(tlb_entries, DEFAULT_TLB_WAYS).unwrap();
+
+        let root = ROOT_TABLE as usize;
+        assert!(vm.ram_write_u32(
+            root + 4 * (VIRTUAL_PAGE >> 22) as usize,
+            pte(LEAF_TABLE, PTE_V)
+        ));
+        assert!(vm.ram_write_u32(
+            root + 4 * (VIRTUAL_MEGAPAGE >> 22) as usize,
+            pte(MEGAPAGE, PTE_V | PTE_R | PTE_W),
+        ));
+        assert!(vm.ram_write_u32(LEAF_TABLE as usize, pte(PAGE, PTE_V | PTE_R | PTE_W)));
+
+        // one entry granting everything, like a firmware handing the memory to supervisor mode
+        vm.csrs.write(PMPADDR0, u32::MAX);
+        vm.csrs.write(PMPCFG0, 0x1f);
+        vm.csrs.write(SATP, SATP_MODE | ROOT_TABLE >> 12);
+        vm.privilege = Privilege::Supervisor;
+        vm
+    }
+
+    fn leaf_pte(vm: &VM) -> u32 {
+        vm.ram_read_u32(LEAF_TABLE as usize).unwrap()
+    }
+
+    #[test]
+    fn walk_translates_pages_and_megapages() {
+        let mut vm = sv32_vm(0);
+
+        assert_eq!(
+            vm.translate(VIRTUAL_PAGE + 0x10, 4, Access::Load),
+            Ok(PAGE as usize + 0x10)
+        );
+        assert_eq!(
+            vm.translate(VIRTUAL_MEGAPAGE + 0x234, 4, Access::Load),
+            Ok(MEGAPAGE as usize + 0x234)
+        );
+
+        // the walk sets the accessed bit, and the dirty bit for stores
+        assert_eq!(leaf_pte(&vm) & (PTE_A | PTE_D), PTE_A);
+        vm.translate(VIRTUAL_PAGE, 4, Access::Store).unwrap();
+        assert_eq!(leaf_pte(&vm) & (PTE_A | PTE_D), PTE_A | PTE_D);
+    }
+
+    #[test]
+    fn walk_raises_page_faults() {
+        let mut vm = sv32_vm(0);
+
+        // unmapped page of the leaf table, and not executable
+        let unmapped = VIRTUAL_PAGE + 0x1000;
+        assert_eq!(
+            vm.translate(unmapped, 4, Access::Store),
+            Err(Exception::new(csr::CAUSE_STORE_PAGE_FAULT, unmapped))
+        );
+        assert_eq!(
+            vm.translate(VIRTUAL_PAGE, 4, Access::Fetch),
+            Err(Exception::new(
+                csr::CAUSE_INSTRUCTION_PAGE_FAULT,
+                VIRTUAL_PAGE
+            ))
+        );
+
+        // user mode can't access supervisor pages
+        vm.privilege = Privilege::User;
+        assert_eq!(
+            vm.translate(VIRTUAL_PAGE + 8, 4, Access::Load),
+            Err(Exception::new(csr::CAUSE_LOAD_PAGE_FAULT, VIRTUAL_PAGE + 8))
+        );
+    }
+
+    #[test]
+    fn tlb_keeps_translations_until_flushed() {
+        let mut vm = sv32_vm(16);
+
+        assert_eq!(
+            vm.translate(VIRTUAL_PAGE, 4, Access::Load),
+            Ok(PAGE as usize)
+        );
+        assert_eq!(vm.tlb.get_misses(), 1);
+        assert_eq!(
+            vm.translate(VIRTUAL_PAGE + 4, 4, Access::Load),
+            Ok(PAGE as usize + 4)
+        );
+        assert_eq!(vm.tlb.get_hits(), 1);
+
+        // a stale entry is used until sfence.vma flushes it
+        let remapped = pte(OTHER_PAGE, PTE_V | PTE_R | PTE_W | PTE_A | PTE_D);
+        assert!(vm.ram_write_u32(LEAF_TABLE as usize, remapped));
+        assert_eq!(
+            vm.translate(VIRTUAL_PAGE, 4, Access::Load),
+            Ok(PAGE as usize)
+        );
+
+        vm.tlb.flush(Some(VIRTUAL_PAGE), None);
+        assert_eq!(vm.tlb.get_flushes(), 1);
+        assert_eq!(
+            vm.translate(VIRTUAL_PAGE, 4, Access::Load),
+            Ok(OTHER_PAGE as usize)
+        );
+        assert_eq!(vm.tlb.get_misses(), 2);
+    }
+
+    #[test]
+    fn store_through_a_clean_entry_walks_again() {
+        let mut vm = sv32_vm(16);
+
+        vm.translate(VIRTUAL_PAGE, 4, Access::Load).unwrap();
+        assert_eq!(leaf_pte(&vm) & PTE_D, 0);
+        vm.translate(VIRTUAL_PAGE, 4, Access::Store).unwrap();
+        assert_eq!(vm.tlb.get_misses(), 2);
+        assert_ne!(leaf_pte(&vm) & PTE_D, 0);
+
+        // the dirty entry now serves both
+        vm.translate(VIRTUAL_PAGE, 4, Access::Store).unwrap();
+        vm.translate(VIRTUAL_PAGE, 4, Access::Load).unwrap();
+        assert_eq!(vm.tlb.get_hits(), 2);
+    }
+}
@@ -0,0 +1,119 @@
+// snapshots of the whole machine: the instruction count, the harts, memory, the interrupt
+// controllers and the devices. The configuration (entry point, boot registers, stop conditions,
+// caches, host backends) isn't part of them, a snapshot is restored on a machine configured like
+// the one it was taken on
+
+use crate::snapshot::{SnapshotReader, SnapshotWriter};
+
+use super::VM;
+
+impl VM {
+    // taken between two instructions, usually after start_execution returned
+    pub fn save_snapshot(&mut self) -> Vec<u8> {
+        let mut snapshot = SnapshotWriter::new();
+        snapshot.section("vm");
+        snapshot.write_u64(self.instret);
+        snapshot.write_u64(self.next_regular_tick);
+        snapshot.section("harts");
+        self.save_harts(&mut snapshot);
+        self.bus.save_state(&mut snapshot, self.instret);
+        snapshot.finish()
+    }
+
+    // replaces init_execution, start_execution resumes where the snapshot was taken. The machine
+    // is left half restored when the snapshot doesn't match it
+    pub fn restore_snapshot(&mut self, data: &[u8]) -> Result<(), String> {
+        let mut snapshot = SnapshotReader::new(data)?;
+        snapshot.section("vm")?;
+        self.instret = snapshot.read_u64()?;
+        self.next_regular_tick = snapshot.read_u64()?;
+        snapshot.section("harts")?;
+        self.restore_harts(&mut snapshot)?;
+        self.bus.restore_state(&mut snapshot, self.instret)?;
+        snapshot.finish()?;
+
+        self.exit_code = None;
+        self.resumed_stop = None;
+        self.leave_blocks = false;
+        // the code in memory changed
+        self.flush_code_caches();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        stop_conditions::{StopConditions, StopReason},
+        test_utils::*,
+        vm::VM,
+    };
+
+    const SP: u32 = 2;
+    const T0: u32 = 5;
+    const T1: u32 = 6;
+
+    // fills the stack with a sequence and exits with its sum
+    fn program() -> Vec<u32> {
+        let mut program = vec![
+            addi(T0, 0, 200),
+            addi(A1, 0, 0),
+            addi(SP, SP, -4),
+            add(T1, A1, T0),
+            sw(T1, SP, 0),
+            add(A1, A1, T1),
+            addi(T0, T0, -1),
+            bne(T0, 0, -20),
+        ];
+        program.extend(exit());
+        program
+    }
+
+    fn stop_after(instructions: u64) -> StopConditions {
+        let mut stop_conditions = StopConditions::new();
+        stop_conditions.set_max_instructions(instructions);
+        stop_conditions
+    }
+
+    #[test]
+    fn restored_machine_runs_like_the_original() {
+        let program = program();
+        let (reason, mut original) = run(&program, |vm| {
+            vm.set_stop_conditions(stop_after(500));
+        });
+        assert_eq!(reason, StopReason::InstructionLimit);
+        let snapshot = original.save_snapshot();
+
+        let mut restored = VM::new(flash(&program));
+        restored.restore_snapshot(&snapshot).unwrap();
+        assert_eq!(restored.get_instret(), 500);
+        assert_eq!(restored.get_pc(), original.get_pc());
+        assert_eq!(restored.get_registers(), original.get_registers());
+        // the snapshot of the restored machine is the same
+        assert_eq!(restored.save_snapshot(), snapshot);
+
+        for vm in [&mut original, &mut restored] {
+            vm.set_stop_conditions(StopConditions::new());
+        }
+        let reason = original.start_execution();
+        assert!(matches!(reason, StopReason::Exit(_)));
+        assert_eq!(restored.start_execution(), reason);
+        assert_eq!(restored.get_instret(), original.get_instret());
+        assert_eq!(restored.get_registers(), original.get_registers());
+
+        let sp = original.get_registers()[SP as usize] as usize;
+        assert_eq!(restored.read_n(sp, 800), original.read_n(sp, 800));
+    }
+
+    #[test]
+    fn snapshot_of_another_machine_is_rejected() {
+        let (_, mut vm) = run(&program(), |vm| vm.set_stop_conditions(stop_after(10)));
+        let snapshot = vm.save_snapshot();
+
+        let mut other = VM::without_memory();
+        assert!(other.restore_snapshot(&snapshot).is_err());
+        assert!(vm
+            .restore_snapshot(&snapshot[..snapshot.len() - 1])
+            .is_err());
+    }
+}
@@ -0,0 +1,256 @@
+use crate::{
+    bus::{Device, MachineRequest},
+    device_tree::DeviceTreeNode,
+    snapshot::{SnapshotReader, SnapshotWriter},
+    stop_conditions::StopReason,
+};
+
+// watchdog timer counting retired instructions, one instruction is one cycle. Once enabled it
+// must be kicked before the timeout runs out
+
+pub const WATCHDOG_ADDRESS: usize = 0x10080000;
+const WATCHDOG_SIZE: usize = 0x100;
+
+// register offsets
+const CTRL: usize = 0x00;
+const TIMEOUT: usize = 0x04;
+// any write reloads the counter
+const KICK: usize = 0x08;
+// instructions left before the expiry, read-only
+const COUNT: usize = 0x0c;
+const STATUS: usize = 0x10;
+
+const CTRL_ENABLE: u32 = 1;
+// the last reset was caused by the watchdog, writing 1 clears it
+const STATUS_RESET: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchdogAction {
+    // warm reset of the machine, the firmware finds STATUS_RESET set
+    Reset,
+    // non-maskable interrupt, raised again if the watchdog isn't kicked
+    Nmi,
+    // the execution stops with StopReason::WatchdogExpired
+    Stop,
+}
+
+pub struct Watchdog {
+    action: WatchdogAction,
+    // the watchdog is armed with it out of reset
+    reset_timeout: Option<u32>,
+    enabled: bool,
+    timeout: u32,
+    // instruction count of the expiry
+    deadline: u64,
+    reset_by_watchdog: bool,
+    request: Option<MachineRequest>,
+    // instruction count of the last access or tick, a reset doesn't get one
+    now: u64,
+}
+
+impl Watchdog {
+    pub fn new(action: WatchdogAction, reset_timeout: Option<u32>) -> Self {
+        let mut watchdog = Self {
+            action,
+            reset_timeout,
+            enabled: false,
+            timeout: 0,
+            deadline: 0,
+            reset_by_watchdog: false,
+            request: None,
+            now: 0,
+        };
+        watchdog.reset();
+        watchdog
+    }
+
+    fn kick(&mut self, instret: u64) {
+        self.deadline = instret + self.timeout as u64;
+    }
+
+    fn expire(&mut self, instret: u64) {
+        self.request = Some(match self.action {
+            WatchdogAction::Reset => {
+                self.reset_by_watchdog = true;
+                MachineRequest::Reset
+            }
+            WatchdogAction::Nmi => {
+                self.kick(instret);
+                MachineRequest::Nmi
+            }
+            WatchdogAction::Stop => {
+                self.enabled = false;
+                MachineRequest::Stop(StopReason::WatchdogExpired)
+            }
+        });
+    }
+}
+
+impl Device for Watchdog {
+    fn size(&self) -> usize {
+        WATCHDOG_SIZE
+    }
+
+    fn read(&mut self, offset: usize, _nb_bytes: usize, instret: u64) -> u32 {
+        self.now = instret;
+        match offset & !3 {
+            CTRL => self.enabled as u32,
+            TIMEOUT => self.timeout,
+            COUNT if self.enabled => self.deadline.saturating_sub(instret) as u32,
+            STATUS => self.reset_by_watchdog as u32,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, offset: usize, _nb_bytes: usize, value: u32, instret: u64) {
+        self.now = instret;
+        match offset & !3 {
+            CTRL => {
+                let enabled = value & CTRL_ENABLE != 0;
+                if enabled && !self.enabled {
+                    self.kick(instret);
+                }
+                self.enabled = enabled;
+            }
+            // takes effect at the next kick
+            TIMEOUT => self.timeout = value,
+            KICK => self.kick(instret),
+            STATUS if value & STATUS_RESET != 0 => self.reset_by_watchdog = false,
+            _ => {}
+        }
+    }
+
+    // the reset cause survives the reset it caused
+    fn reset(&mut self) {
+        self.enabled = self.reset_timeout.is_some();
+        self.timeout = self.reset_timeout.unwrap_or(0);
+        self.kick(self.now);
+        self.request = None;
+    }
+
+    fn tick(&mut self, instret: u64) {
+        self.now = instret;
+        if self.enabled && instret >= self.deadline {
+            self.expire(instret);
+        }
+    }
+
+    fn next_event(&self) -> Option<u64> {
+        self.enabled.then_some(self.deadline)
+    }
+
+    fn take_request(&mut self) -> Option<MachineRequest> {
+        self.request.take()
+    }
+
+    fn device_tree_node(&self) -> Option<DeviceTreeNode> {
+        let node = DeviceTreeNode::new("watchdog", &["riscv-emulator,watchdog"]);
+        Some(node)
+    }
+
+    fn save_state(&self, snapshot: &mut SnapshotWriter) {
+        snapshot.write_bool(self.enabled);
+        snapshot.write_u32(self.timeout);
+        snapshot.write_u64(self.deadline);
+        snapshot.write_bool(self.reset_by_watchdog);
+        snapshot.write_u64(self.now);
+    }
+
+    fn restore_state(&mut self, snapshot: &mut SnapshotReader) -> Result<(), String> {
+        self.enabled = snapshot.read_bool()?;
+        self.timeout = snapshot.read_u32()?;
+        self.deadline = snapshot.read_u64()?;
+        self.reset_by_watchdog = snapshot.read_bool()?;
+        self.now = snapshot.read_u64()?;
+        self.request = None;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        csr::{MCAUSE, MTVEC},
+        test_utils::*,
+        vm::VM,
+    };
+
+    const T0: u32 = 5;
+    const T1: u32 = 6;
+    const T2: u32 = 7;
+    const TIMEOUT_INSTRUCTIONS: u32 = 100;
+
+    // the program runs with a watchdog armed out of reset, with and without translated blocks
+    fn run_watched(program: &[u32], action: WatchdogAction) -> Vec<(StopReason, VM)> {
+        [false, true]
+            .into_iter()
+            .map(|blocks| {
+                run(program, |vm| {
+                    vm.set_block_cache(blocks);
+                    let watchdog = Watchdog::new(action, Some(TIMEOUT_INSTRUCTIONS));
+                    vm.get_bus_mut()
+                        .add_device("watchdog", WATCHDOG_ADDRESS, Box::new(watchdog), None)
+                        .unwrap();
+                })
+            })
+            .collect()
+    }
+
+    #[test]
+    fn reset_keeps_the_memory() {
+        // counts the boots in RAM, the third one exits with the count and the reset cause
+        let mut program = Vec::new();
+        program.extend(li(T0, 0xfffff000));
+        program.extend([
+            lw(A1, T0, 0),
+            addi(A1, A1, 1),
+            sw(A1, T0, 0),
+            addi(T1, 0, 3),
+        ]);
+        program.push(bne(A1, T1, 4 * 9));
+        program.extend(li(T0, WATCHDOG_ADDRESS as u32));
+        program.extend([
+            lw(T1, T0, STATUS as i32),
+            addi(T2, 0, 10),
+            mul(T1, T1, T2),
+            add(A1, A1, T1),
+        ]);
+        program.extend(exit());
+        program.push(jal(0, 0));
+
+        for (reason, vm) in run_watched(&program, WatchdogAction::Reset) {
+            assert_eq!(reason, StopReason::Exit(13));
+            assert!(vm.get_instret() > 2 * TIMEOUT_INSTRUCTIONS as u64);
+        }
+    }
+
+    #[test]
+    fn nmi_is_raised_again_until_the_watchdog_is_kicked() {
+        // the handler counts the NMIs, the third one exits with the count if mcause was right
+        let mut program = Vec::new();
+        program.extend(li(T0, address_of(4)));
+        program.extend([csrw(MTVEC, T0), jal(0, 0)]);
+        program.extend([addi(A1, A1, 1), addi(T1, 0, 3), bne(A1, T1, -12)]);
+        program.extend([csrr(T0, MCAUSE), lui(T1, 0x80000000), xor(T0, T0, T1)]);
+        program.push(add(A1, A1, T0));
+        program.extend(exit());
+
+        for (reason, vm) in run_watched(&program, WatchdogAction::Nmi) {
+            assert_eq!(reason, StopReason::Exit(3));
+            let instret = vm.get_instret();
+            assert!(
+                (3 * TIMEOUT_INSTRUCTIONS as u64..3 * TIMEOUT_INSTRUCTIONS as u64 + 20)
+                    .contains(&instret)
+            );
+        }
+    }
+
+    #[test]
+    fn stop_ends_the_execution_at_the_timeout() {
+        for (reason, vm) in run_watched(&[jal(0, 0)], WatchdogAction::Stop) {
+            assert_eq!(reason, StopReason::WatchdogExpired);
+            assert_eq!(vm.get_instret(), TIMEOUT_INSTRUCTIONS as u64);
+        }
+    }
+}